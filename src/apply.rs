@@ -0,0 +1,271 @@
+//! Bring NICs and their VLANs to the state of a whole config file, and remove
+//! again everything libtsn created.
+//!
+//! [`apply`] compares each NIC with the config recorded in the
+//! [registry](crate::registry) and only changes what differs, the same way as
+//! [`reconfigure`](crate::reconfigure()), so applying a file twice does
+//! nothing the second time. The VLANs it creates are pinned in the registry:
+//! closing their last socket does not delete them, and [`teardown`] knows they
+//! are libtsn's. VLANs that libtsn did not create are never deleted.
+
+use crate::config::Config;
+use crate::error::TsnError;
+use crate::netlink::Netlink;
+use crate::reconfigure::{self, Change};
+use crate::registry::{Registration, Registry};
+use crate::validate::{self, Diagnostic};
+use crate::vlan;
+use itertools::Itertools;
+use nix::net::if_::if_nametoindex;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// One step of [`apply`] or [`teardown`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum Operation {
+    CreateVlan {
+        ifname: String,
+        vlanid: u16,
+    },
+    DeleteVlan {
+        ifname: String,
+        vlanid: u16,
+    },
+    /// The VLAN is in use and left to its sockets, the last one to close
+    /// deletes it
+    ReleaseVlan {
+        ifname: String,
+        vlanid: u16,
+        users: usize,
+    },
+    /// The qdiscs or an egress-qos-map change, see [`reconfigure::diff`]
+    Update {
+        ifname: String,
+        #[serde(flatten)]
+        change: Change,
+    },
+    DeleteRoot {
+        ifname: String,
+    },
+}
+
+/// What [`apply`] did, or would do with `dry_run`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Applied {
+    pub operations: Vec<Operation>,
+    /// Warnings of the TAS schedules, by NIC
+    pub warnings: BTreeMap<String, Vec<Diagnostic>>,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::CreateVlan { ifname, vlanid } => {
+                write!(
+                    f,
+                    "{}: create vlan {}",
+                    ifname,
+                    vlan::get_vlan_name(ifname, *vlanid)
+                )
+            }
+            Operation::DeleteVlan { ifname, vlanid } => {
+                write!(
+                    f,
+                    "{}: delete vlan {}",
+                    ifname,
+                    vlan::get_vlan_name(ifname, *vlanid)
+                )
+            }
+            Operation::ReleaseVlan {
+                ifname,
+                vlanid,
+                users,
+            } => write!(
+                f,
+                "{}: leave vlan {} to the {} processes using it",
+                ifname,
+                vlan::get_vlan_name(ifname, *vlanid),
+                users
+            ),
+            Operation::Update { ifname, change } => write!(f, "{}: update {}", ifname, change),
+            Operation::DeleteRoot { ifname } => write!(f, "{}: delete root qdisc", ifname),
+        }
+    }
+}
+
+fn vlan_exists(ifname: &str, vlanid: u16) -> bool {
+    if_nametoindex(vlan::get_vlan_name(ifname, vlanid).as_str()).is_ok()
+}
+
+fn alive_users(reg: &Registration) -> usize {
+    reg.users.iter().filter(|user| user.is_alive()).count()
+}
+
+/// Delete the pinned VLANs of `regs` that are not in `keep`, or leave them to
+/// their sockets, and forget them.
+fn unpin(
+    registry: &Registry,
+    nl: &mut Netlink,
+    regs: &[&Registration],
+    keep: &[u16],
+    ops: &[Operation],
+) -> Result<(), TsnError> {
+    for op in ops {
+        if let Operation::DeleteVlan { ifname, vlanid } = op {
+            vlan::delete_link(nl, ifname, *vlanid)?;
+        }
+    }
+    for reg in regs
+        .iter()
+        .filter(|reg| reg.pinned && !keep.contains(&reg.vlanid))
+    {
+        registry.pin(&reg.ifname, reg.vlanid, false)?;
+    }
+    Ok(())
+}
+
+/// What [`unpin`] does, as operations.
+fn unpin_operations(regs: &[&Registration], keep: &[u16]) -> Vec<Operation> {
+    regs.iter()
+        .filter(|reg| reg.pinned && !keep.contains(&reg.vlanid))
+        .filter_map(|reg| {
+            let (ifname, vlanid) = (reg.ifname.clone(), reg.vlanid);
+            match alive_users(reg) {
+                // Already gone, only the registration is dropped
+                0 if !vlan_exists(&ifname, vlanid) => None,
+                0 => Some(Operation::DeleteVlan { ifname, vlanid }),
+                users => Some(Operation::ReleaseVlan {
+                    ifname,
+                    vlanid,
+                    users,
+                }),
+            }
+        })
+        .collect()
+}
+
+/// Bring `ifname` to `config`, and return what was, or with `dry_run` would
+/// be, done.
+fn apply_nic(
+    registry: &Registry,
+    regs: &[Registration],
+    ifname: &str,
+    config: &Config,
+    dry_run: bool,
+) -> Result<Vec<Operation>, TsnError> {
+    let regs: Vec<&Registration> = regs.iter().filter(|reg| reg.ifname == ifname).collect();
+    let wanted: Vec<u16> = config
+        .egress_qos_map
+        .keys()
+        .map(|vlanid| *vlanid as u16)
+        .sorted()
+        .collect();
+    let (existing, missing): (Vec<u16>, Vec<u16>) = wanted
+        .iter()
+        .partition(|vlanid| vlan_exists(ifname, **vlanid));
+
+    let mut ops = unpin_operations(&regs, &wanted);
+    let old = registry
+        .applied(ifname)?
+        .unwrap_or_else(|| Config::new(HashMap::new()));
+    let changes = reconfigure::diff(&old, config, &existing)?;
+    ops.extend(changes.iter().map(|change| Operation::Update {
+        ifname: ifname.to_string(),
+        change: change.clone(),
+    }));
+    ops.extend(missing.iter().map(|vlanid| Operation::CreateVlan {
+        ifname: ifname.to_string(),
+        vlanid: *vlanid,
+    }));
+    if dry_run {
+        return Ok(ops);
+    }
+
+    let mut nl = Netlink::open()?;
+    unpin(registry, &mut nl, &regs, &wanted, &ops)?;
+    vlan::apply_changes(&mut nl, ifname, &old, config, &changes)?;
+    for vlanid in &missing {
+        vlan::add_vlan(&mut nl, config, ifname, *vlanid)?;
+    }
+    // Also keep the existing VLANs that sockets created
+    for vlanid in &wanted {
+        if missing.contains(vlanid) || regs.iter().any(|reg| reg.vlanid == *vlanid) {
+            registry.pin(ifname, *vlanid, true)?;
+        }
+    }
+    registry.set_applied(ifname, Some(config))?;
+    Ok(ops)
+}
+
+/// Bring every NIC of `configs`, and every VLAN in its egress-qos-map, to its
+/// config. Pinned VLANs of these NICs that are no longer in their config are
+/// deleted, or left to their sockets when in use.
+///
+/// With `dry_run` nothing is changed, and the operations are only returned.
+pub fn apply(configs: &HashMap<String, Config>, dry_run: bool) -> Result<Applied, TsnError> {
+    let registry = Registry::lock()?;
+    let regs = registry.list()?;
+    let mut applied = Applied::default();
+    // Check all the schedules before changing anything
+    let mut resolved = Vec::new();
+    for (ifname, config) in configs.iter().sorted_by_key(|(ifname, _)| *ifname) {
+        let config = config.resolve(ifname)?;
+        let warnings = validate::check(&config, ifname)?;
+        if !warnings.is_empty() {
+            applied.warnings.insert(ifname.to_string(), warnings);
+        }
+        resolved.push((ifname, config));
+    }
+    for (ifname, config) in &resolved {
+        let ops = apply_nic(&registry, &regs, ifname, config, dry_run)?;
+        applied.operations.extend(ops);
+    }
+    Ok(applied)
+}
+
+/// Remove what libtsn created on `ifnames`, or on every NIC when empty: the
+/// pinned VLANs, and the qdiscs once no socket uses a VLAN of the NIC.
+///
+/// With `dry_run` nothing is changed, and the operations are only returned.
+pub fn teardown(ifnames: &[&str], dry_run: bool) -> Result<Vec<Operation>, TsnError> {
+    let registry = Registry::lock()?;
+    let regs = registry.list()?;
+    let nics: Vec<String> = registry
+        .applied_nics()?
+        .into_iter()
+        .chain(
+            regs.iter()
+                .filter(|reg| reg.pinned)
+                .map(|reg| reg.ifname.clone()),
+        )
+        .filter(|ifname| ifnames.is_empty() || ifnames.contains(&ifname.as_str()))
+        .sorted()
+        .dedup()
+        .collect();
+
+    let mut ops = Vec::new();
+    for ifname in &nics {
+        let regs: Vec<&Registration> = regs.iter().filter(|reg| reg.ifname == *ifname).collect();
+        let mut nic_ops = unpin_operations(&regs, &[]);
+        // The sockets still using a VLAN need the qdiscs
+        let in_use = regs.iter().any(|reg| alive_users(reg) > 0);
+        let applied = registry.applied(ifname)?.is_some();
+        if applied && !in_use {
+            nic_ops.push(Operation::DeleteRoot {
+                ifname: ifname.clone(),
+            });
+        }
+        if !dry_run {
+            let mut nl = Netlink::open()?;
+            unpin(&registry, &mut nl, &regs, &[], &nic_ops)?;
+            if applied && !in_use {
+                vlan::clear_root(&mut nl, ifname)?;
+                registry.set_applied(ifname, None)?;
+            }
+        }
+        ops.extend(nic_ops);
+    }
+    Ok(ops)
+}
@@ -0,0 +1,123 @@
+//! [`TsnSocket`] on the tokio reactor, with the `tokio` feature.
+//!
+//! The fd is made non-blocking and registered for readability, writability
+//! and priority, which is how the error queue of TX timestamps shows up once
+//! [`TsnSocket::enable_tx_timestamp`] has set `SO_SELECT_ERR_QUEUE`. Many
+//! sockets can then wait on one thread, instead of one thread each blocked
+//! in `recv` with `SO_RCVTIMEO`; use `tokio::time::timeout` for timeouts.
+
+use crate::rxtime::{self, RxMeta};
+use crate::{error::TsnError, time::Timespec, TsnSocket};
+use std::io::Error;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+/// An `AF_PACKET` [`TsnSocket`] whose I/O is `async`.
+pub struct AsyncTsnSocket {
+    inner: AsyncFd<TsnSocket>,
+}
+
+fn set_nonblocking(sock: &TsnSocket, nonblocking: bool) -> Result<(), TsnError> {
+    let flags = unsafe { libc::fcntl(sock.fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(TsnError::socket("fcntl F_GETFL"));
+    }
+    let flags = match nonblocking {
+        true => flags | libc::O_NONBLOCK,
+        false => flags & !libc::O_NONBLOCK,
+    };
+    if unsafe { libc::fcntl(sock.fd, libc::F_SETFL, flags) } < 0 {
+        return Err(TsnError::socket("fcntl F_SETFL"));
+    }
+    Ok(())
+}
+
+/// The result of a system call, or its error.
+fn cvt(res: isize) -> Result<isize, Error> {
+    if res < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(res)
+}
+
+impl AsyncTsnSocket {
+    /// Put `sock` on the reactor of the current tokio runtime, which must
+    /// have I/O enabled.
+    pub fn new(sock: TsnSocket) -> Result<AsyncTsnSocket, TsnError> {
+        sock.packet_socket("register")?;
+        set_nonblocking(&sock, true)?;
+        let interest = Interest::READABLE | Interest::WRITABLE | Interest::PRIORITY;
+        // The fd is only closed by dropping or closing the TsnSocket, which
+        // the AsyncFd owns until it is dropped or taken back with into_inner
+        let inner = unsafe { AsyncFd::register_with_interest(sock, interest) }.map_err(|e| {
+            TsnError::Socket {
+                op: "register",
+                source: e.into(),
+            }
+        })?;
+        Ok(AsyncTsnSocket { inner })
+    }
+
+    pub fn get_ref(&self) -> &TsnSocket {
+        self.inner.get_ref()
+    }
+
+    /// Take the socket off the reactor, blocking again.
+    pub fn into_inner(self) -> Result<TsnSocket, TsnError> {
+        let sock = self.inner.into_inner();
+        set_nonblocking(&sock, false)?;
+        Ok(sock)
+    }
+
+    /// Wait for `interest`, then run `f` until it does not block.
+    async fn io<R>(
+        &self,
+        interest: Interest,
+        op: &'static str,
+        mut f: impl FnMut(&TsnSocket) -> Result<R, Error>,
+    ) -> Result<R, TsnError> {
+        loop {
+            let mut guard = self
+                .inner
+                .ready(interest)
+                .await
+                .map_err(|source| TsnError::Socket { op, source })?;
+            match guard.try_io(|inner| f(inner.get_ref())) {
+                Ok(res) => return res.map_err(|source| TsnError::Socket { op, source }),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    pub async fn send(&self, buf: &[u8]) -> Result<isize, TsnError> {
+        self.io(Interest::WRITABLE, "send", |sock| {
+            cvt(unsafe { libc::send(sock.fd, buf.as_ptr() as *const libc::c_void, buf.len(), 0) })
+        })
+        .await
+    }
+
+    pub async fn recv(&self, buf: &mut [u8]) -> Result<isize, TsnError> {
+        self.io(Interest::READABLE, "recv", |sock| {
+            cvt(unsafe { libc::recv(sock.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) })
+        })
+        .await
+    }
+
+    /// Receive a frame and its [`RxMeta`], see
+    /// [`TsnSocket::recv_with_timestamp`].
+    pub async fn recv_with_timestamp(&self, buf: &mut [u8]) -> Result<(isize, RxMeta), TsnError> {
+        self.io(Interest::READABLE, "recvmsg", |sock| {
+            rxtime::recv_meta(sock.fd, buf)
+        })
+        .await
+    }
+
+    /// The TX timestamp of the next frame sent, see
+    /// [`TsnSocket::get_tx_timestamp`].
+    pub async fn tx_timestamp(&self) -> Result<Timespec, TsnError> {
+        self.io(Interest::PRIORITY, "recvmsg MSG_ERRQUEUE", |sock| {
+            crate::read_tx_timestamp(sock.fd)
+        })
+        .await
+    }
+}
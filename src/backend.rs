@@ -0,0 +1,86 @@
+//! Where the VLAN and qdisc requests built by [`vlan`](crate::vlan) go.
+//!
+//! `Netlink` sends them to the kernel. [`Recorder`]
+//! only keeps them, with the `ip`/`tc` command each one is equivalent to, which
+//! is what `tsn create --dry-run` prints and what the tests look at.
+
+use crate::error::TsnError;
+use crate::netlink::{Message, Netlink};
+use crate::vlan;
+
+/// Somewhere to send rtnetlink requests.
+pub trait Backend {
+    /// Index of interface `ifname`, `request` being the command that needs it.
+    fn ifindex(&mut self, ifname: &str, request: &str) -> Result<i32, TsnError>;
+
+    /// Carry out `msg`, described by the command `request`.
+    fn request(&mut self, request: &str, msg: &mut Message) -> Result<(), TsnError>;
+}
+
+impl Backend for Netlink {
+    fn ifindex(&mut self, ifname: &str, request: &str) -> Result<i32, TsnError> {
+        vlan::get_ifindex(ifname, request)
+    }
+
+    fn request(&mut self, request: &str, msg: &mut Message) -> Result<(), TsnError> {
+        Netlink::request(self, request, msg)
+    }
+}
+
+/// A request kept by [`Recorder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// The equivalent `ip` or `tc` command
+    pub command: String,
+    /// `RTM_NEWQDISC`, `RTM_NEWLINK`...
+    pub msg_type: u16,
+    /// The family header and the attributes, without the netlink header
+    pub payload: Vec<u8>,
+}
+
+/// A backend that changes nothing and keeps every request.
+///
+/// Interfaces are not looked up: each name gets an index of its own, from 1
+/// in the order they are first asked for, so that the NIC does not have to
+/// exist.
+#[derive(Debug, Default)]
+pub struct Recorder {
+    pub requests: Vec<Request>,
+    interfaces: Vec<String>,
+}
+
+impl Recorder {
+    pub fn new() -> Recorder {
+        Recorder::default()
+    }
+
+    /// The commands of the requests, in order.
+    pub fn commands(&self) -> Vec<&str> {
+        self.requests
+            .iter()
+            .map(|request| request.command.as_str())
+            .collect()
+    }
+}
+
+impl Backend for Recorder {
+    fn ifindex(&mut self, ifname: &str, _request: &str) -> Result<i32, TsnError> {
+        let index = match self.interfaces.iter().position(|name| name == ifname) {
+            Some(index) => index,
+            None => {
+                self.interfaces.push(ifname.to_string());
+                self.interfaces.len() - 1
+            }
+        };
+        Ok(index as i32 + 1)
+    }
+
+    fn request(&mut self, request: &str, msg: &mut Message) -> Result<(), TsnError> {
+        self.requests.push(Request {
+            command: request.to_string(),
+            msg_type: msg.msg_type(),
+            payload: msg.payload().to_vec(),
+        });
+        Ok(())
+    }
+}
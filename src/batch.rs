@@ -0,0 +1,165 @@
+//! Several frames per system call with `sendmmsg` and `recvmmsg`.
+//!
+//! [`send_batch`] sends a slice of frames, [`recv_batch`] fills as many
+//! [`RecvSlot`]s as there are frames waiting, after blocking for the first
+//! one. The slots are allocated once and reused from call to call.
+
+use crate::{error::TsnError, rxtime, time::Timespec, TsnSocket};
+use std::mem;
+
+/// The most messages the kernel takes in one call, `UIO_MAXIOV`
+const MAX_BATCH: usize = 1024;
+/// Room for `SO_TIMESTAMPING`, three timespecs, or `SO_TIMESTAMPNS`
+const CONTROL_LEN: usize = 128;
+
+/// A buffer for one frame of [`recv_batch`], and what came with the frame.
+#[derive(Debug, Clone)]
+pub struct RecvSlot {
+    buf: Vec<u8>,
+    control: Vec<u8>,
+    /// Length of the frame, which is larger than the buffer when truncated
+    pub len: usize,
+    /// MAC address the frame came from, zeros for a frame too short to
+    /// have one
+    pub source: [u8; 6],
+    /// When the frame was received, if RX timestamps are enabled on the
+    /// socket: the hardware one when there is one, else the kernel's
+    pub timestamp: Option<Timespec>,
+}
+
+impl RecvSlot {
+    /// A slot for frames of up to `capacity` bytes.
+    pub fn new(capacity: usize) -> RecvSlot {
+        RecvSlot {
+            buf: vec![0; capacity],
+            control: vec![0; CONTROL_LEN],
+            len: 0,
+            source: [0; 6],
+            timestamp: None,
+        }
+    }
+
+    /// The frame received in the slot, cut to the buffer.
+    pub fn frame(&self) -> &[u8] {
+        &self.buf[..self.len.min(self.buf.len())]
+    }
+
+    /// Whether the frame was larger than the buffer.
+    pub fn truncated(&self) -> bool {
+        self.len > self.buf.len()
+    }
+}
+
+/// Send `frames`, and return how many were sent.
+///
+/// Fewer than all of them are sent only when sending fails part way, the
+/// error is then returned by the next call. An error is returned when not
+/// even the first frame could be sent.
+pub fn send_batch(sock: &TsnSocket, frames: &[&[u8]]) -> Result<usize, TsnError> {
+    if let Some(xdp) = &sock.xdp {
+        return xdp.send_batch(sock.fd, frames);
+    }
+    let mut sent = 0;
+    while sent < frames.len() {
+        let chunk = &frames[sent..frames.len().min(sent + MAX_BATCH)];
+        let mut iovs: Vec<libc::iovec> = chunk
+            .iter()
+            .map(|frame| libc::iovec {
+                iov_base: frame.as_ptr() as *mut libc::c_void,
+                iov_len: frame.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovs
+            .iter_mut()
+            .map(|iov| {
+                let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_iov = iov;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg
+            })
+            .collect();
+        let res = unsafe { libc::sendmmsg(sock.fd, msgs.as_mut_ptr(), msgs.len() as u32, 0) };
+        if res < 0 {
+            if sent > 0 {
+                break;
+            }
+            return Err(TsnError::socket("sendmmsg"));
+        }
+        sent += res as usize;
+        if (res as usize) < chunk.len() {
+            break;
+        }
+    }
+    Ok(sent)
+}
+
+/// Wait for a frame, then fill `slots` with it and the frames already
+/// waiting, and return how many slots were filled.
+///
+/// The socket timeout, see [`TsnSocket::set_timeout`], applies to the wait.
+pub fn recv_batch(sock: &TsnSocket, slots: &mut [RecvSlot]) -> Result<usize, TsnError> {
+    if let Some(xdp) = &sock.xdp {
+        let mut slots = slots.iter_mut();
+        return xdp.recv(sock.fd, slots.len(), |frame| {
+            let slot = slots.next().unwrap();
+            let len = frame.len().min(slot.buf.len());
+            slot.buf[..len].copy_from_slice(&frame[..len]);
+            slot.len = frame.len();
+            // A runt has no whole source address
+            slot.source = frame
+                .get(6..12)
+                .map_or([0; 6], |source| source.try_into().unwrap());
+            slot.timestamp = None;
+        });
+    }
+    let count = slots.len().min(MAX_BATCH);
+    let slots = &mut slots[..count];
+    let mut addrs: Vec<libc::sockaddr_ll> = vec![unsafe { mem::zeroed() }; slots.len()];
+    let mut iovs: Vec<libc::iovec> = slots
+        .iter_mut()
+        .map(|slot| libc::iovec {
+            iov_base: slot.buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: slot.buf.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = slots
+        .iter_mut()
+        .zip(iovs.iter_mut())
+        .zip(addrs.iter_mut())
+        .map(|((slot, iov), addr)| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_ll>() as u32;
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg.msg_hdr.msg_control = slot.control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_controllen = {
+                // aarch64 has msg_controllen as u32, not usize
+                #[allow(clippy::useless_conversion)]
+                slot.control.len().try_into().unwrap()
+            };
+            msg
+        })
+        .collect();
+    // MSG_TRUNC makes the lengths those of the frames, not what was copied
+    let res = unsafe {
+        libc::recvmmsg(
+            sock.fd,
+            msgs.as_mut_ptr(),
+            msgs.len() as u32,
+            libc::MSG_WAITFORONE | libc::MSG_TRUNC,
+            std::ptr::null_mut(),
+        )
+    };
+    if res < 0 {
+        return Err(TsnError::socket("recvmmsg"));
+    }
+    let received = res as usize;
+    for ((slot, msg), addr) in slots.iter_mut().zip(&msgs).zip(&addrs).take(received) {
+        let meta = rxtime::rx_meta(&msg.msg_hdr, addr);
+        slot.len = msg.msg_len as usize;
+        slot.source = meta.source;
+        slot.timestamp = meta.timestamp();
+    }
+    Ok(received)
+}
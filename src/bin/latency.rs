@@ -1,12 +1,9 @@
 use std::collections::HashMap;
-use std::io::Error;
-use std::mem;
 use std::option::Option;
 use std::thread;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::vec::Vec;
 
-use nix::sys::socket::{cmsghdr, msghdr};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use rand::Rng;
@@ -21,7 +18,8 @@ use pnet_packet::{MutablePacket, Packet};
 use pnet::datalink::{self, NetworkInterface};
 use pnet::packet::ethernet::{EtherType, EthernetPacket, MutableEthernetPacket};
 use pnet::util::MacAddr;
-use tsn::time::tsn_time_sleep_until;
+use tsn::rxtime::RxTimestampMode;
+use tsn::time::{tsn_time_sleep_until, Timespec};
 
 extern crate socket as soc;
 
@@ -188,36 +186,19 @@ fn do_server(iface_name: String) {
     });
 
     let mut packet = [0u8; 1514];
-    let mut iov: libc::iovec = libc::iovec {
-        iov_base: packet.as_mut_ptr() as *mut libc::c_void,
-        iov_len: packet.len(),
-    };
-    let msg: Option<msghdr> = match enable_rx_timestamp(&sock, &mut iov) {
-        Ok(msg) => {
-            eprintln!("Socket RX timestamp enabled");
-            Some(msg)
-        }
-        Err(e) => {
-            eprintln!("Failed to set sock timestamp: {}", e);
-            None
-        }
-    };
+    let is_rx_ts_enabled = enable_rx_timestamp(&sock);
     let mut timestamps: HashMap<u32 /* id */, SystemTime /* ts */> = HashMap::new();
     while unsafe { RUNNING } {
         // TODO: Cleanup this code
-        let (mut rx_timestamp, mut eth_pkt) = match recv_perf_packet(&sock, msg, &mut packet) {
-            Some(value) => value,
-            None => continue,
-        };
+        let (rx_timestamp, mut eth_pkt) =
+            match recv_perf_packet(&sock, is_rx_ts_enabled, &mut packet) {
+                Some(value) => value,
+                None => continue,
+            };
         let mut perf_pkt = MutablePerfPacket::new(eth_pkt.payload_mut()).unwrap();
 
         match PerfOp::from_u8(perf_pkt.get_op()) {
             Some(PerfOp::Tx) => {
-                if let Some(msg) = msg {
-                    if let Ok(ts) = get_rx_timestamp(msg) {
-                        rx_timestamp = ts;
-                    }
-                }
                 let tx_id = perf_pkt.get_id();
                 timestamps.insert(tx_id, rx_timestamp);
             }
@@ -311,23 +292,7 @@ fn do_client(args: ClientArgs) {
     eth_pkt.set_ethertype(EtherType(ETHERTYPE_PERF));
 
     let mut rx_eth_buff = [0u8; 1514];
-    let mut iov: libc::iovec = libc::iovec {
-        iov_base: rx_eth_buff.as_mut_ptr() as *mut libc::c_void,
-        iov_len: rx_eth_buff.len(),
-    };
-    let msg: Option<msghdr> = match args.oneway {
-        true => None,
-        false => match enable_rx_timestamp(&sock, &mut iov) {
-            Ok(msg) => {
-                eprintln!("Set sock timestamp");
-                Some(msg)
-            }
-            Err(e) => {
-                eprintln!("Failed to set sock timestamp: {}", e);
-                None
-            }
-        },
-    };
+    let is_rx_ts_enabled = !args.oneway && enable_rx_timestamp(&sock);
     let mut timestamps: HashMap<u32 /* id */, SystemTime /* ts */> = HashMap::new();
 
     for ping_id in 1..=args.count {
@@ -355,7 +320,7 @@ fn do_client(args: ClientArgs) {
             let msg_ts = sock.get_tx_timestamp();
             match msg_ts {
                 Ok(ts) => {
-                    tx_timestamp = UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32);
+                    tx_timestamp = system_time(ts);
                 }
                 Err(e) => {
                     eprintln!("Failed to get TX timestamp: {}", e);
@@ -384,10 +349,11 @@ fn do_client(args: ClientArgs) {
             }
         } else {
             timestamps.insert(ping_id as u32, tx_timestamp);
-            let (rx_timestamp, rx_eth_pkt) = match recv_perf_packet(&sock, msg, &mut rx_eth_buff) {
-                Some(value) => value,
-                None => continue,
-            };
+            let (rx_timestamp, rx_eth_pkt) =
+                match recv_perf_packet(&sock, is_rx_ts_enabled, &mut rx_eth_buff) {
+                    Some(value) => value,
+                    None => continue,
+                };
 
             let pong_pkt = PerfPacket::new(rx_eth_pkt.payload()).unwrap();
             let pong_id = pong_pkt.get_id() as usize;
@@ -421,10 +387,11 @@ fn do_client(args: ClientArgs) {
 
     let wait_start = Instant::now();
     while !timestamps.is_empty() && wait_start.elapsed().as_secs() < TIMEOUT_SEC {
-        let (rx_timestamp, rx_eth_pkt) = match recv_perf_packet(&sock, msg, &mut rx_eth_buff) {
-            Some(value) => value,
-            None => continue,
-        };
+        let (rx_timestamp, rx_eth_pkt) =
+            match recv_perf_packet(&sock, is_rx_ts_enabled, &mut rx_eth_buff) {
+                Some(value) => value,
+                None => continue,
+            };
 
         let pong_pkt = PerfPacket::new(rx_eth_pkt.payload()).unwrap();
         let pong_id = pong_pkt.get_id() as usize;
@@ -447,41 +414,29 @@ fn do_client(args: ClientArgs) {
 
 fn recv_perf_packet<'a>(
     sock: &tsn::TsnSocket,
-    msg: Option<msghdr>,
+    is_rx_ts_enabled: bool,
     packet: &'a mut [u8; 1514],
 ) -> Option<(SystemTime, MutableEthernetPacket<'a>)> {
     let start = Instant::now();
     while start.elapsed().as_secs() < TIMEOUT_SEC {
-        let mut rx_timestamp;
-        let recv_bytes = {
-            match msg {
-                Some(mut msg) => {
-                    let res = unsafe { libc::recvmsg(sock.fd, &mut msg, 0) };
-                    rx_timestamp = SystemTime::now(); // Fallback default value
-                    if res == -1 {
-                        continue;
-                    } else if res == 0 {
-                        eprintln!("????");
-                        continue;
-                    }
-                    match get_rx_timestamp(msg) {
-                        Ok(ts) => rx_timestamp = ts,
-                        Err(_) => {
-                            eprintln!("Failed to get RX timestamp");
-                        }
+        let (recv_bytes, timestamp) = match is_rx_ts_enabled {
+            true => match sock.recv_with_timestamp(packet) {
+                Ok((size, meta)) => {
+                    if meta.timestamp().is_none() {
+                        eprintln!("Failed to get RX timestamp");
                     }
-                    res as usize
+                    (size as usize, meta.timestamp())
                 }
-                _ => match sock.recv(packet) {
-                    Ok(size) => {
-                        rx_timestamp = SystemTime::now();
-                        size as usize
-                    }
-                    Err(_) => {
-                        continue;
-                    }
-                },
-            }
+                Err(_) => continue,
+            },
+            false => match sock.recv(packet) {
+                Ok(size) => (size as usize, None),
+                Err(_) => continue,
+            },
+        };
+        let rx_timestamp = match timestamp {
+            Some(ts) => system_time(ts),
+            None => SystemTime::now(), // Fallback default value
         };
 
         let bytes = &packet[..recv_bytes];
@@ -513,76 +468,26 @@ fn print_latency(id: usize, rx_timestamp: SystemTime, tx_timestamp: SystemTime)
     );
 }
 
-fn enable_rx_timestamp(sock: &tsn::TsnSocket, iov: &mut libc::iovec) -> Result<msghdr, String> {
-    const CONTROLSIZE: usize = 1024;
-    let mut control: [libc::c_char; CONTROLSIZE] = [0; CONTROLSIZE];
-
-    let msg = msghdr {
-        msg_iov: iov,
-        msg_iovlen: 1,
-        msg_control: control.as_mut_ptr() as *mut libc::c_void,
-        msg_controllen: CONTROLSIZE,
-        msg_flags: 0,
-        msg_name: std::ptr::null_mut::<libc::c_void>(),
-        msg_namelen: 0,
-    };
-
-    let sockflags: u32 = libc::SOF_TIMESTAMPING_RX_HARDWARE
-        | libc::SOF_TIMESTAMPING_RAW_HARDWARE
-        | libc::SOF_TIMESTAMPING_SOFTWARE;
-
-    let res = unsafe {
-        libc::setsockopt(
-            sock.fd,
-            libc::SOL_SOCKET,
-            libc::SO_TIMESTAMPNS,
-            &sockflags as *const u32 as *const libc::c_void,
-            mem::size_of_val(&sockflags) as u32,
-        )
-    };
-
-    if res < 0 {
-        Err(format!(
-            "Cannot set socket timestamp: {}",
-            Error::last_os_error()
-        ))
-    } else {
-        Ok(msg)
-    }
-}
-
-fn get_rx_timestamp(msg: msghdr) -> Result<SystemTime, u32> {
-    let mut tend: libc::timespec = libc::timespec {
-        tv_sec: 0,
-        tv_nsec: 0,
-    };
-    let mut cmsg: *mut cmsghdr;
-
-    let mut cmsg_level;
-    let mut cmsg_type;
-    unsafe {
-        cmsg = libc::CMSG_FIRSTHDR(&msg);
-    }
-    while !cmsg.is_null() {
-        unsafe {
-            cmsg_level = (*cmsg).cmsg_level;
-            cmsg_type = (*cmsg).cmsg_type;
-            if cmsg_level != libc::SOL_SOCKET {
-                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
-                continue;
-            }
+/// RX timestamps from the NIC, or from the kernel when the NIC has none.
+fn enable_rx_timestamp(sock: &tsn::TsnSocket) -> bool {
+    let res = sock
+        .enable_rx_timestamp(RxTimestampMode::Hardware)
+        .or_else(|e| {
+            eprintln!("Failed to enable hardware RX timestamp: {}", e);
+            sock.enable_rx_timestamp(RxTimestampMode::Software)
+        });
+    match res {
+        Ok(()) => {
+            eprintln!("Socket RX timestamp enabled");
+            true
         }
-        if libc::SO_TIMESTAMPNS == cmsg_type {
-            unsafe {
-                libc::memcpy(
-                    &mut tend as *mut _ as *mut libc::c_void,
-                    libc::CMSG_DATA(cmsg) as *const libc::c_void,
-                    mem::size_of_val(&tend),
-                );
-            }
-            let time = UNIX_EPOCH + Duration::new(tend.tv_sec as u64, tend.tv_nsec as u32);
-            return Ok(time);
+        Err(e) => {
+            eprintln!("Failed to enable RX timestamp: {}", e);
+            false
         }
     }
-    Err(1)
+}
+
+fn system_time(ts: Timespec) -> SystemTime {
+    UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
 }
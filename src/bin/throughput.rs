@@ -11,13 +11,15 @@ use pnet::packet::ethernet::{EtherType, EthernetPacket, MutableEthernetPacket};
 use pnet::util::MacAddr;
 use pnet_macros::packet;
 use pnet_macros_support::types::u32be;
-use pnet_packet::Packet;
 use pnet_packet::PrimitiveValues;
+use pnet_packet::{MutablePacket, Packet};
 
 const VLAN_ID_PERF: u16 = 10;
 const VLAN_PRI_PERF: u32 = 3;
 const ETHERTYPE_PERF: u16 = 0x1337;
 const ETH_P_PERF: u16 = libc::ETH_P_ALL as u16; // FIXME: use ETHERTYPE_PERF
+/// Frames sent or received per system call
+const BATCH_SIZE: usize = 64;
 
 static mut RUNNING: bool = false;
 static mut TEST_RUNNING: bool = false;
@@ -89,7 +91,8 @@ fn main() {
     let server_command = Command::new("server")
         .about("Server mode")
         .short_flag('s')
-        .arg(arg!(interface: -i --interface <interface> "interface to use").required(true));
+        .arg(arg!(interface: -i --interface <interface> "interface to use").required(true))
+        .arg(arg!(ring: -r --ring "Receive through a PACKET_MMAP ring"));
 
     let client_command = Command::new("client")
         .about("Client mode")
@@ -105,7 +108,8 @@ fn main() {
             arg!(duration: -d --duration <duration>)
                 .required(false)
                 .default_value("10"),
-        );
+        )
+        .arg(arg!(ring: -r --ring "Send through a PACKET_MMAP ring"));
 
     let matched_command = Command::new("throughput")
         .author(crate_authors!())
@@ -119,7 +123,8 @@ fn main() {
     match matched_command.subcommand().unwrap() {
         ("server", server_matches) => {
             let iface = server_matches.value_of("interface").unwrap().to_string();
-            do_server(iface)
+            let ring = server_matches.is_present("ring");
+            do_server(iface, ring)
         }
         ("client", client_matches) => {
             let iface = client_matches.value_of("interface").unwrap().to_string();
@@ -130,14 +135,15 @@ fn main() {
                 .unwrap()
                 .parse()
                 .unwrap();
+            let ring = client_matches.is_present("ring");
 
-            do_client(iface, target, size, duration)
+            do_client(iface, target, size, duration, ring)
         }
         _ => panic!("Invalid command"),
     }
 }
 
-fn do_server(iface_name: String) {
+fn do_server(iface_name: String, ring: bool) {
     let interface_name_match = |iface: &NetworkInterface| iface.name == iface_name;
     let interfaces = datalink::interfaces();
     let interface = interfaces.into_iter().find(interface_name_match).unwrap();
@@ -165,103 +171,30 @@ fn do_server(iface_name: String) {
         }
     });
 
-    while unsafe { RUNNING } {
-        let mut packet = [0u8; 1514];
-        let packet_size;
-
-        match sock.recv(&mut packet) {
-            Ok(n) => packet_size = n as usize,
-            Err(_) => continue,
+    if ring {
+        let mut rx_ring = match sock.rx_ring(tsn::ring::RxRingConfig::default()) {
+            Ok(rx_ring) => rx_ring,
+            Err(e) => panic!("Failed to set up the RX ring: {}", e),
         };
-
-        let eth_pkt: EthernetPacket = EthernetPacket::new(&packet).unwrap();
-        if eth_pkt.get_ethertype() != EtherType(ETHERTYPE_PERF) {
-            continue;
-        }
-
-        let perf_pkt: PerfPacket = PerfPacket::new(eth_pkt.payload()).unwrap();
-
-        match perf_pkt.get_op() {
-            PerfOpFieldValues::ReqStart => {
-                println!("Received ReqStart");
-
-                if unsafe { TEST_RUNNING } {
-                    println!("Already running");
-                    continue;
-                }
-
-                let req_start: PerfStartReqPacket =
-                    PerfStartReqPacket::new(perf_pkt.payload()).unwrap();
-                let duration: Duration = Duration::from_secs(req_start.get_duration().into());
-
-                unsafe {
-                    STATS.duration = duration.as_secs() as usize;
-                    STATS.pkt_count = 0;
-                    STATS.total_bytes = 0;
-                    STATS.last_id = 0;
-                    TEST_RUNNING = true;
-                }
-
-                // Make thread for statistics
-                thread::spawn(stats_worker);
-
-                let mut perf_buffer = vec![0; 8];
-                let mut eth_buffer = vec![0; 14 + 8];
-
-                let mut perf_pkt = MutablePerfPacket::new(&mut perf_buffer).unwrap();
-                perf_pkt.set_id(perf_pkt.get_id());
-                perf_pkt.set_op(PerfOpFieldValues::ResStart);
-
-                let mut eth_pkt = MutableEthernetPacket::new(&mut eth_buffer).unwrap();
-                eth_pkt.set_destination(eth_pkt.get_source());
-                eth_pkt.set_source(my_mac);
-                eth_pkt.set_ethertype(EtherType(ETHERTYPE_PERF));
-
-                eth_pkt.set_payload(perf_pkt.packet());
-                if let Err(e) = sock.send(eth_pkt.packet()) {
-                    eprintln!("Failed to send packet: {}", e)
-                }
-            }
-            PerfOpFieldValues::Data => {
-                unsafe {
-                    STATS.last_id = perf_pkt.get_id();
-                    STATS.pkt_count += 1;
-                    STATS.total_bytes += packet_size + 4/* hidden VLAN tag */;
-                }
+        while unsafe { RUNNING } {
+            let block = match rx_ring.next_block(Some(Duration::from_secs(1))) {
+                Ok(Some(block)) => block,
+                _ => continue,
+            };
+            for frame in block.frames() {
+                handle_frame(&sock, my_mac, frame.data, frame.len);
             }
-            PerfOpFieldValues::ReqEnd => {
-                println!("Received ReqEnd");
-
-                unsafe { TEST_RUNNING = false }
-
-                let mut perf_buffer = vec![0; 8];
-                let mut eth_buffer = vec![0; 14 + 8];
-
-                let mut perf_pkt = MutablePerfPacket::new(&mut perf_buffer).unwrap();
-                perf_pkt.set_id(perf_pkt.get_id());
-                perf_pkt.set_op(PerfOpFieldValues::ResEnd);
-
-                let mut eth_pkt = MutableEthernetPacket::new(&mut eth_buffer).unwrap();
-                eth_pkt.set_destination(eth_pkt.get_source());
-                eth_pkt.set_source(my_mac);
-                eth_pkt.set_ethertype(EtherType(ETHERTYPE_PERF));
-
-                eth_pkt.set_payload(perf_pkt.packet());
-                if let Err(e) = sock.send(eth_pkt.packet()) {
-                    eprintln!("Failed to send packet: {}", e)
-                }
-
-                // Print statistics
-                unsafe {
-                    println!(
-                        "{} packets, {} bytes {} bps",
-                        STATS.pkt_count,
-                        STATS.total_bytes,
-                        STATS.total_bytes * 8 / STATS.duration
-                    );
-                }
+        }
+    } else {
+        let mut slots = vec![tsn::batch::RecvSlot::new(1514); BATCH_SIZE];
+        while unsafe { RUNNING } {
+            let received = match sock.recv_batch(&mut slots) {
+                Ok(n) => n,
+                Err(_) => continue,
+            };
+            for slot in &slots[..received] {
+                handle_frame(&sock, my_mac, slot.frame(), slot.len);
             }
-            _ => {}
         }
     }
 
@@ -271,7 +204,103 @@ fn do_server(iface_name: String) {
     }
 }
 
-fn do_client(iface_name: String, target: String, size: usize, duration: usize) {
+/// Count a data frame or answer a request, `packet_size` is the length of
+/// the frame on the wire, which `packet` may be cut from.
+fn handle_frame(sock: &tsn::TsnSocket, my_mac: MacAddr, packet: &[u8], packet_size: usize) {
+    let eth_pkt: EthernetPacket = match EthernetPacket::new(packet) {
+        Some(eth_pkt) => eth_pkt,
+        None => return,
+    };
+    if eth_pkt.get_ethertype() != EtherType(ETHERTYPE_PERF) {
+        return;
+    }
+
+    let perf_pkt: PerfPacket = PerfPacket::new(eth_pkt.payload()).unwrap();
+
+    match perf_pkt.get_op() {
+        PerfOpFieldValues::ReqStart => {
+            println!("Received ReqStart");
+
+            if unsafe { TEST_RUNNING } {
+                println!("Already running");
+                return;
+            }
+
+            let req_start: PerfStartReqPacket =
+                PerfStartReqPacket::new(perf_pkt.payload()).unwrap();
+            let duration: Duration = Duration::from_secs(req_start.get_duration().into());
+
+            unsafe {
+                STATS.duration = duration.as_secs() as usize;
+                STATS.pkt_count = 0;
+                STATS.total_bytes = 0;
+                STATS.last_id = 0;
+                TEST_RUNNING = true;
+            }
+
+            // Make thread for statistics
+            thread::spawn(stats_worker);
+
+            let mut perf_buffer = vec![0; 8];
+            let mut eth_buffer = vec![0; 14 + 8];
+
+            let mut perf_pkt = MutablePerfPacket::new(&mut perf_buffer).unwrap();
+            perf_pkt.set_id(perf_pkt.get_id());
+            perf_pkt.set_op(PerfOpFieldValues::ResStart);
+
+            let mut eth_pkt = MutableEthernetPacket::new(&mut eth_buffer).unwrap();
+            eth_pkt.set_destination(eth_pkt.get_source());
+            eth_pkt.set_source(my_mac);
+            eth_pkt.set_ethertype(EtherType(ETHERTYPE_PERF));
+
+            eth_pkt.set_payload(perf_pkt.packet());
+            if let Err(e) = sock.send(eth_pkt.packet()) {
+                eprintln!("Failed to send packet: {}", e)
+            }
+        }
+        PerfOpFieldValues::Data => {
+            unsafe {
+                STATS.last_id = perf_pkt.get_id();
+                STATS.pkt_count += 1;
+                STATS.total_bytes += packet_size + 4/* hidden VLAN tag */;
+            }
+        }
+        PerfOpFieldValues::ReqEnd => {
+            println!("Received ReqEnd");
+
+            unsafe { TEST_RUNNING = false }
+
+            let mut perf_buffer = vec![0; 8];
+            let mut eth_buffer = vec![0; 14 + 8];
+
+            let mut perf_pkt = MutablePerfPacket::new(&mut perf_buffer).unwrap();
+            perf_pkt.set_id(perf_pkt.get_id());
+            perf_pkt.set_op(PerfOpFieldValues::ResEnd);
+
+            let mut eth_pkt = MutableEthernetPacket::new(&mut eth_buffer).unwrap();
+            eth_pkt.set_destination(eth_pkt.get_source());
+            eth_pkt.set_source(my_mac);
+            eth_pkt.set_ethertype(EtherType(ETHERTYPE_PERF));
+
+            eth_pkt.set_payload(perf_pkt.packet());
+            if let Err(e) = sock.send(eth_pkt.packet()) {
+                eprintln!("Failed to send packet: {}", e)
+            }
+
+            // Print statistics
+            let stats = unsafe { &*std::ptr::addr_of!(STATS) };
+            println!(
+                "{} packets, {} bytes {} bps",
+                stats.pkt_count,
+                stats.total_bytes,
+                stats.total_bytes * 8 / stats.duration
+            );
+        }
+        _ => {}
+    }
+}
+
+fn do_client(iface_name: String, target: String, size: usize, duration: usize, ring: bool) {
     let interface_name_match = |iface: &NetworkInterface| iface.name == iface_name;
     let interfaces = datalink::interfaces();
     let interface = interfaces.into_iter().find(interface_name_match).unwrap();
@@ -334,30 +363,45 @@ fn do_client(iface_name: String, target: String, size: usize, duration: usize) {
 
     // Send data
     println!("Sending data");
-    let mut perf_buffer = vec![0; 8 + size];
     let mut eth_buffer = vec![0; 14 + 8 + size];
     let mut eth_pkt = MutableEthernetPacket::new(&mut eth_buffer).unwrap();
     eth_pkt.set_destination(target);
     eth_pkt.set_source(my_mac);
     eth_pkt.set_ethertype(EtherType(ETHERTYPE_PERF));
+    let mut frames = vec![eth_buffer; BATCH_SIZE];
+    let mut tx_ring = ring.then(|| match sock.tx_ring(tsn::ring::TxRingConfig::default()) {
+        Ok(tx_ring) => tx_ring,
+        Err(e) => panic!("Failed to set up the TX ring: {}", e),
+    });
 
     let now = Instant::now();
     let mut last_id = 0;
     loop {
-        let mut perf_pkt = MutablePerfPacket::new(&mut perf_buffer).unwrap();
-        perf_pkt.set_id(last_id); // TODO: Randomize
-        perf_pkt.set_op(PerfOpFieldValues::Data);
-
-        eth_pkt.set_payload(perf_pkt.packet());
-        if sock.send(eth_pkt.packet()).is_err() {}
+        for frame in frames.iter_mut() {
+            let mut eth_pkt = MutableEthernetPacket::new(frame).unwrap();
+            let mut perf_pkt = MutablePerfPacket::new(eth_pkt.payload_mut()).unwrap();
+            perf_pkt.set_id(last_id); // TODO: Randomize
+            perf_pkt.set_op(PerfOpFieldValues::Data);
+            last_id += 1;
+        }
 
-        last_id += 1;
+        let batch: Vec<&[u8]> = frames.iter().map(|frame| frame.as_slice()).collect();
+        match tx_ring.as_mut() {
+            Some(tx_ring) => {
+                let _ = tx_ring.send(&batch);
+            }
+            None => {
+                let _ = sock.send_batch(&batch);
+            }
+        }
 
         if now.elapsed().as_secs() > duration as u64 || !unsafe { RUNNING } {
             break;
         }
     }
 
+    drop(tx_ring);
+
     // Request end
     println!("Requesting end");
     let mut perf_buffer = vec![0; 8];
@@ -416,7 +460,7 @@ fn wait_for_response(sock: &mut tsn::TsnSocket, op: PerfOpField) -> Result<(), (
 }
 
 fn stats_worker() {
-    let stats = unsafe { &mut STATS };
+    let stats = unsafe { &mut *std::ptr::addr_of_mut!(STATS) };
     let mut last_id = 0;
     let mut last_bytes = 0;
     let mut last_packets = 0;
@@ -1,15 +1,20 @@
-use serde_yaml::{self, Value};
-use std::collections::HashMap;
-use std::process::Command;
+use crate::config::{check_prio, child, Issues};
+use crate::schema::{CbsStream, IntKey};
+use crate::tas::parse_digits;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::str;
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct CbsChild {
     pub prio: i64,
     pub max_frame: i64,
     pub bandwidth: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_interfering_frame: Option<i64>,
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CbsCredit {
     pub sendslope: i64,
     pub idleslope: i64,
@@ -17,194 +22,391 @@ pub struct CbsCredit {
     pub locredit: i64,
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct CbsConfig {
     pub tc_map: HashMap<i64, i64>,
     pub num_tc: i64,
-    pub queues: Vec<String>,
+    /// `(count, offset)` of the TX queues for each traffic class
+    pub queues: Vec<(u16, u16)>,
+    /// Credits by class id of the root qdisc, i.e. traffic class + 1
     pub children: HashMap<i64, CbsCredit>,
-    pub streams: HashMap<char, Vec<CbsChild>>,
+    /// Streams by SR class, from the highest, `'a'`
+    pub streams: BTreeMap<char, Vec<CbsChild>>,
+    pub credits: BTreeMap<char, CbsCredit>,
+    /// Link speed the credits are computed for, in bits per second, 0 until
+    /// the link of the NIC is known, see [`Config::resolve`](crate::config::Config::resolve)
+    #[serde(default)]
+    pub link_speed: i64,
 }
 
-pub fn get_linkspeed(ifname: &str) -> Result<String, String> {
-    let interface = interfaces::Interface::get_by_name(ifname);
-    if interface.is_err() || interface.unwrap().is_none() {
-        return Err(format!("Interface {} not found", ifname));
+impl CbsConfig {
+    /// Put the cbs classes on the traffic classes of another qdisc, e.g. taprio.
+    ///
+    /// All the prios of a cbs class must share one traffic class, which no
+    /// other prio uses.
+    pub fn set_tc_map(
+        &mut self,
+        tc_map: &HashMap<i64, i64>,
+        num_tc: i64,
+        queues: &[(u16, u16)],
+    ) -> Result<(), String> {
+        self.tc_map = tc_map.clone();
+        self.num_tc = num_tc;
+        self.queues = queues.to_vec();
+        self.assign_children()
     }
 
-    let output = Command::new("ethtool").arg(ifname).output();
-    match output {
-        Ok(output) => {
-            let out = str::from_utf8(&output.stdout).unwrap();
-            let pattern = regex::Regex::new(r"Speed: (?P<speed>\d+(?:|k|M|G)b[p/]?s)").unwrap();
-            match pattern.captures(out) {
-                Some(matched) => {
-                    return Ok(matched.name("speed").unwrap().as_str().to_string());
-                }
-                None => {
-                    return Err(format!("Speed cannot be found for {}", ifname));
+    /// Compute the credits again for a link of `link_speed`, e.g. after it
+    /// renegotiated.
+    pub fn set_link_speed(&mut self, link_speed: i64) -> Result<(), String> {
+        self.credits = calc_credits(&self.streams, link_speed)?;
+        self.link_speed = link_speed;
+        self.assign_children()
+    }
+
+    fn assign_children(&mut self) -> Result<(), String> {
+        let mut children = HashMap::new();
+        for (class, streams) in &self.streams {
+            let tcs: Vec<i64> = streams
+                .iter()
+                .map(|stream| self.tc_map[&stream.prio])
+                .unique()
+                .collect();
+            let tc = match tcs[..] {
+                [] => continue,
+                [tc] => tc,
+                _ => {
+                    return Err(format!(
+                        "cbs class {} spans traffic classes {:?}",
+                        class, tcs
+                    ))
                 }
+            };
+            let shared = self.tc_map.iter().any(|(prio, other)| {
+                *other == tc && !streams.iter().any(|stream| stream.prio == *prio)
+            });
+            if shared {
+                return Err(format!(
+                    "traffic class {} of cbs class {} is shared with other prios",
+                    tc, class
+                ));
+            }
+            // No credits yet while the link speed is unknown
+            if let Some(credit) = self.credits.get(class) {
+                children.insert(tc + 1, credit.clone());
             }
         }
-        Err(e) => Err(e.to_string()),
+        self.children = children;
+        Ok(())
     }
 }
-pub fn to_bits(input: &Value) -> Result<i64, String> {
-    if let Some(value) = input.as_str() {
-        let matched =
-            regex::Regex::new(r"^(?P<v>[\d_]+)\s*(?P<modifier>|k|M|G|ki|Mi|Gi)(?P<b>b|B)$")
-                .unwrap()
-                .captures(value)
-                .unwrap();
-        let v = matched.name("v").unwrap().as_str().parse::<i64>().unwrap();
-        let modifier = matched.name("modifier").unwrap().as_str();
-        let b = matched.name("b").unwrap().as_str();
-        let multiplier_bits = match b {
-            "b" => 1,
-            "B" => 8,
-            _ => unreachable!(),
-        };
-        let multiplier_modifier = match modifier {
-            "" => 1,
-            "k" => 1000,
-            "M" => 1000 * 1000,
-            "G" => 1000 * 1000 * 1000,
-            "ki" => 1024,
-            "Mi" => 1024 * 1024,
-            "Gi" => 1024 * 1024 * 1024,
-            _ => unreachable!(),
-        };
-        return Ok(v * multiplier_bits * multiplier_modifier);
-    }
-    Ok(input.as_i64().unwrap())
+
+pub fn to_bits(value: &str) -> Result<i64, String> {
+    let matched = regex::Regex::new(r"^(?P<v>[\d_]+)\s*(?P<modifier>|k|M|G|ki|Mi|Gi)(?P<b>b|B)$")
+        .unwrap()
+        .captures(value)
+        .ok_or_else(|| format!("'{}' is not a size, e.g. 1522B", value))?;
+    let v = parse_digits(&matched["v"])?;
+    let multiplier_bits = match &matched["b"] {
+        "b" => 1,
+        "B" => 8,
+        _ => unreachable!(),
+    };
+    let multiplier_modifier = match &matched["modifier"] {
+        "" => 1,
+        "k" => 1000,
+        "M" => 1000 * 1000,
+        "G" => 1000 * 1000 * 1000,
+        "ki" => 1024,
+        "Mi" => 1024 * 1024,
+        "Gi" => 1024 * 1024 * 1024,
+        _ => unreachable!(),
+    };
+    v.checked_mul(multiplier_bits * multiplier_modifier)
+        .ok_or_else(|| format!("'{}' is too large", value))
 }
 
-pub fn to_bps(input: &Value) -> Result<i64, String> {
-    if let Some(value) = input.as_str() {
-        let matched = regex::Regex::new(r"^(?P<v>[\d_]+)\s*(?P<modifier>|k|M|G)(?P<b>b|B)[p/]s$")
-            .unwrap()
-            .captures(value)
-            .unwrap();
-        let v = matched.name("v").unwrap().as_str().parse::<i64>().unwrap();
-        let modifier = matched.name("modifier").unwrap().as_str();
-        return {
-            match modifier {
-                "" => Ok(v),
-                "k" => Ok(v * 1000),
-                "M" => Ok(v * 1000 * 1000),
-                "G" => Ok(v * 1000 * 1000 * 1000),
-                _ => Err(format!("{} is not valid bandwidth", value)),
-            }
-        };
-    }
-    Ok(input.as_i64().unwrap())
+pub fn to_bps(value: &str) -> Result<i64, String> {
+    let matched = regex::Regex::new(r"^(?P<v>[\d_]+)\s*(?P<modifier>|k|M|G)(?P<b>b|B)[p/]s$")
+        .unwrap()
+        .captures(value)
+        .ok_or_else(|| format!("'{}' is not a bandwidth, e.g. 30Mbps", value))?;
+    let v = parse_digits(&matched["v"])?;
+    let multiplier_bits = match &matched["b"] {
+        "b" => 1,
+        "B" => 8,
+        _ => unreachable!(),
+    };
+    let multiplier_modifier: i64 = match &matched["modifier"] {
+        "" => 1,
+        "k" => 1000,
+        "M" => 1000 * 1000,
+        "G" => 1000 * 1000 * 1000,
+        _ => unreachable!(),
+    };
+    v.checked_mul(multiplier_bits * multiplier_modifier)
+        .ok_or_else(|| format!("'{}' is too large", value))
 }
 
+/// The credits of each SR class, after 802.1Q Annex L.
+///
+/// A frame of a class waits for at most one interfering frame, then for a
+/// frame of each class above it, whose credits grow meanwhile:
+///
+/// - hicredit = idleslope × (interfering / (link − idleslopes above)
+///   \+ max_frames above / link)
+/// - locredit = sendslope × max_frame / link
+///
+/// `max_frame` of a class is the sum of its streams', and the interfering
+/// frame is its `max_frame` unless set. The classes together must leave
+/// some of the link to the others.
 pub fn calc_credits(
-    streams: &HashMap<char, Vec<CbsChild>>,
+    streams: &BTreeMap<char, Vec<CbsChild>>,
     linkspeed: i64,
-) -> (CbsCredit, CbsCredit) {
-    let mut idle_slope_a = 0;
-    let mut max_frame_a = 0;
-    for stream in streams.get(&'a').unwrap() {
-        idle_slope_a += stream.bandwidth;
-        max_frame_a += stream.max_frame;
-    }
-    let send_slope_a = idle_slope_a - linkspeed;
-    let hicredit_a = f64::ceil(idle_slope_a as f64 * max_frame_a as f64 / linkspeed as f64) as i64;
-    let locredit_a = f64::ceil(send_slope_a as f64 * max_frame_a as f64 / linkspeed as f64) as i64;
-    let credits_a = CbsCredit {
-        sendslope: f64::floor(send_slope_a as f64 / 1000.0) as i64,
-        idleslope: f64::floor(idle_slope_a as f64 / 1000.0) as i64,
-        hicredit: hicredit_a,
-        locredit: locredit_a,
-    };
-
-    let mut idle_slope_b = 0;
-    let mut max_frame_b = 0;
-    for stream in streams.get(&'b').unwrap() {
-        idle_slope_b += stream.bandwidth;
-        max_frame_b += stream.max_frame;
-    }
-    let send_slope_b = idle_slope_b - linkspeed;
-    let hicredit_b = f64::ceil(
-        idle_slope_b as f64
-            * ((max_frame_b as f64 / (linkspeed - idle_slope_a) as f64)
-                + (max_frame_a as f64 / linkspeed as f64)),
-    ) as i64;
-    let locredit_b = f64::ceil(send_slope_b as f64 * max_frame_b as f64 / linkspeed as f64) as i64;
-    let credits_b = CbsCredit {
-        sendslope: f64::floor(send_slope_b as f64 / 1000.0) as i64,
-        idleslope: f64::floor(idle_slope_b as f64 / 1000.0) as i64,
-        hicredit: hicredit_b,
-        locredit: locredit_b,
-    };
-    (credits_a, credits_b)
+) -> Result<BTreeMap<char, CbsCredit>, String> {
+    let mut credits = BTreeMap::new();
+    let mut idle_slope_above = 0;
+    let mut max_frame_above = 0;
+    for (class, streams) in streams.iter().filter(|(_, streams)| !streams.is_empty()) {
+        let idle_slope: i64 = streams.iter().map(|stream| stream.bandwidth).sum();
+        let max_frame: i64 = streams.iter().map(|stream| stream.max_frame).sum();
+        let interfering = streams
+            .iter()
+            .filter_map(|stream| stream.max_interfering_frame)
+            .max()
+            .unwrap_or(max_frame);
+        if idle_slope_above + idle_slope >= linkspeed {
+            return Err(format!(
+                "classes up to {} reserve {}bps of a {}bps link",
+                class,
+                idle_slope_above + idle_slope,
+                linkspeed
+            ));
+        }
+        let send_slope = idle_slope - linkspeed;
+        let hicredit = f64::ceil(
+            idle_slope as f64
+                * ((interfering as f64 / (linkspeed - idle_slope_above) as f64)
+                    + (max_frame_above as f64 / linkspeed as f64)),
+        ) as i64;
+        let locredit = f64::ceil(send_slope as f64 * max_frame as f64 / linkspeed as f64) as i64;
+        credits.insert(
+            *class,
+            CbsCredit {
+                sendslope: f64::floor(send_slope as f64 / 1000.0) as i64,
+                idleslope: f64::floor(idle_slope as f64 / 1000.0) as i64,
+                hicredit,
+                locredit,
+            },
+        );
+        idle_slope_above += idle_slope;
+        max_frame_above += max_frame;
+    }
+    Ok(credits)
 }
 
-pub fn normalise_cbs(ifname: &str, config: &Value) -> Result<CbsConfig, String> {
+/// The cbs classes of `config`, with credits for `link_speed`. Without it
+/// they wait for the link of the NIC, see [`Config::resolve`](crate::config::Config::resolve).
+pub fn normalise_cbs(
+    issues: &mut Issues,
+    path: &str,
+    config: &BTreeMap<IntKey, CbsStream>,
+    link_speed: Option<i64>,
+) -> Option<CbsConfig> {
+    let before = issues.len();
     let mut tc_map = HashMap::new();
     let mut ret_map = HashMap::new();
-    let link = get_linkspeed(ifname);
-    let mut streams = HashMap::new();
-    let mut children: HashMap<i64, CbsCredit> = HashMap::new();
-    let mut queues: Vec<String> = Vec::new();
-    streams.insert('a', Vec::new());
-    streams.insert('b', Vec::new());
-    let linkspeed: i64 = match link {
-        Ok(speed) => to_bps(&Value::String(speed))?,
-        Err(_) => 1_000_000_000, // 1000Mbps
-    };
-    for (prio, priomap) in config.as_mapping().unwrap() {
-        if !tc_map.contains_key(&prio.as_i64().unwrap()) {
-            tc_map.insert(prio.as_i64().unwrap(), tc_map.len() as i64);
+    let mut streams: BTreeMap<char, Vec<CbsChild>> = BTreeMap::new();
+    let mut queues: Vec<(u16, u16)> = Vec::new();
+    for (prio, stream) in config {
+        let path = child(path, &prio.to_string());
+        if let Some(prio) = issues.check(&path, check_prio(prio.0)) {
+            streams
+                .entry(stream.class.name())
+                .or_default()
+                .push(CbsChild {
+                    prio,
+                    max_frame: stream.max_frame.0,
+                    bandwidth: stream.bandwidth.0,
+                    max_interfering_frame: stream.max_interfering_frame.map(|bits| bits.0),
+                });
         }
-        let child = CbsChild {
-            prio: prio.as_i64().unwrap(),
-            max_frame: to_bits(
-                priomap
-                    .get(&Value::String("max_frame".to_string()))
-                    .expect("max_frame should be present"),
-            )?,
-            bandwidth: to_bps(
-                priomap
-                    .get(&Value::String("bandwidth".to_string()))
-                    .expect("bandwidth should be present"),
-            )?,
-        };
-        let index = priomap
-            .get(Value::String("class".to_string()).as_str().unwrap())
-            .expect("class should be present")
-            .as_str()
-            .expect("class should be a string")
-            .chars()
-            .next()
-            .expect("Cannot parse class");
-        streams
-            .get_mut(&index)
-            .expect("Cannot parse cbs class")
-            .push(child);
-    }
-    tc_map.insert(-1, tc_map.len() as i64);
-    let num_tc = tc_map.len() as i64;
-    for i in 0..16 {
-        if tc_map.contains_key(&i) {
-            ret_map.insert(i, *tc_map.get(&i).unwrap());
-        } else {
-            ret_map.insert(i, *tc_map.get(&-1).unwrap());
+    }
+    if issues.len() > before {
+        return None;
+    }
+    // One traffic class per cbs class, the rest is best effort
+    for class_streams in streams.values() {
+        let tc = tc_map.values().unique().count() as i64;
+        for stream in class_streams {
+            tc_map.insert(stream.prio, tc);
         }
     }
-    let (credits_a, credits_b) = calc_credits(&streams, linkspeed);
-    children.insert(1, credits_a);
-    children.insert(2, credits_b);
+    let num_tc = tc_map.values().unique().count() as i64 + 1;
+    for i in 0..16 {
+        ret_map.insert(i, *tc_map.get(&i).unwrap_or(&(num_tc - 1)));
+    }
+    let credits = match link_speed {
+        Some(speed) => issues.check(path, calc_credits(&streams, speed))?,
+        None => BTreeMap::new(),
+    };
     for i in 0..num_tc {
-        queues.push(format!("1@{}", i));
+        queues.push((1, i as u16));
     }
-    Ok(CbsConfig {
+    let mut cbs = CbsConfig {
         tc_map: ret_map,
         num_tc,
         queues,
-        children,
+        children: HashMap::new(),
         streams,
-    })
+        credits,
+        link_speed: link_speed.unwrap_or(0),
+    };
+    let res = cbs.assign_children();
+    issues.check(path, res)?;
+    Some(cbs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    use crate::schema::Format;
+
+    fn child(prio: i64, max_frame: i64, bandwidth: i64) -> CbsChild {
+        CbsChild {
+            prio,
+            max_frame,
+            bandwidth,
+            max_interfering_frame: None,
+        }
+    }
+
+    #[test]
+    fn sizes_and_bandwidths() {
+        assert_eq!(to_bits("1522B"), Ok(1522 * 8));
+        assert_eq!(to_bits("12176b"), Ok(12176));
+        assert_eq!(to_bits("1kiB"), Ok(8192));
+        assert!(to_bits("1522").is_err());
+        assert_eq!(to_bps("30Mbps"), Ok(30_000_000));
+        assert_eq!(to_bps("70000kbps"), Ok(70_000_000));
+        assert_eq!(to_bps("1Gb/s"), Ok(1_000_000_000));
+        assert_eq!(to_bps("1MBps"), Ok(8_000_000));
+        assert!(to_bps("30M").is_err());
+    }
+
+    fn slopes_and_credits(credit: &CbsCredit) -> (i64, i64, i64, i64) {
+        (
+            credit.idleslope,
+            credit.sendslope,
+            credit.hicredit,
+            credit.locredit,
+        )
+    }
+
+    #[test]
+    fn credits() {
+        let streams = BTreeMap::from([
+            ('a', vec![child(3, 4096, 70_000_000)]),
+            ('b', vec![child(2, 4096, 30_000_000)]),
+        ]);
+        let credits = calc_credits(&streams, 1_000_000_000).unwrap();
+        assert_eq!(
+            slopes_and_credits(&credits[&'a']),
+            (70_000, -930_000, 287, -3809)
+        );
+        // Class B also waits for a frame of class A
+        assert_eq!(
+            slopes_and_credits(&credits[&'b']),
+            (30_000, -970_000, 256, -3973)
+        );
+    }
+
+    #[test]
+    fn credits_of_any_classes() {
+        // Only class B
+        let streams = BTreeMap::from([('b', vec![child(2, 4096, 30_000_000)])]);
+        let credits = calc_credits(&streams, 1_000_000_000).unwrap();
+        assert_eq!(credits.keys().collect::<Vec<_>>(), [&'b']);
+        assert_eq!(
+            slopes_and_credits(&credits[&'b']),
+            (30_000, -970_000, 123, -3973)
+        );
+
+        // Audio, video and control, control waiting for both
+        let mut control = child(1, 1024, 10_000_000);
+        control.max_interfering_frame = Some(12176);
+        let streams = BTreeMap::from([
+            ('a', vec![child(3, 4096, 70_000_000)]),
+            ('b', vec![child(2, 4096, 30_000_000)]),
+            ('c', vec![control]),
+        ]);
+        let credits = calc_credits(&streams, 1_000_000_000).unwrap();
+        // 10Mbps × (12176b / 900Mbps + 8192b / 1Gbps)
+        assert_eq!(
+            slopes_and_credits(&credits[&'c']),
+            (10_000, -990_000, 218, -1013)
+        );
+
+        let streams = BTreeMap::from([
+            ('a', vec![child(3, 4096, 700_000_000)]),
+            ('b', vec![child(2, 4096, 300_000_000)]),
+        ]);
+        assert!(calc_credits(&streams, 1_000_000_000).is_err());
+    }
+
+    #[test]
+    fn link_speed_is_not_guessed() {
+        let yaml = |link_speed: &str| {
+            format!(
+                "nics:\n  nonexistent0:\n{}    cbs:\n      3: {{class: a, max_frame: 4096b, bandwidth: 70Mbps}}\n",
+                link_speed
+            )
+        };
+        // Only the NIC without a link fails, once it is configured
+        let config = parse_config(&yaml(""), Format::Yaml).unwrap()["nonexistent0"].clone();
+        let cbs = config.cbs.as_ref().unwrap();
+        assert_eq!(cbs.link_speed, 0);
+        assert!(cbs.credits.is_empty() && cbs.children.is_empty());
+        let e = config.resolve("nonexistent0").err().unwrap();
+        assert!(e.to_string().ends_with("set link_speed"), "{}", e);
+
+        let mut configs = parse_config(&yaml("    link_speed: 100Mbps\n"), Format::Yaml).unwrap();
+        let config = configs.remove("nonexistent0").unwrap();
+        // Nothing left to look up
+        assert!(config.resolve("nonexistent0").unwrap() == config);
+        let mut cbs = config.cbs.unwrap();
+        assert_eq!(cbs.link_speed, 100_000_000);
+        assert_eq!(cbs.children[&1].hicredit, 2868);
+        // The link renegotiated to 1Gbps
+        cbs.set_link_speed(1_000_000_000).unwrap();
+        assert_eq!(cbs.children[&1], cbs.credits[&'a']);
+        assert_eq!(cbs.children[&1].hicredit, 287);
+        assert!(cbs.set_link_speed(10_000_000).is_err());
+    }
+
+    #[test]
+    fn cbs_class_needs_its_own_traffic_class() {
+        let credit = CbsCredit {
+            idleslope: 70_000,
+            sendslope: -930_000,
+            hicredit: 287,
+            locredit: -3809,
+        };
+        let mut cbs = CbsConfig {
+            tc_map: HashMap::new(),
+            num_tc: 0,
+            queues: Vec::new(),
+            children: HashMap::new(),
+            streams: BTreeMap::from([('a', vec![child(3, 4096, 70_000_000)]), ('b', vec![])]),
+            credits: BTreeMap::from([('a', credit.clone()), ('b', credit)]),
+            link_speed: 1_000_000_000,
+        };
+        let tc_map: HashMap<i64, i64> = (0..16).map(|prio| (prio, (prio == 3) as i64)).collect();
+        cbs.set_tc_map(&tc_map, 2, &[(1, 0), (1, 1)]).unwrap();
+        assert_eq!(cbs.children.keys().collect::<Vec<_>>(), [&2]);
+
+        let tc_map: HashMap<i64, i64> = (0..16).map(|prio| (prio, (prio >= 3) as i64)).collect();
+        assert!(cbs.set_tc_map(&tc_map, 2, &[(1, 0), (1, 1)]).is_err());
+    }
 }
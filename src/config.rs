@@ -1,16 +1,56 @@
 use crate::cbs::{normalise_cbs, CbsConfig};
+use crate::error::TsnError;
+use crate::ethtool;
+use crate::schema::{Clock, ConfigFile, EtfSection, Format, IntKey, NicConfig};
 use crate::tas::{normalise_tas, TasConfig};
-use serde_yaml::{self, Value};
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::BufReader;
+use serde::{Deserialize, Serialize};
+use serde_path_to_error::Segment;
+use serde_yaml::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
 use std::str;
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
 
-#[derive(Clone)]
+pub use crate::tas::BaseTime;
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     pub egress_qos_map: HashMap<i64, HashMap<i64, i64>>,
     pub tas: Option<TasConfig>,
     pub cbs: Option<CbsConfig>,
+    /// ETF qdiscs by skb prio, installed under the mqprio (or cbs) classes
+    pub etf: HashMap<i64, EtfConfig>,
+    /// Link speed set in the config, in bits per second, instead of the one
+    /// the NIC reports
+    pub link_speed: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EtfConfig {
+    pub clockid: libc::clockid_t,
+    /// How long before its txtime a frame is dequeued, in ns
+    pub delta: i64,
+    pub offload: bool,
+    pub deadline_mode: bool,
+    pub skip_sock_check: bool,
+}
+
+/// Name of `clockid` as written in the config.
+pub fn clock_name(clockid: libc::clockid_t) -> &'static str {
+    match clockid {
+        libc::CLOCK_REALTIME => "CLOCK_REALTIME",
+        libc::CLOCK_MONOTONIC => "CLOCK_MONOTONIC",
+        libc::CLOCK_BOOTTIME => "CLOCK_BOOTTIME",
+        _ => "CLOCK_TAI",
+    }
+}
+
+impl EtfConfig {
+    pub fn clock_name(&self) -> &'static str {
+        clock_name(self.clockid)
+    }
 }
 
 impl Config {
@@ -19,76 +59,487 @@ impl Config {
             egress_qos_map: vlan_config,
             tas: None,
             cbs: None,
+            etf: HashMap::new(),
+            link_speed: None,
+        }
+    }
+
+    /// This config with the cbs credits computed for the speed of the link of
+    /// `ifname` when `link_speed` is not set, so that a NIC without a link
+    /// only fails where it is configured.
+    pub fn resolve(&self, ifname: &str) -> Result<Config, TsnError> {
+        let mut config = self.clone();
+        if let Some(cbs) = config.cbs.as_mut().filter(|cbs| cbs.link_speed == 0) {
+            let speed = ethtool::link_speed(ifname).map_err(|e| {
+                TsnError::Config(format!(
+                    "cannot compute the cbs credits of {}: {}, set link_speed",
+                    ifname, e
+                ))
+            })?;
+            cbs.set_link_speed(speed)
+                .map_err(|e| TsnError::Config(format!("{} at {}bps: {}", ifname, speed, e)))?;
+        }
+        Ok(config)
+    }
+}
+
+/// A problem found in the config file.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigIssue {
+    /// Where in the file, e.g. `nics.enp37s0.cbs.2.bandwidth`
+    pub path: String,
+    /// 1-based line and column of `path`, or of its closest parent
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = if self.path.is_empty() {
+            "<root>"
+        } else {
+            &self.path
+        };
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => {
+                write!(
+                    f,
+                    "{} (line {}, column {}): {}",
+                    path, line, column, self.message
+                )
+            }
+            _ => write!(f, "{}: {}", path, self.message),
+        }
+    }
+}
+
+/// Records where each node of the document starts, by its path.
+struct MarkerIndex {
+    markers: HashMap<String, Marker>,
+    // Path of each open collection, and for mappings the key being read
+    stack: Vec<(String, Collection)>,
+}
+
+enum Collection {
+    Mapping(Option<(String, Marker)>),
+    Sequence(usize),
+}
+
+impl MarkedEventReceiver for MarkerIndex {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        let is_node = matches!(
+            ev,
+            Event::Scalar(..)
+                | Event::Alias(..)
+                | Event::SequenceStart(..)
+                | Event::MappingStart(..)
+        );
+        if matches!(ev, Event::SequenceEnd | Event::MappingEnd) {
+            self.stack.pop();
+        }
+        if !is_node {
+            return;
+        }
+        let is_collection = matches!(ev, Event::SequenceStart(..) | Event::MappingStart(..));
+        let path = match self.stack.last_mut() {
+            None => Some((String::new(), mark)),
+            Some((_, Collection::Mapping(key @ None))) => {
+                let name = match &ev {
+                    Event::Scalar(value, ..) => value.clone(),
+                    _ => "?".to_string(),
+                };
+                *key = Some((name, mark));
+                None
+            }
+            Some((parent, Collection::Mapping(key @ Some(_)))) => {
+                let (name, key_mark) = key.take().unwrap();
+                // A block collection starts on the line after its key
+                let mark = if is_collection { key_mark } else { mark };
+                Some((child(parent, &name), mark))
+            }
+            Some((parent, Collection::Sequence(index))) => {
+                *index += 1;
+                Some((format!("{}[{}]", parent, *index - 1), mark))
+            }
+        };
+        // Keys are not nodes of their own, but may still open a collection
+        let path = match path {
+            Some((path, mark)) => {
+                self.markers.insert(path.clone(), mark);
+                path
+            }
+            None => "?".to_string(),
+        };
+        match ev {
+            Event::MappingStart(..) => self.stack.push((path, Collection::Mapping(None))),
+            Event::SequenceStart(..) => self.stack.push((path, Collection::Sequence(0))),
+            _ => {}
+        }
+    }
+}
+
+/// The issues found while reading a config, with the position of each path.
+pub(crate) struct Issues {
+    markers: HashMap<String, Marker>,
+    list: Vec<ConfigIssue>,
+}
+
+impl Issues {
+    fn new(content: &str, format: Format) -> Issues {
+        let mut index = MarkerIndex {
+            markers: HashMap::new(),
+            stack: Vec::new(),
+        };
+        // JSON is also YAML, syntax errors are reported by the deserializer
+        if format != Format::Toml {
+            let _ = Parser::new(content.chars()).load(&mut index, false);
+        }
+        Issues {
+            markers: index.markers,
+            list: Vec::new(),
+        }
+    }
+
+    fn without_positions() -> Issues {
+        Issues {
+            markers: HashMap::new(),
+            list: Vec::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    pub(crate) fn push(&mut self, path: &str, message: impl Into<String>) {
+        let mut parent = path;
+        let marker = loop {
+            if let Some(marker) = self.markers.get(parent) {
+                break Some(marker);
+            }
+            match parent.rfind(['.', '[']) {
+                Some(end) => parent = &parent[..end],
+                None => break self.markers.get(""),
+            }
+        };
+        self.list.push(ConfigIssue {
+            path: path.to_string(),
+            line: marker.map(|m| m.line()),
+            column: marker.map(|m| m.col() + 1),
+            message: message.into(),
+        });
+    }
+
+    /// Record the error of `res` against `path`.
+    pub(crate) fn check<T>(&mut self, path: &str, res: Result<T, String>) -> Option<T> {
+        match res {
+            Ok(value) => Some(value),
+            Err(message) => {
+                self.push(path, message);
+                None
+            }
         }
     }
 }
 
-pub fn normalise_vlan(input: &Value) -> HashMap<i64, HashMap<i64, i64>> {
+pub(crate) fn child(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+/// A socket priority, 0 to 15.
+pub(crate) fn check_prio(prio: i64) -> Result<i64, String> {
+    if (0..16).contains(&prio) {
+        Ok(prio)
+    } else {
+        Err(format!("prio {} should be between 0 and 15", prio))
+    }
+}
+
+pub(crate) fn normalise_vlan(
+    issues: &mut Issues,
+    path: &str,
+    input: &BTreeMap<IntKey, BTreeMap<IntKey, i64>>,
+) -> HashMap<i64, HashMap<i64, i64>> {
     let mut ret_map = HashMap::new();
-    for (valnid, prio) in input.as_mapping().unwrap() {
+    for (vlanid, prios) in input {
+        let vlan_path = child(path, &vlanid.to_string());
+        let vlanid = if (1..4095).contains(&vlanid.0) {
+            Some(vlanid.0)
+        } else {
+            issues.push(
+                &vlan_path,
+                format!("vlan id {} should be between 1 and 4094", vlanid),
+            );
+            None
+        };
         let mut vlan_map = HashMap::new();
-        for (prio, pri) in prio.as_mapping().unwrap() {
-            vlan_map.insert(prio.as_i64().unwrap(), pri.as_i64().unwrap());
+        for (prio, &pri) in prios {
+            let prio_path = child(&vlan_path, &prio.to_string());
+            let prio = issues.check(&prio_path, check_prio(prio.0));
+            let pri = if (0..8).contains(&pri) {
+                Some(pri)
+            } else {
+                issues.push(
+                    &prio_path,
+                    format!("vlan pri {} should be between 0 and 7", pri),
+                );
+                None
+            };
+            if let (Some(prio), Some(pri)) = (prio, pri) {
+                vlan_map.insert(prio, pri);
+            }
+        }
+        if let Some(vlanid) = vlanid {
+            ret_map.insert(vlanid, vlan_map);
         }
-        ret_map.insert(valnid.as_i64().unwrap(), vlan_map);
     }
     ret_map
 }
 
-pub fn read_config(config_path: &str) -> Result<HashMap<String, Config>, i64> {
-    let file = File::open(config_path).expect("failed to open config.yaml");
-    let reader = BufReader::new(file);
-    let config: Value = serde_yaml::from_reader(reader).expect("failed to parse YAML");
-    let config = config
-        .as_mapping()
-        .expect("config file should be a dictionary")
-        .get(&Value::String("nics".to_string()))
-        .expect("config file should have a 'nics' key")
-        .as_mapping()
-        .expect("'nics' value should be a dictionary");
-    let mut ifname;
-    let mut ret = HashMap::new();
-    for (key, value) in config {
-        let mut info = Config::new(HashMap::new());
-        let value = value
-            .as_mapping()
-            .expect("config value should be a dictionary");
-        ifname = key.as_str().unwrap();
-        if value.contains_key(&Value::String("egress-qos-map".to_string())) {
-            info.egress_qos_map = normalise_vlan(
-                value
-                    .get(&Value::String("egress-qos-map".to_string()))
-                    .expect("egress-qos-map should be a dictionary"),
+/// Check an `etf` section, a dictionary of skb prio to ETF parameters.
+pub(crate) fn normalise_etf(
+    issues: &mut Issues,
+    path: &str,
+    input: &BTreeMap<IntKey, EtfSection>,
+) -> HashMap<i64, EtfConfig> {
+    let mut ret_map = HashMap::new();
+    for (prio, etf) in input {
+        let prio_path = child(path, &prio.to_string());
+        if let Some(prio) = issues.check(&prio_path, check_prio(prio.0)) {
+            ret_map.insert(
+                prio,
+                EtfConfig {
+                    clockid: etf.clockid.unwrap_or(Clock::Tai).id(),
+                    delta: etf.delta.0,
+                    offload: etf.offload.unwrap_or_default(),
+                    deadline_mode: etf.deadline_mode.unwrap_or_default(),
+                    skip_sock_check: etf.skip_sock_check.unwrap_or_default(),
+                },
             );
         }
-        if value.contains_key(&Value::String("tas".to_string())) {
-            match normalise_tas(
-                value
-                    .get(&Value::String("tas".to_string()))
-                    .expect("tas should be a dictionary"),
-            ) {
-                Ok(tas) => info.tas = Some(tas),
-                Err(e) => {
-                    eprintln!("{}", e);
-                    return Err(-1);
-                }
+    }
+    ret_map
+}
+
+fn normalise_nic(issues: &mut Issues, path: &str, nic: &NicConfig) -> Config {
+    let mut info = Config::new(normalise_vlan(
+        issues,
+        &child(path, "egress-qos-map"),
+        &nic.egress_qos_map,
+    ));
+    info.link_speed = nic.link_speed.map(|speed| speed.0);
+    if let Some(tas) = &nic.tas {
+        info.tas = normalise_tas(issues, &child(path, "tas"), tas);
+    }
+    if let Some(cbs) = &nic.cbs {
+        let cbs_path = child(path, "cbs");
+        info.cbs = normalise_cbs(issues, &cbs_path, cbs, info.link_speed);
+        if let (Some(tas), Some(cbs)) = (&info.tas, &mut info.cbs) {
+            // cbs runs under the taprio classes, so both share its mapping
+            let res = cbs
+                .set_tc_map(&tas.tc_map, tas.num_tc, &tas.queues)
+                .map_err(|e| format!("with tas: {}", e));
+            issues.check(&cbs_path, res);
+        }
+    }
+    if let Some(etf) = &nic.etf {
+        let etf_path = child(path, "etf");
+        if info.tas.is_some() {
+            issues.push(&etf_path, "should be under tas when tas is used");
+        }
+        info.etf = normalise_etf(issues, &etf_path, etf);
+        if let Some(cbs) = &info.cbs {
+            let cbs_prios: Vec<i64> = cbs.streams.values().flatten().map(|s| s.prio).collect();
+            for prio in info.etf.keys().filter(|prio| !cbs_prios.contains(prio)) {
+                issues.push(
+                    &child(&etf_path, &prio.to_string()),
+                    format!("prio {} is not a cbs prio", prio),
+                );
             }
         }
-        if value.contains_key(&Value::String("cbs".to_string())) {
-            match normalise_cbs(
-                ifname,
-                value
-                    .get(&Value::String("cbs".to_string()))
-                    .expect("cbs should be a dictionary"),
-            ) {
-                Ok(cbs) => info.cbs = Some(cbs),
-                Err(e) => {
-                    eprintln!("{}", e);
-                    return Err(-1);
-                }
+    }
+    info
+}
+
+fn normalise(issues: &mut Issues, file: &ConfigFile) -> HashMap<String, Config> {
+    file.nics
+        .iter()
+        .map(|(ifname, nic)| {
+            let path = child("nics", ifname);
+            (ifname.clone(), normalise_nic(issues, &path, nic))
+        })
+        .collect()
+}
+
+/// `path` of a serde error in our `a.b[0]` form.
+fn error_path(path: &serde_path_to_error::Path, prefix: &str) -> String {
+    path.iter()
+        .fold(prefix.to_string(), |path, segment| match segment {
+            Segment::Seq { index } => format!("{}[{}]", path, index),
+            Segment::Map { key } | Segment::Enum { variant: key } => child(&path, key),
+            Segment::Unknown => child(&path, "?"),
+        })
+}
+
+/// 1-based line and column of the byte `offset` of `content`.
+fn position(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// The message of a deserializer error, without the position it appends.
+fn message(error: &impl fmt::Display, location: Option<(usize, usize)>) -> String {
+    let message = error.to_string();
+    let message = match location {
+        Some((line, column)) => message
+            .strip_suffix(&format!(" at line {} column {}", line, column))
+            .unwrap_or(&message)
+            .to_string(),
+        None => message,
+    };
+    message.trim().replace('\n', ", ")
+}
+
+fn located(path: String, message: String, location: Option<(usize, usize)>) -> ConfigIssue {
+    ConfigIssue {
+        path,
+        line: location.map(|(line, _)| line),
+        column: location.map(|(_, column)| column),
+        message,
+    }
+}
+
+/// Read `content` into the shape of a config file, one NIC at a time so that
+/// a mistake in one NIC does not hide those of the others. NICs with mistakes
+/// are left out of the file.
+fn parse(content: &str, format: Format) -> (Issues, ConfigFile) {
+    // The file with the NICs left as they are
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct RawFile {
+        nics: BTreeMap<String, Value>,
+    }
+
+    let mut issues = Issues::new(content, format);
+    let mut file = ConfigFile::default();
+    let raw: Result<RawFile, ConfigIssue> = match format {
+        Format::Yaml => serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(
+            content,
+        ))
+        .map_err(|e| {
+            let location = e.inner().location().map(|l| (l.line(), l.column()));
+            located(
+                error_path(e.path(), ""),
+                message(e.inner(), location),
+                location,
+            )
+        }),
+        Format::Json => {
+            let mut deserializer = serde_json::Deserializer::from_str(content);
+            serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+                let location =
+                    (e.inner().line() > 0).then(|| (e.inner().line(), e.inner().column()));
+                located(
+                    error_path(e.path(), ""),
+                    message(e.inner(), location),
+                    location,
+                )
+            })
+        }
+        Format::Toml => {
+            serde_path_to_error::deserialize(toml::Deserializer::new(content)).map_err(|e| {
+                let location = e.inner().span().map(|span| position(content, span.start));
+                located(
+                    error_path(e.path(), ""),
+                    message(&e.inner().message(), None),
+                    location,
+                )
+            })
+        }
+    };
+    let raw = match raw {
+        Ok(raw) => raw,
+        Err(issue) => {
+            match issue.line {
+                Some(_) => issues.list.push(issue),
+                None => issues.push(&issue.path, issue.message),
             }
+            return (issues, file);
         }
-        ret.insert(ifname.to_string(), info);
+    };
+    for (ifname, value) in raw.nics {
+        let path = child("nics", &ifname);
+        match serde_path_to_error::deserialize::<_, NicConfig>(value) {
+            Ok(nic) => {
+                file.nics.insert(ifname, nic);
+            }
+            Err(e) => issues.push(&error_path(e.path(), &path), message(e.inner(), None)),
+        }
+    }
+    (issues, file)
+}
+
+/// Read a config file in `format`, without checking it further than its
+/// shape.
+pub fn parse_file(content: &str, format: Format) -> Result<ConfigFile, Vec<ConfigIssue>> {
+    let (issues, file) = parse(content, format);
+    if issues.list.is_empty() {
+        Ok(file)
+    } else {
+        Err(issues.list)
+    }
+}
+
+/// Read and check the config of every NIC in `content`.
+///
+/// All the problems of the config are reported at once, each with its place
+/// in the file when `format` tells where. Only the first mistake in the shape
+/// of a NIC is found, as nothing can be said about the rest of it.
+pub fn parse_config(
+    content: &str,
+    format: Format,
+) -> Result<HashMap<String, Config>, Vec<ConfigIssue>> {
+    let (mut issues, file) = parse(content, format);
+    let config = normalise(&mut issues, &file);
+    if issues.list.is_empty() {
+        Ok(config)
+    } else {
+        Err(issues.list)
+    }
+}
+
+/// Check a config built in memory, like [`parse_config`] does with a file.
+pub fn normalise_config(file: &ConfigFile) -> Result<HashMap<String, Config>, Vec<ConfigIssue>> {
+    let mut issues = Issues::without_positions();
+    let config = normalise(&mut issues, file);
+    if issues.list.is_empty() {
+        Ok(config)
+    } else {
+        Err(issues.list)
     }
-    Ok(ret)
+}
+
+/// Read the config of every NIC in `config_path`, in YAML, or TOML or JSON
+/// by its extension.
+///
+/// All the problems of the file are reported at once, each with its place in
+/// the file, as [`TsnError::ConfigInvalid`].
+pub fn read_config(config_path: &str) -> Result<HashMap<String, Config>, TsnError> {
+    let content = fs::read_to_string(config_path).map_err(|e| TsnError::ConfigIo {
+        path: config_path.to_string(),
+        source: e,
+    })?;
+    parse_config(&content, Format::of(config_path)).map_err(|issues| TsnError::ConfigInvalid {
+        path: config_path.to_string(),
+        issues,
+    })
 }
@@ -0,0 +1,113 @@
+use std::io;
+
+use thiserror::Error;
+
+use crate::config::ConfigIssue;
+use crate::validate::{self, Diagnostic};
+
+/// Errors returned by every public function of the `tsn` crate.
+///
+/// Variants are split by where the failure happened so callers can decide
+/// what is worth retrying: a `VlanCreate` may be a race with another process,
+/// while a `Config` error will fail the same way every time.
+#[derive(Debug, Error)]
+pub enum TsnError {
+    /// The config file could not be read.
+    #[error("cannot read config {path}: {source}")]
+    ConfigIo {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The config file was read but its content is invalid.
+    #[error("invalid config: {0}")]
+    Config(String),
+
+    /// The config file has mistakes, each with its place in the file.
+    #[error("invalid config {path}: {}", issues.iter().map(|i| i.to_string()).collect::<Vec<_>>().join("; "))]
+    ConfigInvalid {
+        path: String,
+        issues: Vec<ConfigIssue>,
+    },
+
+    /// The TAS schedule failed validation, see [`crate::validate`].
+    #[error(
+        "invalid tas schedule for {ifname}: {}",
+        validate::summary(diagnostics)
+    )]
+    Schedule {
+        ifname: String,
+        diagnostics: Vec<Diagnostic>,
+    },
+
+    /// Creating or bringing up the VLAN interface failed.
+    #[error("cannot create vlan {name}: {source}")]
+    VlanCreate {
+        name: String,
+        #[source]
+        source: io::Error,
+    },
+
+    /// Deleting the VLAN interface failed.
+    #[error("cannot delete vlan {name}: {source}")]
+    VlanDelete {
+        name: String,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The kernel rejected an rtnetlink request.
+    ///
+    /// `request` is the operation in `ip`/`tc` syntax and `extack` the reason
+    /// reported by the kernel through netlink extended ack, when it gave one.
+    #[error("`{request}` failed: {source}{}", extack.as_ref().map(|m| format!(": {}", m)).unwrap_or_default())]
+    Netlink {
+        request: String,
+        extack: Option<String>,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The speed or state of the link of a NIC could not be read.
+    #[error("cannot read the link settings of {ifname}: {source}")]
+    Link {
+        ifname: String,
+        #[source]
+        source: io::Error,
+    },
+
+    /// Reading, writing or locking the registry of VLAN users failed.
+    #[error("registry {op} {path} failed: {source}")]
+    Registry {
+        path: String,
+        op: &'static str,
+        #[source]
+        source: io::Error,
+    },
+
+    /// A socket system call failed.
+    #[error("socket {op} failed: {source}")]
+    Socket {
+        op: &'static str,
+        #[source]
+        source: io::Error,
+    },
+}
+
+impl TsnError {
+    pub(crate) fn socket(op: &'static str) -> TsnError {
+        TsnError::Socket {
+            op,
+            source: io::Error::last_os_error(),
+        }
+    }
+
+    /// A socket `op` refused because of what it was asked, not of the kernel.
+    pub(crate) fn invalid(op: &'static str, message: &str) -> TsnError {
+        TsnError::Socket {
+            op,
+            source: io::Error::new(io::ErrorKind::InvalidInput, message),
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Link settings of a NIC, read with the `SIOCETHTOOL` ioctl as `ethtool`
+//! does.
+//!
+//! The speed and duplex come from `ETHTOOL_GLINKSETTINGS` and whether the
+//! link is up from `ETHTOOL_GLINK`. Both need nothing but a socket, no
+//! privileges.
+
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+const ETHTOOL_GLINK: u32 = 0x0000_000a;
+const ETHTOOL_GLINKSETTINGS: u32 = 0x0000_004c;
+const SPEED_UNKNOWN: u32 = u32::MAX;
+const DUPLEX_HALF: u8 = 0;
+const DUPLEX_FULL: u8 = 1;
+/// `SCHAR_MAX`, the most `link_mode_masks_nwords` can be
+const LINK_MODE_MASKS_NWORDS_MAX: usize = 127;
+
+/// `struct ethtool_link_settings`, with room for the largest link mode masks.
+#[repr(C)]
+struct EthtoolLinkSettings {
+    cmd: u32,
+    speed: u32,
+    duplex: u8,
+    port: u8,
+    phy_address: u8,
+    autoneg: u8,
+    mdio_support: u8,
+    eth_tp_mdix: u8,
+    eth_tp_mdix_ctrl: u8,
+    link_mode_masks_nwords: i8,
+    transceiver: u8,
+    master_slave_cfg: u8,
+    master_slave_state: u8,
+    rate_matching: u8,
+    reserved: [u32; 7],
+    /// Supported, advertised and link partner modes
+    link_mode_masks: [u32; 3 * LINK_MODE_MASKS_NWORDS_MAX],
+}
+
+/// `struct ethtool_value`
+#[repr(C)]
+struct EthtoolValue {
+    cmd: u32,
+    data: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Duplex {
+    Half,
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkSettings {
+    /// Bits per second, `None` when the NIC does not know, e.g. without link
+    pub speed: Option<i64>,
+    pub duplex: Option<Duplex>,
+    pub link_up: bool,
+}
+
+/// Send the ethtool command `data` starts with to `ifname`.
+fn ethtool<T>(fd: &OwnedFd, ifname: &str, data: &mut T) -> Result<(), Error> {
+    let mut ifr_name: [libc::c_char; libc::IFNAMSIZ] = [0; libc::IFNAMSIZ];
+    if ifname.len() >= libc::IFNAMSIZ {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "interface name too long",
+        ));
+    }
+    for (source, target) in ifname.as_bytes().iter().zip(ifr_name.iter_mut()) {
+        *target = *source as libc::c_char;
+    }
+    let ifreq = libc::ifreq {
+        ifr_name,
+        ifr_ifru: libc::__c_anonymous_ifr_ifru {
+            ifru_data: (data as *mut T) as *mut libc::c_char,
+        },
+    };
+    let res = unsafe {
+        // Not useless conversion because aarch64 has different type
+        #[allow(clippy::useless_conversion)]
+        libc::ioctl(
+            fd.as_raw_fd(),
+            libc::SIOCETHTOOL.try_into().unwrap(),
+            &ifreq,
+        )
+    };
+    if res < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Speed, duplex and link state of `ifname`.
+pub fn link_settings(ifname: &str) -> Result<LinkSettings, Error> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // The kernel answers a request without masks with the number of words
+    // they take, negated, and only fills in the settings when asked again
+    // with that number.
+    let mut settings: Box<EthtoolLinkSettings> = Box::new(unsafe { mem::zeroed() });
+    settings.cmd = ETHTOOL_GLINKSETTINGS;
+    ethtool(&fd, ifname, settings.as_mut())?;
+    let nwords = settings.link_mode_masks_nwords;
+    if nwords >= 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "the kernel did not give the size of the link mode masks",
+        ));
+    }
+    let mut settings: Box<EthtoolLinkSettings> = Box::new(unsafe { mem::zeroed() });
+    settings.cmd = ETHTOOL_GLINKSETTINGS;
+    settings.link_mode_masks_nwords = -nwords;
+    ethtool(&fd, ifname, settings.as_mut())?;
+
+    let mut link = EthtoolValue {
+        cmd: ETHTOOL_GLINK,
+        data: 0,
+    };
+    ethtool(&fd, ifname, &mut link)?;
+
+    Ok(LinkSettings {
+        speed: match settings.speed {
+            0 | SPEED_UNKNOWN => None,
+            mbps => Some(mbps as i64 * 1_000_000),
+        },
+        duplex: match settings.duplex {
+            DUPLEX_HALF => Some(Duplex::Half),
+            DUPLEX_FULL => Some(Duplex::Full),
+            _ => None,
+        },
+        link_up: link.data != 0,
+    })
+}
+
+/// Speed of the link of `ifname` in bits per second, or why it is unknown.
+pub fn link_speed(ifname: &str) -> Result<i64, String> {
+    let settings = link_settings(ifname)
+        .map_err(|e| format!("cannot read the link settings of {}: {}", ifname, e))?;
+    if !settings.link_up {
+        return Err(format!("{} has no link", ifname));
+    }
+    settings
+        .speed
+        .ok_or_else(|| format!("{} does not report its link speed", ifname))
+}
@@ -1,29 +1,339 @@
-use crate::config::Config;
+use crate::config::{BaseTime, Config, EtfConfig};
+use itertools::Itertools;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use tsn::state::{self, handle_name, LinkState, QdiscOptions};
 
-pub fn get_info(config: &Config) {
-    if let Some(cbs) = &config.cbs {
+/// What `tsn info` shows for one NIC.
+#[derive(Serialize)]
+pub struct NicInfo {
+    pub egress_qos_map: BTreeMap<i64, BTreeMap<i64, i64>>,
+    pub tas: Option<TasInfo>,
+    pub cbs: Option<CbsInfo>,
+    /// ETF of the NIC when it has no TAS, see `tas.etf` otherwise
+    pub etf: BTreeMap<i64, EtfInfo>,
+    pub traffic_classes: Vec<TrafficClassInfo>,
+    /// What the kernel has installed, `None` when it cannot be read
+    pub live: Option<LinkState>,
+    /// Why `live` cannot be read
+    pub live_error: Option<String>,
+    /// How `live` differs from the config
+    pub drift: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum BaseTimeInfo {
+    Absolute(i64),
+    NextCycle { next_cycle: i64 },
+    Phase { phase: i64 },
+}
+
+#[derive(Serialize)]
+pub struct ScheduleInfo {
+    pub time: i64,
+    pub prio: Vec<i64>,
+}
+
+#[derive(Serialize)]
+pub struct SchedEntryInfo {
+    pub gate_mask: u32,
+    pub interval: u32,
+}
+
+#[derive(Serialize)]
+pub struct QueuesInfo {
+    pub count: u16,
+    pub offset: u16,
+}
+
+#[derive(Serialize)]
+pub struct TasInfo {
+    pub mode: String,
+    pub base_time: BaseTimeInfo,
+    pub cycle_time: Option<i64>,
+    pub cycle_time_extension: Option<i64>,
+    /// `cycle_time`, or the length of the schedule when it is not set
+    pub cycle: i64,
+    pub txtime_delay: i64,
+    pub schedule: Vec<ScheduleInfo>,
+    /// The gates given to taprio
+    pub sched_entries: Vec<SchedEntryInfo>,
+    pub num_tc: i64,
+    /// Traffic class of each prio
+    pub tc_map: Vec<i64>,
+    pub queues: Vec<QueuesInfo>,
+    pub etf: BTreeMap<i64, EtfInfo>,
+}
+
+#[derive(Serialize)]
+pub struct CreditsInfo {
+    pub hicredit: i64,
+    pub idleslope: i64,
+    pub locredit: i64,
+    pub sendslope: i64,
+}
+
+#[derive(Serialize)]
+pub struct CbsPrioInfo {
+    pub bandwidth: i64,
+    pub max_frame: i64,
+}
+
+#[derive(Serialize)]
+pub struct CbsClassInfo {
+    /// `None` while the link speed is unknown
+    pub credits: Option<CreditsInfo>,
+    pub prios: BTreeMap<i64, CbsPrioInfo>,
+}
+
+#[derive(Serialize)]
+pub struct CbsInfo {
+    pub classes: BTreeMap<String, CbsClassInfo>,
+    pub num_tc: i64,
+    pub tc_map: Vec<i64>,
+    pub queues: Vec<QueuesInfo>,
+    /// Why the credits are unknown
+    pub credits_error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct EtfInfo {
+    pub clockid: String,
+    pub deadline_mode: bool,
+    pub delta: i64,
+    pub offload: bool,
+    pub skip_sock_check: bool,
+}
+
+/// Which prios, cbs class and ETF a traffic class ends up with.
+#[derive(Serialize)]
+pub struct TrafficClassInfo {
+    pub tc: i64,
+    pub prios: Vec<i64>,
+    /// Schedule entries whose gate is open for this class, with TAS only
+    pub windows: Option<Vec<usize>>,
+    pub cbs: Option<String>,
+    pub etf: bool,
+}
+
+fn tc_map_info(tc_map: &HashMap<i64, i64>) -> Vec<i64> {
+    (0..16).map(|prio| tc_map[&prio]).collect()
+}
+
+fn queues_info(queues: &[(u16, u16)]) -> Vec<QueuesInfo> {
+    queues
+        .iter()
+        .map(|(count, offset)| QueuesInfo {
+            count: *count,
+            offset: *offset,
+        })
+        .collect()
+}
+
+fn etf_info(etf: &HashMap<i64, EtfConfig>) -> BTreeMap<i64, EtfInfo> {
+    etf.iter()
+        .map(|(prio, etf)| {
+            let info = EtfInfo {
+                clockid: etf.clock_name().to_string(),
+                deadline_mode: etf.deadline_mode,
+                delta: etf.delta,
+                offload: etf.offload,
+                skip_sock_check: etf.skip_sock_check,
+            };
+            (*prio, info)
+        })
+        .collect()
+}
+
+fn traffic_classes(config: &Config) -> Vec<TrafficClassInfo> {
+    let (tc_map, num_tc) = match (&config.tas, &config.cbs) {
+        (Some(tas), _) => (&tas.tc_map, tas.num_tc),
+        (None, Some(cbs)) => (&cbs.tc_map, cbs.num_tc),
+        (None, None) => return Vec::new(),
+    };
+    let etf = match &config.tas {
+        Some(tas) => &tas.etf,
+        None => &config.etf,
+    };
+    (0..num_tc)
+        .map(|tc| {
+            let prios: Vec<i64> = (0..16).filter(|prio| tc_map[prio] == tc).collect();
+            let cbs = config.cbs.as_ref().and_then(|cbs| {
+                cbs.streams
+                    .iter()
+                    .find(|(_, streams)| streams.iter().any(|s| tc_map[&s.prio] == tc))
+                    .map(|(class, _)| class.to_string())
+            });
+            let windows = config.tas.as_ref().map(|tas| {
+                tas.sched_entries
+                    .iter()
+                    .positions(|entry| entry.gate_mask & (1 << tc) != 0)
+                    .collect()
+            });
+            TrafficClassInfo {
+                tc,
+                etf: prios.iter().any(|prio| etf.contains_key(prio)),
+                prios,
+                windows,
+                cbs,
+            }
+        })
+        .collect()
+}
+
+pub fn get_info(ifname: &str, config: &Config) -> NicInfo {
+    let resolved = config.resolve(ifname);
+    let (config, credits_error) = match &resolved {
+        Ok(resolved) => (resolved, None),
+        Err(e) => (config, Some(e.to_string())),
+    };
+    let tas = config.tas.as_ref().map(|tas| TasInfo {
+        mode: tas.mode.to_string(),
+        base_time: match tas.base_time {
+            BaseTime::Absolute(time) => BaseTimeInfo::Absolute(time),
+            BaseTime::NextCycle(delay) => BaseTimeInfo::NextCycle { next_cycle: delay },
+            BaseTime::Phase(offset) => BaseTimeInfo::Phase { phase: offset },
+        },
+        cycle_time: tas.cycle_time,
+        cycle_time_extension: tas.cycle_time_extension,
+        cycle: tas.cycle(),
+        txtime_delay: tas.txtime_delay,
+        schedule: tas
+            .schedule
+            .iter()
+            .map(|sch| ScheduleInfo {
+                time: sch.time,
+                prio: sch.prio.clone(),
+            })
+            .collect(),
+        sched_entries: tas
+            .sched_entries
+            .iter()
+            .map(|entry| SchedEntryInfo {
+                gate_mask: entry.gate_mask,
+                interval: entry.interval,
+            })
+            .collect(),
+        num_tc: tas.num_tc,
+        tc_map: tc_map_info(&tas.tc_map),
+        queues: queues_info(&tas.queues),
+        etf: etf_info(&tas.etf),
+    });
+    let cbs = config.cbs.as_ref().map(|cbs| CbsInfo {
+        classes: cbs
+            .streams
+            .iter()
+            .filter(|(_, streams)| !streams.is_empty())
+            .map(|(class, streams)| {
+                let info = CbsClassInfo {
+                    credits: cbs.credits.get(class).map(|credit| CreditsInfo {
+                        hicredit: credit.hicredit,
+                        idleslope: credit.idleslope,
+                        locredit: credit.locredit,
+                        sendslope: credit.sendslope,
+                    }),
+                    prios: streams
+                        .iter()
+                        .map(|stream| {
+                            let prio = CbsPrioInfo {
+                                bandwidth: stream.bandwidth,
+                                max_frame: stream.max_frame,
+                            };
+                            (stream.prio, prio)
+                        })
+                        .collect(),
+                };
+                (class.to_string(), info)
+            })
+            .collect(),
+        num_tc: cbs.num_tc,
+        tc_map: tc_map_info(&cbs.tc_map),
+        queues: queues_info(&cbs.queues),
+        credits_error,
+    });
+    let (live, live_error, drift) = match state::read(ifname) {
+        Ok(live) => {
+            let drift = live.drift(config);
+            (Some(live), None, Some(drift))
+        }
+        Err(e) => (None, Some(e.to_string()), None),
+    };
+    NicInfo {
+        egress_qos_map: config
+            .egress_qos_map
+            .iter()
+            .map(|(vlanid, map)| (*vlanid, map.iter().map(|(k, v)| (*k, *v)).collect()))
+            .collect(),
+        tas,
+        cbs,
+        etf: etf_info(&config.etf),
+        traffic_classes: traffic_classes(config),
+        live,
+        live_error,
+        drift,
+    }
+}
+
+fn print_etf(etf: &BTreeMap<i64, EtfInfo>, indent: &str) {
+    println!("{}etf:", indent);
+    for (prio, etf) in etf {
+        println!(
+            "{}  {}: {{clockid: {}, deadline_mode: {}, delta: {}, offload: {}, skip_sock_check: {}}}",
+            indent,
+            prio,
+            etf.clockid,
+            etf.deadline_mode,
+            etf.delta,
+            etf.offload,
+            etf.skip_sock_check
+        );
+    }
+}
+
+pub fn print_info(info: &NicInfo) {
+    if let Some(cbs) = &info.cbs {
         println!("  cbs:");
-        let mut n = 1;
-        for (class, value) in &cbs.streams {
+        for (class, value) in &cbs.classes {
             println!("    {}:", class);
-            let credit = cbs.children.get(&n).unwrap();
-            println!(
-                "      credits: {{hicredit: {}, idleslope: {}, locredit: {}, sendslope: {}}}",
-                credit.hicredit, credit.idleslope, credit.locredit, credit.sendslope
-            );
-            n += 1;
+            match (&value.credits, &cbs.credits_error) {
+                (Some(credit), _) => println!(
+                    "      credits: {{hicredit: {}, idleslope: {}, locredit: {}, sendslope: {}}}",
+                    credit.hicredit, credit.idleslope, credit.locredit, credit.sendslope
+                ),
+                (None, error) => println!(
+                    "      credits: unknown, {}",
+                    error.as_deref().unwrap_or("the link speed is unknown")
+                ),
+            }
             println!("      prios:");
-            for prio in value {
+            for (prio, stream) in &value.prios {
                 println!(
                     "        {}: {{bandwidth: {}, class: {}, max_frame: {}}}",
-                    prio.prio, prio.bandwidth, class, prio.max_frame
+                    prio, stream.bandwidth, class, stream.max_frame
                 );
             }
         }
     }
-    if let Some(tas) = &config.tas {
+    if let Some(tas) = &info.tas {
         println!("  tas:");
-        println!("    base_time: {}", tas.base_time);
+        match tas.base_time {
+            BaseTimeInfo::Absolute(time) => println!("    base_time: {}", time),
+            BaseTimeInfo::NextCycle { next_cycle } => {
+                println!("    base_time: {{next_cycle: {}}}", next_cycle)
+            }
+            BaseTimeInfo::Phase { phase } => println!("    base_time: {{phase: {}}}", phase),
+        }
+        if let Some(cycle_time) = tas.cycle_time {
+            println!("    cycle_time: {}", cycle_time);
+        }
+        if let Some(extension) = tas.cycle_time_extension {
+            println!("    cycle_time_extension: {}", extension);
+        }
+        if !tas.etf.is_empty() {
+            print_etf(&tas.etf, "    ");
+        }
+        println!("    mode: {}", tas.mode);
         println!("    schedule:");
         for sch in &tas.schedule {
             println!("      - prio: {:?}", sch.prio);
@@ -31,4 +341,99 @@ pub fn get_info(config: &Config) {
         }
         println!("    txtime_delay: {}", tas.txtime_delay);
     }
+    if !info.etf.is_empty() {
+        print_etf(&info.etf, "  ");
+    }
+    print_traffic_classes(&info.traffic_classes);
+    match (&info.live, &info.live_error) {
+        (Some(live), _) => {
+            print_live(live);
+            print_drift(info.drift.as_deref().unwrap_or_default());
+        }
+        (None, Some(e)) => println!("  live: unavailable, {}", e),
+        (None, None) => {}
+    }
+}
+
+/// What the kernel has installed on the NIC.
+fn print_live(live: &LinkState) {
+    println!("  live:");
+    println!("    qdiscs:");
+    for qdisc in &live.qdiscs {
+        println!(
+            "      - {{kind: {}, handle: {}, parent: {}, {}}}",
+            qdisc.kind,
+            handle_name(qdisc.handle),
+            handle_name(qdisc.parent),
+            qdisc.stats
+        );
+        if !matches!(qdisc.options, QdiscOptions::Other) {
+            println!("        options: {}", qdisc.options);
+        }
+        if let QdiscOptions::Taprio { oper, admin, .. } = &qdisc.options {
+            if let Some(oper) = oper {
+                println!("        oper: {}", oper);
+            }
+            if let Some(admin) = admin {
+                println!("        admin: {}", admin);
+            }
+        }
+    }
+    if !live.classes.is_empty() {
+        println!("    queues:");
+        for class in &live.classes {
+            println!(
+                "      - {{class: {}, parent: {}, {}}}",
+                handle_name(class.handle),
+                handle_name(class.parent),
+                class.stats
+            );
+        }
+    }
+    if !live.vlans.is_empty() {
+        println!("    vlans:");
+        for vlan in &live.vlans {
+            let map = vlan
+                .egress_qos_map
+                .iter()
+                .map(|(prio, pri)| format!("{}: {}", prio, pri))
+                .join(", ");
+            println!(
+                "      {}: {{name: {}, egress-qos-map: {{{}}}}}",
+                vlan.vlanid, vlan.name, map
+            );
+        }
+    }
+}
+
+fn print_drift(drift: &[String]) {
+    if drift.is_empty() {
+        println!("  drift: none");
+        return;
+    }
+    println!("  drift:");
+    for line in drift {
+        println!("    - {}", line);
+    }
+}
+
+fn print_traffic_classes(traffic_classes: &[TrafficClassInfo]) {
+    if traffic_classes.is_empty() {
+        return;
+    }
+    println!("  traffic_classes:");
+    for tc in traffic_classes {
+        let mut line = format!("    {}: {{prios: {:?}", tc.tc, tc.prios);
+        if let Some(windows) = &tc.windows {
+            line.push_str(&format!(", windows: {:?}", windows));
+        }
+        if let Some(class) = &tc.cbs {
+            line.push_str(&format!(", cbs: {}", class));
+        }
+        if tc.etf {
+            line.push_str(", etf: true");
+        }
+        line.push('}');
+        println!("{}", line);
+    }
 }
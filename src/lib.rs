@@ -1,137 +1,415 @@
-use core::slice;
+use nix::libc;
 use nix::net::if_::if_nametoindex;
 use nix::sys::socket::msghdr;
 use nix::sys::time::{TimeSpec, TimeValLike};
 use nix::unistd::close;
-use nix::{
-    fcntl::{fcntl, FcntlArg::F_SETLKW, OFlag},
-    libc::{self, flock, ftruncate, msync, MS_SYNC},
-    sys::{
-        mman::{mmap, munmap, shm_open, shm_unlink, MapFlags, ProtFlags},
-        signal::kill,
-        stat::Mode,
-    },
-    unistd::Pid,
-};
 use std::io::{Error, ErrorKind};
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::{env, mem, str};
-use std::{mem::size_of, num::NonZeroUsize, os::raw::c_void, process, time::Duration};
 
 extern crate socket;
 
+/// A raw socket bound to a TSN VLAN interface.
+///
+/// The socket holds a reference on its VLAN, unless opened with
+/// [`ConfigSource::None`]. The reference is released and the fd closed when
+/// the socket is dropped, or earlier with [`TsnSocket::close`] when the caller
+/// wants to see errors.
 pub struct TsnSocket {
     pub fd: i32,
     pub ifname: String,
     pub vlanid: u16,
+    closed: bool,
+    /// Whether this socket holds a reference on its VLAN in the registry
+    registered: bool,
+    /// The rings and program of an AF_XDP socket, see [`xdp`]
+    xdp: Option<xdp::XdpSocket>,
 }
 
+/// Where [`TsnSocketBuilder::open`] takes the config of the NIC from.
+#[derive(Clone)]
+pub enum ConfigSource {
+    /// The file in `CONFIG_PATH`, or `./config.yaml`
+    Default,
+    /// A config file, see [`config::read_config`]
+    Path(PathBuf),
+    /// A config built in memory, e.g. with [`config::normalise_config`]
+    Config(Box<config::Config>),
+    /// Attach to a VLAN that already exists, leaving it and its qdiscs alone
+    None,
+}
+
+impl ConfigSource {
+    fn load(&self, ifname: &str) -> Result<Option<config::Config>, TsnError> {
+        let path = match self {
+            ConfigSource::Default => env::var("CONFIG_PATH").unwrap_or("./config.yaml".to_string()),
+            ConfigSource::Path(path) => path.to_string_lossy().into_owned(),
+            ConfigSource::Config(config) => return config.resolve(ifname).map(Some),
+            ConfigSource::None => return Ok(None),
+        };
+        let configs = config::read_config(&path)?;
+        match configs.get(ifname) {
+            Some(v) => v.resolve(ifname).map(Some),
+            None => Err(TsnError::Config(format!("No config for {}", ifname))),
+        }
+    }
+}
+
+impl From<config::Config> for ConfigSource {
+    fn from(config: config::Config) -> ConfigSource {
+        ConfigSource::Config(Box::new(config))
+    }
+}
+
+impl From<&str> for ConfigSource {
+    fn from(path: &str) -> ConfigSource {
+        ConfigSource::Path(path.into())
+    }
+}
+
+impl From<String> for ConfigSource {
+    fn from(path: String) -> ConfigSource {
+        ConfigSource::Path(path.into())
+    }
+}
+
+impl From<&Path> for ConfigSource {
+    fn from(path: &Path) -> ConfigSource {
+        ConfigSource::Path(path.into())
+    }
+}
+
+impl From<PathBuf> for ConfigSource {
+    fn from(path: PathBuf) -> ConfigSource {
+        ConfigSource::Path(path)
+    }
+}
+
+/// Options of a [`TsnSocket`], see [`TsnSocket::builder`].
+#[derive(Clone)]
+pub struct TsnSocketBuilder {
+    ifname: String,
+    vlanid: Option<u16>,
+    priority: u32,
+    protocol: u16,
+    config: ConfigSource,
+    xdp: Option<xdp::XdpConfig>,
+}
+
+impl TsnSocketBuilder {
+    pub fn vlan(mut self, vlanid: u16) -> TsnSocketBuilder {
+        self.vlanid = Some(vlanid);
+        self
+    }
+
+    /// The `SO_PRIORITY` of the socket, 0 when not set.
+    pub fn priority(mut self, priority: u32) -> TsnSocketBuilder {
+        self.priority = priority;
+        self
+    }
+
+    /// The ethertype to send and receive, every ethertype when not set.
+    pub fn protocol(mut self, protocol: u16) -> TsnSocketBuilder {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Where the config of the NIC comes from, [`ConfigSource::Default`] when
+    /// not set. Takes a [`config::Config`] or a path as well.
+    pub fn config(mut self, config: impl Into<ConfigSource>) -> TsnSocketBuilder {
+        self.config = config.into();
+        self
+    }
+
+    /// Open an AF_XDP socket on a queue of the NIC instead of a raw socket
+    /// on the VLAN interface, see [`xdp`].
+    pub fn xdp(mut self, config: xdp::XdpConfig) -> TsnSocketBuilder {
+        self.xdp = Some(config);
+        self
+    }
+
+    /// Open the socket, creating the VLAN and its qdiscs if this process is
+    /// its first user.
+    pub fn open(self) -> Result<TsnSocket, TsnError> {
+        let vlanid = self
+            .vlanid
+            .ok_or_else(|| TsnError::Config("no vlan id given for the socket".to_string()))?;
+        open_socket(
+            &self.ifname,
+            vlanid,
+            self.priority,
+            self.protocol,
+            &self.config,
+            self.xdp.as_ref(),
+        )
+    }
+}
+
+pub mod apply;
+#[cfg(feature = "tokio")]
+pub mod async_socket;
+pub mod backend;
+pub mod batch;
 mod cbs;
-mod config;
+pub mod config;
+mod error;
+pub mod ethtool;
+mod netlink;
+pub mod reconfigure;
+pub mod registry;
+pub mod ring;
+pub mod rxtime;
+pub mod schema;
+pub mod state;
 mod tas;
 pub mod time;
-mod vlan;
-const SHM_SIZE: usize = 128;
+pub mod txtime;
+pub mod validate;
+pub mod vlan;
+pub mod xdp;
+
+#[cfg(feature = "tokio")]
+pub use async_socket::AsyncTsnSocket;
+pub use error::TsnError;
+pub use reconfigure::reconfigure;
+use registry::{Registry, User};
 
 // Make imple for TsnSocket
 impl TsnSocket {
-    pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), String> {
+    /// Start building a socket on `ifname`, e.g.
+    ///
+    /// ```no_run
+    /// # use tsn::{ConfigSource, TsnSocket};
+    /// let sock = TsnSocket::builder("enp37s0")
+    ///     .vlan(10)
+    ///     .priority(3)
+    ///     .protocol(0x1337)
+    ///     .config("/etc/tsn/config.yaml")
+    ///     .open()?;
+    /// # Ok::<(), tsn::TsnError>(())
+    /// ```
+    pub fn builder(ifname: &str) -> TsnSocketBuilder {
+        TsnSocketBuilder {
+            ifname: ifname.to_string(),
+            vlanid: None,
+            priority: 0,
+            protocol: libc::ETH_P_ALL as u16,
+            config: ConfigSource::Default,
+            xdp: None,
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), TsnError> {
         sock_set_timeout(self, timeout)
     }
 
-    pub fn send(&self, buf: &[u8]) -> Result<isize, String> {
+    pub fn send(&self, buf: &[u8]) -> Result<isize, TsnError> {
         send(self, buf)
     }
 
-    pub fn recv(&self, buf: &mut [u8]) -> Result<isize, String> {
+    pub fn recv(&self, buf: &mut [u8]) -> Result<isize, TsnError> {
         recv(self, buf)
     }
 
-    pub fn recv_msg(&self, msg: &mut msghdr) -> Result<isize, String> {
+    pub fn recv_msg(&self, msg: &mut msghdr) -> Result<isize, TsnError> {
         recv_msg(self, msg)
     }
 
-    pub fn enable_tx_timestamp(&self) -> Result<(), Error> {
+    /// Send several frames with one system call, see [`batch::send_batch`].
+    pub fn send_batch(&self, frames: &[&[u8]]) -> Result<usize, TsnError> {
+        batch::send_batch(self, frames)
+    }
+
+    /// Receive several frames with one system call, see
+    /// [`batch::recv_batch`].
+    pub fn recv_batch(&self, slots: &mut [batch::RecvSlot]) -> Result<usize, TsnError> {
+        batch::recv_batch(self, slots)
+    }
+
+    pub fn enable_tx_timestamp(&self) -> Result<(), TsnError> {
         enable_tx_timestamp(self)
     }
 
-    pub fn get_tx_timestamp(&self) -> Result<time::Timespec, Error> {
+    /// Timestamp received frames, for [`TsnSocket::recv_with_timestamp`].
+    pub fn enable_rx_timestamp(&self, mode: rxtime::RxTimestampMode) -> Result<(), TsnError> {
+        rxtime::enable_rx_timestamp(self, mode)
+    }
+
+    /// Receive a frame with its timestamps and more, see [`rxtime`].
+    pub fn recv_with_timestamp(&self, buf: &mut [u8]) -> Result<(isize, rxtime::RxMeta), TsnError> {
+        rxtime::recv_with_timestamp(self, buf)
+    }
+
+    /// Receive through a memory mapped ring of blocks, see [`ring`].
+    pub fn rx_ring(&self, config: ring::RxRingConfig) -> Result<ring::RxRing<'_>, TsnError> {
+        ring::RxRing::new(self, config)
+    }
+
+    /// Send through a memory mapped ring of frames, see [`ring`].
+    pub fn tx_ring(&self, config: ring::TxRingConfig) -> Result<ring::TxRing<'_>, TsnError> {
+        ring::TxRing::new(self, config)
+    }
+
+    pub fn get_tx_timestamp(&self) -> Result<time::Timespec, TsnError> {
         get_tx_timestamp(self)
     }
 
-    pub fn close(&mut self) -> Result<(), String> {
-        sock_close(self)
+    /// Attach a launch time to every frame sent with [`TsnSocket::send_at`].
+    ///
+    /// `clockid` must match the clock of the ETF qdisc, usually `CLOCK_TAI`.
+    pub fn enable_txtime(
+        &self,
+        clockid: libc::clockid_t,
+        deadline_mode: bool,
+        report_errors: bool,
+    ) -> Result<(), TsnError> {
+        txtime::enable_txtime(self, clockid, deadline_mode, report_errors)
     }
-}
 
-fn create_vlan(ifname: &str, vlanid: u16) -> Result<String, String> {
-    let config = get_config(ifname)?;
-    let shm_name = get_shmem_name(ifname, vlanid);
-    let shm_fd = get_shmem_fd(&shm_name)?;
-    lock_shmem(&shm_fd)?;
-    let mut vlan_vec = read_shmem(&shm_name)?;
-    let name = vlan::get_vlan_name(ifname, vlanid);
-    // If I am the frist user of this vlan, create it
-    let result = if vlan_vec.is_empty() {
-        vlan::create_vlan(&config, ifname, vlanid)
-    } else {
-        Ok(0)
-    };
-    vlan_vec.push(process::id());
-    write_shmem(&shm_name, &vlan_vec)?;
-    unlock_shmem(&shm_fd)?;
-    match result {
-        Ok(_) => Ok(name),
-        Err(_) => Err(format!("Create vlan fails {}", Error::last_os_error())),
+    pub fn send_at(&self, buf: &[u8], txtime: time::Timespec) -> Result<isize, TsnError> {
+        txtime::send_at(self, buf, txtime)
+    }
+
+    /// Read the next dropped or missed frame reported with `report_errors`.
+    ///
+    /// This shares the error queue with [`TsnSocket::get_tx_timestamp`], so
+    /// TX timestamps read here are discarded.
+    pub fn recv_txtime_event(&self) -> Result<Option<txtime::TxtimeEvent>, TsnError> {
+        txtime::recv_txtime_event(self)
+    }
+
+    pub fn close(mut self) -> Result<(), TsnError> {
+        sock_close(&mut self)
     }
 }
 
-fn delete_vlan(ifname: &str, vlanid: u16) -> Result<i32, String> {
-    let shm_name = get_shmem_name(ifname, vlanid);
-    let shm_fd = get_shmem_fd(&shm_name)?;
-    lock_shmem(&shm_fd)?;
-    let mut vlan_vec = read_shmem(&shm_name)?;
-    // remove my pid from shmem
-    for i in 0..vlan_vec.len() {
-        if vlan_vec[i] == process::id() {
-            vlan_vec.remove(i);
-            break;
+impl Drop for TsnSocket {
+    fn drop(&mut self) {
+        if let Err(e) = sock_close(self) {
+            eprintln!("Failed to close TSN socket: {}", e);
         }
     }
-    // delete dead process from vector
-    vlan_vec.retain(|x| kill(Pid::from_raw(*x as i32), None).is_ok());
-    let exit_flag = vlan_vec.is_empty();
-    vlan_vec.resize(SHM_SIZE / size_of::<u32>(), 0);
-    write_shmem(&shm_name, &vlan_vec)?;
-    let result = if exit_flag {
-        if shm_unlink(&*shm_name).is_err() {
-            return Err(format!("Delete shmem fails {}", Error::last_os_error()));
+}
+
+impl AsRawFd for TsnSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl IntoRawFd for TsnSocket {
+    /// Hand the fd over to the caller.
+    ///
+    /// The VLAN reference is kept until this process exits, since the fd still
+    /// needs the VLAN interface; it is then pruned like any dead user. An
+    /// AF_XDP socket loses its rings and XDP program.
+    fn into_raw_fd(mut self) -> RawFd {
+        self.closed = true;
+        self.fd
+    }
+}
+
+fn create_vlan(ifname: &str, vlanid: u16, config: &config::Config) -> Result<String, TsnError> {
+    let registry = Registry::lock()?;
+    let mut reg = registry.get(ifname, vlanid)?;
+    let stale = reg.reap();
+    let name = vlan::get_vlan_name(ifname, vlanid);
+    // If I am the frist user of this vlan, create it, unless it is kept
+    // without users
+    if reg.users.is_empty() && !reg.pinned {
+        if !stale.is_empty() {
+            // Left behind by users that died without closing their sockets
+            let _ = vlan::delete_vlan(ifname, vlanid);
         }
-        match vlan::delete_vlan(ifname, vlanid) {
-            Ok(v) => Ok(v),
-            Err(_) => Err(format!("Delete vlan fails {}", Error::last_os_error())),
+        // The warnings of the schedule are left to `tsn validate` to show
+        if let Err(e) = vlan::create_vlan(config, ifname, vlanid) {
+            registry.put(&reg)?;
+            return Err(e);
         }
-    } else {
-        Ok(0)
-    };
-    unlock_shmem(&shm_fd)?;
-    result
+        registry.set_applied(ifname, Some(config))?;
+    }
+    reg.users.push(User::current()?);
+    registry.put(&reg)?;
+    Ok(name)
+}
+
+fn delete_vlan(ifname: &str, vlanid: u16) -> Result<i32, TsnError> {
+    let registry = Registry::lock()?;
+    let mut reg = registry.get(ifname, vlanid)?;
+    let me = User::current()?;
+    // remove my entry from the registry
+    if let Some(i) = reg.users.iter().position(|user| *user == me) {
+        reg.users.remove(i);
+    }
+    // delete dead process from registry
+    reg.reap();
+    registry.put(&reg)?;
+    if reg.users.is_empty() && !reg.pinned {
+        registry::release_vlan(&registry, ifname, vlanid)?;
+    }
+    Ok(0)
 }
 
+/// Open a socket with the config in `CONFIG_PATH` or `./config.yaml`, see
+/// [`TsnSocket::builder`] for the other options.
 pub fn sock_open(
     ifname: &str,
     vlanid: u16,
     priority: u32,
     proto: u16,
-) -> Result<TsnSocket, String> {
-    let name = match create_vlan(ifname, vlanid) {
-        Ok(v) => v,
-        Err(_) => {
-            return Err(format!("Create vlan fails {}", Error::last_os_error()));
-        }
+) -> Result<TsnSocket, TsnError> {
+    open_socket(
+        ifname,
+        vlanid,
+        priority,
+        proto,
+        &ConfigSource::Default,
+        None,
+    )
+}
+
+fn open_socket(
+    ifname: &str,
+    vlanid: u16,
+    priority: u32,
+    proto: u16,
+    source: &ConfigSource,
+    xdp: Option<&xdp::XdpConfig>,
+) -> Result<TsnSocket, TsnError> {
+    let name = match source.load(ifname)? {
+        Some(config) => create_vlan(ifname, vlanid, &config)?,
+        None => vlan::get_vlan_name(ifname, vlanid),
+    };
+    // From here on, dropping `tsn_sock` on error releases the VLAN again
+    let mut tsn_sock = TsnSocket {
+        fd: -1,
+        ifname: ifname.to_string(),
+        vlanid,
+        closed: false,
+        registered: !matches!(source, ConfigSource::None),
+        xdp: None,
     };
+    // Bound to the NIC, the VLAN interface is only there for its settings
+    if let Some(config) = xdp {
+        let (fd, xdp) = xdp::XdpSocket::open(ifname, vlanid, priority, proto, config)?;
+        tsn_sock.fd = fd.into_raw_fd();
+        tsn_sock.xdp = Some(xdp);
+        return Ok(tsn_sock);
+    }
     let sock;
     let mut res;
-    let ifindex = if_nametoindex(name.as_bytes()).expect("vlan_ifname index");
+    let ifindex = if_nametoindex(name.as_bytes()).map_err(|e| match source {
+        ConfigSource::None => TsnError::Config(format!(
+            "vlan {} does not exist and no config was given to create it",
+            name
+        )),
+        _ => TsnError::VlanCreate {
+            name: name.clone(),
+            source: e.into(),
+        },
+    })?;
     unsafe {
         sock = libc::socket(
             libc::AF_PACKET,
@@ -140,8 +418,9 @@ pub fn sock_open(
         );
     }
     if sock < 0 {
-        return Err(Error::last_os_error().to_string());
+        return Err(TsnError::socket("open"));
     }
+    tsn_sock.fd = sock;
     let prio: *const u32 = &priority;
     unsafe {
         res = libc::setsockopt(
@@ -154,7 +433,7 @@ pub fn sock_open(
     }
 
     if res < 0 {
-        return Err(format!("Socket option error: {}", Error::last_os_error()));
+        return Err(TsnError::socket("setsockopt SO_PRIORITY"));
     }
 
     let sock_ll = libc::sockaddr_ll {
@@ -175,27 +454,40 @@ pub fn sock_open(
         );
     }
     if res < 0 {
-        return Err(format!("Bind error: {}", Error::last_os_error()));
+        return Err(TsnError::socket("bind"));
     }
 
-    Ok(TsnSocket {
-        fd: sock,
-        ifname: ifname.to_string(),
-        vlanid,
-    })
+    Ok(tsn_sock)
 }
 
-pub fn sock_close(sock: &mut TsnSocket) -> Result<(), String> {
-    match delete_vlan(&sock.ifname, sock.vlanid) {
-        Ok(_) => {
-            close(sock.fd).unwrap();
-            Ok(())
-        }
-        Err(_) => Err(format!("Delete vlan fails: {}", Error::last_os_error())),
+pub fn sock_close(sock: &mut TsnSocket) -> Result<(), TsnError> {
+    if sock.closed {
+        return Ok(());
     }
+    sock.closed = true;
+    // Detach the XDP program before the VLAN goes
+    sock.xdp = None;
+    let res = if sock.registered {
+        delete_vlan(&sock.ifname, sock.vlanid).map(|_| ())
+    } else {
+        Ok(())
+    };
+    let closed = match sock.fd {
+        fd if fd >= 0 => close(fd).map_err(|e| TsnError::Socket {
+            op: "close",
+            source: e.into(),
+        }),
+        _ => Ok(()),
+    };
+    // The VLAN error, if any, came first
+    res.and(closed)
 }
 
-pub fn sock_set_timeout(sock: &mut TsnSocket, timeout: Duration) -> Result<(), String> {
+pub fn sock_set_timeout(sock: &mut TsnSocket, timeout: Duration) -> Result<(), TsnError> {
+    if let Some(xdp) = &mut sock.xdp {
+        // Zero waits forever, as SO_RCVTIMEO
+        xdp.timeout = Some(timeout).filter(|timeout| !timeout.is_zero());
+    }
     let sock_timeout = libc::timeval {
         tv_sec: timeout.as_secs() as i64,
         tv_usec: timeout.subsec_micros() as i64,
@@ -212,13 +504,16 @@ pub fn sock_set_timeout(sock: &mut TsnSocket, timeout: Duration) -> Result<(), S
     };
 
     if res < 0 {
-        Err(format!("Set timeout error: {}", Error::last_os_error()))
+        Err(TsnError::socket("setsockopt SO_RCVTIMEO"))
     } else {
         Ok(())
     }
 }
 
-pub fn send(sock: &TsnSocket, buf: &[u8]) -> Result<isize, String> {
+pub fn send(sock: &TsnSocket, buf: &[u8]) -> Result<isize, TsnError> {
+    if let Some(xdp) = &sock.xdp {
+        return xdp.send(sock.fd, buf);
+    }
     let res = unsafe {
         libc::sendto(
             sock.fd,
@@ -231,13 +526,21 @@ pub fn send(sock: &TsnSocket, buf: &[u8]) -> Result<isize, String> {
     };
 
     if res < 0 {
-        Err(format!("Send error: {}", Error::last_os_error()))
+        Err(TsnError::socket("send"))
     } else {
         Ok(res)
     }
 }
 
-pub fn recv(sock: &TsnSocket, buf: &mut [u8]) -> Result<isize, String> {
+pub fn recv(sock: &TsnSocket, buf: &mut [u8]) -> Result<isize, TsnError> {
+    if let Some(xdp) = &sock.xdp {
+        let mut len = 0;
+        xdp.recv(sock.fd, 1, |frame| {
+            len = frame.len().min(buf.len());
+            buf[..len].copy_from_slice(&frame[..len]);
+        })?;
+        return Ok(len as isize);
+    }
     let res = unsafe {
         libc::recvfrom(
             sock.fd,
@@ -250,23 +553,25 @@ pub fn recv(sock: &TsnSocket, buf: &mut [u8]) -> Result<isize, String> {
     };
 
     if res < 0 {
-        Err(format!("Recv error: {}", Error::last_os_error()))
+        Err(TsnError::socket("recv"))
     } else {
         Ok(res)
     }
 }
 
-pub fn recv_msg(sock: &TsnSocket, msg: &mut msghdr) -> Result<isize, String> {
+pub fn recv_msg(sock: &TsnSocket, msg: &mut msghdr) -> Result<isize, TsnError> {
+    sock.packet_socket("recv")?;
     let res = unsafe { libc::recvmsg(sock.fd, msg, 0) };
 
     if res < 0 {
-        Err(format!("Recv error: {}", Error::last_os_error()))
+        Err(TsnError::socket("recv"))
     } else {
         Ok(res)
     }
 }
 
-pub fn enable_tx_timestamp(sock: &TsnSocket) -> Result<(), Error> {
+pub fn enable_tx_timestamp(sock: &TsnSocket) -> Result<(), TsnError> {
+    sock.packet_socket("enable tx timestamp")?;
     let sockfd = sock.fd;
     let interface_name = &sock.ifname;
 
@@ -278,18 +583,7 @@ pub fn enable_tx_timestamp(sock: &TsnSocket) -> Result<(), Error> {
         | libc::SOF_TIMESTAMPING_RX_SOFTWARE
         | libc::SOF_TIMESTAMPING_SOFTWARE;
 
-    let err = unsafe {
-        libc::setsockopt(
-            sockfd,
-            libc::SOL_SOCKET,
-            libc::SO_TIMESTAMPING,
-            &ts_flags as *const _ as *const libc::c_void,
-            std::mem::size_of::<u32>().try_into().unwrap(),
-        )
-    };
-    if err < 0 {
-        return Err(Error::last_os_error());
-    }
+    rxtime::add_timestamping(sockfd, ts_flags)?;
 
     // setsockopt for err queue
 
@@ -304,7 +598,7 @@ pub fn enable_tx_timestamp(sock: &TsnSocket) -> Result<(), Error> {
         )
     };
     if err < 0 {
-        return Err(Error::last_os_error());
+        return Err(TsnError::socket("setsockopt SO_SELECT_ERR_QUEUE"));
     }
 
     // ioctl
@@ -340,9 +634,44 @@ pub fn enable_tx_timestamp(sock: &TsnSocket) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn get_tx_timestamp(sock: &TsnSocket) -> Result<time::Timespec, Error> {
-    let sockfd = sock.fd;
+pub fn get_tx_timestamp(sock: &TsnSocket) -> Result<time::Timespec, TsnError> {
+    let pfd = libc::pollfd {
+        fd: sock.fd,
+        events: libc::POLLPRI,
+        revents: 0,
+    };
+
+    let res = unsafe { libc::poll(&pfd as *const _ as *mut libc::pollfd, 1, 1000) };
+
+    match res {
+        0 => {
+            return Err(TsnError::Socket {
+                op: "poll",
+                source: Error::new(ErrorKind::TimedOut, "poll timeout"),
+            });
+        }
+        res if res < 0 => {
+            return Err(TsnError::socket("poll"));
+        }
+        _ => {}
+    }
+
+    // XXX: IDK why but this doesn't work on NXP
+    // Commenting this out for now
+    // if !(pfd.revents & libc::POLLPRI) != 0 {
+    //     return Err(Error::new(ErrorKind::Other, format!("unexpected revents {}", pfd.revents)));
+    // }
 
+    // Poll done. Now read the timestamp
+    read_tx_timestamp(sock.fd).map_err(|source| TsnError::Socket {
+        op: "recvmsg MSG_ERRQUEUE",
+        source,
+    })
+}
+
+/// The next TX timestamp on the error queue of `sockfd`, which never blocks:
+/// `WouldBlock` when there is none yet.
+pub(crate) fn read_tx_timestamp(sockfd: RawFd) -> Result<time::Timespec, Error> {
     let buf: [u8; 256] = [0u8; 256];
     let buflen = std::mem::size_of_val(&buf);
 
@@ -371,32 +700,6 @@ pub fn get_tx_timestamp(sock: &TsnSocket) -> Result<time::Timespec, Error> {
         msg
     };
 
-    let pfd = libc::pollfd {
-        fd: sockfd,
-        events: libc::POLLPRI,
-        revents: 0,
-    };
-
-    let res = unsafe { libc::poll(&pfd as *const _ as *mut libc::pollfd, 1, 1000) };
-
-    match res {
-        0 => {
-            return Err(Error::new(ErrorKind::TimedOut, "poll timeout"));
-        }
-        res if res < 0 => {
-            return Err(Error::last_os_error());
-        }
-        _ => {}
-    }
-
-    // XXX: IDK why but this doesn't work on NXP
-    // Commenting this out for now
-    // if !(pfd.revents & libc::POLLPRI) != 0 {
-    //     return Err(Error::new(ErrorKind::Other, format!("unexpected revents {}", pfd.revents)));
-    // }
-
-    // Poll done. Now read the timestamp
-
     let cnt = unsafe {
         libc::recvmsg(
             sockfd,
@@ -471,128 +774,3 @@ pub fn timespecff_diff(start: &mut TimeSpec, stop: &mut TimeSpec, result: &mut T
         *result = result_sec + result_nsec;
     }
 }
-
-fn open_shmem(shm_name: &str) -> Result<*mut c_void, String> {
-    let shm_fd = shm_open(
-        shm_name,
-        OFlag::O_CREAT | OFlag::O_RDWR,
-        Mode::S_IRWXU | Mode::S_IRWXG | Mode::S_IRWXO,
-    );
-    let shm_fd = match shm_fd {
-        Ok(v) => v,
-        Err(_) => return Err(format!("Open shmem fails: {}", Error::last_os_error())),
-    };
-    let shm_ptr = unsafe {
-        ftruncate(shm_fd, SHM_SIZE as libc::off_t);
-        mmap(
-            None,
-            NonZeroUsize::new_unchecked(SHM_SIZE),
-            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-            MapFlags::MAP_SHARED,
-            shm_fd,
-            0,
-        )
-    };
-    let shm_ptr = match shm_ptr {
-        Ok(v) => v,
-        Err(_) => return Err(format!("Open shmem fails: {}", Error::last_os_error())),
-    };
-
-    unsafe { msync(shm_ptr, SHM_SIZE, MS_SYNC) };
-
-    Ok(shm_ptr)
-}
-
-fn read_shmem(shm_name: &str) -> Result<Vec<u32>, String> {
-    let shm_ptr = open_shmem(shm_name)?;
-
-    let mut vec_data: Vec<u32> = unsafe {
-        let data = slice::from_raw_parts(shm_ptr as *const u8, SHM_SIZE);
-        slice::from_raw_parts(
-            data.to_vec().as_ptr() as *const u32,
-            data.len() / size_of::<u32>(),
-        )
-        .to_vec()
-    };
-    vec_data.retain(|&x| x != 0);
-    unsafe {
-        match munmap(shm_ptr, SHM_SIZE) {
-            Ok(_) => Ok(vec_data),
-            Err(_) => Err(format!("Read shmem fails: {}", Error::last_os_error())),
-        }
-    }
-}
-
-fn write_shmem(shm_name: &str, input: &Vec<u32>) -> Result<String, String> {
-    let shm_ptr = open_shmem(shm_name)?;
-    let shm_byte = unsafe {
-        slice::from_raw_parts(input.as_ptr() as *const u8, size_of::<u32>() * input.len())
-    };
-    let addr = shm_ptr as *mut u8;
-    for (i, item) in shm_byte.iter().enumerate() {
-        unsafe { *addr.add(i) = *item };
-    }
-    unsafe {
-        match munmap(shm_ptr, SHM_SIZE) {
-            Ok(_) => Ok("".to_string()),
-            Err(_) => Err(format!("Write shmem fails: {}", Error::last_os_error())),
-        }
-    }
-}
-
-fn lock_shmem(shm_fd: &i32) -> Result<i32, String> {
-    let lock = flock {
-        l_type: libc::F_WRLCK as i16,
-        l_whence: libc::SEEK_SET as i16,
-        l_start: 0,
-        l_len: 0,
-        l_pid: 0,
-    };
-    match fcntl(*shm_fd, F_SETLKW(&lock)) {
-        Ok(v) => Ok(v),
-        Err(_) => Err(format!("Lock shmem fails: {}", Error::last_os_error())),
-    }
-}
-
-fn unlock_shmem(shm_fd: &i32) -> Result<i32, String> {
-    let lock = flock {
-        l_type: libc::F_UNLCK as i16,
-        l_whence: libc::SEEK_SET as i16,
-        l_start: 0,
-        l_len: 0,
-        l_pid: 0,
-    };
-    match fcntl(*shm_fd, F_SETLKW(&lock)) {
-        Ok(v) => Ok(v),
-        Err(_) => Err(format!("Unlock shmem fails: {}", Error::last_os_error())),
-    }
-}
-
-fn get_config(ifname: &str) -> Result<config::Config, String> {
-    let config_path = env::var("CONFIG_PATH").unwrap_or("./config.yaml".to_string());
-    let configs = config::read_config(&config_path);
-    let configs = match configs {
-        Ok(v) => v,
-        Err(_) => return Err(format!("Read config fails: {}", Error::last_os_error())),
-    };
-    let config = configs.get(ifname);
-    match config {
-        Some(v) => Ok(v.clone()),
-        None => Err(format!("No config for {}", ifname)),
-    }
-}
-
-fn get_shmem_name(ifname: &str, vlanid: u16) -> String {
-    format!("libtsn_vlan_{}", vlan::get_vlan_name(ifname, vlanid))
-}
-
-fn get_shmem_fd(shm_name: &str) -> Result<i32, String> {
-    match shm_open(
-        shm_name,
-        OFlag::O_CREAT | OFlag::O_RDWR,
-        Mode::S_IRWXU | Mode::S_IRWXG | Mode::S_IRWXO,
-    ) {
-        Ok(v) => Ok(v),
-        Err(_) => Err(format!("Open shmem fails: {}", Error::last_os_error())),
-    }
-}
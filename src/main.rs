@@ -1,27 +1,106 @@
-use crate::{
-    config::read_config,
-    vlan::{create_vlan, delete_vlan},
-};
 use clap::{arg, Arg, ArgMatches, Command as ClapCommand};
-mod cbs;
-mod config;
+use output::{exit_with, ErrorClass, Output};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tsn::{
+    apply::{apply, teardown, Operation},
+    backend::Recorder,
+    config::{self, parse_file, read_config},
+    reconfigure::{self, reconfigure, Change, Reconfigured},
+    registry::{self, Registry},
+    schema::{self, Format},
+    validate::{self, Diagnostic, Limits, Severity},
+    vlan::{create_vlan, create_vlan_with, get_vlan_name},
+    TsnError,
+};
 mod info;
-mod tas;
-mod vlan;
+mod output;
+
+/// What `create` and `delete` did.
+#[derive(Serialize)]
+struct VlanReport {
+    interface: String,
+    vlanid: u16,
+    vlan: String,
+    /// The commands `--dry-run` would have run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commands: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<Diagnostic>,
+}
+
+/// What `watch` did to an interface whose link changed.
+#[derive(Serialize)]
+struct LinkEvent<'a> {
+    interface: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    changes: Option<Vec<Change>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<Diagnostic>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// What `apply` and `teardown` did, or would do with `dry_run`.
+#[derive(Serialize)]
+struct OperationsReport<'a> {
+    dry_run: bool,
+    operations: &'a [Operation],
+    /// Warnings of the TAS schedules, by interface
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    warnings: &'a BTreeMap<String, Vec<Diagnostic>>,
+}
+
+#[derive(Serialize)]
+struct ValidateReport {
+    ok: bool,
+    diagnostics: Vec<Diagnostic>,
+}
+
+#[derive(Serialize)]
+struct UserReport {
+    pid: u32,
+    start_time: u64,
+    alive: bool,
+}
+
+#[derive(Serialize)]
+struct RegistrationReport {
+    interface: String,
+    vlanid: u16,
+    users: Vec<UserReport>,
+}
+
+#[derive(Serialize)]
+struct CollectedReport {
+    interface: String,
+    vlanid: u16,
+    reaped: Vec<u32>,
+    deleted: bool,
+    error: Option<String>,
+}
+
 fn main() {
     let arg_config = arg!(-c --config <config> "Config file path")
         .required(false)
         .default_value("config.yaml");
 
+    let arg_dry_run = arg!(--"dry-run" "Only print what would be done");
     let create_parser = ClapCommand::new("create")
         .about("Create a TSN interface")
         .arg(&arg_config)
+        .arg(&arg_dry_run)
         .arg(
             Arg::new("interface")
                 .help("Interface name to create")
                 .required(true),
         )
-        .arg(Arg::new("vlanid").help("VLAN ID to create").required(true));
+        .arg(
+            Arg::new("vlanid")
+                .help("VLAN ID to create")
+                .required(true)
+                .value_parser(clap::value_parser!(u16)),
+        );
     let delete_parser = ClapCommand::new("delete")
         .about("Delete a TSN interface")
         .arg(&arg_config)
@@ -30,7 +109,42 @@ fn main() {
                 .help("Interface name to delete")
                 .required(true),
         )
-        .arg(Arg::new("vlanid").help("VLAN ID to delete").required(true));
+        .arg(
+            Arg::new("vlanid")
+                .help("VLAN ID to delete")
+                .required(true)
+                .value_parser(clap::value_parser!(u16)),
+        );
+    let apply_parser = ClapCommand::new("apply")
+        .about("Bring every NIC and VLAN of a config to its configured state")
+        .arg(&arg_config)
+        .arg(&arg_dry_run);
+    let teardown_parser = ClapCommand::new("teardown")
+        .about("Remove the VLANs and qdiscs created by libtsn")
+        .arg(&arg_dry_run)
+        .arg(
+            Arg::new("interface")
+                .help("Interface name to clean up, all of them by default")
+                .required(false)
+                .multiple_values(true),
+        );
+    let reconfigure_parser = ClapCommand::new("reconfigure")
+        .about("Apply a changed config to interfaces whose VLANs are in use")
+        .arg(&arg_config)
+        .arg(
+            Arg::new("interface")
+                .help("Interface name to reconfigure")
+                .required(true)
+                .multiple_values(true),
+        );
+    let watch_parser = ClapCommand::new("watch")
+        .about("Recompute the cbs credits of interfaces whenever their link speed changes")
+        .arg(
+            Arg::new("interface")
+                .help("Interface name to watch, all those with a config by default")
+                .required(false)
+                .multiple_values(true),
+        );
     let info_parser = ClapCommand::new("info")
         .about("Show TSN interface information")
         .arg(&arg_config)
@@ -40,57 +154,424 @@ fn main() {
                 .required(false)
                 .multiple_values(true),
         );
+    let validate_parser = ClapCommand::new("validate")
+        .about("Check the TAS schedules of a config")
+        .arg(&arg_config)
+        .arg(
+            Arg::new("interface")
+                .help("Interface name to check")
+                .required(false)
+                .multiple_values(true),
+        );
+    let schema_parser =
+        ClapCommand::new("schema").about("Print the JSON Schema of the config file");
+    let convert_parser = ClapCommand::new("convert")
+        .about("Print a config file in another format")
+        .arg(&arg_config)
+        .arg(
+            Arg::new("format")
+                .help("Format to print, yaml, toml or json")
+                .required(true),
+        );
+    let registry_parser = ClapCommand::new("registry")
+        .about("Show or clean up the processes using libtsn VLANs")
+        .subcommand_required(true)
+        .subcommand(ClapCommand::new("list").about("List VLANs and the processes using them"))
+        .subcommand(
+            ClapCommand::new("gc")
+                .about("Forget dead processes and delete the VLANs nobody uses anymore"),
+        );
     let matched_command: ArgMatches = ClapCommand::new("tsnlib")
         .about("TSN socket manager")
+        .after_help(output::EXIT_CODES)
         .arg_required_else_help(true)
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .short('o')
+                .help("Output format")
+                .global(true)
+                .takes_value(true)
+                .possible_values(["text", "json", "yaml"])
+                .default_value("text"),
+        )
         .subcommand(create_parser)
         .subcommand(delete_parser)
+        .subcommand(apply_parser)
+        .subcommand(teardown_parser)
+        .subcommand(reconfigure_parser)
+        .subcommand(watch_parser)
         .subcommand(info_parser)
+        .subcommand(validate_parser)
+        .subcommand(schema_parser)
+        .subcommand(convert_parser)
+        .subcommand(registry_parser)
         .get_matches();
+    let output: Output = matched_command.value_of("output").unwrap().parse().unwrap();
     match matched_command.subcommand() {
         Some(("create", create_matches)) => {
-            let config = read_config(create_matches.value_of("config").unwrap());
-            if config.is_err() {
-                return;
-            }
+            let config = read_config(create_matches.value_of("config").unwrap())
+                .unwrap_or_else(|e| exit_with(output, e));
             let interface = create_matches.value_of("interface").unwrap();
-            let vlan_id = create_matches
-                .value_of("vlanid")
-                .unwrap()
-                .parse::<u16>()
-                .unwrap();
-            let config = config.as_ref().unwrap().get(interface).unwrap();
-            create_vlan(config, interface, vlan_id).unwrap();
+            let vlan_id = *create_matches.get_one::<u16>("vlanid").unwrap();
+            let config = config.get(interface).unwrap_or_else(|| {
+                exit_with(
+                    output,
+                    TsnError::Config(format!("No config for {}", interface)),
+                )
+            });
+            let config = &config
+                .resolve(interface)
+                .unwrap_or_else(|e| exit_with(output, e));
+            if create_matches.is_present("dry-run") {
+                let mut recorder = Recorder::new();
+                let warnings = create_vlan_with(&mut recorder, config, interface, vlan_id)
+                    .unwrap_or_else(|e| exit_with(output, e));
+                let commands = recorder.commands().iter().map(|c| c.to_string()).collect();
+                print_vlan(output, interface, vlan_id, Some(commands), warnings);
+            } else {
+                let warnings = create_vlan(config, interface, vlan_id)
+                    .unwrap_or_else(|e| exit_with(output, e));
+                Registry::lock()
+                    .and_then(|registry| {
+                        registry.set_applied(interface, Some(config))?;
+                        registry.pin(interface, vlan_id, true)
+                    })
+                    .unwrap_or_else(|e| exit_with(output, e));
+                print_vlan(output, interface, vlan_id, None, warnings);
+            }
         }
         Some(("delete", delete_matches)) => {
             let interface = delete_matches.value_of("interface").unwrap();
-            let vlan_id = delete_matches
-                .value_of("vlanid")
-                .unwrap()
-                .parse::<u16>()
-                .unwrap();
-            delete_vlan(interface, vlan_id).unwrap();
+            let vlan_id = *delete_matches.get_one::<u16>("vlanid").unwrap();
+            Registry::lock()
+                .and_then(|registry| {
+                    // The qdiscs stay while another VLAN of the NIC needs them
+                    registry::release_vlan(&registry, interface, vlan_id)?;
+                    registry.pin(interface, vlan_id, false)
+                })
+                .unwrap_or_else(|e| exit_with(output, e));
+            print_vlan(output, interface, vlan_id, None, Vec::new());
+        }
+        Some(("apply", apply_matches)) => {
+            let config = read_config(apply_matches.value_of("config").unwrap())
+                .unwrap_or_else(|e| exit_with(output, e));
+            let dry_run = apply_matches.is_present("dry-run");
+            let applied = apply(&config, dry_run).unwrap_or_else(|e| exit_with(output, e));
+            print_operations(output, dry_run, &applied.operations, &applied.warnings);
+        }
+        Some(("teardown", teardown_matches)) => {
+            let interfaces: Vec<&str> = teardown_matches
+                .values_of("interface")
+                .map(|interfaces| interfaces.collect())
+                .unwrap_or_default();
+            let dry_run = teardown_matches.is_present("dry-run");
+            let operations =
+                teardown(&interfaces, dry_run).unwrap_or_else(|e| exit_with(output, e));
+            print_operations(output, dry_run, &operations, &BTreeMap::new());
+        }
+        Some(("reconfigure", reconfigure_matches)) => {
+            let config = read_config(reconfigure_matches.value_of("config").unwrap())
+                .unwrap_or_else(|e| exit_with(output, e));
+            let mut report: BTreeMap<&str, Reconfigured> = BTreeMap::new();
+            for interface in reconfigure_matches.values_of("interface").unwrap() {
+                let config = config.get(interface).unwrap_or_else(|| {
+                    exit_with(
+                        output,
+                        TsnError::Config(format!("No config for {}", interface)),
+                    )
+                });
+                let reconfigured =
+                    reconfigure(interface, config).unwrap_or_else(|e| exit_with(output, e));
+                // Printed as it goes, a later interface may fail
+                if output == Output::Text {
+                    warn(interface, &reconfigured.warnings);
+                    println!("{}:", interface);
+                    if reconfigured.changes.is_empty() {
+                        println!("  unchanged");
+                    }
+                    for change in &reconfigured.changes {
+                        println!("  {}", change);
+                    }
+                }
+                report.insert(interface, reconfigured);
+            }
+            if output != Output::Text {
+                output.print(&report, || {});
+            }
+        }
+        Some(("watch", watch_matches)) => {
+            let interfaces: Vec<&str> = watch_matches
+                .values_of("interface")
+                .map(|interfaces| interfaces.collect())
+                .unwrap_or_default();
+            let res = reconfigure::watch(&interfaces, |interface, res| match res {
+                Ok(reconfigured) if reconfigured.changes.is_empty() => {}
+                Ok(reconfigured) => {
+                    let event = LinkEvent {
+                        interface,
+                        changes: Some(reconfigured.changes),
+                        warnings: reconfigured.warnings,
+                        error: None,
+                    };
+                    output.print_event(&event, || {
+                        warn(interface, &event.warnings);
+                        for change in event.changes.iter().flatten() {
+                            println!("{}: {}", interface, change);
+                        }
+                    });
+                }
+                // The other interfaces are still watched
+                Err(e) => {
+                    let event = LinkEvent {
+                        interface,
+                        changes: None,
+                        warnings: Vec::new(),
+                        error: Some(e.to_string()),
+                    };
+                    output.print_event(&event, || eprintln!("{}: {}", interface, e));
+                }
+            });
+            if let Err(e) = res {
+                exit_with(output, e);
+            }
         }
         Some(("info", info_matches)) => {
-            let config = read_config(info_matches.value_of("config").unwrap());
-            if config.is_err() {
-                return;
+            let config = read_config(info_matches.value_of("config").unwrap())
+                .unwrap_or_else(|e| exit_with(output, e));
+            let interfaces: Vec<&str> = match info_matches.values_of("interface") {
+                Some(interfaces) => interfaces.collect(),
+                None => config.keys().map(|k| k.as_str()).collect(),
+            };
+            let mut report = BTreeMap::new();
+            for interface in interfaces {
+                let config = config.get(interface).unwrap_or_else(|| {
+                    exit_with(
+                        output,
+                        TsnError::Config(format!("No config for {}", interface)),
+                    )
+                });
+                report.insert(interface, info::get_info(interface, config));
             }
-            let config = config.as_ref().unwrap();
-            if info_matches.is_present("interface") {
-                let interfaces = info_matches.values_of("interface").unwrap();
-                for interface in interfaces {
+            output.print(&report, || {
+                for (interface, info) in &report {
                     println!("{}:", interface);
-                    let config = config.get(interface).unwrap();
-                    info::get_info(config);
+                    info::print_info(info);
                 }
-            } else {
-                for (interface, config) in config {
+            });
+        }
+        Some(("validate", validate_matches)) => {
+            let config = read_config(validate_matches.value_of("config").unwrap())
+                .unwrap_or_else(|e| exit_with(output, e));
+            let interfaces: Vec<&str> = match validate_matches.values_of("interface") {
+                Some(interfaces) => interfaces.collect(),
+                None => config.keys().map(|k| k.as_str()).collect(),
+            };
+            let mut report = BTreeMap::new();
+            for interface in interfaces {
+                let config = config.get(interface).unwrap_or_else(|| {
+                    exit_with(
+                        output,
+                        TsnError::Config(format!("No config for {}", interface)),
+                    )
+                });
+                let diagnostics = match config.resolve(interface) {
+                    Ok(config) => validate::validate_tas(&config, &Limits::of(interface)),
+                    // Only this interface, the others are still checked
+                    Err(e) => vec![Diagnostic {
+                        severity: Severity::Error,
+                        entry: None,
+                        problem: e.to_string(),
+                        suggestion: "bring the link up".to_string(),
+                    }],
+                };
+                let ok = !validate::has_errors(&diagnostics);
+                report.insert(interface, ValidateReport { ok, diagnostics });
+            }
+            output.print(&report, || {
+                for (interface, nic) in &report {
                     println!("{}:", interface);
-                    info::get_info(config);
+                    if nic.diagnostics.is_empty() {
+                        println!("  ok");
+                    }
+                    for diagnostic in &nic.diagnostics {
+                        println!("  {}", diagnostic);
+                    }
                 }
+            });
+            if report.values().any(|nic| !nic.ok) {
+                std::process::exit(ErrorClass::Schedule.exit_code());
             }
         }
+        Some(("schema", _)) => {
+            let schema: serde_json::Value = serde_json::from_str(&schema::json_schema()).unwrap();
+            match output {
+                Output::Yaml => output.print(&schema, || {}),
+                _ => println!("{}", schema::json_schema()),
+            }
+        }
+        Some(("convert", convert_matches)) => {
+            let path = convert_matches.value_of("config").unwrap();
+            let format: Format = convert_matches
+                .value_of("format")
+                .unwrap()
+                .parse()
+                .unwrap_or_else(|e| exit_with(output, TsnError::Config(e)));
+            let content = std::fs::read_to_string(path).unwrap_or_else(|e| {
+                exit_with(
+                    output,
+                    TsnError::ConfigIo {
+                        path: path.to_string(),
+                        source: e,
+                    },
+                )
+            });
+            let file = parse_file(&content, Format::of(path)).unwrap_or_else(|issues| {
+                exit_with(
+                    output,
+                    TsnError::ConfigInvalid {
+                        path: path.to_string(),
+                        issues,
+                    },
+                )
+            });
+            let converted = file
+                .to_string(format)
+                .unwrap_or_else(|e| exit_with(output, TsnError::Config(e)));
+            print!("{}", converted);
+        }
+        Some(("registry", registry_matches)) => match registry_matches.subcommand() {
+            Some(("list", _)) => list_registry(output),
+            Some(("gc", _)) => gc_registry(output),
+            _ => unreachable!(),
+        },
         _ => unreachable!(),
     }
 }
+
+fn print_operations(
+    output: Output,
+    dry_run: bool,
+    operations: &[Operation],
+    warnings: &BTreeMap<String, Vec<Diagnostic>>,
+) {
+    let report = OperationsReport {
+        dry_run,
+        operations,
+        warnings,
+    };
+    output.print(&report, || {
+        for (interface, warnings) in warnings {
+            warn(interface, warnings);
+        }
+        if operations.is_empty() {
+            println!("nothing to do");
+        }
+        for operation in operations {
+            println!("{}", operation);
+        }
+    });
+}
+
+/// Print the warnings of the schedule of `interface` in text mode, on stderr.
+/// The other outputs have them in their report.
+fn warn(interface: &str, warnings: &[Diagnostic]) {
+    for warning in warnings {
+        eprintln!("{}: {}", interface, warning);
+    }
+}
+
+fn print_vlan(
+    output: Output,
+    interface: &str,
+    vlanid: u16,
+    commands: Option<Vec<String>>,
+    warnings: Vec<Diagnostic>,
+) {
+    let report = VlanReport {
+        interface: interface.to_string(),
+        vlanid,
+        vlan: get_vlan_name(interface, vlanid),
+        commands,
+        warnings,
+    };
+    output.print(&report, || {
+        warn(interface, &report.warnings);
+        for command in report.commands.iter().flatten() {
+            println!("{}", command);
+        }
+    });
+}
+
+/// Report the registry files that were skipped, on stderr whatever the
+/// output so that stdout stays one document.
+fn report_skipped(errors: &[TsnError]) {
+    for e in errors {
+        eprintln!("skipped {}", e);
+    }
+}
+
+fn list_registry(output: Output) {
+    let (regs, errors) = Registry::lock()
+        .and_then(|registry| registry.scan())
+        .unwrap_or_else(|e| exit_with(output, e));
+    report_skipped(&errors);
+    let report: Vec<RegistrationReport> = regs
+        .iter()
+        .map(|reg| RegistrationReport {
+            interface: reg.ifname.clone(),
+            vlanid: reg.vlanid,
+            users: reg
+                .users
+                .iter()
+                .map(|user| UserReport {
+                    pid: user.pid,
+                    start_time: user.start_time,
+                    alive: user.is_alive(),
+                })
+                .collect(),
+        })
+        .collect();
+    output.print(&report, || {
+        for reg in &report {
+            println!("{} vlan {}:", reg.interface, reg.vlanid);
+            for user in &reg.users {
+                let state = if user.alive { "alive" } else { "dead" };
+                println!(
+                    "  pid {} (start time {}): {}",
+                    user.pid, user.start_time, state
+                );
+            }
+        }
+    });
+}
+
+fn gc_registry(output: Output) {
+    let (collected, errors) = registry::gc().unwrap_or_else(|e| exit_with(output, e));
+    report_skipped(&errors);
+    let report: Vec<CollectedReport> = collected
+        .into_iter()
+        .map(|c| CollectedReport {
+            interface: c.ifname,
+            vlanid: c.vlanid,
+            reaped: c.reaped.iter().map(|user| user.pid).collect(),
+            deleted: c.deleted,
+            error: c.error.map(|e| e.to_string()),
+        })
+        .collect();
+    output.print(&report, || {
+        for c in &report {
+            for pid in &c.reaped {
+                println!(
+                    "{} vlan {}: removed dead pid {}",
+                    c.interface, c.vlanid, pid
+                );
+            }
+            match (c.deleted, &c.error) {
+                (true, None) => println!("{} vlan {}: deleted", c.interface, c.vlanid),
+                (true, Some(e)) => eprintln!("{} vlan {}: {}", c.interface, c.vlanid, e),
+                _ => {}
+            }
+        }
+    });
+}
@@ -0,0 +1,554 @@
+use crate::error::TsnError;
+use std::io::Error;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+// include/uapi/linux/if_link.h
+pub const IFLA_INFO_DATA: u16 = 2;
+pub const IFLA_VLAN_ID: u16 = 1;
+pub const IFLA_VLAN_EGRESS_QOS: u16 = 3;
+pub const IFLA_VLAN_QOS_MAPPING: u16 = 1;
+
+// include/uapi/linux/pkt_sched.h
+pub const TC_H_ROOT: u32 = 0xFFFF_FFFF;
+pub const TC_QOPT_MAX_QUEUE: usize = 16;
+
+pub const TCA_TAPRIO_ATTR_PRIOMAP: u16 = 1;
+pub const TCA_TAPRIO_ATTR_SCHED_ENTRY_LIST: u16 = 2;
+pub const TCA_TAPRIO_ATTR_SCHED_BASE_TIME: u16 = 3;
+pub const TCA_TAPRIO_ATTR_SCHED_CLOCKID: u16 = 5;
+pub const TCA_TAPRIO_ATTR_ADMIN_SCHED: u16 = 7;
+pub const TCA_TAPRIO_ATTR_SCHED_CYCLE_TIME: u16 = 8;
+pub const TCA_TAPRIO_ATTR_SCHED_CYCLE_TIME_EXTENSION: u16 = 9;
+pub const TCA_TAPRIO_ATTR_FLAGS: u16 = 10;
+pub const TCA_TAPRIO_ATTR_TXTIME_DELAY: u16 = 11;
+pub const TCA_TAPRIO_SCHED_ENTRY: u16 = 1;
+pub const TCA_TAPRIO_SCHED_ENTRY_CMD: u16 = 2;
+pub const TCA_TAPRIO_SCHED_ENTRY_GATE_MASK: u16 = 3;
+pub const TCA_TAPRIO_SCHED_ENTRY_INTERVAL: u16 = 4;
+pub const TC_TAPRIO_CMD_SET_GATES: u8 = 0;
+
+pub const TCA_CBS_PARMS: u16 = 1;
+
+// include/uapi/linux/gen_stats.h
+pub const TCA_STATS_BASIC: u16 = 1;
+pub const TCA_STATS_QUEUE: u16 = 3;
+
+pub const TCA_ETF_PARMS: u16 = 1;
+pub const TC_ETF_DEADLINE_MODE_ON: u32 = 1 << 0;
+pub const TC_ETF_OFFLOAD_ON: u32 = 1 << 1;
+pub const TC_ETF_SKIP_SOCK_CHECK: u32 = 1 << 2;
+
+// include/uapi/linux/netlink.h
+const NLMSG_HDRLEN: usize = 16;
+const NLA_HDRLEN: usize = 4;
+const NLMSGERR_ATTR_MSG: u16 = 1;
+const NLM_F_CAPPED: u16 = 0x100;
+const NLM_F_ACK_TLVS: u16 = 0x200;
+const NLA_F_NESTED: u16 = 1 << 15;
+const NLA_TYPE_MASK: u16 = !(NLA_F_NESTED | 1 << 14);
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// tc handle `major:minor` as the kernel stores it.
+pub fn tc_handle(major: u32, minor: u32) -> u32 {
+    (major << 16) | (minor & 0xFFFF)
+}
+
+/// A single rtnetlink request being built.
+///
+/// The header is filled in by [`Netlink::request`], everything else is
+/// appended in wire order: the family header (`ifinfomsg`/`tcmsg`) first,
+/// then attributes.
+pub struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(msg_type: u16, flags: u16) -> Message {
+        let mut buf = vec![0u8; NLMSG_HDRLEN];
+        buf[4..6].copy_from_slice(&msg_type.to_ne_bytes());
+        buf[6..8].copy_from_slice(
+            &(flags | libc::NLM_F_REQUEST as u16 | libc::NLM_F_ACK as u16).to_ne_bytes(),
+        );
+        Message { buf }
+    }
+
+    /// A request for every object of `msg_type`, answered with many messages.
+    pub fn dump(msg_type: u16) -> Message {
+        let mut buf = vec![0u8; NLMSG_HDRLEN];
+        buf[4..6].copy_from_slice(&msg_type.to_ne_bytes());
+        buf[6..8].copy_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+        Message { buf }
+    }
+
+    /// Append `struct ifinfomsg`.
+    pub fn ifinfomsg(&mut self, index: i32, flags: u32, change: u32) -> &mut Message {
+        self.buf.push(libc::AF_UNSPEC as u8);
+        self.buf.push(0);
+        self.buf.extend_from_slice(&0u16.to_ne_bytes());
+        self.buf.extend_from_slice(&index.to_ne_bytes());
+        self.buf.extend_from_slice(&flags.to_ne_bytes());
+        self.buf.extend_from_slice(&change.to_ne_bytes());
+        self
+    }
+
+    /// Append `struct tcmsg`.
+    pub fn tcmsg(&mut self, ifindex: i32, handle: u32, parent: u32) -> &mut Message {
+        self.buf.push(libc::AF_UNSPEC as u8);
+        self.buf.push(0);
+        self.buf.extend_from_slice(&0u16.to_ne_bytes());
+        self.buf.extend_from_slice(&ifindex.to_ne_bytes());
+        self.buf.extend_from_slice(&handle.to_ne_bytes());
+        self.buf.extend_from_slice(&parent.to_ne_bytes());
+        self.buf.extend_from_slice(&0u32.to_ne_bytes());
+        self
+    }
+
+    pub fn attr(&mut self, attr_type: u16, data: &[u8]) -> &mut Message {
+        let len = NLA_HDRLEN + data.len();
+        self.buf.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&attr_type.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align(self.buf.len()), 0);
+        self
+    }
+
+    pub fn attr_u8(&mut self, attr_type: u16, value: u8) -> &mut Message {
+        self.attr(attr_type, &[value])
+    }
+
+    pub fn attr_u16(&mut self, attr_type: u16, value: u16) -> &mut Message {
+        self.attr(attr_type, &value.to_ne_bytes())
+    }
+
+    pub fn attr_u32(&mut self, attr_type: u16, value: u32) -> &mut Message {
+        self.attr(attr_type, &value.to_ne_bytes())
+    }
+
+    pub fn attr_i64(&mut self, attr_type: u16, value: i64) -> &mut Message {
+        self.attr(attr_type, &value.to_ne_bytes())
+    }
+
+    pub fn attr_str(&mut self, attr_type: u16, value: &str) -> &mut Message {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.attr(attr_type, &data)
+    }
+
+    /// Append a nested attribute whose content is written by `f`.
+    pub fn nested<F>(&mut self, attr_type: u16, f: F) -> &mut Message
+    where
+        F: FnOnce(&mut Message),
+    {
+        let start = self.buf.len();
+        self.buf.extend_from_slice(&[0u8; NLA_HDRLEN]);
+        f(self);
+        let len = self.buf.len() - start;
+        self.buf[start..start + 2].copy_from_slice(&(len as u16).to_ne_bytes());
+        self.buf[start + 2..start + 4].copy_from_slice(&(attr_type | NLA_F_NESTED).to_ne_bytes());
+        self
+    }
+
+    pub fn msg_type(&self) -> u16 {
+        u16::from_ne_bytes([self.buf[4], self.buf[5]])
+    }
+
+    /// The family header and the attributes appended so far.
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[NLMSG_HDRLEN..]
+    }
+
+    fn finish(&mut self, seq: u32) -> &[u8] {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        &self.buf
+    }
+}
+
+/// The attributes in `buf`, with their type and payload.
+pub fn attrs(mut buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = Vec::new();
+    while buf.len() >= NLA_HDRLEN {
+        let len = u16::from_ne_bytes([buf[0], buf[1]]) as usize;
+        let attr_type = u16::from_ne_bytes([buf[2], buf[3]]) & NLA_TYPE_MASK;
+        if len < NLA_HDRLEN || len > buf.len() {
+            break;
+        }
+        attrs.push((attr_type, &buf[NLA_HDRLEN..len]));
+        buf = &buf[align(len).min(buf.len())..];
+    }
+    attrs
+}
+
+/// The payload of the first `attr_type` attribute in `buf`.
+pub fn attr(buf: &[u8], attr_type: u16) -> Option<&[u8]> {
+    attrs(buf)
+        .into_iter()
+        .find(|(t, _)| *t == attr_type)
+        .map(|(_, data)| data)
+}
+
+/// The native endian integer at `offset` of `data`.
+pub fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_ne_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+pub fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+pub fn read_i32(data: &[u8], offset: usize) -> Option<i32> {
+    read_u32(data, offset).map(|v| v as i32)
+}
+
+pub fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_ne_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+pub fn read_i64(data: &[u8], offset: usize) -> Option<i64> {
+    read_u64(data, offset).map(|v| v as i64)
+}
+
+/// A string attribute, without its trailing NUL.
+pub fn read_str(data: &[u8]) -> String {
+    let data = data.split(|b| *b == 0).next().unwrap_or_default();
+    String::from_utf8_lossy(data).into_owned()
+}
+
+/// `struct tc_mqprio_qopt`, shared by mqprio and taprio.
+pub fn mqprio_qopt(num_tc: u8, prio_tc_map: &[u8; 16], queues: &[(u16, u16)], hw: u8) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(82);
+    buf.push(num_tc);
+    buf.extend_from_slice(prio_tc_map);
+    buf.push(hw);
+    let mut count = [0u16; TC_QOPT_MAX_QUEUE];
+    let mut offset = [0u16; TC_QOPT_MAX_QUEUE];
+    for (i, (c, o)) in queues.iter().take(TC_QOPT_MAX_QUEUE).enumerate() {
+        count[i] = *c;
+        offset[i] = *o;
+    }
+    count
+        .iter()
+        .for_each(|c| buf.extend_from_slice(&c.to_ne_bytes()));
+    offset
+        .iter()
+        .for_each(|o| buf.extend_from_slice(&o.to_ne_bytes()));
+    buf
+}
+
+/// `struct tc_cbs_qopt`
+pub fn cbs_qopt(
+    offload: bool,
+    hicredit: i32,
+    locredit: i32,
+    idleslope: i32,
+    sendslope: i32,
+) -> Vec<u8> {
+    let mut buf = vec![offload as u8, 0, 0, 0];
+    buf.extend_from_slice(&hicredit.to_ne_bytes());
+    buf.extend_from_slice(&locredit.to_ne_bytes());
+    buf.extend_from_slice(&idleslope.to_ne_bytes());
+    buf.extend_from_slice(&sendslope.to_ne_bytes());
+    buf
+}
+
+/// `struct tc_etf_qopt`
+pub fn etf_qopt(delta: i32, clockid: i32, flags: u32) -> Vec<u8> {
+    let mut buf = delta.to_ne_bytes().to_vec();
+    buf.extend_from_slice(&clockid.to_ne_bytes());
+    buf.extend_from_slice(&flags.to_ne_bytes());
+    buf
+}
+
+/// `struct ifla_vlan_qos_mapping`
+pub fn vlan_qos_mapping(from: u32, to: u32) -> Vec<u8> {
+    let mut buf = from.to_ne_bytes().to_vec();
+    buf.extend_from_slice(&to.to_ne_bytes());
+    buf
+}
+
+/// A NETLINK_ROUTE socket with extended acks enabled.
+pub struct Netlink {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl Netlink {
+    pub fn open() -> Result<Netlink, TsnError> {
+        Netlink::bind(0)
+    }
+
+    /// A socket that also gets the notifications of the multicast `groups`,
+    /// e.g. `RTMGRP_LINK`.
+    pub fn listen(groups: u32) -> Result<Netlink, TsnError> {
+        Netlink::bind(groups)
+    }
+
+    fn bind(groups: u32) -> Result<Netlink, TsnError> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(TsnError::socket("open netlink"));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // Both are best effort: old kernels still answer, only without the reason.
+        let one: libc::c_int = 1;
+        for opt in [libc::NETLINK_EXT_ACK, libc::NETLINK_CAP_ACK] {
+            unsafe {
+                libc::setsockopt(
+                    fd.as_raw_fd(),
+                    libc::SOL_NETLINK,
+                    opt,
+                    &one as *const _ as *const libc::c_void,
+                    mem::size_of_val(&one) as u32,
+                );
+            }
+        }
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        addr.nl_groups = groups;
+        let res = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of_val(&addr) as u32,
+            )
+        };
+        if res < 0 {
+            return Err(TsnError::socket("bind netlink"));
+        }
+
+        Ok(Netlink { fd, seq: 0 })
+    }
+
+    /// Send `msg` and wait for the kernel's ack.
+    ///
+    /// `request` is a human readable description of the operation, in `ip`/`tc`
+    /// syntax, used in the error.
+    pub fn request(&mut self, request: &str, msg: &mut Message) -> Result<(), TsnError> {
+        self.seq = self.seq.wrapping_add(1);
+        let seq = self.seq;
+        let data = msg.finish(seq);
+        let res = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                data.as_ptr() as *const libc::c_void,
+                data.len(),
+                0,
+            )
+        };
+        if res < 0 {
+            return Err(netlink_error(request, Error::last_os_error(), None));
+        }
+
+        let mut buf = vec![0u8; 32 * 1024];
+        loop {
+            let len = unsafe {
+                libc::recv(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                let err = Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(netlink_error(request, err, None));
+            }
+            let mut msgs = &buf[..len as usize];
+            while msgs.len() >= NLMSG_HDRLEN {
+                let msg_len = u32::from_ne_bytes(msgs[0..4].try_into().unwrap()) as usize;
+                let msg_type = u16::from_ne_bytes(msgs[4..6].try_into().unwrap());
+                let msg_flags = u16::from_ne_bytes(msgs[6..8].try_into().unwrap());
+                let msg_seq = u32::from_ne_bytes(msgs[8..12].try_into().unwrap());
+                if msg_len < NLMSG_HDRLEN || msg_len > msgs.len() {
+                    break;
+                }
+                if msg_seq == seq && msg_type == libc::NLMSG_ERROR as u16 {
+                    return parse_ack(request, msg_flags, &msgs[NLMSG_HDRLEN..msg_len]);
+                }
+                msgs = &msgs[align(msg_len).min(msgs.len())..];
+            }
+        }
+    }
+}
+
+impl Netlink {
+    /// Send the dump request `msg` and collect the answers, each without its
+    /// netlink header.
+    pub fn dump(&mut self, request: &str, msg: &mut Message) -> Result<Vec<Vec<u8>>, TsnError> {
+        self.seq = self.seq.wrapping_add(1);
+        let seq = self.seq;
+        let data = msg.finish(seq);
+        let res = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                data.as_ptr() as *const libc::c_void,
+                data.len(),
+                0,
+            )
+        };
+        if res < 0 {
+            return Err(netlink_error(request, Error::last_os_error(), None));
+        }
+
+        let mut answers = Vec::new();
+        let mut buf = vec![0u8; 32 * 1024];
+        loop {
+            let len = unsafe {
+                libc::recv(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                let err = Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(netlink_error(request, err, None));
+            }
+            let mut msgs = &buf[..len as usize];
+            while msgs.len() >= NLMSG_HDRLEN {
+                let msg_len = u32::from_ne_bytes(msgs[0..4].try_into().unwrap()) as usize;
+                let msg_type = u16::from_ne_bytes(msgs[4..6].try_into().unwrap());
+                let msg_flags = u16::from_ne_bytes(msgs[6..8].try_into().unwrap());
+                let msg_seq = u32::from_ne_bytes(msgs[8..12].try_into().unwrap());
+                if msg_len < NLMSG_HDRLEN || msg_len > msgs.len() {
+                    break;
+                }
+                if msg_seq == seq {
+                    match msg_type as i32 {
+                        libc::NLMSG_DONE => return Ok(answers),
+                        libc::NLMSG_ERROR => {
+                            parse_ack(request, msg_flags, &msgs[NLMSG_HDRLEN..msg_len])?;
+                            return Ok(answers);
+                        }
+                        _ => answers.push(msgs[NLMSG_HDRLEN..msg_len].to_vec()),
+                    }
+                }
+                msgs = &msgs[align(msg_len).min(msgs.len())..];
+            }
+        }
+    }
+}
+
+/// A notification: its message type and payload.
+pub type Notification = (u16, Vec<u8>);
+
+impl Netlink {
+    /// Wait for notifications, and return each one's type and payload without
+    /// its netlink header.
+    ///
+    /// None is returned when some were lost because the socket buffer was
+    /// full, after which whatever they were about has to be read again.
+    pub fn notifications(&mut self) -> Result<Option<Vec<Notification>>, TsnError> {
+        let mut buf = vec![0u8; 32 * 1024];
+        let len = loop {
+            let len = unsafe {
+                libc::recv(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if len >= 0 {
+                break len as usize;
+            }
+            let err = Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::ENOBUFS) => return Ok(None),
+                _ => return Err(netlink_error("receive notifications", err, None)),
+            }
+        };
+        let mut notifications = Vec::new();
+        let mut msgs = &buf[..len];
+        while msgs.len() >= NLMSG_HDRLEN {
+            let msg_len = u32::from_ne_bytes(msgs[0..4].try_into().unwrap()) as usize;
+            let msg_type = u16::from_ne_bytes(msgs[4..6].try_into().unwrap());
+            if msg_len < NLMSG_HDRLEN || msg_len > msgs.len() {
+                break;
+            }
+            notifications.push((msg_type, msgs[NLMSG_HDRLEN..msg_len].to_vec()));
+            msgs = &msgs[align(msg_len).min(msgs.len())..];
+        }
+        Ok(Some(notifications))
+    }
+}
+
+/// Parse the payload of an NLMSG_ERROR message: `struct nlmsgerr` followed by
+/// either the full original request or just its header, then the ack TLVs.
+fn parse_ack(request: &str, flags: u16, payload: &[u8]) -> Result<(), TsnError> {
+    if payload.len() < 4 + NLMSG_HDRLEN {
+        return Err(netlink_error(
+            request,
+            Error::new(std::io::ErrorKind::InvalidData, "truncated netlink ack"),
+            None,
+        ));
+    }
+    let errno = -i32::from_ne_bytes(payload[0..4].try_into().unwrap());
+    if errno == 0 {
+        return Ok(());
+    }
+
+    let mut extack = None;
+    if flags & NLM_F_ACK_TLVS != 0 {
+        let orig_len = if flags & NLM_F_CAPPED != 0 {
+            NLMSG_HDRLEN
+        } else {
+            u32::from_ne_bytes(payload[4..8].try_into().unwrap()) as usize
+        };
+        let mut tlvs = payload.get(4 + align(orig_len)..).unwrap_or_default();
+        while tlvs.len() >= NLA_HDRLEN {
+            let len = u16::from_ne_bytes(tlvs[0..2].try_into().unwrap()) as usize;
+            let attr_type = u16::from_ne_bytes(tlvs[2..4].try_into().unwrap());
+            if len < NLA_HDRLEN || len > tlvs.len() {
+                break;
+            }
+            if attr_type == NLMSGERR_ATTR_MSG {
+                let msg = &tlvs[NLA_HDRLEN..len];
+                let msg = msg.split(|b| *b == 0).next().unwrap_or_default();
+                extack = Some(String::from_utf8_lossy(msg).into_owned());
+            }
+            tlvs = &tlvs[align(len).min(tlvs.len())..];
+        }
+    }
+
+    Err(netlink_error(
+        request,
+        Error::from_raw_os_error(errno),
+        extack,
+    ))
+}
+
+fn netlink_error(request: &str, source: Error, extack: Option<String>) -> TsnError {
+    TsnError::Netlink {
+        request: request.to_string(),
+        extack,
+        source,
+    }
+}
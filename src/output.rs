@@ -0,0 +1,138 @@
+//! The `--output` format of the subcommands, and the exit code of each class
+//! of error.
+//!
+//! Scripts should use `--output json` or `--output yaml`: the text output is
+//! for people and may change, the fields of the other two are only added to.
+
+use serde::Serialize;
+use std::str::FromStr;
+use tsn::config::ConfigIssue;
+use tsn::validate::Diagnostic;
+use tsn::TsnError;
+
+pub const EXIT_CODES: &str = "Exit codes:
+    0  success
+    1  any other error
+    2  invalid command line
+    3  the config file cannot be read or is invalid
+    4  a TAS schedule failed validation
+    5  the kernel refused to create, change or delete a VLAN or qdisc
+    6  the registry of VLAN users cannot be read or written";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Text,
+    Json,
+    Yaml,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Output, String> {
+        match s {
+            "text" => Ok(Output::Text),
+            "json" => Ok(Output::Json),
+            "yaml" => Ok(Output::Yaml),
+            _ => Err(format!("unknown output {}, use text, json or yaml", s)),
+        }
+    }
+}
+
+impl Output {
+    /// Print `report` on stdout, or run `text` in text mode.
+    pub fn print<T: Serialize>(self, report: &T, text: impl FnOnce()) {
+        match self {
+            Output::Text => text(),
+            Output::Json => println!("{}", serde_json::to_string_pretty(report).unwrap()),
+            Output::Yaml => print!("{}", serde_yaml::to_string(report).unwrap()),
+        }
+    }
+
+    /// Print `event`, one of a stream, on stdout: a line of JSON or a YAML
+    /// document. `text` is run in text mode.
+    pub fn print_event<T: Serialize>(self, event: &T, text: impl FnOnce()) {
+        match self {
+            Output::Text => text(),
+            Output::Json => println!("{}", serde_json::to_string(event).unwrap()),
+            Output::Yaml => print!("---\n{}", serde_yaml::to_string(event).unwrap()),
+        }
+    }
+}
+
+/// What an error is about, which decides the exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorClass {
+    Config,
+    Schedule,
+    Kernel,
+    Registry,
+    Other,
+}
+
+impl ErrorClass {
+    pub fn of(err: &TsnError) -> ErrorClass {
+        match err {
+            TsnError::ConfigIo { .. } | TsnError::Config(_) | TsnError::ConfigInvalid { .. } => {
+                ErrorClass::Config
+            }
+            TsnError::Schedule { .. } => ErrorClass::Schedule,
+            TsnError::VlanCreate { .. }
+            | TsnError::VlanDelete { .. }
+            | TsnError::Netlink { .. }
+            | TsnError::Link { .. } => ErrorClass::Kernel,
+            TsnError::Registry { .. } => ErrorClass::Registry,
+            TsnError::Socket { .. } => ErrorClass::Other,
+        }
+    }
+
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorClass::Other => 1,
+            ErrorClass::Config => 3,
+            ErrorClass::Schedule => 4,
+            ErrorClass::Kernel => 5,
+            ErrorClass::Registry => 6,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorReport<'a> {
+    class: ErrorClass,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    issues: Option<&'a [ConfigIssue]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diagnostics: Option<&'a [Diagnostic]>,
+}
+
+/// Report `err` and exit with the code of its class. Outside of text mode
+/// the error is printed on stdout as `{"error": {...}}`, so that stdout is
+/// always one document.
+pub fn exit_with(output: Output, err: TsnError) -> ! {
+    let class = ErrorClass::of(&err);
+    let (issues, diagnostics) = match &err {
+        TsnError::ConfigInvalid { issues, .. } => (Some(&issues[..]), None),
+        TsnError::Schedule { diagnostics, .. } => (None, Some(&diagnostics[..])),
+        _ => (None, None),
+    };
+    let report = ErrorReport {
+        class,
+        message: err.to_string(),
+        issues,
+        diagnostics,
+    };
+    let report = std::collections::BTreeMap::from([("error", report)]);
+    output.print(&report, || match &err {
+        TsnError::ConfigInvalid { path, issues } => {
+            eprintln!("invalid config {}:", path);
+            for issue in issues {
+                eprintln!("  {}", issue);
+            }
+        }
+        err => eprintln!("{}", err),
+    });
+    std::process::exit(class.exit_code());
+}
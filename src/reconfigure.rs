@@ -0,0 +1,410 @@
+//! Apply a new config to a NIC while sockets are bound to its VLANs.
+//!
+//! The new config is compared with the one installed on the NIC, and only
+//! what differs is changed: a new admin schedule for taprio, new credits for
+//! cbs, new ETF parameters or VLAN egress-qos-maps. The root qdisc is
+//! installed again only when its traffic classes change, which a running
+//! taprio or mqprio does not allow. The VLAN interfaces are never deleted.
+//!
+//! [`watch`] does the same for the cbs credits when a link renegotiates its
+//! speed.
+
+use crate::config::{Config, EtfConfig};
+use crate::error::TsnError;
+use crate::ethtool;
+use crate::netlink::{self, Netlink};
+use crate::registry::Registry;
+use crate::validate::{self, Diagnostic};
+use crate::vlan;
+use itertools::Itertools;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+
+// sizeof(struct ifinfomsg)
+const IFINFOMSG_LEN: usize = 16;
+
+/// One step from the installed config to the new one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+    /// The egress-qos-map of a VLAN in use
+    EgressQosMap { vlanid: u16 },
+    /// The root qdisc and everything under it is installed again
+    Root,
+    /// taprio gets a new admin schedule, with the same traffic classes
+    Schedule,
+    /// New credits for the cbs qdisc on class `class` of the root qdisc
+    Cbs { class: i64 },
+    /// The ETF qdisc of `prio` is added or changed
+    Etf { prio: i64 },
+    /// The ETF qdisc of `prio` is removed
+    EtfRemoved { prio: i64 },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::EgressQosMap { vlanid } => write!(f, "egress-qos-map of vlan {}", vlanid),
+            Change::Root => write!(f, "root qdisc"),
+            Change::Schedule => write!(f, "tas schedule"),
+            Change::Cbs { class } => write!(f, "cbs on class {:x}", class),
+            Change::Etf { prio } => write!(f, "etf of prio {}", prio),
+            Change::EtfRemoved { prio } => write!(f, "etf of prio {} removed", prio),
+        }
+    }
+}
+
+/// What [`reconfigure`] did to a NIC.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Reconfigured {
+    pub changes: Vec<Change>,
+    /// Warnings of the new TAS schedule, which was installed anyway
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// The ETF qdiscs of `config`, wherever they are installed.
+fn etf(config: &Config) -> &HashMap<i64, EtfConfig> {
+    match &config.tas {
+        Some(tas) => &tas.etf,
+        None => &config.etf,
+    }
+}
+
+/// Whether going from `old` to `new` changes the traffic classes of the root
+/// qdisc, or which qdiscs hang under them.
+fn root_changed(old: &Config, new: &Config) -> bool {
+    if old.tas.is_some() != new.tas.is_some()
+        || old.cbs.is_some() != new.cbs.is_some()
+        || old.etf.is_empty() != new.etf.is_empty()
+    {
+        return true;
+    }
+    if let (Some(old), Some(new)) = (&old.tas, &new.tas) {
+        if old.tc_map != new.tc_map
+            || old.num_tc != new.num_tc
+            || old.queues != new.queues
+            || old.mode != new.mode
+            || old.txtime_delay != new.txtime_delay
+        {
+            return true;
+        }
+    }
+    if let (Some(old), Some(new)) = (&old.cbs, &new.cbs) {
+        if old.tc_map != new.tc_map
+            || old.num_tc != new.num_tc
+            || old.queues != new.queues
+            || !old
+                .children
+                .keys()
+                .sorted()
+                .eq(new.children.keys().sorted())
+        {
+            return true;
+        }
+    }
+    // The mqprio of NIC level ETF has a traffic class for each ETF prio
+    old.tas.is_none() && old.cbs.is_none() && !old.etf.keys().sorted().eq(new.etf.keys().sorted())
+}
+
+/// What to change to go from the `old` config of a NIC to `new`, `vlans`
+/// being the VLANs in use on it.
+pub fn diff(old: &Config, new: &Config, vlans: &[u16]) -> Result<Vec<Change>, TsnError> {
+    let mut changes = Vec::new();
+    for vlanid in vlans.iter().sorted() {
+        let key = *vlanid as i64;
+        match new.egress_qos_map.get(&key) {
+            None => {
+                return Err(TsnError::Config(format!(
+                    "vlan {} is in use but the new config has no egress-qos-map for it",
+                    vlanid
+                )))
+            }
+            Some(map) if old.egress_qos_map.get(&key) != Some(map) => {
+                changes.push(Change::EgressQosMap { vlanid: *vlanid });
+            }
+            Some(_) => {}
+        }
+    }
+    if root_changed(old, new) {
+        changes.push(Change::Root);
+        return Ok(changes);
+    }
+    if let (Some(old), Some(new)) = (&old.tas, &new.tas) {
+        if old.sched_entries != new.sched_entries
+            || old.base_time != new.base_time
+            || old.cycle_time != new.cycle_time
+            || old.cycle_time_extension != new.cycle_time_extension
+        {
+            changes.push(Change::Schedule);
+        }
+    }
+    if let (Some(old), Some(new)) = (&old.cbs, &new.cbs) {
+        for (class, credit) in new.children.iter().sorted_by_key(|(class, _)| **class) {
+            if old.children.get(class) != Some(credit) {
+                changes.push(Change::Cbs { class: *class });
+            }
+        }
+    }
+    let (old_etf, new_etf) = (etf(old), etf(new));
+    for (prio, config) in new_etf.iter().sorted_by_key(|(prio, _)| **prio) {
+        if old_etf.get(prio) != Some(config) {
+            changes.push(Change::Etf { prio: *prio });
+        }
+    }
+    for prio in old_etf
+        .keys()
+        .filter(|prio| !new_etf.contains_key(prio))
+        .sorted()
+    {
+        changes.push(Change::EtfRemoved { prio: *prio });
+    }
+    Ok(changes)
+}
+
+/// Apply `config` to `ifname` without deleting the VLANs in use on it, and
+/// return what was changed and the warnings of its schedule.
+///
+/// The config installed by the first socket user of a VLAN is remembered in
+/// the [registry](crate::registry), and is what `config` is compared with.
+pub fn reconfigure(ifname: &str, config: &Config) -> Result<Reconfigured, TsnError> {
+    reconfigure_locked(&Registry::lock()?, ifname, &config.resolve(ifname)?)
+}
+
+/// [`reconfigure`], with the registry already locked by the caller.
+fn reconfigure_locked(
+    registry: &Registry,
+    ifname: &str,
+    config: &Config,
+) -> Result<Reconfigured, TsnError> {
+    let vlans: Vec<u16> = registry
+        .list()?
+        .into_iter()
+        .filter(|reg| reg.ifname == ifname && (reg.pinned || !reg.users.is_empty()))
+        .map(|reg| reg.vlanid)
+        .collect();
+    let (old, known) = match registry.applied(ifname)? {
+        Some(old) => (old, true),
+        None if vlans.is_empty() => {
+            return Err(TsnError::Config(format!(
+                "no config is installed on {}, there is nothing to reconfigure",
+                ifname
+            )))
+        }
+        // Installed before configs were recorded, so everything is replaced
+        None => (Config::new(HashMap::new()), false),
+    };
+    let warnings = validate::check(config, ifname)?;
+    let mut changes = diff(&old, config, &vlans)?;
+    if !known && !changes.contains(&Change::Root) {
+        changes.push(Change::Root);
+    }
+    vlan::apply_changes(&mut Netlink::open()?, ifname, &old, config, &changes)?;
+    registry.set_applied(ifname, Some(config))?;
+    Ok(Reconfigured { changes, warnings })
+}
+
+/// Compute the cbs credits of the config installed on `ifname` again for the
+/// speed its link has now, and install them if they changed.
+///
+/// Nothing changes when the config sets `link_speed` or has no cbs, nor
+/// while the link is down: the credits are kept until it is up again.
+pub fn follow_link_speed(ifname: &str) -> Result<Reconfigured, TsnError> {
+    // Held until the new credits are installed, so that a reconfigure in
+    // between is not undone with the config read here
+    let registry = Registry::lock()?;
+    let mut config = match registry.applied(ifname)? {
+        Some(config) if config.cbs.is_some() && config.link_speed.is_none() => config,
+        _ => return Ok(Reconfigured::default()),
+    };
+    let settings = ethtool::link_settings(ifname).map_err(|source| TsnError::Link {
+        ifname: ifname.to_string(),
+        source,
+    })?;
+    let speed = match settings.speed {
+        Some(speed) if settings.link_up => speed,
+        _ => return Ok(Reconfigured::default()),
+    };
+    let cbs = config.cbs.as_mut().unwrap();
+    if cbs.link_speed == speed {
+        return Ok(Reconfigured::default());
+    }
+    cbs.set_link_speed(speed)
+        .map_err(|e| TsnError::Config(format!("{} at {}bps: {}", ifname, speed, e)))?;
+    reconfigure_locked(&registry, ifname, &config)
+}
+
+/// Name of the interface of an `RTM_NEWLINK` notification.
+fn link_name(payload: &[u8]) -> Option<String> {
+    let attrs = payload.get(IFINFOMSG_LEN..)?;
+    netlink::attr(attrs, libc::IFLA_IFNAME).map(netlink::read_str)
+}
+
+/// Follow the link speed of `ifnames`, or of every NIC with an installed
+/// config when empty, with [`follow_link_speed`], forever.
+///
+/// Each NIC is checked once at the start, then whenever its link changes.
+/// `report` gets what each check did, and the watch only stops when the
+/// link notifications cannot be read.
+pub fn watch(
+    ifnames: &[&str],
+    mut report: impl FnMut(&str, Result<Reconfigured, TsnError>),
+) -> Result<(), TsnError> {
+    let mut nl = Netlink::listen(libc::RTMGRP_LINK as u32)?;
+    let nics = || -> Result<Vec<String>, TsnError> {
+        if ifnames.is_empty() {
+            Registry::lock()?.applied_nics()
+        } else {
+            Ok(ifnames.iter().map(|ifname| ifname.to_string()).collect())
+        }
+    };
+    let mut pending = nics()?;
+    loop {
+        for ifname in pending.drain(..) {
+            report(&ifname, follow_link_speed(&ifname));
+        }
+        match nl.notifications()? {
+            // Some were lost, check them all again
+            None => pending = nics()?,
+            Some(notifications) => {
+                for (msg_type, payload) in notifications {
+                    if msg_type != libc::RTM_NEWLINK {
+                        continue;
+                    }
+                    if let Some(ifname) = link_name(&payload) {
+                        if !pending.contains(&ifname) && nics()?.contains(&ifname) {
+                            pending.push(ifname);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    use crate::schema::Format;
+
+    const QOS: &str = "    egress-qos-map:\n      5: {2: 2, 3: 3}\n      10: {2: 2, 3: 3}\n";
+    const TAS: &str =
+        "    tas:\n      schedule: [{time: 300us, prio: [2, 3]}, {time: 200us, prio: [-1]}]\n";
+    const CBS: &str = "    link_speed: 1Gbps\n    cbs:\n      3: {class: a, max_frame: 512B, bandwidth: 70Mbps}\n      2: {class: b, max_frame: 512B, bandwidth: 30Mbps}\n";
+
+    /// A name, the sections of the old and new config, and the changes
+    type Case<'a> = (&'a str, &'a [&'a str], &'a [&'a str], &'a [Change]);
+
+    fn nic(sections: &[&str]) -> Config {
+        let yaml = format!("nics:\n  tsn0:\n{}", sections.concat());
+        parse_config(&yaml, Format::Yaml).unwrap()["tsn0"].clone()
+    }
+
+    #[test]
+    fn changes() {
+        let cases: &[Case] = &[
+            ("no change", &[QOS, TAS, CBS], &[QOS, TAS, CBS], &[]),
+            (
+                "schedule only",
+                &[QOS, TAS],
+                &[
+                    QOS,
+                    "    tas:\n      schedule: [{time: 400us, prio: [2, 3]}, {time: 100us, prio: [-1]}]\n      cycle_time_extension: 10us\n",
+                ],
+                &[Change::Schedule],
+            ),
+            (
+                "traffic classes of taprio",
+                &[QOS, TAS],
+                &[
+                    QOS,
+                    "    tas:\n      schedule: [{time: 300us, prio: [3]}, {time: 200us, prio: [-1]}]\n",
+                ],
+                &[Change::Root],
+            ),
+            ("tas added", &[QOS, CBS], &[QOS, TAS, CBS], &[Change::Root]),
+            ("cbs removed", &[QOS, TAS, CBS], &[QOS, TAS], &[Change::Root]),
+            (
+                "taprio mode",
+                &[QOS, TAS],
+                &[QOS, TAS, "      mode: software\n"],
+                &[Change::Root],
+            ),
+            (
+                "credits of class b",
+                &[QOS, CBS],
+                &[
+                    QOS,
+                    "    link_speed: 1Gbps\n    cbs:\n      3: {class: a, max_frame: 512B, bandwidth: 70Mbps}\n      2: {class: b, max_frame: 512B, bandwidth: 20Mbps}\n",
+                ],
+                &[Change::Cbs { class: 2 }],
+            ),
+            (
+                "etf added under taprio",
+                &[QOS, TAS],
+                &[QOS, TAS, "      etf:\n        3: {delta: 200us}\n"],
+                &[Change::Etf { prio: 3 }],
+            ),
+            (
+                "etf changed under taprio",
+                &[QOS, TAS, "      etf:\n        3: {delta: 200us}\n"],
+                &[QOS, TAS, "      etf:\n        3: {delta: 300us}\n"],
+                &[Change::Etf { prio: 3 }],
+            ),
+            (
+                "etf removed under taprio",
+                &[QOS, TAS, "      etf:\n        2: {delta: 200us}\n        3: {delta: 200us}\n"],
+                &[QOS, TAS, "      etf:\n        3: {delta: 200us}\n"],
+                &[Change::EtfRemoved { prio: 2 }],
+            ),
+            (
+                "etf under cbs, which then needs no new root",
+                &[QOS, CBS, "    etf:\n      3: {delta: 200us}\n"],
+                &[QOS, CBS, "    etf:\n      3: {delta: 100us}\n"],
+                &[Change::Etf { prio: 3 }],
+            ),
+            (
+                "first etf under cbs",
+                &[QOS, CBS],
+                &[QOS, CBS, "    etf:\n      3: {delta: 200us}\n"],
+                &[Change::Root],
+            ),
+            (
+                "egress-qos-map of vlan 10",
+                &[QOS, TAS],
+                &[
+                    "    egress-qos-map:\n      5: {2: 2, 3: 3}\n      10: {2: 2, 3: 4}\n",
+                    TAS,
+                ],
+                &[Change::EgressQosMap { vlanid: 10 }],
+            ),
+            (
+                "egress-qos-map of a vlan not in use",
+                &[QOS, TAS],
+                &[
+                    "    egress-qos-map:\n      5: {2: 2, 3: 3}\n      10: {2: 2, 3: 3}\n      20: {3: 3}\n",
+                    TAS,
+                ],
+                &[],
+            ),
+            (
+                "egress-qos-map and root",
+                &[QOS, TAS],
+                &["    egress-qos-map:\n      5: {3: 5}\n      10: {2: 2, 3: 3}\n"],
+                &[Change::EgressQosMap { vlanid: 5 }, Change::Root],
+            ),
+        ];
+        for (name, old, new, expected) in cases {
+            let changes = diff(&nic(old), &nic(new), &[10, 5]).unwrap();
+            assert_eq!(changes, *expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn vlan_in_use_needs_its_map() {
+        let new = nic(&["    egress-qos-map:\n      5: {2: 2, 3: 3}\n", TAS]);
+        assert!(diff(&nic(&[QOS, TAS]), &new, &[5]).unwrap().is_empty());
+        assert!(diff(&nic(&[QOS, TAS]), &new, &[5, 10]).is_err());
+    }
+}
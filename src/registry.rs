@@ -0,0 +1,395 @@
+//! Processes using each VLAN created by libtsn.
+//!
+//! Every VLAN has a file `<dir>/<vlan name>.users` listing the processes that
+//! hold a [`TsnSocket`](crate::TsnSocket) on it. A process is identified by its
+//! pid and its start time from `/proc/<pid>/stat`, so a recycled pid is not
+//! mistaken for the original user. The whole directory is serialised by an
+//! `flock` on `<dir>/lock`, held for as long as a [`Registry`] is alive.
+//!
+//! File layout, version 2:
+//!
+//! ```text
+//! libtsn-registry 2
+//! ifname enp37s0
+//! vlanid 10
+//! pinned
+//! 1234 5678901
+//! ```
+//!
+//! where `pinned` is only there for VLANs created by `tsn apply` or
+//! `tsn create`, which stay when their last socket is closed, and each line
+//! after it is `<pid> <start time>`. Version 1 files are the same without
+//! `pinned`.
+//!
+//! The config installed on each NIC is kept as JSON in `<dir>/<ifname>.applied`,
+//! for [`reconfigure`](crate::reconfigure()) to compare a new config against.
+
+use crate::config::Config;
+use crate::error::TsnError;
+use crate::netlink::Netlink;
+use crate::vlan;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::{env, process};
+
+const MAGIC: &str = "libtsn-registry";
+const VERSION: u32 = 2;
+const DEFAULT_DIR: &str = "/run/libtsn";
+const SUFFIX: &str = ".users";
+const APPLIED_SUFFIX: &str = ".applied";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct User {
+    pub pid: u32,
+    /// Start time of the process in clock ticks after boot
+    pub start_time: u64,
+}
+
+impl User {
+    pub fn current() -> Result<User, TsnError> {
+        let pid = process::id();
+        match process_start_time(pid) {
+            Some(start_time) => Ok(User { pid, start_time }),
+            None => Err(TsnError::Registry {
+                path: format!("/proc/{}/stat", pid),
+                op: "read",
+                source: Error::last_os_error(),
+            }),
+        }
+    }
+
+    /// Whether the process that registered this entry is still running.
+    pub fn is_alive(&self) -> bool {
+        process_start_time(self.pid) == Some(self.start_time)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Registration {
+    pub ifname: String,
+    pub vlanid: u16,
+    /// Whether the VLAN is kept when it has no users
+    pub pinned: bool,
+    pub users: Vec<User>,
+}
+
+impl Registration {
+    /// Drop users whose process is gone and return them.
+    pub fn reap(&mut self) -> Vec<User> {
+        let (alive, dead) = self.users.iter().partition(|user| user.is_alive());
+        self.users = alive;
+        dead
+    }
+}
+
+/// Exclusive access to the registry directory.
+pub struct Registry {
+    dir: PathBuf,
+    // Keeps the flock, released on drop
+    _lock: File,
+}
+
+impl Registry {
+    pub fn lock() -> Result<Registry, TsnError> {
+        let dir = env::var("TSN_REGISTRY_DIR").unwrap_or_else(|_| DEFAULT_DIR.to_string());
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir).map_err(|e| registry_error(&dir, "create", e))?;
+        let lock_path = dir.join("lock");
+        let lock = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(|e| registry_error(&lock_path, "open", e))?;
+        let res = unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) };
+        if res < 0 {
+            return Err(registry_error(&lock_path, "lock", Error::last_os_error()));
+        }
+        Ok(Registry { dir, _lock: lock })
+    }
+
+    fn path(&self, ifname: &str, vlanid: u16) -> PathBuf {
+        self.dir
+            .join(format!("{}{}", vlan::get_vlan_name(ifname, vlanid), SUFFIX))
+    }
+
+    pub fn get(&self, ifname: &str, vlanid: u16) -> Result<Registration, TsnError> {
+        let path = self.path(ifname, vlanid);
+        match fs::read_to_string(&path) {
+            Ok(content) => parse(&path, &content),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Registration {
+                ifname: ifname.to_string(),
+                vlanid,
+                pinned: false,
+                users: Vec::new(),
+            }),
+            Err(e) => Err(registry_error(&path, "read", e)),
+        }
+    }
+
+    /// Write `reg` back, or remove its file when nobody uses the VLAN anymore.
+    pub fn put(&self, reg: &Registration) -> Result<(), TsnError> {
+        let path = self.path(&reg.ifname, reg.vlanid);
+        if reg.users.is_empty() && !reg.pinned {
+            return match fs::remove_file(&path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    Err(registry_error(&path, "remove", e))
+                }
+                _ => Ok(()),
+            };
+        }
+
+        let mut content = format!(
+            "{} {}\nifname {}\nvlanid {}\n",
+            MAGIC, VERSION, reg.ifname, reg.vlanid
+        );
+        if reg.pinned {
+            content.push_str("pinned\n");
+        }
+        for user in &reg.users {
+            content.push_str(&format!("{} {}\n", user.pid, user.start_time));
+        }
+        write_atomic(&path, &content).map_err(|e| registry_error(&path, "write", e))
+    }
+
+    /// Keep the VLAN `vlanid` of `ifname` when it has no users, or not.
+    pub fn pin(&self, ifname: &str, vlanid: u16, pinned: bool) -> Result<(), TsnError> {
+        let mut reg = self.get(ifname, vlanid)?;
+        reg.pinned = pinned;
+        self.put(&reg)
+    }
+
+    /// The config last installed on `ifname`, if its qdiscs are still there.
+    pub fn applied(&self, ifname: &str) -> Result<Option<Config>, TsnError> {
+        let path = self.dir.join(format!("{}{}", ifname, APPLIED_SUFFIX));
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(registry_error(&path, "read", e)),
+        };
+        serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|e| registry_error(&path, "parse", Error::new(ErrorKind::InvalidData, e)))
+    }
+
+    /// Record `config` as installed on `ifname`, or forget it with `None` once
+    /// the qdiscs are deleted.
+    pub fn set_applied(&self, ifname: &str, config: Option<&Config>) -> Result<(), TsnError> {
+        let path = self.dir.join(format!("{}{}", ifname, APPLIED_SUFFIX));
+        let config = match config {
+            Some(config) => config,
+            None => {
+                return match fs::remove_file(&path) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => {
+                        Err(registry_error(&path, "remove", e))
+                    }
+                    _ => Ok(()),
+                }
+            }
+        };
+        let content = serde_json::to_string(config)
+            .map_err(|e| registry_error(&path, "write", Error::new(ErrorKind::InvalidData, e)))?;
+        write_atomic(&path, &content).map_err(|e| registry_error(&path, "write", e))
+    }
+
+    /// The NICs with a recorded config, see [`Registry::applied`].
+    pub fn applied_nics(&self) -> Result<Vec<String>, TsnError> {
+        let entries = fs::read_dir(&self.dir).map_err(|e| registry_error(&self.dir, "list", e))?;
+        let mut nics = Vec::new();
+        for entry in entries {
+            let name = entry
+                .map_err(|e| registry_error(&self.dir, "list", e))?
+                .file_name();
+            if let Some(ifname) = name.to_string_lossy().strip_suffix(APPLIED_SUFFIX) {
+                nics.push(ifname.to_string());
+            }
+        }
+        nics.sort();
+        Ok(nics)
+    }
+
+    /// The registrations that can be read, see [`Registry::scan`] for the
+    /// files that cannot.
+    pub fn list(&self) -> Result<Vec<Registration>, TsnError> {
+        self.scan().map(|(regs, _)| regs)
+    }
+
+    /// The registrations, and the errors of the files that cannot be read or
+    /// parsed, which are left in place for someone to look at.
+    pub fn scan(&self) -> Result<(Vec<Registration>, Vec<TsnError>), TsnError> {
+        let entries = fs::read_dir(&self.dir).map_err(|e| registry_error(&self.dir, "list", e))?;
+        let mut regs = Vec::new();
+        let mut errors = Vec::new();
+        for entry in entries {
+            let path = entry
+                .map_err(|e| registry_error(&self.dir, "list", e))?
+                .path();
+            if !path.to_string_lossy().ends_with(SUFFIX) {
+                continue;
+            }
+            let reg = fs::read_to_string(&path)
+                .map_err(|e| registry_error(&path, "read", e))
+                .and_then(|content| parse(&path, &content));
+            match reg {
+                Ok(reg) => regs.push(reg),
+                Err(e) => errors.push(e),
+            }
+        }
+        regs.sort_by(|a, b| (&a.ifname, a.vlanid).cmp(&(&b.ifname, b.vlanid)));
+        Ok((regs, errors))
+    }
+}
+
+/// Whether a VLAN of `ifname` other than `vlanid` is in use or pinned in
+/// `regs`, and so still needs the qdiscs of the NIC.
+fn nic_in_use(regs: &[Registration], ifname: &str, vlanid: u16) -> bool {
+    regs.iter().any(|reg| {
+        reg.ifname == ifname && reg.vlanid != vlanid && (reg.pinned || !reg.users.is_empty())
+    })
+}
+
+/// Delete the VLAN `vlanid` of `ifname`, and the root qdisc of the NIC with
+/// its recorded config once no other VLAN of it is in use or pinned.
+pub fn release_vlan(registry: &Registry, ifname: &str, vlanid: u16) -> Result<(), TsnError> {
+    release(registry, &registry.list()?, ifname, vlanid)
+}
+
+/// [`release_vlan`], with `regs` being the registrations as they are now.
+fn release(
+    registry: &Registry,
+    regs: &[Registration],
+    ifname: &str,
+    vlanid: u16,
+) -> Result<(), TsnError> {
+    let mut nl = Netlink::open()?;
+    let res = vlan::delete_link(&mut nl, ifname, vlanid).map(|_| ());
+    if nic_in_use(regs, ifname, vlanid) {
+        return res;
+    }
+    // The qdiscs are the NIC's, they go with its last VLAN
+    let cleared = vlan::clear_root(&mut nl, ifname).map(|_| ());
+    registry.set_applied(ifname, None)?;
+    res.and(cleared)
+}
+
+/// Write aside and rename so a crash never leaves a half written file.
+fn write_atomic(path: &Path, content: &str) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+fn parse(path: &Path, content: &str) -> Result<Registration, TsnError> {
+    let invalid =
+        |msg: &str| registry_error(path, "parse", Error::new(ErrorKind::InvalidData, msg));
+    let mut lines = content.lines();
+
+    let header = lines.next().unwrap_or_default();
+    match header.split_once(' ') {
+        Some((MAGIC, version)) if (1..=VERSION).any(|v| version == v.to_string()) => {}
+        Some((MAGIC, version)) => {
+            return Err(invalid(&format!(
+                "unsupported registry version {}",
+                version
+            )))
+        }
+        _ => return Err(invalid("not a libtsn registry file")),
+    }
+    let ifname = match lines.next().and_then(|l| l.strip_prefix("ifname ")) {
+        Some(ifname) => ifname.to_string(),
+        None => return Err(invalid("missing ifname")),
+    };
+    let vlanid = match lines.next().and_then(|l| l.strip_prefix("vlanid ")) {
+        Some(vlanid) => vlanid.parse().map_err(|_| invalid("invalid vlanid"))?,
+        None => return Err(invalid("missing vlanid")),
+    };
+
+    let mut lines = lines.peekable();
+    let pinned = lines.next_if_eq(&"pinned").is_some();
+    let mut users = Vec::new();
+    for line in lines.filter(|l| !l.trim().is_empty()) {
+        let user = line
+            .split_once(' ')
+            .and_then(|(pid, start_time)| {
+                Some(User {
+                    pid: pid.parse().ok()?,
+                    start_time: start_time.parse().ok()?,
+                })
+            })
+            .ok_or_else(|| invalid(&format!("invalid entry '{}'", line)))?;
+        users.push(user);
+    }
+    Ok(Registration {
+        ifname,
+        vlanid,
+        pinned,
+        users,
+    })
+}
+
+/// Field 22 of `/proc/<pid>/stat`, `None` if the process does not exist.
+fn process_start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // comm may contain spaces and parentheses, fields restart after the last ')'
+    let fields = &stat[stat.rfind(')')? + 2..];
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+fn registry_error(path: &Path, op: &'static str, source: Error) -> TsnError {
+    TsnError::Registry {
+        path: path.display().to_string(),
+        op,
+        source,
+    }
+}
+
+/// What [`gc`] did for one VLAN.
+#[derive(Debug)]
+pub struct Collected {
+    pub ifname: String,
+    pub vlanid: u16,
+    /// Users whose process is gone
+    pub reaped: Vec<User>,
+    /// Whether the VLAN was left without users and deleted by this call
+    pub deleted: bool,
+    /// Why deleting the VLAN failed, its registration is removed regardless
+    pub error: Option<TsnError>,
+}
+
+/// Remove users that died without closing their sockets, and delete the VLANs
+/// they leave unused. Also returns the errors of the registry files that
+/// cannot be read, which are skipped.
+pub fn gc() -> Result<(Vec<Collected>, Vec<TsnError>), TsnError> {
+    let registry = Registry::lock()?;
+    let (mut regs, errors) = registry.scan()?;
+    // All of them first, a NIC keeps its qdiscs only for live users
+    let reaped: Vec<Vec<User>> = regs.iter_mut().map(|reg| reg.reap()).collect();
+    let mut collected = Vec::new();
+    for (reg, reaped) in regs.iter().zip(reaped) {
+        if reaped.is_empty() {
+            continue;
+        }
+        let vlan_name = vlan::get_vlan_name(&reg.ifname, reg.vlanid);
+        let exists = nix::net::if_::if_nametoindex(vlan_name.as_str()).is_ok();
+        let deleted = reg.users.is_empty() && !reg.pinned && exists;
+        let error = if deleted {
+            release(&registry, &regs, &reg.ifname, reg.vlanid).err()
+        } else {
+            None
+        };
+        registry.put(reg)?;
+        collected.push(Collected {
+            ifname: reg.ifname.clone(),
+            vlanid: reg.vlanid,
+            reaped,
+            deleted,
+            error,
+        });
+    }
+    Ok((collected, errors))
+}
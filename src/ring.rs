@@ -0,0 +1,663 @@
+//! Memory mapped rings of frames shared with the kernel, `PACKET_MMAP`.
+//!
+//! [`RxRing`] is a `TPACKET_V3` ring of blocks on the socket itself: the
+//! kernel fills a block with frames, each with its timestamp in its header,
+//! and hands it over whole, so frames are read without a copy or a system
+//! call each. While the ring exists, frames only go to it, not to
+//! [`TsnSocket::recv`].
+//!
+//! [`TxRing`] is a `TPACKET_V2` ring of frames, which the kernel sends on
+//! [`TxRing::flush`]. The packet version is the socket's, so the TX ring has
+//! a socket of its own, bound to the same VLAN with the same priority.
+
+use crate::{error::TsnError, time::Timespec, TsnSocket};
+use std::io::Error;
+use std::marker::PhantomData;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+use std::time::Duration;
+
+// include/uapi/linux/if_packet.h
+const PACKET_RX_RING: libc::c_int = 5;
+const PACKET_VERSION: libc::c_int = 10;
+const PACKET_TX_RING: libc::c_int = 13;
+const PACKET_TIMESTAMP: libc::c_int = 17;
+const PACKET_QDISC_BYPASS: libc::c_int = 20;
+const TPACKET_V1: libc::c_int = 0;
+const TPACKET_V2: libc::c_int = 1;
+const TPACKET_V3: libc::c_int = 2;
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1 << 0;
+const TP_STATUS_AVAILABLE: u32 = 0;
+const TP_STATUS_SEND_REQUEST: u32 = 1 << 0;
+const TP_STATUS_WRONG_FORMAT: u32 = 1 << 2;
+const TP_STATUS_TS_RAW_HARDWARE: u32 = 1 << 31;
+/// Where the frame starts in a `TPACKET_V2` slot, after the aligned
+/// `struct tpacket2_hdr`
+const TPACKET2_DATA_OFFSET: usize = 32;
+
+/// `struct tpacket_req3`
+#[repr(C)]
+#[derive(Default)]
+struct TpacketReq3 {
+    block_size: u32,
+    block_nr: u32,
+    frame_size: u32,
+    frame_nr: u32,
+    retire_blk_tov: u32,
+    sizeof_priv: u32,
+    feature_req_word: u32,
+}
+
+/// `struct tpacket_req`
+#[repr(C)]
+#[derive(Default)]
+struct TpacketReq {
+    block_size: u32,
+    block_nr: u32,
+    frame_size: u32,
+    frame_nr: u32,
+}
+
+/// `struct tpacket_block_desc`, with its `struct tpacket_hdr_v1`
+#[repr(C)]
+struct BlockDesc {
+    version: u32,
+    offset_to_priv: u32,
+    block_status: u32,
+    num_pkts: u32,
+    offset_to_first_pkt: u32,
+    blk_len: u32,
+    seq_num: u64,
+    ts_first_pkt: [u32; 2],
+    ts_last_pkt: [u32; 2],
+}
+
+/// `struct tpacket3_hdr`
+#[repr(C)]
+struct Tpacket3Hdr {
+    next_offset: u32,
+    sec: u32,
+    nsec: u32,
+    snaplen: u32,
+    len: u32,
+    status: u32,
+    mac: u16,
+    net: u16,
+    rxhash: u32,
+    vlan_tci: u32,
+    vlan_tpid: u16,
+    padding: u16,
+}
+
+/// `struct tpacket2_hdr`
+#[repr(C)]
+struct Tpacket2Hdr {
+    status: u32,
+    len: u32,
+    snaplen: u32,
+    mac: u16,
+    net: u16,
+    sec: u32,
+    nsec: u32,
+    vlan_tci: u16,
+    vlan_tpid: u16,
+}
+
+/// Sizes of an [`RxRing`].
+#[derive(Debug, Clone, Copy)]
+pub struct RxRingConfig {
+    /// Bytes per block, a multiple of the page size
+    pub block_size: u32,
+    pub block_count: u32,
+    /// Largest frame, with its header, that a block takes
+    pub frame_size: u32,
+    /// How long the kernel waits for a block to fill before handing it over
+    pub retire_timeout: Duration,
+    /// Timestamp frames in the NIC, which [`TsnSocket::enable_rx_timestamp`]
+    /// with [`RxTimestampMode::Hardware`](crate::rxtime::RxTimestampMode::Hardware)
+    /// turns on
+    pub hw_timestamps: bool,
+}
+
+impl Default for RxRingConfig {
+    fn default() -> RxRingConfig {
+        RxRingConfig {
+            block_size: 1 << 18,
+            block_count: 64,
+            frame_size: 2048,
+            retire_timeout: Duration::from_millis(10),
+            hw_timestamps: false,
+        }
+    }
+}
+
+/// Sizes of a [`TxRing`].
+#[derive(Debug, Clone, Copy)]
+pub struct TxRingConfig {
+    /// Largest frame, with its header, a slot takes
+    pub frame_size: u32,
+    pub frame_count: u32,
+    /// Hand frames to the NIC driver directly. This also skips the qdiscs,
+    /// so the frames are sent outside of the TAS schedule and cbs shaping.
+    pub qdisc_bypass: bool,
+}
+
+impl Default for TxRingConfig {
+    fn default() -> TxRingConfig {
+        TxRingConfig {
+            frame_size: 2048,
+            frame_count: 512,
+            qdisc_bypass: false,
+        }
+    }
+}
+
+pub(crate) fn setsockopt<T>(
+    fd: RawFd,
+    level: libc::c_int,
+    opt: libc::c_int,
+    value: &T,
+) -> libc::c_int {
+    unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            opt,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as u32,
+        )
+    }
+}
+
+/// A mapping of a ring, unmapped on drop.
+pub(crate) struct Mapping {
+    pub(crate) addr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    pub(crate) fn new(fd: RawFd, len: usize) -> Result<Mapping, TsnError> {
+        Mapping::at(fd, len, 0)
+    }
+
+    /// The rings of `fd` that start at `offset`, for sockets that have
+    /// several.
+    pub(crate) fn at(fd: RawFd, len: usize, offset: libc::off_t) -> Result<Mapping, TsnError> {
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(TsnError::socket("mmap ring"));
+        }
+        Ok(Mapping {
+            addr: addr as *mut u8,
+            len,
+        })
+    }
+
+    /// Memory of this process only, which a socket then shares.
+    pub(crate) fn anonymous(len: usize) -> Result<Mapping, TsnError> {
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(TsnError::socket("mmap"));
+        }
+        Ok(Mapping {
+            addr: addr as *mut u8,
+            len,
+        })
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.len) };
+    }
+}
+
+/// A status word the kernel writes, read after it.
+fn read_status(status: *const u32) -> u32 {
+    let value = unsafe { ptr::read_volatile(status) };
+    fence(Ordering::Acquire);
+    value
+}
+
+/// Hand a slot over, once everything else written to it is.
+fn write_status(status: *mut u32, value: u32) {
+    fence(Ordering::Release);
+    unsafe { ptr::write_volatile(status, value) };
+}
+
+/// Wait for `events` on `fd`, and return false on timeout.
+pub(crate) fn poll(
+    fd: RawFd,
+    events: libc::c_short,
+    timeout: Option<Duration>,
+) -> Result<bool, TsnError> {
+    let timeout = timeout.map_or(-1, |timeout| {
+        timeout.as_millis().min(i32::MAX as u128) as i32
+    });
+    let mut pfd = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    loop {
+        match unsafe { libc::poll(&mut pfd, 1, timeout) } {
+            res if res > 0 => return Ok(true),
+            0 => return Ok(false),
+            _ if Error::last_os_error().raw_os_error() == Some(libc::EINTR) => continue,
+            _ => return Err(TsnError::socket("poll")),
+        }
+    }
+}
+
+/// A `TPACKET_V3` RX ring on a [`TsnSocket`], see [`TsnSocket::rx_ring`].
+pub struct RxRing<'a> {
+    sock: &'a TsnSocket,
+    map: Mapping,
+    block_size: usize,
+    block_count: usize,
+    next: usize,
+}
+
+impl<'a> RxRing<'a> {
+    pub(crate) fn new(sock: &'a TsnSocket, config: RxRingConfig) -> Result<RxRing<'a>, TsnError> {
+        sock.packet_socket("rx ring")?;
+        if config.frame_size == 0 {
+            return Err(TsnError::invalid("rx ring", "the frame size must not be 0"));
+        }
+        let frame_nr = (config.block_size / config.frame_size)
+            .checked_mul(config.block_count)
+            .ok_or_else(|| TsnError::invalid("rx ring", "too many frames for the ring"))?;
+        if setsockopt(sock.fd, libc::SOL_PACKET, PACKET_VERSION, &TPACKET_V3) < 0 {
+            return Err(TsnError::socket("setsockopt PACKET_VERSION"));
+        }
+        if config.hw_timestamps {
+            let flags = libc::SOF_TIMESTAMPING_RAW_HARDWARE as libc::c_int;
+            if setsockopt(sock.fd, libc::SOL_PACKET, PACKET_TIMESTAMP, &flags) < 0 {
+                let err = TsnError::socket("setsockopt PACKET_TIMESTAMP");
+                setsockopt(sock.fd, libc::SOL_PACKET, PACKET_VERSION, &TPACKET_V1);
+                return Err(err);
+            }
+        }
+        let req = TpacketReq3 {
+            block_size: config.block_size,
+            block_nr: config.block_count,
+            frame_size: config.frame_size,
+            frame_nr,
+            retire_blk_tov: config.retire_timeout.as_millis().max(1) as u32,
+            ..Default::default()
+        };
+        if setsockopt(sock.fd, libc::SOL_PACKET, PACKET_RX_RING, &req) < 0 {
+            let err = TsnError::socket("setsockopt PACKET_RX_RING");
+            setsockopt(sock.fd, libc::SOL_PACKET, PACKET_VERSION, &TPACKET_V1);
+            return Err(err);
+        }
+        let len = config.block_size as usize * config.block_count as usize;
+        let map = Mapping::new(sock.fd, len).inspect_err(|_| remove_ring(sock.fd))?;
+        Ok(RxRing {
+            sock,
+            map,
+            block_size: config.block_size as usize,
+            block_count: config.block_count as usize,
+            next: 0,
+        })
+    }
+
+    fn desc(&self) -> *mut BlockDesc {
+        unsafe { self.map.addr.add(self.next * self.block_size) as *mut BlockDesc }
+    }
+
+    /// The next block of frames, waiting for at most `timeout` for one to be
+    /// filled, forever with `None`.
+    ///
+    /// The block goes back to the kernel when dropped, so frames are only
+    /// lost when the ring is full of blocks not dropped yet.
+    pub fn next_block(&mut self, timeout: Option<Duration>) -> Result<Option<Block<'_>>, TsnError> {
+        loop {
+            let desc = self.desc();
+            if read_status(unsafe { ptr::addr_of!((*desc).block_status) }) & TP_STATUS_USER != 0 {
+                return Ok(Some(Block {
+                    desc,
+                    next: &mut self.next,
+                    block_count: self.block_count,
+                }));
+            }
+            if !poll(self.sock.fd, libc::POLLIN | libc::POLLERR, timeout)? {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// Release the RX ring of `fd`, which then works as a plain socket again.
+fn remove_ring(fd: RawFd) {
+    setsockopt(
+        fd,
+        libc::SOL_PACKET,
+        PACKET_RX_RING,
+        &TpacketReq3::default(),
+    );
+    setsockopt(fd, libc::SOL_PACKET, PACKET_VERSION, &TPACKET_V1);
+}
+
+impl Drop for RxRing<'_> {
+    fn drop(&mut self) {
+        // The ring cannot be released while it is mapped
+        let map = mem::replace(
+            &mut self.map,
+            Mapping {
+                addr: ptr::null_mut(),
+                len: 0,
+            },
+        );
+        drop(map);
+        remove_ring(self.sock.fd);
+    }
+}
+
+/// A block of an [`RxRing`] filled by the kernel.
+pub struct Block<'a> {
+    desc: *mut BlockDesc,
+    /// Index of the next block of the ring, moved on when this one is done
+    next: &'a mut usize,
+    block_count: usize,
+}
+
+impl Block<'_> {
+    /// The frames of the block, in the order they were received.
+    pub fn frames(&self) -> Frames<'_> {
+        let (count, first) = unsafe { ((*self.desc).num_pkts, (*self.desc).offset_to_first_pkt) };
+        Frames {
+            hdr: unsafe { (self.desc as *const u8).add(first as usize) },
+            left: count,
+            block: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        unsafe { (*self.desc).num_pkts as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for Block<'_> {
+    fn drop(&mut self) {
+        write_status(
+            unsafe { ptr::addr_of_mut!((*self.desc).block_status) },
+            TP_STATUS_KERNEL,
+        );
+        *self.next = (*self.next + 1) % self.block_count;
+    }
+}
+
+/// A frame in a [`Block`].
+#[derive(Debug, Clone, Copy)]
+pub struct RingFrame<'a> {
+    /// The frame as captured, at most the frame size of the ring
+    pub data: &'a [u8],
+    /// Length of the frame on the wire
+    pub len: usize,
+    pub timestamp: Timespec,
+    /// Whether `timestamp` was taken by the NIC
+    pub hw_timestamp: bool,
+}
+
+/// The frames of a [`Block`], see [`Block::frames`].
+pub struct Frames<'a> {
+    hdr: *const u8,
+    left: u32,
+    block: PhantomData<&'a Block<'a>>,
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = RingFrame<'a>;
+
+    fn next(&mut self) -> Option<RingFrame<'a>> {
+        if self.left == 0 {
+            return None;
+        }
+        let hdr = unsafe { &*(self.hdr as *const Tpacket3Hdr) };
+        let data = unsafe {
+            std::slice::from_raw_parts(self.hdr.add(hdr.mac as usize), hdr.snaplen as usize)
+        };
+        self.left -= 1;
+        self.hdr = unsafe { self.hdr.add(hdr.next_offset as usize) };
+        Some(RingFrame {
+            data,
+            len: hdr.len as usize,
+            timestamp: Timespec {
+                tv_sec: hdr.sec as i64,
+                tv_nsec: hdr.nsec as i64,
+            },
+            hw_timestamp: hdr.status & TP_STATUS_TS_RAW_HARDWARE != 0,
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.left as usize, Some(self.left as usize))
+    }
+}
+
+/// A `TPACKET_V2` TX ring for a [`TsnSocket`], see [`TsnSocket::tx_ring`].
+pub struct TxRing<'a> {
+    fd: OwnedFd,
+    map: Mapping,
+    block_size: usize,
+    /// Frames per block, which frames do not cross
+    per_block: usize,
+    frame_size: usize,
+    frame_count: usize,
+    next: usize,
+    sock: PhantomData<&'a TsnSocket>,
+}
+
+impl<'a> TxRing<'a> {
+    pub(crate) fn new(sock: &'a TsnSocket, config: TxRingConfig) -> Result<TxRing<'a>, TsnError> {
+        sock.packet_socket("tx ring")?;
+        if config.frame_size as usize <= TPACKET2_DATA_OFFSET {
+            return Err(TsnError::invalid(
+                "tx ring",
+                "the frame size must be larger than the slot header",
+            ));
+        }
+        // Protocol 0, it receives nothing
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(TsnError::socket("open"));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut priority: libc::c_int = 0;
+        let mut len = mem::size_of_val(&priority) as u32;
+        let res = unsafe {
+            libc::getsockopt(
+                sock.fd,
+                libc::SOL_SOCKET,
+                libc::SO_PRIORITY,
+                &mut priority as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if res < 0
+            || setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PRIORITY,
+                &priority,
+            ) < 0
+        {
+            return Err(TsnError::socket("setsockopt SO_PRIORITY"));
+        }
+        if setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_PACKET,
+            PACKET_VERSION,
+            &TPACKET_V2,
+        ) < 0
+        {
+            return Err(TsnError::socket("setsockopt PACKET_VERSION"));
+        }
+        if config.qdisc_bypass {
+            let one: libc::c_int = 1;
+            if setsockopt(fd.as_raw_fd(), libc::SOL_PACKET, PACKET_QDISC_BYPASS, &one) < 0 {
+                return Err(TsnError::socket("setsockopt PACKET_QDISC_BYPASS"));
+            }
+        }
+
+        // A block is a page, or one frame when they are larger
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+        let block_size = config.frame_size.div_ceil(page_size) * page_size;
+        let per_block = block_size / config.frame_size;
+        let block_nr = config.frame_count.div_ceil(per_block);
+        let req = TpacketReq {
+            block_size,
+            block_nr,
+            frame_size: config.frame_size,
+            frame_nr: block_nr * per_block,
+        };
+        if setsockopt(fd.as_raw_fd(), libc::SOL_PACKET, PACKET_TX_RING, &req) < 0 {
+            return Err(TsnError::socket("setsockopt PACKET_TX_RING"));
+        }
+        let map = Mapping::new(fd.as_raw_fd(), block_size as usize * block_nr as usize)?;
+
+        // Where the socket of the VLAN is bound
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        let mut len = mem::size_of_val(&addr) as u32;
+        let res = unsafe {
+            libc::getsockname(
+                sock.fd,
+                &mut addr as *mut _ as *mut libc::sockaddr,
+                &mut len,
+            )
+        };
+        if res < 0 {
+            return Err(TsnError::socket("getsockname"));
+        }
+        addr.sll_protocol = 0;
+        let res = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of_val(&addr) as u32,
+            )
+        };
+        if res < 0 {
+            return Err(TsnError::socket("bind"));
+        }
+
+        Ok(TxRing {
+            fd,
+            map,
+            block_size: block_size as usize,
+            per_block: per_block as usize,
+            frame_size: config.frame_size as usize,
+            frame_count: (block_nr * per_block) as usize,
+            next: 0,
+            sock: PhantomData,
+        })
+    }
+
+    /// The frame slot `index`.
+    fn slot(&self, index: usize) -> *mut u8 {
+        let (block, frame) = (index / self.per_block, index % self.per_block);
+        unsafe {
+            self.map
+                .addr
+                .add(block * self.block_size + frame * self.frame_size)
+        }
+    }
+
+    /// The largest frame a slot takes.
+    pub fn max_frame(&self) -> usize {
+        self.frame_size - TPACKET2_DATA_OFFSET
+    }
+
+    /// Copy `frame` into the next free slot, to be sent on [`TxRing::flush`],
+    /// and return false when the ring is full.
+    pub fn push(&mut self, frame: &[u8]) -> Result<bool, TsnError> {
+        if frame.len() > self.max_frame() {
+            return Err(TsnError::invalid(
+                "tx ring",
+                &format!(
+                    "a frame of {} bytes is larger than the slots, {} bytes",
+                    frame.len(),
+                    self.max_frame()
+                ),
+            ));
+        }
+        let slot = self.slot(self.next);
+        let hdr = slot as *mut Tpacket2Hdr;
+        let status = read_status(unsafe { ptr::addr_of!((*hdr).status) });
+        if status & TP_STATUS_WRONG_FORMAT != 0 {
+            return Err(TsnError::Socket {
+                op: "tx ring",
+                source: Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "the kernel refused a frame of the ring",
+                ),
+            });
+        }
+        if status != TP_STATUS_AVAILABLE {
+            return Ok(false);
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(frame.as_ptr(), slot.add(TPACKET2_DATA_OFFSET), frame.len());
+            (*hdr).len = frame.len() as u32;
+        }
+        write_status(
+            unsafe { ptr::addr_of_mut!((*hdr).status) },
+            TP_STATUS_SEND_REQUEST,
+        );
+        self.next = (self.next + 1) % self.frame_count;
+        Ok(true)
+    }
+
+    /// Send the frames pushed so far, and wait for the slots to be free.
+    pub fn flush(&mut self) -> Result<(), TsnError> {
+        let res = unsafe { libc::send(self.fd.as_raw_fd(), ptr::null(), 0, 0) };
+        if res < 0 {
+            return Err(TsnError::socket("send tx ring"));
+        }
+        Ok(())
+    }
+
+    /// Push all of `frames`, flushing whenever the ring is full.
+    pub fn send(&mut self, frames: &[&[u8]]) -> Result<(), TsnError> {
+        for frame in frames {
+            if !self.push(frame)? {
+                self.flush()?;
+                // A slot still being sent is freed once the send is done
+                while !self.push(frame)? {
+                    poll(self.fd.as_raw_fd(), libc::POLLOUT, None)?;
+                }
+            }
+        }
+        self.flush()
+    }
+}
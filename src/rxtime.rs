@@ -0,0 +1,308 @@
+//! RX timestamps, and what else the kernel knows of a received frame.
+//!
+//! Once [`enable_rx_timestamp`] is set on a socket, [`recv_with_timestamp`]
+//! returns the frame with its [`RxMeta`]: the software, hardware and raw
+//! hardware timestamps of `SO_TIMESTAMPING`, and from `PACKET_AUXDATA` and
+//! the `sockaddr_ll` the source MAC, VLAN tag and packet type.
+
+use crate::{error::TsnError, time::Timespec, TsnSocket};
+use std::io::Error;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+// include/uapi/linux/if_packet.h
+const PACKET_HOST: u8 = 0;
+const PACKET_BROADCAST: u8 = 1;
+const PACKET_MULTICAST: u8 = 2;
+const PACKET_OTHERHOST: u8 = 3;
+const PACKET_OUTGOING: u8 = 4;
+const PACKET_AUXDATA: libc::c_int = 8;
+const TP_STATUS_VLAN_VALID: u32 = 1 << 4;
+/// Room for `SO_TIMESTAMPING`, three timespecs, and `PACKET_AUXDATA`
+const CONTROL_LEN: usize = 256;
+
+/// `struct tpacket_auxdata`
+#[repr(C)]
+struct TpacketAuxdata {
+    status: u32,
+    len: u32,
+    snaplen: u32,
+    mac: u16,
+    net: u16,
+    vlan_tci: u16,
+    vlan_tpid: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RxTimestampMode {
+    /// Timestamps taken by the kernel when the frame reaches the stack
+    Software,
+    /// Timestamps taken by the NIC, which is set to stamp every frame, and
+    /// the kernel's as well
+    Hardware,
+}
+
+/// Who a received frame was for, `sll_pkttype`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Host,
+    Broadcast,
+    Multicast,
+    /// For another host, seen in promiscuous mode
+    OtherHost,
+    /// Sent by this host, seen on the way out
+    Outgoing,
+    /// Any other type the kernel may add later
+    Other(u8),
+}
+
+impl From<u8> for PacketType {
+    fn from(pkttype: u8) -> PacketType {
+        match pkttype {
+            PACKET_HOST => PacketType::Host,
+            PACKET_BROADCAST => PacketType::Broadcast,
+            PACKET_MULTICAST => PacketType::Multicast,
+            PACKET_OTHERHOST => PacketType::OtherHost,
+            PACKET_OUTGOING => PacketType::Outgoing,
+            other => PacketType::Other(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VlanTag {
+    pub vid: u16,
+    pub pcp: u8,
+    pub dei: bool,
+}
+
+impl From<u16> for VlanTag {
+    fn from(tci: u16) -> VlanTag {
+        VlanTag {
+            vid: tci & 0xfff,
+            pcp: (tci >> 13) as u8,
+            dei: tci & 0x1000 != 0,
+        }
+    }
+}
+
+/// What came with a frame from [`recv_with_timestamp`].
+#[derive(Debug, Clone, Copy)]
+pub struct RxMeta {
+    /// Taken by the kernel
+    pub software: Option<Timespec>,
+    /// Taken by the NIC and moved to the system clock, which few drivers
+    /// still do
+    pub hardware: Option<Timespec>,
+    /// Taken by the NIC, on its PTP clock
+    pub raw_hardware: Option<Timespec>,
+    /// MAC address the frame came from
+    pub source: [u8; 6],
+    /// The tag the frame arrived with. The kernel takes it off before frames
+    /// reach the VLAN interface of a socket, or a socket of one protocol, so
+    /// only a socket on the NIC itself taking `ETH_P_ALL` sees it.
+    pub vlan: Option<VlanTag>,
+    pub packet_type: PacketType,
+}
+
+impl RxMeta {
+    /// The most precise timestamp there is, in the order
+    /// [`TsnSocket::get_tx_timestamp`] picks them: raw hardware, hardware,
+    /// software.
+    pub fn timestamp(&self) -> Option<Timespec> {
+        self.raw_hardware.or(self.hardware).or(self.software)
+    }
+}
+
+/// Turn the `SO_TIMESTAMPING` `flags` on for `fd`, keeping those already on.
+pub(crate) fn add_timestamping(fd: RawFd, flags: u32) -> Result<(), TsnError> {
+    let mut current: u32 = 0;
+    let mut len = mem::size_of_val(&current) as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPING,
+            &mut current as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res < 0 {
+        return Err(TsnError::socket("getsockopt SO_TIMESTAMPING"));
+    }
+    let flags = current | flags;
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPING,
+            &flags as *const _ as *const libc::c_void,
+            mem::size_of_val(&flags) as u32,
+        )
+    };
+    if res < 0 {
+        return Err(TsnError::socket("setsockopt SO_TIMESTAMPING"));
+    }
+    Ok(())
+}
+
+/// Have the NIC of `sock` stamp every frame it receives, leaving its TX
+/// timestamping as it is.
+fn enable_hw_rx_timestamp(sock: &TsnSocket) -> Result<(), TsnError> {
+    let mut config = libc::hwtstamp_config {
+        flags: 0,
+        tx_type: libc::HWTSTAMP_TX_OFF as i32,
+        rx_filter: libc::HWTSTAMP_FILTER_NONE as i32,
+    };
+    // Written by the kernel through the ifreq
+    let ts_cfg = &mut config as *mut libc::hwtstamp_config;
+    let mut ifr_name: [libc::c_char; libc::IFNAMSIZ] = [0; libc::IFNAMSIZ];
+    for (source, target) in sock.ifname.as_bytes().iter().zip(ifr_name.iter_mut()) {
+        *target = *source as libc::c_char;
+    }
+    let ifreq = libc::ifreq {
+        ifr_name,
+        ifr_ifru: libc::__c_anonymous_ifr_ifru {
+            ifru_data: ts_cfg as *mut libc::c_char,
+        },
+    };
+
+    // Drivers older than SIOCGHWTSTAMP leave TX timestamps off
+    unsafe {
+        // Not useless conversion because aarch64 has different type
+        #[allow(clippy::useless_conversion)]
+        libc::ioctl(sock.fd, libc::SIOCGHWTSTAMP.try_into().unwrap(), &ifreq)
+    };
+    unsafe { (*ts_cfg).rx_filter = libc::HWTSTAMP_FILTER_ALL as i32 };
+    let res = unsafe {
+        #[allow(clippy::useless_conversion)]
+        libc::ioctl(sock.fd, libc::SIOCSHWTSTAMP.try_into().unwrap(), &ifreq)
+    };
+    if res < 0 {
+        return Err(TsnError::socket("ioctl SIOCSHWTSTAMP"));
+    }
+    Ok(())
+}
+
+pub fn enable_rx_timestamp(sock: &TsnSocket, mode: RxTimestampMode) -> Result<(), TsnError> {
+    sock.packet_socket("enable rx timestamp")?;
+    let mut flags = libc::SOF_TIMESTAMPING_RX_SOFTWARE | libc::SOF_TIMESTAMPING_SOFTWARE;
+    if mode == RxTimestampMode::Hardware {
+        enable_hw_rx_timestamp(sock)?;
+        flags |= libc::SOF_TIMESTAMPING_RX_HARDWARE
+            | libc::SOF_TIMESTAMPING_SYS_HARDWARE
+            | libc::SOF_TIMESTAMPING_RAW_HARDWARE;
+    }
+    add_timestamping(sock.fd, flags)?;
+
+    let on: libc::c_int = 1;
+    let res = unsafe {
+        libc::setsockopt(
+            sock.fd,
+            libc::SOL_PACKET,
+            PACKET_AUXDATA,
+            &on as *const _ as *const libc::c_void,
+            mem::size_of_val(&on) as u32,
+        )
+    };
+    if res < 0 {
+        return Err(TsnError::socket("setsockopt PACKET_AUXDATA"));
+    }
+    Ok(())
+}
+
+fn timespec(ts: libc::timespec) -> Option<Timespec> {
+    if ts.tv_sec == 0 && ts.tv_nsec == 0 {
+        return None;
+    }
+    Some(Timespec {
+        tv_sec: ts.tv_sec,
+        tv_nsec: ts.tv_nsec,
+    })
+}
+
+/// The meta data of a message received from `addr`, from its control
+/// messages.
+pub(crate) fn rx_meta(msg: &libc::msghdr, addr: &libc::sockaddr_ll) -> RxMeta {
+    let mut meta = RxMeta {
+        software: None,
+        hardware: None,
+        raw_hardware: None,
+        source: [0; 6],
+        vlan: None,
+        packet_type: addr.sll_pkttype.into(),
+    };
+    meta.source.copy_from_slice(&addr.sll_addr[..6]);
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(msg) };
+    while !cmsg.is_null() {
+        let hdr = unsafe { &*cmsg };
+        match (hdr.cmsg_level, hdr.cmsg_type) {
+            (libc::SOL_SOCKET, libc::SO_TIMESTAMPING) => {
+                let ts = unsafe {
+                    std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const [libc::timespec; 3])
+                };
+                meta.software = timespec(ts[0]);
+                meta.hardware = timespec(ts[1]);
+                meta.raw_hardware = timespec(ts[2]);
+            }
+            // Set by hand on the socket, as tools used to
+            (libc::SOL_SOCKET, libc::SO_TIMESTAMPNS) => {
+                let ts = unsafe {
+                    std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::timespec)
+                };
+                meta.software = timespec(ts);
+            }
+            (libc::SOL_PACKET, PACKET_AUXDATA) => {
+                let aux = unsafe {
+                    std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const TpacketAuxdata)
+                };
+                if aux.status & TP_STATUS_VLAN_VALID != 0 {
+                    meta.vlan = Some(aux.vlan_tci.into());
+                }
+            }
+            _ => {}
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(msg, cmsg) };
+    }
+    meta
+}
+
+/// Receive a frame into `buf` with `recvmsg`, and its meta data.
+pub(crate) fn recv_meta(fd: RawFd, buf: &mut [u8]) -> Result<(isize, RxMeta), Error> {
+    let mut control = [0u64; CONTROL_LEN / 8];
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of_val(&addr) as u32;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = {
+        // aarch64 has msg_controllen as u32, not usize
+        #[allow(clippy::useless_conversion)]
+        mem::size_of_val(&control).try_into().unwrap()
+    };
+
+    let res = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if res < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok((res, rx_meta(&msg, &addr)))
+}
+
+/// Receive a frame, and its [`RxMeta`]. The timestamps and VLAN tag are only
+/// there once [`enable_rx_timestamp`] is set.
+///
+/// The socket timeout, see [`TsnSocket::set_timeout`], applies.
+pub fn recv_with_timestamp(sock: &TsnSocket, buf: &mut [u8]) -> Result<(isize, RxMeta), TsnError> {
+    sock.packet_socket("recvmsg")?;
+    recv_meta(sock.fd, buf).map_err(|source| TsnError::Socket {
+        op: "recvmsg",
+        source,
+    })
+}
@@ -0,0 +1,481 @@
+//! The config file as typed structs.
+//!
+//! The same schema is read from and written to YAML, TOML and JSON, and
+//! [`json_schema`] describes it for editors. Values with a unit, like `300us`
+//! or `1522B`, are parsed into [`Nanoseconds`], [`Bits`] and [`Bps`].
+//!
+//! These types only describe the shape of the file, see
+//! [`crate::config::parse_config`] for the checks that need the whole config.
+
+use crate::cbs::{to_bits, to_bps};
+use crate::tas::to_ns;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{
+    InstanceType, Metadata, Schema, SchemaObject, StringValidation, SubschemaValidation,
+};
+use schemars::JsonSchema;
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+pub use crate::tas::TasMode;
+
+/// The file formats a config can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// The format of `path` by its extension, YAML when it is not known.
+    pub fn of(path: &str) -> Format {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Yaml,
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "toml" => Ok(Format::Toml),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("'{}' is not a format, use yaml, toml or json", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Config of each NIC, by interface name
+    pub nics: BTreeMap<String, NicConfig>,
+}
+
+impl ConfigFile {
+    pub fn to_string(&self, format: Format) -> Result<String, String> {
+        match format {
+            Format::Yaml => serde_yaml::to_string(self).map_err(|e| e.to_string()),
+            Format::Json => serde_json::to_string_pretty(self).map_err(|e| e.to_string()),
+            // TOML keys are strings, which JSON turns the prios and VLAN ids into
+            Format::Toml => serde_json::to_value(self)
+                .map_err(|e| e.to_string())
+                .and_then(|value| toml::to_string(&value).map_err(|e| e.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct NicConfig {
+    /// skb prio to VLAN pri, by VLAN id
+    #[serde(
+        rename = "egress-qos-map",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub egress_qos_map: BTreeMap<IntKey, BTreeMap<IntKey, i64>>,
+    /// Speed of the link the cbs credits and tas guard bands are computed
+    /// for, read from the NIC when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_speed: Option<Bps>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tas: Option<TasSection>,
+    /// Credit based shaped streams, by skb prio
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cbs: Option<BTreeMap<IntKey, CbsStream>>,
+    /// ETF qdiscs by skb prio, when tas is not used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etf: Option<BTreeMap<IntKey, EtfSection>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TasSection {
+    pub schedule: Vec<ScheduleEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub txtime_delay: Option<Nanoseconds>,
+    /// 0 when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_time: Option<BaseTimeSpec>,
+    /// The sum of the schedule when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cycle_time: Option<Nanoseconds>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cycle_time_extension: Option<Nanoseconds>,
+    /// full-offload when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<TasMode>,
+    /// Largest frame sent through the schedule, 1522B when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_frame: Option<Bits>,
+    /// ETF qdiscs by skb prio, under the taprio classes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etf: Option<BTreeMap<IntKey, EtfSection>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleEntry {
+    pub time: Nanoseconds,
+    /// skb prios let through, -1 for the ones not in any entry
+    pub prio: Vec<i64>,
+}
+
+/// A TAI time in ns, or when to start relative to the cycle.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(
+    untagged,
+    deny_unknown_fields,
+    expecting = "a time, {next_cycle: <time>} or {phase: <time>}"
+)]
+pub enum BaseTimeSpec {
+    Absolute(Nanoseconds),
+    NextCycle { next_cycle: Nanoseconds },
+    Phase { phase: Nanoseconds },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CbsStream {
+    pub class: CbsClass,
+    pub max_frame: Bits,
+    pub bandwidth: Bps,
+    /// Largest frame a frame of the class may wait for before the classes
+    /// above it, the class's max_frame when not set. The largest of the
+    /// streams of a class counts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_interfering_frame: Option<Bits>,
+}
+
+/// An SR class, a letter from `a`, which goes before all the others, to `z`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CbsClass(pub char);
+
+impl CbsClass {
+    pub fn name(&self) -> char {
+        self.0
+    }
+}
+
+impl Serialize for CbsClass {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for CbsClass {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CbsClass, D::Error> {
+        struct ClassVisitor;
+
+        impl<'de> Visitor<'de> for ClassVisitor {
+            type Value = CbsClass;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a class from a to z")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<CbsClass, E> {
+                match v.chars().collect::<Vec<_>>()[..] {
+                    [class @ 'a'..='z'] => Ok(CbsClass(class)),
+                    _ => Err(E::custom(format!("'{}' should be a class from a to z", v))),
+                }
+            }
+        }
+
+        deserializer.deserialize_str(ClassVisitor)
+    }
+}
+
+impl JsonSchema for CbsClass {
+    fn schema_name() -> String {
+        "CbsClass".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                description: Some(
+                    "an SR class, a single lowercase letter such as a or b".to_string(),
+                ),
+                ..Default::default()
+            })),
+            instance_type: Some(InstanceType::String.into()),
+            string: Some(Box::new(StringValidation {
+                pattern: Some("^[a-z]$".to_string()),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct EtfSection {
+    /// CLOCK_TAI when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clockid: Option<Clock>,
+    /// How long before its txtime a frame is dequeued
+    pub delta: Nanoseconds,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offload: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deadline_mode: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_sock_check: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum Clock {
+    #[serde(rename = "CLOCK_TAI")]
+    Tai,
+    #[serde(rename = "CLOCK_REALTIME")]
+    Realtime,
+    #[serde(rename = "CLOCK_MONOTONIC")]
+    Monotonic,
+    #[serde(rename = "CLOCK_BOOTTIME")]
+    Boottime,
+}
+
+impl Clock {
+    pub fn id(&self) -> libc::clockid_t {
+        match self {
+            Clock::Tai => libc::CLOCK_TAI,
+            Clock::Realtime => libc::CLOCK_REALTIME,
+            Clock::Monotonic => libc::CLOCK_MONOTONIC,
+            Clock::Boottime => libc::CLOCK_BOOTTIME,
+        }
+    }
+}
+
+/// A dictionary key that is a number, like a prio or a VLAN id.
+///
+/// YAML writes them as numbers, TOML and JSON as strings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IntKey(pub i64);
+
+impl fmt::Display for IntKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Serialize for IntKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for IntKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<IntKey, D::Error> {
+        struct KeyVisitor;
+
+        impl<'de> Visitor<'de> for KeyVisitor {
+            type Value = IntKey;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an integer")
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<IntKey, E> {
+                Ok(IntKey(v))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<IntKey, E> {
+                i64::try_from(v)
+                    .map(IntKey)
+                    .map_err(|_| E::custom(format!("{} is too large", v)))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<IntKey, E> {
+                v.parse()
+                    .map(IntKey)
+                    .map_err(|_| E::custom(format!("'{}' should be an integer", v)))
+            }
+        }
+
+        deserializer.deserialize_any(KeyVisitor)
+    }
+}
+
+/// Deserialize a number, or a string parsed by `parse`.
+fn deserialize_unit<'de, D: Deserializer<'de>>(
+    deserializer: D,
+    what: &'static str,
+    parse: fn(&str) -> Result<i64, String>,
+) -> Result<i64, D::Error> {
+    struct UnitVisitor {
+        what: &'static str,
+        parse: fn(&str) -> Result<i64, String>,
+    }
+
+    impl<'de> Visitor<'de> for UnitVisitor {
+        type Value = i64;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}", self.what)
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<i64, E> {
+            Ok(v)
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<i64, E> {
+            i64::try_from(v).map_err(|_| E::custom(format!("{} is too large", v)))
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<i64, E> {
+            (self.parse)(v).map_err(E::custom)
+        }
+    }
+
+    deserializer.deserialize_any(UnitVisitor { what, parse })
+}
+
+/// `value` with the largest of `units` that divides it.
+fn with_unit(value: i64, units: &[(&str, i64)], suffix: &str) -> String {
+    let (unit, size) = units
+        .iter()
+        .find(|(_, size)| value % size == 0)
+        .unwrap_or(&units[units.len() - 1]);
+    format!("{}{}{}", value / size, unit, suffix)
+}
+
+/// Schema of a number, or a string with a unit matching `pattern`.
+fn unit_schema(description: &str, pattern: &str) -> Schema {
+    let integer = SchemaObject {
+        instance_type: Some(InstanceType::Integer.into()),
+        ..Default::default()
+    };
+    let string = SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        string: Some(Box::new(StringValidation {
+            pattern: Some(pattern.to_string()),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    SchemaObject {
+        metadata: Some(Box::new(Metadata {
+            description: Some(description.to_string()),
+            ..Default::default()
+        })),
+        subschemas: Some(Box::new(SubschemaValidation {
+            one_of: Some(vec![integer.into(), string.into()]),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
+}
+
+macro_rules! unit_newtype {
+    ($name:ident, $what:expr, $parse:ident, $pattern:expr) => {
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<$name, D::Error> {
+                deserialize_unit(deserializer, $what, $parse).map($name)
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                // The units do not take a sign
+                if self.0 < 0 {
+                    serializer.serialize_i64(self.0)
+                } else {
+                    serializer.serialize_str(&self.to_string())
+                }
+            }
+        }
+
+        impl JsonSchema for $name {
+            fn schema_name() -> String {
+                stringify!($name).to_string()
+            }
+
+            fn json_schema(_: &mut SchemaGenerator) -> Schema {
+                unit_schema($what, $pattern)
+            }
+        }
+    };
+}
+
+/// A duration, a number of ns or e.g. `300us`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Nanoseconds(pub i64);
+
+impl fmt::Display for Nanoseconds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let units = [
+            ("s", 1_000_000_000),
+            ("ms", 1_000_000),
+            ("us", 1000),
+            ("ns", 1),
+        ];
+        write!(f, "{}", with_unit(self.0, &units, ""))
+    }
+}
+
+unit_newtype!(
+    Nanoseconds,
+    "a time, e.g. 300us",
+    to_ns,
+    r"^[0-9_]+\s*(|ns|us|µs|ms|s)$"
+);
+
+/// A size, a number of bits or e.g. `1522B`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Bits(pub i64);
+
+impl fmt::Display for Bits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let units = [("G", 1_000_000_000), ("M", 1_000_000), ("k", 1000), ("", 1)];
+        if self.0 % 8 == 0 {
+            write!(f, "{}", with_unit(self.0 / 8, &units, "B"))
+        } else {
+            write!(f, "{}", with_unit(self.0, &units, "b"))
+        }
+    }
+}
+
+unit_newtype!(
+    Bits,
+    "a size, e.g. 1522B",
+    to_bits,
+    r"^[0-9_]+\s*(|k|M|G|ki|Mi|Gi)(b|B)$"
+);
+
+/// A bandwidth, a number of bits per second or e.g. `30Mbps`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Bps(pub i64);
+
+impl fmt::Display for Bps {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let units = [("G", 1_000_000_000), ("M", 1_000_000), ("k", 1000), ("", 1)];
+        write!(f, "{}", with_unit(self.0, &units, "bps"))
+    }
+}
+
+unit_newtype!(
+    Bps,
+    "a bandwidth, e.g. 30Mbps",
+    to_bps,
+    r"^[0-9_]+\s*(|k|M|G)(b|B)[p/]s$"
+);
+
+/// The JSON Schema of the config file, for editors to check it with.
+pub fn json_schema() -> String {
+    let schema = schemars::schema_for!(ConfigFile);
+    serde_json::to_string_pretty(&schema).expect("a schema is always valid JSON")
+}
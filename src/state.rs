@@ -0,0 +1,1216 @@
+//! What the kernel has installed on a NIC, read back through rtnetlink, and
+//! where it drifts from the config.
+//!
+//! The qdiscs, the classes of the root qdisc with their per queue stats, and
+//! the VLANs on top of the NIC are read as `tc qdisc show`, `tc -s class show`
+//! and `ip -d link show` would.
+
+use crate::cbs::CbsCredit;
+use crate::config::{self, Config, EtfConfig};
+use crate::error::TsnError;
+use crate::netlink::{self, Message, Netlink};
+use crate::tas::{BaseTime, SchedEntry};
+use crate::vlan;
+use itertools::Itertools;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+
+// sizeof(struct tcmsg) and sizeof(struct ifinfomsg)
+const TCMSG_LEN: usize = 20;
+const IFINFOMSG_LEN: usize = 16;
+// sizeof(struct tc_mqprio_qopt)
+const MQPRIO_QOPT_LEN: usize = 82;
+
+/// Counters of a qdisc or class, from `TCA_STATS2`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct QueueStats {
+    pub bytes: u64,
+    pub packets: u32,
+    pub drops: u32,
+    pub overlimits: u32,
+    pub requeues: u32,
+    pub backlog: u32,
+    pub qlen: u32,
+}
+
+impl fmt::Display for QueueStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "sent: {}B {}p, dropped: {}, overlimits: {}, requeues: {}, backlog: {}B {}p",
+            self.bytes,
+            self.packets,
+            self.drops,
+            self.overlimits,
+            self.requeues,
+            self.backlog,
+            self.qlen
+        )
+    }
+}
+
+/// The traffic classes of mqprio or taprio, from `struct tc_mqprio_qopt`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TrafficClasses {
+    pub num_tc: u8,
+    pub tc_map: [u8; 16],
+    /// `(count, offset)` of the TX queues for each traffic class
+    pub queues: Vec<(u16, u16)>,
+}
+
+impl TrafficClasses {
+    /// The first TX queue of the traffic class frames of `priority` go to.
+    pub fn queue(&self, priority: u32) -> Option<u16> {
+        let tc = self.tc_map[(priority & 0xf) as usize];
+        self.queues.get(tc as usize).map(|(_, offset)| *offset)
+    }
+}
+
+impl fmt::Display for TrafficClasses {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let queues = self
+            .queues
+            .iter()
+            .map(|(count, offset)| format!("{}@{}", count, offset))
+            .join(", ");
+        write!(
+            f,
+            "num_tc: {}, map: {:?}, queues: [{}]",
+            self.num_tc, self.tc_map, queues
+        )
+    }
+}
+
+/// A schedule of taprio, the one running or the one waiting for its base time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TaprioSchedule {
+    pub base_time: i64,
+    pub cycle_time: i64,
+    pub cycle_time_extension: i64,
+    pub entries: Vec<SchedEntry>,
+}
+
+impl fmt::Display for TaprioSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{{base_time: {}, cycle_time: {}, cycle_time_extension: {}, entries: [{}]}}",
+            self.base_time,
+            self.cycle_time,
+            self.cycle_time_extension,
+            self.entries.iter().join(", ")
+        )
+    }
+}
+
+/// The options of the qdiscs libtsn installs.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QdiscOptions {
+    Taprio {
+        classes: TrafficClasses,
+        flags: u32,
+        /// Not used when the schedule is offloaded
+        clockid: Option<libc::clockid_t>,
+        txtime_delay: u32,
+        /// The schedule running now
+        oper: Option<TaprioSchedule>,
+        /// The schedule that takes over at its base time
+        admin: Option<TaprioSchedule>,
+    },
+    Mqprio(TrafficClasses),
+    Cbs {
+        offload: bool,
+        credit: CbsCredit,
+    },
+    Etf(EtfConfig),
+    /// Any other qdisc, whose options are not read
+    Other,
+}
+
+impl fmt::Display for QdiscOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QdiscOptions::Taprio {
+                classes,
+                flags,
+                clockid,
+                txtime_delay,
+                ..
+            } => {
+                write!(f, "{{{}, flags: {:#x}", classes, flags)?;
+                if let Some(clockid) = clockid {
+                    write!(f, ", clockid: {}", config::clock_name(*clockid))?;
+                }
+                write!(f, ", txtime_delay: {}}}", txtime_delay)
+            }
+            QdiscOptions::Mqprio(classes) => write!(f, "{{{}}}", classes),
+            QdiscOptions::Cbs { offload, credit } => write!(
+                f,
+                "{{hicredit: {}, idleslope: {}, locredit: {}, sendslope: {}, offload: {}}}",
+                credit.hicredit, credit.idleslope, credit.locredit, credit.sendslope, offload
+            ),
+            QdiscOptions::Etf(etf) => write!(
+                f,
+                "{{clockid: {}, deadline_mode: {}, delta: {}, offload: {}, skip_sock_check: {}}}",
+                etf.clock_name(),
+                etf.deadline_mode,
+                etf.delta,
+                etf.offload,
+                etf.skip_sock_check
+            ),
+            QdiscOptions::Other => write!(f, "{{}}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Qdisc {
+    pub kind: String,
+    #[serde(serialize_with = "serialize_handle")]
+    pub handle: u32,
+    #[serde(serialize_with = "serialize_handle")]
+    pub parent: u32,
+    pub options: QdiscOptions,
+    pub stats: QueueStats,
+}
+
+/// A class of a qdisc. The classes of mqprio and taprio are the TX queues.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Class {
+    #[serde(serialize_with = "serialize_handle")]
+    pub handle: u32,
+    #[serde(serialize_with = "serialize_handle")]
+    pub parent: u32,
+    pub stats: QueueStats,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VlanState {
+    pub name: String,
+    pub vlanid: u16,
+    /// The kernel leaves out the prios that map to pri 0
+    pub egress_qos_map: BTreeMap<u32, u32>,
+}
+
+/// Everything libtsn may have installed on a NIC.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LinkState {
+    pub qdiscs: Vec<Qdisc>,
+    pub classes: Vec<Class>,
+    pub vlans: Vec<VlanState>,
+}
+
+/// `handle` as `tc` writes it, e.g. `100:` or `100:1`.
+pub fn handle_name(handle: u32) -> String {
+    match handle {
+        netlink::TC_H_ROOT => "root".to_string(),
+        _ if handle & 0xffff == 0 => format!("{:x}:", handle >> 16),
+        _ => format!("{:x}:{:x}", handle >> 16, handle & 0xffff),
+    }
+}
+
+fn serialize_handle<S: Serializer>(handle: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&handle_name(*handle))
+}
+
+fn parse_stats(attrs: &[u8]) -> QueueStats {
+    let mut stats = QueueStats::default();
+    let stats2 = match netlink::attr(attrs, libc::TCA_STATS2) {
+        Some(stats2) => stats2,
+        None => return stats,
+    };
+    if let Some(basic) = netlink::attr(stats2, netlink::TCA_STATS_BASIC) {
+        stats.bytes = netlink::read_u64(basic, 0).unwrap_or_default();
+        stats.packets = netlink::read_u32(basic, 8).unwrap_or_default();
+    }
+    if let Some(queue) = netlink::attr(stats2, netlink::TCA_STATS_QUEUE) {
+        stats.qlen = netlink::read_u32(queue, 0).unwrap_or_default();
+        stats.backlog = netlink::read_u32(queue, 4).unwrap_or_default();
+        stats.drops = netlink::read_u32(queue, 8).unwrap_or_default();
+        stats.requeues = netlink::read_u32(queue, 12).unwrap_or_default();
+        stats.overlimits = netlink::read_u32(queue, 16).unwrap_or_default();
+    }
+    stats
+}
+
+fn parse_classes(qopt: &[u8]) -> Option<TrafficClasses> {
+    if qopt.len() < MQPRIO_QOPT_LEN {
+        return None;
+    }
+    let num_tc = qopt[0];
+    let tc_map = qopt[1..17].try_into().ok()?;
+    let queues = (0..num_tc.min(netlink::TC_QOPT_MAX_QUEUE as u8) as usize)
+        .map(|tc| {
+            let count = netlink::read_u16(qopt, 18 + tc * 2)?;
+            let offset = netlink::read_u16(qopt, 50 + tc * 2)?;
+            Some((count, offset))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(TrafficClasses {
+        num_tc,
+        tc_map,
+        queues,
+    })
+}
+
+/// The schedule in the attributes `attrs`, at the top of the taprio options
+/// for the running one or nested in `TCA_TAPRIO_ATTR_ADMIN_SCHED`.
+fn parse_schedule(attrs: &[u8]) -> Option<TaprioSchedule> {
+    let list = netlink::attr(attrs, netlink::TCA_TAPRIO_ATTR_SCHED_ENTRY_LIST)?;
+    let entries = netlink::attrs(list)
+        .into_iter()
+        .filter(|(attr_type, _)| *attr_type == netlink::TCA_TAPRIO_SCHED_ENTRY)
+        .map(|(_, entry)| {
+            let read =
+                |attr_type| netlink::attr(entry, attr_type).and_then(|v| netlink::read_u32(v, 0));
+            Some(SchedEntry {
+                gate_mask: read(netlink::TCA_TAPRIO_SCHED_ENTRY_GATE_MASK)?,
+                interval: read(netlink::TCA_TAPRIO_SCHED_ENTRY_INTERVAL)?,
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let read = |attr_type| {
+        netlink::attr(attrs, attr_type)
+            .and_then(|v| netlink::read_i64(v, 0))
+            .unwrap_or_default()
+    };
+    Some(TaprioSchedule {
+        base_time: read(netlink::TCA_TAPRIO_ATTR_SCHED_BASE_TIME),
+        cycle_time: read(netlink::TCA_TAPRIO_ATTR_SCHED_CYCLE_TIME),
+        cycle_time_extension: read(netlink::TCA_TAPRIO_ATTR_SCHED_CYCLE_TIME_EXTENSION),
+        entries,
+    })
+}
+
+fn parse_options(kind: &str, options: &[u8]) -> Option<QdiscOptions> {
+    match kind {
+        "taprio" => {
+            let read =
+                |attr_type| netlink::attr(options, attr_type).and_then(|v| netlink::read_u32(v, 0));
+            Some(QdiscOptions::Taprio {
+                classes: parse_classes(netlink::attr(options, netlink::TCA_TAPRIO_ATTR_PRIOMAP)?)?,
+                flags: read(netlink::TCA_TAPRIO_ATTR_FLAGS).unwrap_or_default(),
+                clockid: read(netlink::TCA_TAPRIO_ATTR_SCHED_CLOCKID).map(|v| v as i32),
+                txtime_delay: read(netlink::TCA_TAPRIO_ATTR_TXTIME_DELAY).unwrap_or_default(),
+                oper: parse_schedule(options),
+                admin: netlink::attr(options, netlink::TCA_TAPRIO_ATTR_ADMIN_SCHED)
+                    .and_then(parse_schedule),
+            })
+        }
+        // The only one whose options are not nested attributes
+        "mqprio" => parse_classes(options).map(QdiscOptions::Mqprio),
+        "cbs" => {
+            let parms = netlink::attr(options, netlink::TCA_CBS_PARMS)?;
+            Some(QdiscOptions::Cbs {
+                offload: *parms.first()? != 0,
+                credit: CbsCredit {
+                    hicredit: netlink::read_i32(parms, 4)? as i64,
+                    locredit: netlink::read_i32(parms, 8)? as i64,
+                    idleslope: netlink::read_i32(parms, 12)? as i64,
+                    sendslope: netlink::read_i32(parms, 16)? as i64,
+                },
+            })
+        }
+        "etf" => {
+            let parms = netlink::attr(options, netlink::TCA_ETF_PARMS)?;
+            let flags = netlink::read_u32(parms, 8)?;
+            Some(QdiscOptions::Etf(EtfConfig {
+                delta: netlink::read_i32(parms, 0)? as i64,
+                clockid: netlink::read_i32(parms, 4)?,
+                offload: flags & netlink::TC_ETF_OFFLOAD_ON != 0,
+                deadline_mode: flags & netlink::TC_ETF_DEADLINE_MODE_ON != 0,
+                skip_sock_check: flags & netlink::TC_ETF_SKIP_SOCK_CHECK != 0,
+            }))
+        }
+        _ => Some(QdiscOptions::Other),
+    }
+}
+
+/// `(ifindex, handle, parent)` of a `struct tcmsg` and its attributes.
+fn parse_tcmsg(msg: &[u8]) -> Option<(i32, u32, u32, &[u8])> {
+    Some((
+        netlink::read_i32(msg, 4)?,
+        netlink::read_u32(msg, 8)?,
+        netlink::read_u32(msg, 12)?,
+        msg.get(TCMSG_LEN..)?,
+    ))
+}
+
+fn parse_qdisc(msg: &[u8]) -> Option<(i32, Qdisc)> {
+    let (ifindex, handle, parent, attrs) = parse_tcmsg(msg)?;
+    let kind = netlink::read_str(netlink::attr(attrs, libc::TCA_KIND)?);
+    let options = match netlink::attr(attrs, libc::TCA_OPTIONS) {
+        Some(options) => parse_options(&kind, options).unwrap_or(QdiscOptions::Other),
+        None => QdiscOptions::Other,
+    };
+    let qdisc = Qdisc {
+        kind,
+        handle,
+        parent,
+        options,
+        stats: parse_stats(attrs),
+    };
+    Some((ifindex, qdisc))
+}
+
+/// The VLAN in the `RTM_NEWLINK` message `msg`, if it is one on `ifindex`.
+fn parse_vlan(msg: &[u8], ifindex: i32) -> Option<VlanState> {
+    let attrs = msg.get(IFINFOMSG_LEN..)?;
+    let link = netlink::attr(attrs, libc::IFLA_LINK).and_then(|v| netlink::read_i32(v, 0));
+    if link != Some(ifindex) {
+        return None;
+    }
+    let info = netlink::attr(attrs, libc::IFLA_LINKINFO)?;
+    if netlink::read_str(netlink::attr(info, libc::IFLA_INFO_KIND)?) != "vlan" {
+        return None;
+    }
+    let data = netlink::attr(info, netlink::IFLA_INFO_DATA)?;
+    let egress_qos_map = netlink::attr(data, netlink::IFLA_VLAN_EGRESS_QOS)
+        .map(netlink::attrs)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(attr_type, _)| *attr_type == netlink::IFLA_VLAN_QOS_MAPPING)
+        .filter_map(|(_, mapping)| {
+            Some((
+                netlink::read_u32(mapping, 0)?,
+                netlink::read_u32(mapping, 4)?,
+            ))
+        })
+        .collect();
+    Some(VlanState {
+        name: netlink::read_str(netlink::attr(attrs, libc::IFLA_IFNAME)?),
+        vlanid: netlink::read_u16(netlink::attr(data, netlink::IFLA_VLAN_ID)?, 0)?,
+        egress_qos_map,
+    })
+}
+
+/// Read what is installed on `ifname`.
+pub fn read(ifname: &str) -> Result<LinkState, TsnError> {
+    let desc = format!("tc qdisc show dev {}", ifname);
+    let ifindex = vlan::get_ifindex(ifname, &desc)?;
+    let mut nl = Netlink::open()?;
+
+    // The kernel dumps the qdiscs of every device
+    let mut msg = Message::dump(libc::RTM_GETQDISC);
+    msg.tcmsg(ifindex, 0, 0);
+    let qdiscs = nl
+        .dump(&desc, &mut msg)?
+        .iter()
+        .filter_map(|msg| parse_qdisc(msg))
+        .filter(|(index, _)| *index == ifindex)
+        .map(|(_, qdisc)| qdisc)
+        .collect();
+
+    let desc = format!("tc -s class show dev {}", ifname);
+    let mut msg = Message::dump(libc::RTM_GETTCLASS);
+    msg.tcmsg(ifindex, 0, 0);
+    let classes = nl
+        .dump(&desc, &mut msg)?
+        .iter()
+        .filter_map(|msg| {
+            let (_, handle, parent, attrs) = parse_tcmsg(msg)?;
+            Some(Class {
+                handle,
+                parent,
+                stats: parse_stats(attrs),
+            })
+        })
+        .collect();
+
+    let desc = format!("ip -d link show type vlan link {}", ifname);
+    let mut msg = Message::dump(libc::RTM_GETLINK);
+    msg.ifinfomsg(0, 0, 0);
+    let vlans = nl
+        .dump(&desc, &mut msg)?
+        .iter()
+        .filter_map(|msg| parse_vlan(msg, ifindex))
+        .sorted_by_key(|vlan| vlan.vlanid)
+        .collect();
+
+    Ok(LinkState {
+        qdiscs,
+        classes,
+        vlans,
+    })
+}
+
+impl LinkState {
+    /// The qdisc whose parent is `parent`.
+    pub fn qdisc(&self, parent: u32) -> Option<&Qdisc> {
+        self.qdiscs.iter().find(|qdisc| qdisc.parent == parent)
+    }
+
+    /// The traffic classes of the root taprio or mqprio, if there is one.
+    pub fn traffic_classes(&self) -> Option<&TrafficClasses> {
+        match &self.qdisc(netlink::TC_H_ROOT)?.options {
+            QdiscOptions::Taprio { classes, .. } | QdiscOptions::Mqprio(classes) => Some(classes),
+            _ => None,
+        }
+    }
+
+    /// How what is installed differs from `config`, one line for each
+    /// difference. VLANs that do not exist yet are not a difference, they
+    /// are created when a socket is opened on them.
+    pub fn drift(&self, config: &Config) -> Vec<String> {
+        let mut drift = Vec::new();
+        self.root_drift(config, &mut drift);
+        self.vlan_drift(config, &mut drift);
+        drift
+    }
+
+    fn root_drift(&self, config: &Config, drift: &mut Vec<String>) {
+        let root_handle = 0x100;
+        let (kind, tc_map, num_tc, queues) = match (&config.tas, &config.cbs) {
+            (Some(tas), _) => ("taprio", tas.tc_map.clone(), tas.num_tc, tas.queues.clone()),
+            (None, Some(cbs)) => ("mqprio", cbs.tc_map.clone(), cbs.num_tc, cbs.queues.clone()),
+            (None, None) if !config.etf.is_empty() => {
+                let num_tc = config.etf.len() as i64 + 1;
+                let queues = (0..num_tc).map(|i| (1, i as u16)).collect();
+                ("mqprio", vlan::etf_tc_map(config), num_tc, queues)
+            }
+            (None, None) => return,
+        };
+        let expected = TrafficClasses {
+            num_tc: num_tc as u8,
+            tc_map: vlan::prio_tc_map(&tc_map),
+            queues,
+        };
+        let root = match self.qdisc(netlink::TC_H_ROOT) {
+            Some(root)
+                if root.kind == kind && root.handle == netlink::tc_handle(root_handle, 0) =>
+            {
+                root
+            }
+            Some(root) => {
+                drift.push(format!(
+                    "root qdisc is {} {}, the config needs {} {}",
+                    root.kind,
+                    handle_name(root.handle),
+                    kind,
+                    handle_name(netlink::tc_handle(root_handle, 0))
+                ));
+                return;
+            }
+            None => {
+                drift.push(format!("no root qdisc, the config needs {}", kind));
+                return;
+            }
+        };
+        match &root.options {
+            QdiscOptions::Taprio {
+                classes,
+                flags,
+                txtime_delay,
+                oper,
+                admin,
+                ..
+            } => {
+                let tas = config.tas.as_ref().unwrap();
+                if *classes != expected {
+                    drift.push(format!("taprio has {}, the config {}", classes, expected));
+                }
+                if *flags != tas.mode.flags() {
+                    drift.push(format!(
+                        "taprio flags are {:#x}, mode {} of the config needs {:#x}",
+                        flags,
+                        tas.mode,
+                        tas.mode.flags()
+                    ));
+                }
+                if *txtime_delay as i64 != tas.txtime_delay {
+                    drift.push(format!(
+                        "taprio txtime_delay is {}, the config {}",
+                        txtime_delay, tas.txtime_delay
+                    ));
+                }
+                // A new schedule shows up as admin until its base time
+                match admin.as_ref().or(oper.as_ref()) {
+                    Some(schedule) => {
+                        if schedule.entries != tas.sched_entries {
+                            drift.push(format!(
+                                "taprio schedule is [{}], the config [{}]",
+                                schedule.entries.iter().join(", "),
+                                tas.sched_entries.iter().join(", ")
+                            ));
+                        }
+                        if schedule.cycle_time != tas.cycle() {
+                            drift.push(format!(
+                                "taprio cycle_time is {}, the config {}",
+                                schedule.cycle_time,
+                                tas.cycle()
+                            ));
+                        }
+                        let extension = tas.cycle_time_extension.unwrap_or_default();
+                        if schedule.cycle_time_extension != extension {
+                            drift.push(format!(
+                                "taprio cycle_time_extension is {}, the config {}",
+                                schedule.cycle_time_extension, extension
+                            ));
+                        }
+                        // The other base times are resolved when installing
+                        if let BaseTime::Absolute(base_time) = tas.base_time {
+                            if schedule.base_time != base_time {
+                                drift.push(format!(
+                                    "taprio base_time is {}, the config {}",
+                                    schedule.base_time, base_time
+                                ));
+                            }
+                        }
+                    }
+                    None => drift.push("taprio has no schedule".to_string()),
+                }
+            }
+            QdiscOptions::Mqprio(classes) if *classes != expected => {
+                drift.push(format!("mqprio has {}, the config {}", classes, expected));
+            }
+            _ => {}
+        }
+
+        let mut expected_children = Vec::new();
+        if let Some(cbs) = &config.cbs {
+            let offload = vlan::cbs_offload(config.tas.as_ref());
+            for (class, credit) in cbs.children.iter().sorted_by_key(|(class, _)| **class) {
+                let parent = netlink::tc_handle(root_handle, *class as u32);
+                expected_children.push(parent);
+                match self.qdisc(parent) {
+                    Some(Qdisc {
+                        options:
+                            QdiscOptions::Cbs {
+                                offload: live_offload,
+                                credit: live,
+                            },
+                        ..
+                    }) if live == credit && *live_offload == offload => {}
+                    Some(qdisc) => drift.push(format!(
+                        "qdisc at {} is {} {}, the config needs cbs {}",
+                        handle_name(parent),
+                        qdisc.kind,
+                        qdisc.options,
+                        QdiscOptions::Cbs {
+                            offload,
+                            credit: credit.clone()
+                        }
+                    )),
+                    None => drift.push(format!("no cbs at {}", handle_name(parent))),
+                }
+            }
+        }
+        let etf = match &config.tas {
+            Some(tas) => &tas.etf,
+            None => &config.etf,
+        };
+        for (prio, parent) in vlan::etf_parents(config)
+            .into_iter()
+            .sorted_by_key(|(prio, _)| *prio)
+        {
+            let parent = netlink::tc_handle(parent.0, parent.1);
+            expected_children.push(parent);
+            match self.qdisc(parent) {
+                Some(Qdisc {
+                    options: QdiscOptions::Etf(live),
+                    ..
+                }) if *live == etf[&prio] => {}
+                Some(qdisc) => drift.push(format!(
+                    "qdisc at {} is {} {}, the config needs etf {} for prio {}",
+                    handle_name(parent),
+                    qdisc.kind,
+                    qdisc.options,
+                    QdiscOptions::Etf(etf[&prio].clone()),
+                    prio
+                )),
+                None => drift.push(format!(
+                    "no etf at {} for prio {}",
+                    handle_name(parent),
+                    prio
+                )),
+            }
+        }
+        for qdisc in &self.qdiscs {
+            let ours = matches!(
+                qdisc.options,
+                QdiscOptions::Cbs { .. } | QdiscOptions::Etf(_)
+            );
+            if ours && !expected_children.contains(&qdisc.parent) {
+                drift.push(format!(
+                    "{} at {} is not in the config",
+                    qdisc.kind,
+                    handle_name(qdisc.parent)
+                ));
+            }
+        }
+    }
+
+    fn vlan_drift(&self, config: &Config, drift: &mut Vec<String>) {
+        for vlan in &self.vlans {
+            let map = match config.egress_qos_map.get(&(vlan.vlanid as i64)) {
+                Some(map) => map,
+                None => {
+                    drift.push(format!(
+                        "vlan {} ({}) is not in the config",
+                        vlan.vlanid, vlan.name
+                    ));
+                    continue;
+                }
+            };
+            let expected: BTreeMap<u32, u32> = map
+                .iter()
+                .filter(|(_, pri)| **pri != 0)
+                .map(|(prio, pri)| (*prio as u32, *pri as u32))
+                .collect();
+            if vlan.egress_qos_map != expected {
+                let show = |map: &BTreeMap<u32, u32>| {
+                    map.iter()
+                        .map(|(prio, pri)| format!("{}: {}", prio, pri))
+                        .join(", ")
+                };
+                drift.push(format!(
+                    "egress-qos-map of vlan {} is {{{}}}, the config {{{}}}",
+                    vlan.vlanid,
+                    show(&vlan.egress_qos_map),
+                    show(&expected)
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    use crate::schema::Format;
+
+    const QOS: &str = "    egress-qos-map:\n      10: {2: 2, 3: 3}\n";
+    const TAS: &str = "    tas:\n      schedule: [{time: 300us, prio: [2, 3]}, {time: 200us, prio: [-1]}]\n      etf:\n        3: {delta: 200us}\n";
+    const CBS: &str = "    link_speed: 1Gbps\n    cbs:\n      3: {class: a, max_frame: 512B, bandwidth: 70Mbps}\n      2: {class: b, max_frame: 512B, bandwidth: 30Mbps}\n";
+
+    fn nic(sections: &[&str]) -> Config {
+        let yaml = format!("nics:\n  tsn0:\n{}", sections.concat());
+        parse_config(&yaml, Format::Yaml).unwrap()["tsn0"].clone()
+    }
+
+    /// The attributes written by `f`, without a family header.
+    fn attrs(f: impl FnOnce(&mut Message)) -> Vec<u8> {
+        let mut msg = Message::new(0, 0);
+        f(&mut msg);
+        msg.payload().to_vec()
+    }
+
+    fn sched_entries(msg: &mut Message, entries: &[(u32, u32)]) {
+        msg.nested(netlink::TCA_TAPRIO_ATTR_SCHED_ENTRY_LIST, |msg| {
+            for (gate_mask, interval) in entries {
+                msg.nested(netlink::TCA_TAPRIO_SCHED_ENTRY, |msg| {
+                    msg.attr_u8(
+                        netlink::TCA_TAPRIO_SCHED_ENTRY_CMD,
+                        netlink::TC_TAPRIO_CMD_SET_GATES,
+                    )
+                    .attr_u32(netlink::TCA_TAPRIO_SCHED_ENTRY_GATE_MASK, *gate_mask)
+                    .attr_u32(netlink::TCA_TAPRIO_SCHED_ENTRY_INTERVAL, *interval);
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn taprio() {
+        let mut tc_map = [2u8; 16];
+        tc_map[3] = 0;
+        tc_map[2] = 1;
+        let options = attrs(|msg| {
+            msg.attr(
+                netlink::TCA_TAPRIO_ATTR_PRIOMAP,
+                &netlink::mqprio_qopt(3, &tc_map, &[(1, 0), (1, 1), (2, 2)], 0),
+            )
+            .attr_u32(netlink::TCA_TAPRIO_ATTR_FLAGS, 0x1)
+            .attr_u32(
+                netlink::TCA_TAPRIO_ATTR_SCHED_CLOCKID,
+                libc::CLOCK_TAI as u32,
+            )
+            .attr_u32(netlink::TCA_TAPRIO_ATTR_TXTIME_DELAY, 300000)
+            .attr_i64(netlink::TCA_TAPRIO_ATTR_SCHED_BASE_TIME, 1000)
+            .attr_i64(netlink::TCA_TAPRIO_ATTR_SCHED_CYCLE_TIME, 500000);
+            sched_entries(msg, &[(0x3, 300000), (0x4, 200000)]);
+            msg.nested(netlink::TCA_TAPRIO_ATTR_ADMIN_SCHED, |msg| {
+                msg.attr_i64(netlink::TCA_TAPRIO_ATTR_SCHED_BASE_TIME, 2000)
+                    .attr_i64(netlink::TCA_TAPRIO_ATTR_SCHED_CYCLE_TIME, 400000)
+                    .attr_i64(netlink::TCA_TAPRIO_ATTR_SCHED_CYCLE_TIME_EXTENSION, 10000);
+                sched_entries(msg, &[(0x7, 400000)]);
+            });
+        });
+        let entry = |gate_mask, interval| SchedEntry {
+            gate_mask,
+            interval,
+        };
+        let classes = TrafficClasses {
+            num_tc: 3,
+            tc_map,
+            queues: vec![(1, 0), (1, 1), (2, 2)],
+        };
+        assert_eq!(classes.queue(3), Some(0));
+        assert_eq!(classes.queue(7), Some(2));
+        assert_eq!(
+            parse_options("taprio", &options),
+            Some(QdiscOptions::Taprio {
+                classes,
+                flags: 0x1,
+                clockid: Some(libc::CLOCK_TAI),
+                txtime_delay: 300000,
+                oper: Some(TaprioSchedule {
+                    base_time: 1000,
+                    cycle_time: 500000,
+                    cycle_time_extension: 0,
+                    entries: vec![entry(0x3, 300000), entry(0x4, 200000)],
+                }),
+                admin: Some(TaprioSchedule {
+                    base_time: 2000,
+                    cycle_time: 400000,
+                    cycle_time_extension: 10000,
+                    entries: vec![entry(0x7, 400000)],
+                }),
+            })
+        );
+
+        // Offloaded, with no clock and no schedule running yet
+        let options = attrs(|msg| {
+            msg.attr(
+                netlink::TCA_TAPRIO_ATTR_PRIOMAP,
+                &netlink::mqprio_qopt(3, &tc_map, &[(1, 0), (1, 1), (2, 2)], 0),
+            )
+            .attr_u32(netlink::TCA_TAPRIO_ATTR_FLAGS, 0x2);
+        });
+        match parse_options("taprio", &options) {
+            Some(QdiscOptions::Taprio {
+                clockid,
+                oper,
+                admin,
+                ..
+            }) => assert_eq!((clockid, oper, admin), (None, None, None)),
+            options => panic!("{:?}", options),
+        }
+        assert_eq!(parse_options("taprio", &[]), None);
+    }
+
+    #[test]
+    fn mqprio() {
+        let tc_map = [0, 0, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let qopt = netlink::mqprio_qopt(3, &tc_map, &[(1, 0), (1, 1), (1, 2)], 1);
+        assert_eq!(
+            parse_options("mqprio", &qopt),
+            Some(QdiscOptions::Mqprio(TrafficClasses {
+                num_tc: 3,
+                tc_map,
+                queues: vec![(1, 0), (1, 1), (1, 2)],
+            }))
+        );
+        assert_eq!(parse_options("mqprio", &qopt[..MQPRIO_QOPT_LEN - 1]), None);
+    }
+
+    #[test]
+    fn cbs() {
+        let options = attrs(|msg| {
+            msg.attr(
+                netlink::TCA_CBS_PARMS,
+                &netlink::cbs_qopt(true, 153, -1389, 70000, -930000),
+            );
+        });
+        assert_eq!(
+            parse_options("cbs", &options),
+            Some(QdiscOptions::Cbs {
+                offload: true,
+                credit: CbsCredit {
+                    hicredit: 153,
+                    locredit: -1389,
+                    idleslope: 70000,
+                    sendslope: -930000,
+                },
+            })
+        );
+        assert_eq!(parse_options("cbs", &[]), None);
+    }
+
+    #[test]
+    fn etf() {
+        let options = attrs(|msg| {
+            msg.attr(
+                netlink::TCA_ETF_PARMS,
+                &netlink::etf_qopt(
+                    200000,
+                    libc::CLOCK_TAI,
+                    netlink::TC_ETF_OFFLOAD_ON | netlink::TC_ETF_SKIP_SOCK_CHECK,
+                ),
+            );
+        });
+        assert_eq!(
+            parse_options("etf", &options),
+            Some(QdiscOptions::Etf(EtfConfig {
+                delta: 200000,
+                clockid: libc::CLOCK_TAI,
+                offload: true,
+                deadline_mode: false,
+                skip_sock_check: true,
+            }))
+        );
+        assert_eq!(parse_options("fq_codel", &[]), Some(QdiscOptions::Other));
+    }
+
+    #[test]
+    fn qdisc_and_stats() {
+        let mut msg = Message::new(libc::RTM_NEWQDISC, 0);
+        msg.tcmsg(4, 0x1111_0000, 0x100_0002)
+            .attr_str(libc::TCA_KIND, "cbs")
+            .nested(libc::TCA_OPTIONS, |msg| {
+                msg.attr(
+                    netlink::TCA_CBS_PARMS,
+                    &netlink::cbs_qopt(false, 1, -2, 3, -4),
+                );
+            })
+            .nested(libc::TCA_STATS2, |msg| {
+                let mut basic = 1500u64.to_ne_bytes().to_vec();
+                basic.extend_from_slice(&10u32.to_ne_bytes());
+                let queue: Vec<u8> = [1u32, 64, 2, 3, 4]
+                    .iter()
+                    .flat_map(|v| v.to_ne_bytes())
+                    .collect();
+                msg.attr(netlink::TCA_STATS_BASIC, &basic)
+                    .attr(netlink::TCA_STATS_QUEUE, &queue);
+            });
+        let (ifindex, qdisc) = parse_qdisc(msg.payload()).unwrap();
+        assert_eq!(ifindex, 4);
+        assert_eq!(
+            (handle_name(qdisc.handle), handle_name(qdisc.parent)),
+            ("1111:".to_string(), "100:2".to_string())
+        );
+        assert_eq!(qdisc.kind, "cbs");
+        assert!(matches!(
+            qdisc.options,
+            QdiscOptions::Cbs { offload: false, .. }
+        ));
+        assert_eq!(
+            qdisc.stats,
+            QueueStats {
+                bytes: 1500,
+                packets: 10,
+                drops: 2,
+                overlimits: 4,
+                requeues: 3,
+                backlog: 64,
+                qlen: 1,
+            }
+        );
+
+        // Options that do not parse are not an error, nor are missing stats
+        let mut msg = Message::new(libc::RTM_NEWQDISC, 0);
+        msg.tcmsg(4, 0x100_0000, netlink::TC_H_ROOT)
+            .attr_str(libc::TCA_KIND, "mqprio")
+            .attr(libc::TCA_OPTIONS, &[0; 4]);
+        let (_, qdisc) = parse_qdisc(msg.payload()).unwrap();
+        assert_eq!(handle_name(qdisc.parent), "root");
+        assert_eq!(qdisc.options, QdiscOptions::Other);
+        assert_eq!(qdisc.stats, QueueStats::default());
+    }
+
+    fn vlan_link(kind: &str, link: i32) -> Message {
+        let mut msg = Message::new(libc::RTM_NEWLINK, 0);
+        msg.ifinfomsg(7, 0, 0)
+            .attr_str(libc::IFLA_IFNAME, "tsn0.10")
+            .attr_u32(libc::IFLA_LINK, link as u32)
+            .nested(libc::IFLA_LINKINFO, |msg| {
+                msg.attr_str(libc::IFLA_INFO_KIND, kind)
+                    .nested(netlink::IFLA_INFO_DATA, |msg| {
+                        msg.attr_u16(netlink::IFLA_VLAN_ID, 10).nested(
+                            netlink::IFLA_VLAN_EGRESS_QOS,
+                            |msg| {
+                                msg.attr(
+                                    netlink::IFLA_VLAN_QOS_MAPPING,
+                                    &netlink::vlan_qos_mapping(2, 2),
+                                )
+                                .attr(
+                                    netlink::IFLA_VLAN_QOS_MAPPING,
+                                    &netlink::vlan_qos_mapping(3, 3),
+                                );
+                            },
+                        );
+                    });
+            });
+        msg
+    }
+
+    #[test]
+    fn vlan() {
+        assert_eq!(
+            parse_vlan(vlan_link("vlan", 4).payload(), 4),
+            Some(VlanState {
+                name: "tsn0.10".to_string(),
+                vlanid: 10,
+                egress_qos_map: BTreeMap::from([(2, 2), (3, 3)]),
+            })
+        );
+        assert_eq!(parse_vlan(vlan_link("vlan", 5).payload(), 4), None);
+        assert_eq!(parse_vlan(vlan_link("macvlan", 4).payload(), 4), None);
+    }
+
+    /// What libtsn installs for `config`.
+    fn installed(config: &Config) -> LinkState {
+        let qdisc = |kind: &str, handle, parent, options| Qdisc {
+            kind: kind.to_string(),
+            handle,
+            parent,
+            options,
+            stats: QueueStats::default(),
+        };
+        let root = match (&config.tas, &config.cbs) {
+            (Some(tas), _) => QdiscOptions::Taprio {
+                classes: TrafficClasses {
+                    num_tc: tas.num_tc as u8,
+                    tc_map: vlan::prio_tc_map(&tas.tc_map),
+                    queues: tas.queues.clone(),
+                },
+                flags: tas.mode.flags(),
+                clockid: None,
+                txtime_delay: tas.txtime_delay as u32,
+                oper: Some(TaprioSchedule {
+                    base_time: tas.base_time.resolve(0, tas.cycle()),
+                    cycle_time: tas.cycle(),
+                    cycle_time_extension: 0,
+                    entries: tas.sched_entries.clone(),
+                }),
+                admin: None,
+            },
+            (None, Some(cbs)) => QdiscOptions::Mqprio(TrafficClasses {
+                num_tc: cbs.num_tc as u8,
+                tc_map: vlan::prio_tc_map(&cbs.tc_map),
+                queues: cbs.queues.clone(),
+            }),
+            (None, None) => unreachable!(),
+        };
+        let kind = match root {
+            QdiscOptions::Taprio { .. } => "taprio",
+            _ => "mqprio",
+        };
+        let mut qdiscs = vec![qdisc(kind, 0x100_0000, netlink::TC_H_ROOT, root)];
+        if let Some(cbs) = &config.cbs {
+            for (class, credit) in &cbs.children {
+                let options = QdiscOptions::Cbs {
+                    offload: true,
+                    credit: credit.clone(),
+                };
+                let parent = netlink::tc_handle(0x100, *class as u32);
+                qdiscs.push(qdisc(
+                    "cbs",
+                    (*class as u32 * 0x1111) << 16,
+                    parent,
+                    options,
+                ));
+            }
+        }
+        if let Some(tas) = &config.tas {
+            for (prio, (major, minor)) in vlan::etf_parents(config) {
+                let options = QdiscOptions::Etf(tas.etf[&prio].clone());
+                let parent = netlink::tc_handle(major, minor);
+                qdiscs.push(qdisc("etf", 0x8001_0000, parent, options));
+            }
+        }
+        let vlans = config
+            .egress_qos_map
+            .iter()
+            .map(|(vlanid, map)| VlanState {
+                name: format!("tsn0.{}", vlanid),
+                vlanid: *vlanid as u16,
+                egress_qos_map: map.iter().map(|(k, v)| (*k as u32, *v as u32)).collect(),
+            })
+            .collect();
+        LinkState {
+            qdiscs,
+            classes: Vec::new(),
+            vlans,
+        }
+    }
+
+    /// The start of the drift reported, and how the installed state differs
+    type Case<'a> = (&'a str, fn(&mut LinkState));
+
+    fn taprio_options(state: &mut LinkState) -> &mut QdiscOptions {
+        &mut state.qdiscs[0].options
+    }
+
+    fn oper(state: &mut LinkState) -> &mut TaprioSchedule {
+        match taprio_options(state) {
+            QdiscOptions::Taprio {
+                oper: Some(oper), ..
+            } => oper,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn no_drift() {
+        for sections in [&[QOS, TAS][..], &[QOS, CBS], &[QOS, TAS, CBS]] {
+            let config = nic(sections);
+            assert_eq!(installed(&config).drift(&config), Vec::<String>::new());
+        }
+        // VLANs not created yet and no qdiscs for a config without any
+        let config = nic(&[QOS]);
+        let state = LinkState {
+            qdiscs: Vec::new(),
+            classes: Vec::new(),
+            vlans: Vec::new(),
+        };
+        assert_eq!(state.drift(&config), Vec::<String>::new());
+    }
+
+    #[test]
+    fn taprio_drift() {
+        let config = nic(&[QOS, TAS]);
+        let cases: &[Case] = &[
+            ("no root qdisc", |state| {
+                state.qdiscs.remove(0);
+            }),
+            ("root qdisc is mqprio 100:", |state| {
+                state.qdiscs[0].kind = "mqprio".to_string();
+            }),
+            ("root qdisc is taprio 200:", |state| {
+                state.qdiscs[0].handle = 0x200_0000;
+            }),
+            ("taprio has num_tc: 2", |state| {
+                if let QdiscOptions::Taprio { classes, .. } = taprio_options(state) {
+                    classes.num_tc = 2;
+                }
+            }),
+            ("taprio flags are 0x1", |state| {
+                if let QdiscOptions::Taprio { flags, .. } = taprio_options(state) {
+                    *flags = 0x1;
+                }
+            }),
+            ("taprio txtime_delay is 1", |state| {
+                if let QdiscOptions::Taprio { txtime_delay, .. } = taprio_options(state) {
+                    *txtime_delay = 1;
+                }
+            }),
+            ("taprio has no schedule", |state| {
+                if let QdiscOptions::Taprio { oper, .. } = taprio_options(state) {
+                    *oper = None;
+                }
+            }),
+            ("taprio schedule is [", |state| {
+                oper(state).entries.pop();
+            }),
+            ("taprio cycle_time is 1", |state| {
+                oper(state).cycle_time = 1;
+            }),
+            ("taprio cycle_time_extension is 1", |state| {
+                oper(state).cycle_time_extension = 1;
+            }),
+            ("qdisc at 100:", |state| {
+                state.qdiscs[1].options = QdiscOptions::Other;
+            }),
+            ("no etf at 100:", |state| {
+                state.qdiscs.truncate(1);
+            }),
+            ("etf at 100:8 is not in the config", |state| {
+                let mut etf = state.qdiscs[1].clone();
+                etf.parent = netlink::tc_handle(0x100, 8);
+                state.qdiscs.push(etf);
+            }),
+        ];
+        for (drift, change) in cases {
+            let mut state = installed(&config);
+            change(&mut state);
+            let lines = state.drift(&config);
+            assert_eq!(lines.len(), 1, "{}: {:?}", drift, lines);
+            assert!(lines[0].starts_with(drift), "{}: {:?}", drift, lines);
+        }
+
+        // The schedule waiting for its base time is the one compared
+        let mut state = installed(&config);
+        if let QdiscOptions::Taprio { oper, admin, .. } = taprio_options(&mut state) {
+            *admin = oper.clone();
+            oper.as_mut().unwrap().entries.clear();
+        }
+        assert_eq!(state.drift(&config), Vec::<String>::new());
+
+        // Only an absolute base time can be compared
+        let mut config = config;
+        let state = installed(&config);
+        config.tas.as_mut().unwrap().base_time = BaseTime::Absolute(2000);
+        assert_eq!(
+            state.drift(&config),
+            ["taprio base_time is 0, the config 2000"]
+        );
+    }
+
+    #[test]
+    fn cbs_drift() {
+        let config = nic(&[QOS, CBS]);
+        let classes = config.cbs.as_ref().unwrap().children.keys().sorted();
+        let (low, high) = classes
+            .map(|class| netlink::tc_handle(0x100, *class as u32))
+            .collect_tuple()
+            .unwrap();
+        let mut state = installed(&config);
+        let qdisc = state.qdiscs.iter_mut().find(|q| q.parent == high).unwrap();
+        if let QdiscOptions::Cbs { credit, .. } = &mut qdisc.options {
+            credit.idleslope = 1;
+        }
+        let drift = state.drift(&config);
+        assert_eq!(drift.len(), 1, "{:?}", drift);
+        let prefix = format!("qdisc at {} is cbs {{hicredit", handle_name(high));
+        assert!(drift[0].starts_with(&prefix), "{:?}", drift);
+
+        let mut state = installed(&config);
+        state.qdiscs.retain(|qdisc| qdisc.parent != low);
+        assert_eq!(
+            state.drift(&config),
+            [format!("no cbs at {}", handle_name(low))]
+        );
+
+        // Software taprio leaves cbs to software too
+        let config = nic(&[QOS, TAS, "      mode: software\n", CBS]);
+        let drift = installed(&config).drift(&config);
+        assert_eq!(drift.len(), 2, "{:?}", drift);
+        assert!(drift.iter().all(|line| line.ends_with("offload: false}")));
+
+        let mut state = installed(&nic(&[QOS, CBS]));
+        state.qdiscs[0].options = QdiscOptions::Mqprio(TrafficClasses {
+            num_tc: 1,
+            tc_map: [0; 16],
+            queues: vec![(1, 0)],
+        });
+        let drift = state.drift(&nic(&[QOS, CBS]));
+        assert_eq!(drift.len(), 1, "{:?}", drift);
+        assert!(drift[0].starts_with("mqprio has num_tc: 1"));
+    }
+
+    #[test]
+    fn vlan_drift() {
+        let config = nic(&[QOS]);
+        let vlan = |vlanid, egress_qos_map| VlanState {
+            name: format!("tsn0.{}", vlanid),
+            vlanid,
+            egress_qos_map,
+        };
+        let state = LinkState {
+            qdiscs: Vec::new(),
+            classes: Vec::new(),
+            vlans: vec![
+                // The kernel leaves out prio 0 -> pri 0
+                vlan(10, BTreeMap::from([(2, 2), (3, 3)])),
+                vlan(20, BTreeMap::new()),
+            ],
+        };
+        assert_eq!(
+            state.drift(&config),
+            ["vlan 20 (tsn0.20) is not in the config"]
+        );
+
+        let state = LinkState {
+            vlans: vec![vlan(10, BTreeMap::from([(2, 2)]))],
+            ..state
+        };
+        assert_eq!(
+            state.drift(&config),
+            ["egress-qos-map of vlan 10 is {2: 2}, the config {2: 2, 3: 3}"]
+        );
+    }
+}
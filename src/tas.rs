@@ -1,109 +1,317 @@
-use serde_yaml::{self, Value};
+use crate::config::{child, normalise_etf, EtfConfig, Issues};
+use crate::schema::{BaseTimeSpec, ScheduleEntry, TasSection};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct TasConfig {
     pub txtime_delay: i64,
     pub schedule: Vec<TasSchedule>,
     pub tc_map: HashMap<i64, i64>,
     pub num_tc: i64,
-    pub queues: Vec<String>,
-    pub base_time: i64,
-    pub sched_entries: Vec<String>,
+    /// `(count, offset)` of the TX queues for each traffic class
+    pub queues: Vec<(u16, u16)>,
+    pub base_time: BaseTime,
+    /// Length of the cycle in ns, the sum of the entries if not set
+    pub cycle_time: Option<i64>,
+    pub cycle_time_extension: Option<i64>,
+    pub sched_entries: Vec<SchedEntry>,
+    pub mode: TasMode,
+    /// Largest frame sent through the schedule in bits, for guard bands
+    pub max_frame: i64,
+    /// ETF qdiscs by skb prio, installed under the taprio classes
+    pub etf: HashMap<i64, EtfConfig>,
 }
-#[derive(Debug, Clone)]
+
+/// When the first cycle of the schedule starts, all in TAI ns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BaseTime {
+    Absolute(i64),
+    /// The first cycle boundary at least this long after the qdisc is installed
+    NextCycle(i64),
+    /// Offset of the cycle from the TAI epoch, to shift this node's schedule
+    /// against its neighbours
+    Phase(i64),
+}
+
+impl BaseTime {
+    /// The value given to taprio, `now` being the current TAI time.
+    pub fn resolve(&self, now: i64, cycle_time: i64) -> i64 {
+        match *self {
+            BaseTime::Absolute(time) => time,
+            BaseTime::NextCycle(delay) => {
+                let start = now + delay;
+                (start + cycle_time - 1) / cycle_time * cycle_time
+            }
+            // taprio moves a base time in the past forward by whole cycles
+            BaseTime::Phase(offset) => offset.rem_euclid(cycle_time),
+        }
+    }
+}
+
+impl fmt::Display for BaseTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BaseTime::Absolute(time) => write!(f, "{}", time),
+            BaseTime::NextCycle(delay) => write!(f, "{{next_cycle: {}}}", delay),
+            BaseTime::Phase(offset) => write!(f, "{{phase: {}}}", offset),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum TasMode {
+    /// Gates are run by the kernel
+    Software,
+    /// The kernel sets the txtime of each frame for the NIC's LaunchTime
+    TxtimeAssist,
+    /// Gates are run by the NIC
+    FullOffload,
+}
+
+impl TasMode {
+    /// Value of the taprio `flags` attribute
+    pub fn flags(&self) -> u32 {
+        match self {
+            TasMode::Software => 0x0,
+            TasMode::TxtimeAssist => 0x1,
+            TasMode::FullOffload => 0x2,
+        }
+    }
+}
+
+impl fmt::Display for TasMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TasMode::Software => write!(f, "software"),
+            TasMode::TxtimeAssist => write!(f, "txtime-assist"),
+            TasMode::FullOffload => write!(f, "full-offload"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SchedEntry {
+    pub gate_mask: u32,
+    pub interval: u32,
+}
+
+impl fmt::Display for SchedEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "S {:x} {}", self.gate_mask, self.interval)
+    }
+}
+impl TasConfig {
+    /// `cycle_time`, or the length of the schedule when it is not set.
+    pub fn cycle(&self) -> i64 {
+        self.cycle_time
+            .unwrap_or_else(|| self.schedule.iter().map(|sch| sch.time).sum())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TasSchedule {
     pub time: i64,
     pub prio: Vec<i64>,
 }
-pub fn to_ns(input: &Value) -> Result<i64, String> {
-    if let Some(value) = input.as_str() {
-        let matched = regex::Regex::new(r"^(?P<v>[\d_]+)\s*(?P<unit>|ns|us|µs|ms)$")
-            .unwrap()
-            .captures(value)
-            .unwrap();
-        let v = matched.name("v").unwrap().as_str().parse::<i64>().unwrap();
-        let unit = matched.name("unit").unwrap().as_str();
-        return {
-            match unit {
-                "" => Ok(v),
-                "ns" => Ok(v),
-                "us" => Ok(v * 1000),
-                "µs" => Ok(v * 1000),
-                "ms" => Ok(v * 1000 * 1000),
-                _ => unreachable!(),
-            }
-        };
+/// Parse the digits of a number, `_` may separate thousands.
+pub(crate) fn parse_digits(digits: &str) -> Result<i64, String> {
+    digits
+        .replace('_', "")
+        .parse::<i64>()
+        .map_err(|_| format!("{} is too large", digits))
+}
+
+pub fn to_ns(value: &str) -> Result<i64, String> {
+    let matched = regex::Regex::new(r"^(?P<v>[\d_]+)\s*(?P<unit>|ns|us|µs|ms|s)$")
+        .unwrap()
+        .captures(value)
+        .ok_or_else(|| format!("'{}' is not a time, e.g. 300us", value))?;
+    let v = parse_digits(&matched["v"])?;
+    let multiplier: i64 = match &matched["unit"] {
+        "" | "ns" => 1,
+        "us" | "µs" => 1000,
+        "ms" => 1000 * 1000,
+        "s" => 1000 * 1000 * 1000,
+        _ => unreachable!(),
+    };
+    v.checked_mul(multiplier)
+        .ok_or_else(|| format!("'{}' is too large", value))
+}
+
+fn normalise_schedule(
+    issues: &mut Issues,
+    path: &str,
+    schedule: &ScheduleEntry,
+    tc_map: &mut HashMap<i64, i64>,
+) -> TasSchedule {
+    let mut v = Vec::new();
+    for (i, &prio) in schedule.prio.iter().enumerate() {
+        if prio != -1 && !(0..16).contains(&prio) {
+            issues.push(
+                &format!("{}.prio[{}]", path, i),
+                format!("{} should be a prio from 0 to 15, or -1", prio),
+            );
+            continue;
+        }
+        v.push(prio);
+        if prio > 0 && !tc_map.contains_key(&prio) {
+            tc_map.insert(prio, tc_map.len() as i64);
+        }
+    }
+    TasSchedule {
+        time: schedule.time.0,
+        prio: v,
+    }
+}
+
+impl From<BaseTimeSpec> for BaseTime {
+    fn from(spec: BaseTimeSpec) -> BaseTime {
+        match spec {
+            BaseTimeSpec::Absolute(time) => BaseTime::Absolute(time.0),
+            BaseTimeSpec::NextCycle { next_cycle } => BaseTime::NextCycle(next_cycle.0),
+            BaseTimeSpec::Phase { phase } => BaseTime::Phase(phase.0),
+        }
     }
-    Ok(input.as_i64().expect("Cannot convert input to i64"))
 }
-pub fn normalise_tas(config: &Value) -> Result<TasConfig, String> {
+
+pub fn normalise_tas(issues: &mut Issues, path: &str, config: &TasSection) -> Option<TasConfig> {
+    let before = issues.len();
     let mut tas_schedule: Vec<TasSchedule> = Vec::new();
     let mut tc_map: HashMap<i64, i64> = HashMap::new();
     let mut ret_map = HashMap::new();
-    let schedules = config
-        .get(&Value::String("schedule".to_string()))
-        .expect("tas should have a schedule")
-        .as_sequence()
-        .expect("schedule should be a list");
-    for schedule in schedules {
-        let mut v = Vec::new();
-
-        for prio in schedule
-            .get(&Value::String("prio".to_string()))
-            .expect("schedule should have a prio")
-            .as_sequence()
-            .expect("prio should be a list")
-        {
-            let prio = prio.as_i64().expect("prio should be an integer");
-            v.push(prio.clone());
-            if prio > 0 && !tc_map.contains_key(&prio) {
-                tc_map.insert(prio.clone(), tc_map.len() as i64);
-            }
-        }
-        let time = to_ns(
-            schedule
-                .get(&Value::String("time".to_string()))
-                .expect("schedule must have 'time'"),
-        )?;
-        tas_schedule.push(TasSchedule { time, prio: v });
+    for (i, schedule) in config.schedule.iter().enumerate() {
+        let path = format!("{}.schedule[{}]", path, i);
+        tas_schedule.push(normalise_schedule(issues, &path, schedule, &mut tc_map));
     }
 
     tc_map.insert(-1, tc_map.len() as i64);
     let num_tc = tc_map.len() as i64;
 
     for i in 0..16 {
-        if tc_map.contains_key(&i) {
-            ret_map.insert(i, *tc_map.get(&i).unwrap());
-        } else {
-            ret_map.insert(i, *tc_map.get(&-1).unwrap());
-        }
+        ret_map.insert(i, *tc_map.get(&i).unwrap_or(&tc_map[&-1]));
     }
 
     let mut queues = Vec::new();
     (0..num_tc).for_each(|i| {
-        queues.push(format!("1@{}", i));
+        queues.push((1, i as u16));
     });
     let mut sched_entries = Vec::new();
 
     for sch in &tas_schedule {
         let mut sum = 0;
         for pri in &sch.prio {
-            sum += 1 << tc_map[pri];
+            sum |= 1 << tc_map[pri];
         }
-        sched_entries.push(format!("S {} {}", sum, sch.time));
+        sched_entries.push(SchedEntry {
+            gate_mask: sum,
+            interval: sch.time as u32,
+        });
     }
-    let txtime_delay = match config.get(&Value::String("txtime_delay".to_string())) {
-        Some(val) => to_ns(val).unwrap_or(0),
-        None => 0,
+    let etf = match &config.etf {
+        Some(etf) => {
+            let etf_path = child(path, "etf");
+            let etf = normalise_etf(issues, &etf_path, etf);
+            for prio in etf.keys().filter(|prio| !tc_map.contains_key(prio)) {
+                issues.push(
+                    &child(&etf_path, &prio.to_string()),
+                    format!("prio {} has no traffic class in the tas schedule", prio),
+                );
+            }
+            etf
+        }
+        None => HashMap::new(),
     };
-    Ok(TasConfig {
-        txtime_delay,
+    if issues.len() > before {
+        return None;
+    }
+    Some(TasConfig {
+        txtime_delay: config.txtime_delay.map_or(0, |delay| delay.0),
         schedule: tas_schedule,
         tc_map: ret_map,
         num_tc,
         queues,
-        base_time: 0,
+        base_time: config
+            .base_time
+            .map_or(BaseTime::Absolute(0), BaseTime::from),
+        cycle_time: config.cycle_time.map(|time| time.0),
+        cycle_time_extension: config.cycle_time_extension.map(|time| time.0),
         sched_entries,
+        mode: config.mode.unwrap_or(TasMode::FullOffload),
+        max_frame: config.max_frame.map_or(1522 * 8, |bits| bits.0),
+        etf,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    use crate::schema::Format;
+
+    fn tas(schedule: &str) -> TasConfig {
+        let yaml = format!("nics:\n  tsn0:\n    tas:\n      schedule: {}\n", schedule);
+        parse_config(&yaml, Format::Yaml).unwrap()["tsn0"]
+            .tas
+            .clone()
+            .unwrap()
+    }
+
+    #[test]
+    fn traffic_classes_in_order_of_appearance() {
+        let tas = tas("[{time: 100us, prio: [5, 3]}, {time: 100us, prio: [3, 7]}]");
+        assert_eq!(tas.num_tc, 4);
+        assert_eq!((tas.tc_map[&5], tas.tc_map[&3], tas.tc_map[&7]), (0, 1, 2));
+        // The prios not in the schedule share the best effort class
+        assert!((0..16)
+            .filter(|prio| ![3, 5, 7].contains(prio))
+            .all(|prio| tas.tc_map[&prio] == 3));
+        assert_eq!(tas.queues, [(1, 0), (1, 1), (1, 2), (1, 3)]);
+    }
+
+    #[test]
+    fn gate_masks() {
+        let tas =
+            tas("[{time: 300us, prio: [3, 2]}, {time: 200us, prio: [-1]}, {time: 1ms, prio: []}]");
+        let entries: Vec<String> = tas.sched_entries.iter().map(|e| e.to_string()).collect();
+        assert_eq!(entries, ["S 3 300000", "S 4 200000", "S 0 1000000"]);
+        assert_eq!(tas.cycle(), 1_500_000);
+    }
+
+    #[test]
+    fn bad_prio() {
+        let yaml = "nics:\n  tsn0:\n    tas:\n      schedule: [{time: 1us, prio: [16]}]\n";
+        let issues = parse_config(yaml, Format::Yaml).err().unwrap();
+        assert_eq!(issues[0].path, "nics.tsn0.tas.schedule[0].prio[0]");
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(to_ns("300"), Ok(300));
+        assert_eq!(to_ns("300us"), Ok(300_000));
+        assert_eq!(to_ns("1_500µs"), Ok(1_500_000));
+        assert_eq!(to_ns("2 ms"), Ok(2_000_000));
+        assert_eq!(to_ns("1s"), Ok(1_000_000_000));
+        assert!(to_ns("1h").is_err());
+        assert!(to_ns("99999999999s").is_err());
+    }
+
+    #[test]
+    fn base_times() {
+        let cycle = 1_000_000;
+        assert_eq!(BaseTime::Absolute(42).resolve(5_500_000, cycle), 42);
+        assert_eq!(
+            BaseTime::NextCycle(1_000_000).resolve(5_500_000, cycle),
+            7_000_000
+        );
+        assert_eq!(
+            BaseTime::NextCycle(500_000).resolve(5_500_000, cycle),
+            6_000_000
+        );
+        assert_eq!(BaseTime::Phase(250_000).resolve(5_500_000, cycle), 250_000);
+        assert_eq!(BaseTime::Phase(-250_000).resolve(5_500_000, cycle), 750_000);
+    }
+}
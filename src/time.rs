@@ -3,14 +3,41 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 static mut ERROR_CLOCK_GETTIME: Duration = Duration::new(1, 0);
 static mut ERROR_NANOSLEEP: Duration = Duration::new(1, 0);
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Timespec {
     pub tv_sec: i64,
     pub tv_nsec: i64,
 }
 
+impl Timespec {
+    /// Current time of `clockid`, e.g. `libc::CLOCK_TAI`.
+    pub fn now(clockid: libc::clockid_t) -> Timespec {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(clockid, &mut ts) };
+        Timespec {
+            tv_sec: ts.tv_sec,
+            tv_nsec: ts.tv_nsec,
+        }
+    }
+
+    pub fn from_nanos(nanos: i64) -> Timespec {
+        Timespec {
+            tv_sec: nanos.div_euclid(1_000_000_000),
+            tv_nsec: nanos.rem_euclid(1_000_000_000),
+        }
+    }
+
+    pub fn as_nanos(&self) -> i64 {
+        self.tv_sec * 1_000_000_000 + self.tv_nsec
+    }
+}
+
 fn is_analysed() -> bool {
-    unsafe { ERROR_CLOCK_GETTIME.as_secs() != 1 && ERROR_NANOSLEEP.as_secs() != 1 }
+    let (clock_gettime, nanosleep) = unsafe { (ERROR_CLOCK_GETTIME, ERROR_NANOSLEEP) };
+    clock_gettime.as_secs() != 1 && nanosleep.as_secs() != 1
 }
 
 pub fn tsn_time_analyze() {
@@ -0,0 +1,223 @@
+//! Time-triggered transmission with `SO_TXTIME`.
+//!
+//! Once [`enable_txtime`] is set on a socket, every frame sent with
+//! [`send_at`] carries its launch time to the qdisc (ETF) or the NIC
+//! (LaunchTime offload). Frames the kernel could not send in time are
+//! reported back on the socket's error queue, see [`recv_txtime_event`].
+
+use crate::{error::TsnError, time::Timespec, TsnSocket};
+use std::io::{Error, ErrorKind};
+use std::mem;
+
+// include/uapi/linux/errqueue.h
+const SO_EE_ORIGIN_TXTIME: u8 = 6;
+const SO_EE_CODE_TXTIME_INVALID_PARAM: u8 = 1;
+const SO_EE_CODE_TXTIME_MISSED: u8 = 2;
+// include/uapi/linux/if_packet.h
+const PACKET_TX_TIMESTAMP: libc::c_int = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxtimeEventKind {
+    /// The txtime or the clock did not match the qdisc configuration
+    InvalidParam,
+    /// The frame was dropped because its txtime had already passed
+    Missed,
+    /// Any other code the kernel may add later
+    Other(u8),
+}
+
+/// A frame dropped by ETF, read from the error queue.
+#[derive(Debug, Clone, Copy)]
+pub struct TxtimeEvent {
+    pub kind: TxtimeEventKind,
+    /// The txtime the frame was sent with
+    pub txtime: Timespec,
+    pub errno: i32,
+}
+
+pub fn enable_txtime(
+    sock: &TsnSocket,
+    clockid: libc::clockid_t,
+    deadline_mode: bool,
+    report_errors: bool,
+) -> Result<(), TsnError> {
+    sock.packet_socket("setsockopt SO_TXTIME")?;
+    let mut flags = 0;
+    if deadline_mode {
+        flags |= libc::SOF_TXTIME_DEADLINE_MODE;
+    }
+    if report_errors {
+        flags |= libc::SOF_TXTIME_REPORT_ERRORS;
+    }
+    let txtime = libc::sock_txtime { clockid, flags };
+
+    let res = unsafe {
+        libc::setsockopt(
+            sock.fd,
+            libc::SOL_SOCKET,
+            libc::SO_TXTIME,
+            &txtime as *const _ as *const libc::c_void,
+            mem::size_of_val(&txtime) as u32,
+        )
+    };
+    if res < 0 {
+        return Err(TsnError::socket("setsockopt SO_TXTIME"));
+    }
+    Ok(())
+}
+
+/// Send `buf` to leave the NIC at `txtime`, on the clock given to [`enable_txtime`].
+pub fn send_at(sock: &TsnSocket, buf: &[u8], txtime: Timespec) -> Result<isize, TsnError> {
+    sock.packet_socket("sendmsg SCM_TXTIME")?;
+    let txtime = txtime.as_nanos() as u64;
+    // u64 keeps the buffer aligned for cmsghdr
+    let mut control = [0u64; 4];
+    let iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+
+    let msg = unsafe {
+        let mut msg: libc::msghdr = mem::MaybeUninit::zeroed().assume_init();
+        msg.msg_iov = &iov as *const _ as *mut libc::iovec;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = {
+            // aarch64 has msg_controllen as u32, not usize
+            #[allow(clippy::useless_conversion)]
+            libc::CMSG_SPACE(mem::size_of::<u64>() as u32)
+                .try_into()
+                .unwrap()
+        };
+
+        let cm = libc::CMSG_FIRSTHDR(&msg);
+        (*cm).cmsg_level = libc::SOL_SOCKET;
+        (*cm).cmsg_type = libc::SCM_TXTIME;
+        (*cm).cmsg_len = {
+            #[allow(clippy::useless_conversion)]
+            libc::CMSG_LEN(mem::size_of::<u64>() as u32)
+                .try_into()
+                .unwrap()
+        };
+        std::ptr::write_unaligned(libc::CMSG_DATA(cm) as *mut u64, txtime);
+        msg
+    };
+
+    let res = unsafe { libc::sendmsg(sock.fd, &msg, 0) };
+    if res < 0 {
+        Err(TsnError::socket("sendmsg SCM_TXTIME"))
+    } else {
+        Ok(res)
+    }
+}
+
+/// Read one message from the error queue without blocking.
+///
+/// Returns `Ok(None)` when the queue is empty. Messages that are not txtime
+/// reports, such as TX timestamps, are consumed and skipped.
+pub fn recv_txtime_event(sock: &TsnSocket) -> Result<Option<TxtimeEvent>, TsnError> {
+    sock.packet_socket("recvmsg MSG_ERRQUEUE")?;
+    let mut buf = [0u8; 256];
+    let mut control = [0u64; 64];
+    let iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+
+    loop {
+        let mut msg = unsafe {
+            let mut msg: libc::msghdr = mem::MaybeUninit::zeroed().assume_init();
+            msg.msg_iov = &iov as *const _ as *mut libc::iovec;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = {
+                #[allow(clippy::useless_conversion)]
+                mem::size_of_val(&control).try_into().unwrap()
+            };
+            msg
+        };
+
+        let res =
+            unsafe { libc::recvmsg(sock.fd, &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+        if res < 0 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            return Err(TsnError::Socket {
+                op: "recvmsg MSG_ERRQUEUE",
+                source: err,
+            });
+        }
+
+        let mut cm = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cm.is_null() {
+            let (level, cmsg_type) = unsafe { ((*cm).cmsg_level, (*cm).cmsg_type) };
+            if level == libc::SOL_PACKET && cmsg_type == PACKET_TX_TIMESTAMP {
+                let err = unsafe {
+                    std::ptr::read_unaligned(libc::CMSG_DATA(cm) as *const libc::sock_extended_err)
+                };
+                if err.ee_origin == SO_EE_ORIGIN_TXTIME {
+                    return Ok(Some(parse_txtime_event(&err)));
+                }
+            }
+            cm = unsafe { libc::CMSG_NXTHDR(&msg, cm) };
+        }
+    }
+}
+
+fn parse_txtime_event(err: &libc::sock_extended_err) -> TxtimeEvent {
+    let kind = match err.ee_code {
+        SO_EE_CODE_TXTIME_INVALID_PARAM => TxtimeEventKind::InvalidParam,
+        SO_EE_CODE_TXTIME_MISSED => TxtimeEventKind::Missed,
+        code => TxtimeEventKind::Other(code),
+    };
+    // The kernel splits the 64 bit txtime over ee_data (high) and ee_info (low)
+    let txtime = ((err.ee_data as u64) << 32) | err.ee_info as u64;
+    TxtimeEvent {
+        kind,
+        txtime: Timespec::from_nanos(txtime as i64),
+        errno: err.ee_errno as i32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extended_err(code: u8, data: u32, info: u32) -> libc::sock_extended_err {
+        let mut err: libc::sock_extended_err = unsafe { mem::zeroed() };
+        err.ee_errno = libc::ECANCELED as u32;
+        err.ee_origin = SO_EE_ORIGIN_TXTIME;
+        err.ee_code = code;
+        err.ee_data = data;
+        err.ee_info = info;
+        err
+    }
+
+    #[test]
+    fn txtime_is_split_over_data_and_info() {
+        let txtime: i64 = 1_700_000_000_123_456_789;
+        let err = extended_err(
+            SO_EE_CODE_TXTIME_MISSED,
+            (txtime >> 32) as u32,
+            txtime as u32,
+        );
+        let event = parse_txtime_event(&err);
+        assert_eq!(event.txtime.as_nanos(), txtime);
+        assert_eq!(event.txtime.tv_sec, 1_700_000_000);
+        assert_eq!(event.txtime.tv_nsec, 123_456_789);
+        assert_eq!(event.errno, libc::ECANCELED);
+    }
+
+    #[test]
+    fn codes() {
+        let kind = |code| parse_txtime_event(&extended_err(code, 0, 0)).kind;
+        assert_eq!(
+            kind(SO_EE_CODE_TXTIME_INVALID_PARAM),
+            TxtimeEventKind::InvalidParam
+        );
+        assert_eq!(kind(SO_EE_CODE_TXTIME_MISSED), TxtimeEventKind::Missed);
+        assert_eq!(kind(7), TxtimeEventKind::Other(7));
+    }
+}
@@ -0,0 +1,485 @@
+//! Checks on a TAS schedule that the kernel would otherwise only answer with
+//! `EINVAL`, or worse accept and then never send some traffic.
+
+use crate::config::Config;
+use crate::error::TsnError;
+use crate::ethtool;
+use crate::tas::{BaseTime, TasConfig};
+use itertools::Itertools;
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+
+// Preamble, start of frame delimiter and inter frame gap
+const FRAME_OVERHEAD_BITS: i64 = (7 + 1 + 12) * 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Index of the offending entry in `tas.schedule`, if it is about one
+    pub entry: Option<usize>,
+    pub problem: String,
+    pub suggestion: String,
+}
+
+impl Diagnostic {
+    fn error(entry: Option<usize>, problem: String, suggestion: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            entry,
+            problem,
+            suggestion,
+        }
+    }
+
+    fn warning(entry: Option<usize>, problem: String, suggestion: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            entry,
+            problem,
+            suggestion,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match self.entry {
+            Some(entry) => write!(f, "{}: schedule[{}]: {}", severity, entry, self.problem)?,
+            None => write!(f, "{}: {}", severity, self.problem)?,
+        }
+        write!(f, " ({})", self.suggestion)
+    }
+}
+
+/// What the schedule is checked against, `None` when it could not be found.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Link speed in bits per second
+    pub link_speed: Option<i64>,
+    pub tx_queues: Option<usize>,
+}
+
+impl Limits {
+    pub fn of(ifname: &str) -> Limits {
+        let link_speed = ethtool::link_speed(ifname).ok();
+        let tx_queues = fs::read_dir(format!("/sys/class/net/{}/queues", ifname))
+            .ok()
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| entry.file_name().to_string_lossy().starts_with("tx-"))
+                    .count()
+            });
+        Limits {
+            link_speed,
+            tx_queues,
+        }
+    }
+}
+
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity == Severity::Error)
+}
+
+/// Check the TAS schedule of `config` against `ifname` before installing it:
+/// its errors fail with [`TsnError::Schedule`], its warnings are returned.
+pub fn check(config: &Config, ifname: &str) -> Result<Vec<Diagnostic>, TsnError> {
+    let diagnostics = validate_tas(config, &Limits::of(ifname));
+    if has_errors(&diagnostics) {
+        return Err(TsnError::Schedule {
+            ifname: ifname.to_string(),
+            diagnostics,
+        });
+    }
+    Ok(diagnostics)
+}
+
+/// The errors of `diagnostics` on one line.
+pub fn summary(diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .join("; ")
+}
+
+/// Check the TAS schedule of `config`, if it has one.
+pub fn validate_tas(config: &Config, limits: &Limits) -> Vec<Diagnostic> {
+    let tas = match &config.tas {
+        Some(tas) => tas,
+        None => return Vec::new(),
+    };
+    // The link speed of the config goes before the one the NIC reports
+    let limits = &Limits {
+        link_speed: config.link_speed.or(limits.link_speed),
+        ..*limits
+    };
+    let mut diagnostics = Vec::new();
+    check_cycle(tas, &mut diagnostics);
+    check_guard_bands(tas, limits, &mut diagnostics);
+    check_windows(config, tas, &mut diagnostics);
+    check_queues(tas, limits, &mut diagnostics);
+    diagnostics
+}
+
+fn check_cycle(tas: &TasConfig, diagnostics: &mut Vec<Diagnostic>) {
+    if tas.schedule.is_empty() {
+        diagnostics.push(Diagnostic::error(
+            None,
+            "the schedule has no entries".to_string(),
+            "add at least one entry with a time and prios".to_string(),
+        ));
+        return;
+    }
+    let length: i64 = tas.schedule.iter().map(|sch| sch.time).sum();
+    match tas.cycle_time {
+        Some(cycle_time) if cycle_time <= 0 => diagnostics.push(Diagnostic::error(
+            None,
+            format!("cycle_time is {} ns", cycle_time),
+            "give a positive cycle_time or leave it out".to_string(),
+        )),
+        Some(cycle_time) if cycle_time < length => diagnostics.push(Diagnostic::warning(
+            None,
+            format!(
+                "cycle_time {} ns is shorter than the {} ns of the entries, the last ones are cut",
+                cycle_time, length
+            ),
+            format!("set cycle_time to {} or shorten the entries", length),
+        )),
+        _ => {}
+    }
+    if let Some(extension) = tas.cycle_time_extension.filter(|ext| *ext < 0) {
+        diagnostics.push(Diagnostic::error(
+            None,
+            format!("cycle_time_extension is {} ns", extension),
+            "give a positive cycle_time_extension or leave it out".to_string(),
+        ));
+    }
+    if let BaseTime::Absolute(time) = tas.base_time {
+        if time < 0 {
+            diagnostics.push(Diagnostic::error(
+                None,
+                format!("base_time is {} ns", time),
+                "give a TAI time, next_cycle or phase".to_string(),
+            ));
+        }
+    }
+    for (i, sch) in tas.schedule.iter().enumerate() {
+        if sch.time <= 0 {
+            diagnostics.push(Diagnostic::error(
+                Some(i),
+                format!("time is {} ns, the cycle would never advance", sch.time),
+                "give the entry a positive time or remove it".to_string(),
+            ));
+        } else if sch.time > u32::MAX as i64 {
+            diagnostics.push(Diagnostic::error(
+                Some(i),
+                format!("time {} ns does not fit in 32 bits", sch.time),
+                format!("split it into entries of at most {} ns", u32::MAX),
+            ));
+        }
+        if sch.prio.is_empty() {
+            diagnostics.push(Diagnostic::warning(
+                Some(i),
+                "no prio is open, the link idles for the whole entry".to_string(),
+                "add -1 to let best effort traffic through".to_string(),
+            ));
+        }
+        for prio in sch.prio.iter().filter(|prio| !(-1..16).contains(*prio)) {
+            diagnostics.push(Diagnostic::warning(
+                Some(i),
+                format!("prio {} is outside 0..15 and never matches a frame", prio),
+                "use a socket priority between 0 and 15, or -1".to_string(),
+            ));
+        }
+    }
+}
+
+/// A traffic class has to stay open long enough for its largest frame,
+/// otherwise that frame waits forever for a window it does not fit in.
+fn check_guard_bands(tas: &TasConfig, limits: &Limits, diagnostics: &mut Vec<Diagnostic>) {
+    let link_speed = match limits.link_speed {
+        Some(speed) if speed > 0 => speed,
+        _ => {
+            diagnostics.push(Diagnostic::warning(
+                None,
+                "link speed is unknown, guard bands are not checked".to_string(),
+                "check that the link is up, or set link_speed".to_string(),
+            ));
+            return;
+        }
+    };
+    // In i128, a max_frame of a few GB would overflow i64 once in ns
+    let frame_ns =
+        (tas.max_frame as i128 + FRAME_OVERHEAD_BITS as i128) * 1_000_000_000 / link_speed as i128;
+    let frame_ns = i64::try_from(frame_ns).unwrap_or(i64::MAX);
+    let entries = &tas.sched_entries;
+    let n = entries.len();
+    for tc in 0..tas.num_tc {
+        let bit = 1 << tc;
+        for (i, entry) in entries.iter().enumerate() {
+            let prev = &entries[(i + n - 1) % n];
+            // Only look at windows from where they open
+            if entry.gate_mask & bit == 0 || (n > 1 && prev.gate_mask & bit != 0) {
+                continue;
+            }
+            let window: i64 = (0..n)
+                .map(|k| &entries[(i + k) % n])
+                .take_while(|e| e.gate_mask & bit != 0)
+                .map(|e| e.interval as i64)
+                .sum();
+            if window < frame_ns {
+                diagnostics.push(Diagnostic::error(
+                    Some(i),
+                    format!(
+                        "traffic class {} is open for {} ns, a {} byte frame takes {} ns at {} Mb/s",
+                        tc,
+                        window,
+                        tas.max_frame / 8,
+                        frame_ns,
+                        link_speed / 1_000_000
+                    ),
+                    format!(
+                        "make the window at least {} ns or lower tas max_frame",
+                        frame_ns
+                    ),
+                ));
+            }
+        }
+    }
+}
+
+/// Every prio the config uses must be let through by some entry.
+fn check_windows(config: &Config, tas: &TasConfig, diagnostics: &mut Vec<Diagnostic>) {
+    let mut prios: Vec<i64> = config
+        .egress_qos_map
+        .values()
+        .flat_map(|map| map.keys().copied())
+        .collect();
+    if let Some(cbs) = &config.cbs {
+        prios.extend(cbs.streams.values().flatten().map(|stream| stream.prio));
+    }
+    prios.extend(tas.etf.keys().copied());
+    for prio in prios
+        .into_iter()
+        .filter(|p| (0..16).contains(p))
+        .unique()
+        .sorted()
+    {
+        let tc = tas.tc_map[&prio];
+        if !tas
+            .sched_entries
+            .iter()
+            .any(|entry| entry.gate_mask & (1 << tc) != 0)
+        {
+            diagnostics.push(Diagnostic::error(
+                None,
+                format!("prio {} (traffic class {}) never gets a window", prio, tc),
+                format!("add {} or -1 to the prio list of an entry", prio),
+            ));
+        }
+    }
+}
+
+fn check_queues(tas: &TasConfig, limits: &Limits, diagnostics: &mut Vec<Diagnostic>) {
+    let max = 16;
+    if tas.num_tc > max {
+        diagnostics.push(Diagnostic::error(
+            None,
+            format!(
+                "{} traffic classes are needed, taprio supports {}",
+                tas.num_tc, max
+            ),
+            "use fewer distinct prios in the schedule".to_string(),
+        ));
+    }
+    if let Some(tx_queues) = limits.tx_queues {
+        if tas.num_tc as usize > tx_queues {
+            diagnostics.push(Diagnostic::error(
+                None,
+                format!(
+                    "{} traffic classes are needed, the NIC has {} TX queues",
+                    tas.num_tc, tx_queues
+                ),
+                "use fewer distinct prios in the schedule".to_string(),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    use crate::schema::Format;
+
+    const GIGABIT: Limits = Limits {
+        link_speed: Some(1_000_000_000),
+        tx_queues: Some(8),
+    };
+
+    fn nic(schedule: &str) -> Config {
+        let yaml = format!(
+            "nics:\n  tsn0:\n    egress-qos-map:\n      10: {{2: 2, 3: 3}}\n    tas:\n      schedule: {}\n",
+            schedule
+        );
+        parse_config(&yaml, Format::Yaml).unwrap()["tsn0"].clone()
+    }
+
+    fn errors(diagnostics: &[Diagnostic]) -> Vec<(Option<usize>, &str)> {
+        diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == Severity::Error)
+            .map(|diagnostic| (diagnostic.entry, diagnostic.problem.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn clean_schedule() {
+        let config = nic("[{time: 300us, prio: [2, 3]}, {time: 200us, prio: [-1]}]");
+        let diagnostics = validate_tas(&config, &GIGABIT);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+        let without_tas = Config {
+            tas: None,
+            ..config
+        };
+        assert!(validate_tas(&without_tas, &GIGABIT).is_empty());
+    }
+
+    #[test]
+    fn cycle() {
+        let mut config = nic("[{time: 300us, prio: [2, 3]}, {time: 200us, prio: [-1]}]");
+        let tas = config.tas.as_mut().unwrap();
+        tas.cycle_time = Some(0);
+        let diagnostics = validate_tas(&config, &GIGABIT);
+        assert_eq!(errors(&diagnostics), [(None, "cycle_time is 0 ns")]);
+
+        // Cut short by cycle_time
+        config.tas.as_mut().unwrap().cycle_time = Some(400_000);
+        let diagnostics = validate_tas(&config, &GIGABIT);
+        assert!(errors(&diagnostics).is_empty());
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(
+            diagnostics[0].suggestion,
+            "set cycle_time to 500000 or shorten the entries"
+        );
+
+        let mut config = nic("[{time: 300us, prio: [2, 3]}, {time: 200us, prio: [-1]}]");
+        config.tas.as_mut().unwrap().schedule[1].time = u32::MAX as i64 + 1;
+        let diagnostics = validate_tas(&config, &GIGABIT);
+        assert_eq!(
+            errors(&diagnostics),
+            [(Some(1), "time 4294967296 ns does not fit in 32 bits")]
+        );
+        config.tas.as_mut().unwrap().schedule[1].time = 0;
+        let diagnostics = validate_tas(&config, &GIGABIT);
+        assert_eq!(
+            errors(&diagnostics),
+            [(Some(1), "time is 0 ns, the cycle would never advance")]
+        );
+    }
+
+    #[test]
+    fn guard_band_shorter_than_a_frame() {
+        // A 1522 byte frame and its overhead take 12336 ns at 1 Gb/s
+        let config = nic("[{time: 10us, prio: [2, 3]}, {time: 990us, prio: [-1]}]");
+        let diagnostics = validate_tas(&config, &GIGABIT);
+        let errors = errors(&diagnostics);
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors.iter().all(|(entry, problem)| *entry == Some(0)
+            && problem
+                .ends_with("is open for 10000 ns, a 1522 byte frame takes 12336 ns at 1000 Mb/s")));
+
+        // Long enough at 10 Gb/s
+        let fast = Limits {
+            link_speed: Some(10_000_000_000),
+            ..GIGABIT
+        };
+        assert!(validate_tas(&config, &fast).is_empty());
+
+        // A window open over the end of the cycle counts as one
+        let config = nic(
+            "[{time: 6us, prio: [2, 3]}, {time: 990us, prio: [-1]}, {time: 7us, prio: [2, 3]}]",
+        );
+        assert!(validate_tas(&config, &GIGABIT).is_empty());
+
+        let unknown = Limits {
+            link_speed: None,
+            ..GIGABIT
+        };
+        let diagnostics = validate_tas(&config, &unknown);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn huge_frame_does_not_overflow() {
+        let mut config = nic("[{time: 10us, prio: [2, 3]}, {time: 990us, prio: [-1]}]");
+        config.tas.as_mut().unwrap().max_frame = i64::MAX - 8;
+        let diagnostics = validate_tas(&config, &GIGABIT);
+        let errors = errors(&diagnostics);
+        // No window fits it, the best effort one neither
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors
+            .iter()
+            .all(|(_, problem)| problem.contains(&format!("takes {} ns", i64::MAX))));
+    }
+
+    #[test]
+    fn prio_without_window() {
+        // 2 falls in the best effort class, which no entry opens
+        let config = nic("[{time: 300us, prio: [3]}, {time: 200us, prio: []}]");
+        let diagnostics = validate_tas(&config, &GIGABIT);
+        assert_eq!(
+            errors(&diagnostics),
+            [(None, "prio 2 (traffic class 1) never gets a window")]
+        );
+        assert!(has_errors(&diagnostics));
+        assert_eq!(
+            summary(&diagnostics),
+            "error: prio 2 (traffic class 1) never gets a window (add 2 or -1 to the prio list of an entry)"
+        );
+    }
+
+    #[test]
+    fn more_traffic_classes_than_queues() {
+        let config = nic("[{time: 300us, prio: [1, 2, 3]}, {time: 200us, prio: [-1]}]");
+        let limits = Limits {
+            tx_queues: Some(2),
+            ..GIGABIT
+        };
+        let diagnostics = validate_tas(&config, &limits);
+        assert_eq!(
+            errors(&diagnostics),
+            [(
+                None,
+                "4 traffic classes are needed, the NIC has 2 TX queues"
+            )]
+        );
+        let limits = Limits {
+            tx_queues: None,
+            ..GIGABIT
+        };
+        assert!(validate_tas(&config, &limits).is_empty());
+
+        let mut config = config;
+        config.tas.as_mut().unwrap().num_tc = 17;
+        let diagnostics = validate_tas(&config, &limits);
+        assert!(errors(&diagnostics)
+            .contains(&(None, "17 traffic classes are needed, taprio supports 16")));
+    }
+}
@@ -1,130 +1,688 @@
-use crate::{cbs::CbsConfig, config::Config, tas::TasConfig};
+use crate::{
+    backend::Backend,
+    cbs::{CbsConfig, CbsCredit},
+    config::{Config, EtfConfig},
+    error::TsnError,
+    netlink::{self, tc_handle, Message, Netlink},
+    reconfigure::Change,
+    tas::{TasConfig, TasMode},
+    time::Timespec,
+    validate::{self, Diagnostic},
+};
 use itertools::Itertools;
+use nix::net::if_::if_nametoindex;
 use std::{collections::HashMap, io::Error};
 
-fn run_cmd(input: &str) -> Result<i32, String> {
-    eprintln!("{}", input);
-    let split = input.split(char::is_whitespace);
-    let cmd = split.clone().next().unwrap();
-    let cmd = std::process::Command::new(cmd)
-        .args(split.skip(1))
-        .spawn()
-        .unwrap();
-    let output = cmd.wait_with_output().unwrap();
-    if output.status.success() {
-        Ok(0)
+fn link_error(err: TsnError) -> Error {
+    match err {
+        TsnError::Netlink { ref source, .. } => Error::new(source.kind(), err),
+        _ => Error::other(err),
+    }
+}
+
+pub(crate) fn get_ifindex(ifname: &str, request: &str) -> Result<i32, TsnError> {
+    if_nametoindex(ifname)
+        .map(|index| index as i32)
+        .map_err(|e| TsnError::Netlink {
+            request: request.to_string(),
+            extack: None,
+            source: e.into(),
+        })
+}
+
+pub(crate) fn prio_tc_map(tc_map: &HashMap<i64, i64>) -> [u8; 16] {
+    let mut map = [0u8; 16];
+    for (prio, tc) in tc_map {
+        if (0..16).contains(prio) {
+            map[*prio as usize] = *tc as u8;
+        }
+    }
+    map
+}
+
+fn request(backend: &mut dyn Backend, desc: &str, msg: &mut Message) -> Result<i32, TsnError> {
+    backend.request(desc, msg)?;
+    Ok(0)
+}
+
+fn setup_etf_child(
+    backend: &mut dyn Backend,
+    ifname: &str,
+    ifindex: i32,
+    parent: (u32, u32),
+    config: &EtfConfig,
+) -> Result<i32, TsnError> {
+    let mut flags = 0;
+    let mut options = String::new();
+    if config.deadline_mode {
+        flags |= netlink::TC_ETF_DEADLINE_MODE_ON;
+        options.push_str(" deadline_mode");
+    }
+    if config.offload {
+        flags |= netlink::TC_ETF_OFFLOAD_ON;
+        options.push_str(" offload");
+    }
+    if config.skip_sock_check {
+        flags |= netlink::TC_ETF_SKIP_SOCK_CHECK;
+        options.push_str(" skip_sock_check");
+    }
+    let desc = format!(
+        "tc qdisc replace dev {} parent {:x}:{:x} etf clockid {} delta {}{}",
+        ifname,
+        parent.0,
+        parent.1,
+        config.clock_name(),
+        config.delta,
+        options
+    );
+    let mut msg = Message::new(
+        libc::RTM_NEWQDISC,
+        (libc::NLM_F_CREATE | libc::NLM_F_REPLACE) as u16,
+    );
+    msg.tcmsg(ifindex, 0, tc_handle(parent.0, parent.1))
+        .attr_str(libc::TCA_KIND, "etf")
+        .nested(libc::TCA_OPTIONS, |opts| {
+            opts.attr(
+                netlink::TCA_ETF_PARMS,
+                &netlink::etf_qopt(config.delta as i32, config.clockid, flags),
+            );
+        });
+    request(backend, &desc, &mut msg)
+}
+
+/// Attach a cbs qdisc to class `qid` of `root_handle`, or `change` the one
+/// already there.
+fn setup_cbs_child(
+    backend: &mut dyn Backend,
+    ifname: &str,
+    ifindex: i32,
+    (root_handle, qid): (u32, u32),
+    val: &CbsCredit,
+    offload: bool,
+    change: bool,
+) -> Result<i32, TsnError> {
+    let handle = qid * 0x1111;
+
+    let idleslope = val.idleslope;
+    let sendslope = val.sendslope;
+    let hicredit = val.hicredit;
+    let locredit = val.locredit;
+    let (verb, flags) = if change {
+        ("change", 0)
     } else {
-        Err(Error::from_raw_os_error(output.status.code().unwrap()).to_string())
+        ("replace", libc::NLM_F_CREATE | libc::NLM_F_REPLACE)
+    };
+    let desc = format!(
+        "tc qdisc {} dev {} parent {:x}:{:x} handle {:x} \
+         cbs idleslope {} sendslope {} hicredit {} locredit {} offload {}",
+        verb,
+        ifname,
+        root_handle,
+        qid,
+        handle,
+        idleslope,
+        sendslope,
+        hicredit,
+        locredit,
+        offload as u8
+    );
+    let mut msg = Message::new(libc::RTM_NEWQDISC, flags as u16);
+    msg.tcmsg(ifindex, tc_handle(handle, 0), tc_handle(root_handle, qid))
+        .attr_str(libc::TCA_KIND, "cbs")
+        .nested(libc::TCA_OPTIONS, |opts| {
+            opts.attr(
+                netlink::TCA_CBS_PARMS,
+                &netlink::cbs_qopt(
+                    offload,
+                    hicredit as i32,
+                    locredit as i32,
+                    idleslope as i32,
+                    sendslope as i32,
+                ),
+            );
+        });
+    request(backend, &desc, &mut msg)
+}
+
+/// Attach a cbs qdisc to each class of `root_handle` that has credits.
+fn setup_cbs_children(
+    backend: &mut dyn Backend,
+    ifname: &str,
+    ifindex: i32,
+    root_handle: u32,
+    config: &CbsConfig,
+    offload: bool,
+) -> Result<i32, TsnError> {
+    for (qid, val) in config.children.iter().sorted_by_key(|(qid, _)| **qid) {
+        setup_cbs_child(
+            backend,
+            ifname,
+            ifindex,
+            (root_handle, *qid as u32),
+            val,
+            offload,
+            false,
+        )?;
     }
+    Ok(0)
+}
+
+/// Whether the cbs qdiscs are offloaded to the NIC, which they are unless
+/// they hang under a taprio run by the kernel.
+pub(crate) fn cbs_offload(tas: Option<&TasConfig>) -> bool {
+    tas.is_none_or(|tas| tas.mode != TasMode::Software)
 }
 
-pub fn setup_tas(ifname: &str, config: &TasConfig) -> Result<i32, String> {
-    let handle = 100;
+/// Parent of the ETF qdisc for class `class` of `root_handle`.
+fn etf_parent(root_handle: u32, class: u32, cbs: Option<&CbsConfig>) -> (u32, u32) {
+    match cbs {
+        // cbs is classful, ETF then goes under its only class
+        Some(cbs) if cbs.children.contains_key(&(class as i64)) => (class * 0x1111, 1),
+        _ => (root_handle, class),
+    }
+}
+
+/// The taprio request for `config`. With `schedule_only` it only carries
+/// the schedule, to be given to a running taprio as its new admin schedule.
+fn taprio_request(
+    backend: &mut dyn Backend,
+    ifname: &str,
+    config: &TasConfig,
+    schedule_only: bool,
+) -> Result<(String, i32, Message), TsnError> {
+    let handle = 0x100;
     let num_tc = config.num_tc;
     let mut priomap = String::new();
     let mut queues = String::new();
-    let base_time = config.base_time;
+    let cycle = config.cycle();
+    if cycle <= 0 {
+        return Err(TsnError::Config(format!(
+            "tas cycle of {} should be longer than 0",
+            ifname
+        )));
+    }
+    let base_time = config
+        .base_time
+        .resolve(Timespec::now(libc::CLOCK_TAI).as_nanos(), cycle);
     let txtime_delay = config.txtime_delay;
+    let flags = config.mode.flags();
     let mut sched_entries = String::new();
+    let mut options = String::new();
     for key in config.tc_map.keys().sorted() {
         priomap.push_str(&format!(" {}", config.tc_map.get(key).unwrap()));
     }
-    for queue in &config.queues {
-        queues.push_str(&format!(" {}", queue));
+    for (count, offset) in &config.queues {
+        queues.push_str(&format!(" {}@{}", count, offset));
     }
     for entry in &config.sched_entries {
         sched_entries.push_str(&format!(" sched-entry {}", entry));
     }
-    let cmd = format!(
-        "tc qdisc replace dev {} parent root handle {} taprio num_tc {} map{} \
-         queues{} base-time {}{} flags 0x2 txtime-delay {}",
-        ifname, handle, num_tc, priomap, queues, base_time, sched_entries, txtime_delay
-    );
-    run_cmd(&cmd)?;
-    // TSN NIC does not support ETF for now
-    /*
-    let cmd = format!(
-        "tc qdisc replace dev {} parent {}:1 etf clockid \
-         CLOCK_TAI delta {} offload skip_sock_check",
-        ifname, handle, txtime_delay
+    if let Some(cycle_time) = config.cycle_time {
+        options.push_str(&format!(" cycle-time {}", cycle_time));
+    }
+    if let Some(extension) = config.cycle_time_extension {
+        options.push_str(&format!(" cycle-time-extension {}", extension));
+    }
+    // Only the NIC's own clock is used when the schedule is offloaded
+    if config.mode != TasMode::FullOffload {
+        options.push_str(" clockid CLOCK_TAI");
+    }
+    options.push_str(&format!(" flags {:#x}", flags));
+    if txtime_delay != 0 {
+        options.push_str(&format!(" txtime-delay {}", txtime_delay));
+    }
+    let desc = if schedule_only {
+        format!(
+            "tc qdisc replace dev {} parent root handle {:x} taprio base-time {}{}{}",
+            ifname, handle, base_time, sched_entries, options
+        )
+    } else {
+        format!(
+            "tc qdisc replace dev {} parent root handle {:x} taprio num_tc {} map{} \
+             queues{} base-time {}{}{}",
+            ifname, handle, num_tc, priomap, queues, base_time, sched_entries, options
+        )
+    };
+    let ifindex = backend.ifindex(ifname, &desc)?;
+    let mut msg = Message::new(
+        libc::RTM_NEWQDISC,
+        (libc::NLM_F_CREATE | libc::NLM_F_REPLACE) as u16,
     );
-    run_cmd(&cmd)?;
-    */
+    msg.tcmsg(ifindex, tc_handle(handle, 0), netlink::TC_H_ROOT)
+        .attr_str(libc::TCA_KIND, "taprio")
+        .nested(libc::TCA_OPTIONS, |opts| {
+            // A running taprio refuses to change its traffic classes
+            if !schedule_only {
+                opts.attr(
+                    netlink::TCA_TAPRIO_ATTR_PRIOMAP,
+                    &netlink::mqprio_qopt(
+                        num_tc as u8,
+                        &prio_tc_map(&config.tc_map),
+                        &config.queues,
+                        0,
+                    ),
+                );
+            }
+            opts.nested(netlink::TCA_TAPRIO_ATTR_SCHED_ENTRY_LIST, |list| {
+                for entry in &config.sched_entries {
+                    list.nested(netlink::TCA_TAPRIO_SCHED_ENTRY, |e| {
+                        e.attr_u8(
+                            netlink::TCA_TAPRIO_SCHED_ENTRY_CMD,
+                            netlink::TC_TAPRIO_CMD_SET_GATES,
+                        )
+                        .attr_u32(netlink::TCA_TAPRIO_SCHED_ENTRY_GATE_MASK, entry.gate_mask)
+                        .attr_u32(netlink::TCA_TAPRIO_SCHED_ENTRY_INTERVAL, entry.interval);
+                    });
+                }
+            })
+            .attr_i64(netlink::TCA_TAPRIO_ATTR_SCHED_BASE_TIME, base_time);
+            if let Some(cycle_time) = config.cycle_time {
+                opts.attr_i64(netlink::TCA_TAPRIO_ATTR_SCHED_CYCLE_TIME, cycle_time);
+            }
+            if let Some(extension) = config.cycle_time_extension {
+                opts.attr_i64(
+                    netlink::TCA_TAPRIO_ATTR_SCHED_CYCLE_TIME_EXTENSION,
+                    extension,
+                );
+            }
+            if config.mode != TasMode::FullOffload {
+                opts.attr_u32(
+                    netlink::TCA_TAPRIO_ATTR_SCHED_CLOCKID,
+                    libc::CLOCK_TAI as u32,
+                );
+            }
+            opts.attr_u32(netlink::TCA_TAPRIO_ATTR_FLAGS, flags);
+            // The kernel refuses txtime-delay unless txtime-assist mode is used
+            if txtime_delay != 0 {
+                opts.attr_u32(netlink::TCA_TAPRIO_ATTR_TXTIME_DELAY, txtime_delay as u32);
+            }
+        });
+    Ok((desc, ifindex, msg))
+}
+
+/// Install taprio as root, with `cbs` on its classes if given.
+///
+/// `cbs` must share the traffic classes of `config`, see `CbsConfig::set_tc_map`.
+pub fn setup_tas(
+    backend: &mut dyn Backend,
+    ifname: &str,
+    config: &TasConfig,
+    cbs: Option<&CbsConfig>,
+) -> Result<i32, TsnError> {
+    let handle = 0x100;
+    let (desc, ifindex, mut msg) = taprio_request(backend, ifname, config, false)?;
+    request(backend, &desc, &mut msg)?;
+    if let Some(cbs) = cbs {
+        let offload = cbs_offload(Some(config));
+        setup_cbs_children(backend, ifname, ifindex, handle, cbs, offload)?;
+    }
+    for (prio, etf) in config.etf.iter().sorted_by_key(|(prio, _)| **prio) {
+        let class = config.tc_map[prio] as u32 + 1;
+        let parent = etf_parent(handle, class, cbs);
+        setup_etf_child(backend, ifname, ifindex, parent, etf)?;
+    }
+    Ok(0)
+}
+
+/// The traffic classes of the mqprio installed for NIC level ETF: each ETF
+/// prio gets its own traffic class, the rest share the last.
+pub(crate) fn etf_tc_map(config: &Config) -> HashMap<i64, i64> {
+    let prios: Vec<i64> = config.etf.keys().copied().sorted().collect();
+    let num_tc = prios.len() as i64 + 1;
+    (0..16)
+        .map(|prio| {
+            let tc = prios.iter().position(|p| *p == prio);
+            (prio, tc.map_or(num_tc - 1, |tc| tc as i64))
+        })
+        .collect()
+}
+
+/// Install the NIC level ETF qdiscs, under the cbs children if there are any
+/// or under a new mqprio otherwise.
+pub fn setup_etf(
+    backend: &mut dyn Backend,
+    ifname: &str,
+    config: &Config,
+) -> Result<i32, TsnError> {
+    let root_handle = 0x100;
+    let ifindex = backend.ifindex(ifname, &format!("tc qdisc replace dev {} etf", ifname))?;
+    let tc_map = match &config.cbs {
+        Some(cbs) => cbs.tc_map.clone(),
+        None => {
+            let tc_map = etf_tc_map(config);
+            let num_tc = config.etf.len() as i64 + 1;
+            let queues: Vec<(u16, u16)> = (0..num_tc).map(|i| (1, i as u16)).collect();
+            let mut priomap = String::new();
+            let mut queue_desc = String::new();
+            for key in tc_map.keys().sorted() {
+                priomap.push_str(&format!(" {}", tc_map[key]));
+            }
+            for (count, offset) in &queues {
+                queue_desc.push_str(&format!("{}@{} ", count, offset));
+            }
+            let desc = format!(
+                "tc qdisc add dev {} parent root handle {:x} mqprio \
+                 num_tc {} map{} queues {}hw 0",
+                ifname, root_handle, num_tc, priomap, queue_desc
+            );
+            let mut msg = Message::new(
+                libc::RTM_NEWQDISC,
+                (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16,
+            );
+            msg.tcmsg(ifindex, tc_handle(root_handle, 0), netlink::TC_H_ROOT)
+                .attr_str(libc::TCA_KIND, "mqprio")
+                .attr(
+                    libc::TCA_OPTIONS,
+                    &netlink::mqprio_qopt(num_tc as u8, &prio_tc_map(&tc_map), &queues, 0),
+                );
+            request(backend, &desc, &mut msg)?;
+            tc_map
+        }
+    };
+    for (prio, etf) in config.etf.iter().sorted_by_key(|(prio, _)| **prio) {
+        let class = tc_map[prio] as u32 + 1;
+        let parent = etf_parent(root_handle, class, config.cbs.as_ref());
+        setup_etf_child(backend, ifname, ifindex, parent, etf)?;
+    }
     Ok(0)
 }
 
-pub fn setup_cbs(ifname: &str, config: &CbsConfig) -> Result<i32, String> {
-    let root_handle = 100;
+pub fn setup_cbs(
+    backend: &mut dyn Backend,
+    ifname: &str,
+    config: &CbsConfig,
+) -> Result<i32, TsnError> {
+    let root_handle = 0x100;
     let num_tc = config.num_tc;
     let mut priomap = String::new();
     let mut queues = String::new();
     for key in config.tc_map.keys().sorted() {
         priomap.push_str(&format!(" {}", config.tc_map.get(key).unwrap()));
     }
-    for s in &config.queues {
-        queues.push_str(&format!("{} ", s));
+    for (count, offset) in &config.queues {
+        queues.push_str(&format!("{}@{} ", count, offset));
     }
-    let cmd = format!(
-        "tc qdisc add dev {} parent root handle {} mqprio \
+    let desc = format!(
+        "tc qdisc add dev {} parent root handle {:x} mqprio \
          num_tc {} map{} queues {}hw 0",
         ifname, root_handle, num_tc, priomap, queues
     );
-    run_cmd(&cmd)?;
-    for (qid, val) in &config.children {
-        let handle = qid * 1111;
-
-        let idleslope = val.idleslope;
-        let sendslope = val.sendslope;
-        let hicredit = val.hicredit;
-        let locredit = val.locredit;
-        let cmd = format!(
-            "tc qdisc replace dev {} parent {}:{} handle {} \
-             cbs idleslope {} sendslope {} hicredit {} locredit {} offload 1",
-            ifname, root_handle, qid, handle, idleslope, sendslope, hicredit, locredit
+    let ifindex = backend.ifindex(ifname, &desc)?;
+    let mut msg = Message::new(
+        libc::RTM_NEWQDISC,
+        (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16,
+    );
+    msg.tcmsg(ifindex, tc_handle(root_handle, 0), netlink::TC_H_ROOT)
+        .attr_str(libc::TCA_KIND, "mqprio")
+        .attr(
+            libc::TCA_OPTIONS,
+            &netlink::mqprio_qopt(
+                num_tc as u8,
+                &prio_tc_map(&config.tc_map),
+                &config.queues,
+                0,
+            ),
         );
-        run_cmd(&cmd)?;
-    }
+    request(backend, &desc, &mut msg)?;
+    setup_cbs_children(backend, ifname, ifindex, root_handle, config, true)?;
     Ok(0)
 }
 
-pub fn create_vlan(config: &Config, ifname: &str, vlan_id: u16) -> Result<i32, String> {
+/// Create VLAN `vlan_id` of `ifname` and install the qdiscs of `config`,
+/// returning the warnings of its TAS schedule.
+pub fn create_vlan(
+    config: &Config,
+    ifname: &str,
+    vlan_id: u16,
+) -> Result<Vec<Diagnostic>, TsnError> {
+    create_vlan_with(&mut Netlink::open()?, config, ifname, vlan_id)
+}
+
+/// [`create_vlan`], with the requests going to `backend`.
+pub fn create_vlan_with(
+    backend: &mut dyn Backend,
+    config: &Config,
+    ifname: &str,
+    vlan_id: u16,
+) -> Result<Vec<Diagnostic>, TsnError> {
+    let warnings = validate::check(config, ifname)?;
+    add_vlan(backend, config, ifname, vlan_id)?;
+    setup_qdiscs(backend, ifname, config)?;
+    Ok(warnings)
+}
+
+/// Create the VLAN interface `vlan_id` of `ifname` with its egress-qos-map
+/// from `config`, without touching the qdiscs.
+pub(crate) fn add_vlan(
+    backend: &mut dyn Backend,
+    config: &Config,
+    ifname: &str,
+    vlan_id: u16,
+) -> Result<i32, TsnError> {
     let name = get_vlan_name(ifname, vlan_id);
-    let mut qos_map = HashMap::new();
+    let qos_map = config
+        .egress_qos_map
+        .get(&(vlan_id as i64))
+        .ok_or_else(|| {
+            TsnError::Config(format!(
+                "No egress-qos-map for vlan {} on {}",
+                vlan_id, ifname
+            ))
+        })?;
+    let mut desc = String::new();
+    desc.push_str(&format!(
+        "ip link add link {} name {} up type vlan id {} egress-qos-map",
+        ifname, name, vlan_id
+    ));
+    for (prio, pri) in qos_map.iter().sorted() {
+        desc.push_str(&format!(" {}:{}", prio, pri));
+    }
+    let to_vlan_error = |e| TsnError::VlanCreate {
+        name: name.clone(),
+        source: link_error(e),
+    };
+    let ifindex = backend.ifindex(ifname, &desc).map_err(to_vlan_error)?;
+    let mut msg = Message::new(
+        libc::RTM_NEWLINK,
+        (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16,
+    );
+    msg.ifinfomsg(0, libc::IFF_UP as u32, libc::IFF_UP as u32)
+        .attr_u32(libc::IFLA_LINK, ifindex as u32)
+        .attr_str(libc::IFLA_IFNAME, &name)
+        .nested(libc::IFLA_LINKINFO, |info| {
+            info.attr_str(libc::IFLA_INFO_KIND, "vlan")
+                .nested(netlink::IFLA_INFO_DATA, |data| {
+                    data.attr_u16(netlink::IFLA_VLAN_ID, vlan_id).nested(
+                        netlink::IFLA_VLAN_EGRESS_QOS,
+                        |egress| {
+                            for (prio, pri) in qos_map.iter().sorted() {
+                                egress.attr(
+                                    netlink::IFLA_VLAN_QOS_MAPPING,
+                                    &netlink::vlan_qos_mapping(*prio as u32, *pri as u32),
+                                );
+                            }
+                        },
+                    );
+                });
+        });
+    request(backend, &desc, &mut msg).map_err(to_vlan_error)
+}
 
-    if config.tas.is_some() && config.cbs.is_some() {
-        eprintln!("Does not support both TAS and CBS");
-        return Err("Does not support both TAS and CBS".to_string());
+/// Install the root qdisc of `config` and everything under it.
+fn setup_qdiscs(backend: &mut dyn Backend, ifname: &str, config: &Config) -> Result<i32, TsnError> {
+    match (&config.tas, &config.cbs) {
+        (Some(tas), cbs) => {
+            setup_tas(backend, ifname, tas, cbs.as_ref())?;
+        }
+        (None, Some(cbs)) => {
+            setup_cbs(backend, ifname, cbs)?;
+        }
+        (None, None) => {}
     }
-    for (prio, pri) in config.egress_qos_map.get(&(vlan_id as i64)).unwrap() {
-        qos_map.insert(prio, pri);
+    if !config.etf.is_empty() {
+        setup_etf(backend, ifname, config)?;
     }
-    let mut cmd = String::new();
-    cmd.push_str(&format!(
-        "ip link add link {} name {} type vlan id {} egress-qos-map",
-        ifname, name, vlan_id
-    ));
-    for (prio, pri) in qos_map {
-        cmd.push_str(&format!(" {}:{}", pri, prio));
+    Ok(0)
+}
+
+/// Set the egress-qos-map of a VLAN to `new`, `old` being the one it has.
+fn set_egress_qos_map(
+    backend: &mut dyn Backend,
+    ifname: &str,
+    vlan_id: u16,
+    old: &HashMap<i64, i64>,
+    new: &HashMap<i64, i64>,
+) -> Result<i32, TsnError> {
+    let name = get_vlan_name(ifname, vlan_id);
+    // A prio left out of the map goes back to pri 0
+    let mut qos_map: Vec<(i64, i64)> = old.keys().map(|prio| (*prio, 0)).collect();
+    qos_map.extend(new.iter().map(|(prio, pri)| (*prio, *pri)));
+    let qos_map: HashMap<i64, i64> = qos_map.into_iter().collect();
+    let mut desc = format!("ip link set dev {} type vlan egress-qos-map", name);
+    for (prio, pri) in qos_map.iter().sorted() {
+        desc.push_str(&format!(" {}:{}", prio, pri));
     }
-    run_cmd(&cmd)?;
-    let cmd = format!("ip link set up {}", name);
-    run_cmd(&cmd)?;
-    if let Some(tas) = &config.tas {
-        setup_tas(ifname, tas)?;
+    let ifindex = backend.ifindex(&name, &desc)?;
+    let mut msg = Message::new(libc::RTM_NEWLINK, 0);
+    msg.ifinfomsg(ifindex, 0, 0)
+        .nested(libc::IFLA_LINKINFO, |info| {
+            info.attr_str(libc::IFLA_INFO_KIND, "vlan")
+                .nested(netlink::IFLA_INFO_DATA, |data| {
+                    data.nested(netlink::IFLA_VLAN_EGRESS_QOS, |egress| {
+                        for (prio, pri) in qos_map.iter().sorted() {
+                            egress.attr(
+                                netlink::IFLA_VLAN_QOS_MAPPING,
+                                &netlink::vlan_qos_mapping(*prio as u32, *pri as u32),
+                            );
+                        }
+                    });
+                });
+        });
+    request(backend, &desc, &mut msg)
+}
+
+/// Delete the root qdisc of `ifname` and everything under it.
+fn delete_root(backend: &mut dyn Backend, ifname: &str) -> Result<i32, TsnError> {
+    let desc = format!("tc qdisc delete dev {} root", ifname);
+    let ifindex = backend.ifindex(ifname, &desc)?;
+    let mut msg = Message::new(libc::RTM_DELQDISC, 0);
+    msg.tcmsg(ifindex, 0, netlink::TC_H_ROOT);
+    request(backend, &desc, &mut msg)
+}
+
+/// Delete the root qdisc of `ifname`, if there is one to delete.
+pub(crate) fn clear_root(backend: &mut dyn Backend, ifname: &str) -> Result<i32, TsnError> {
+    match delete_root(backend, ifname) {
+        // Nothing was installed, e.g. the old config had no qdisc
+        Err(TsnError::Netlink { source, .. })
+            if source.raw_os_error() == Some(libc::ENOENT)
+                || source.raw_os_error() == Some(libc::EINVAL) =>
+        {
+            Ok(0)
+        }
+        res => res,
     }
-    if let Some(cbs) = &config.cbs {
-        setup_cbs(ifname, cbs)?;
+}
+
+/// Parents of the ETF qdiscs of `config`, by prio.
+pub(crate) fn etf_parents(config: &Config) -> HashMap<i64, (u32, u32)> {
+    let root_handle = 0x100;
+    let (etf, tc_map) = match (&config.tas, &config.cbs) {
+        (Some(tas), _) => (&tas.etf, tas.tc_map.clone()),
+        (None, Some(cbs)) => (&config.etf, cbs.tc_map.clone()),
+        // The mqprio of setup_etf
+        (None, None) => (&config.etf, etf_tc_map(config)),
+    };
+    etf.keys()
+        .map(|prio| {
+            let class = tc_map[prio] as u32 + 1;
+            (*prio, etf_parent(root_handle, class, config.cbs.as_ref()))
+        })
+        .collect()
+}
+
+/// Make the qdiscs and VLANs of `ifname` run `new` instead of `old`, see
+/// [`crate::reconfigure()`].
+pub(crate) fn apply_changes(
+    backend: &mut dyn Backend,
+    ifname: &str,
+    old: &Config,
+    new: &Config,
+    changes: &[Change],
+) -> Result<i32, TsnError> {
+    for change in changes {
+        match change {
+            Change::EgressQosMap { vlanid } => {
+                let map = |config: &'_ Config| {
+                    config
+                        .egress_qos_map
+                        .get(&(*vlanid as i64))
+                        .cloned()
+                        .unwrap_or_default()
+                };
+                set_egress_qos_map(backend, ifname, *vlanid, &map(old), &map(new))?;
+            }
+            Change::Root => {
+                clear_root(backend, ifname)?;
+                setup_qdiscs(backend, ifname, new)?;
+            }
+            Change::Schedule => {
+                let tas = new.tas.as_ref().expect("a schedule change needs tas");
+                let (desc, _, mut msg) = taprio_request(backend, ifname, tas, true)?;
+                request(backend, &desc, &mut msg)?;
+            }
+            Change::Cbs { class } => {
+                let cbs = new.cbs.as_ref().expect("a cbs change needs cbs");
+                let ifindex =
+                    backend.ifindex(ifname, &format!("tc qdisc change dev {}", ifname))?;
+                let parent = (0x100, *class as u32);
+                let offload = cbs_offload(new.tas.as_ref());
+                let credit = &cbs.children[class];
+                setup_cbs_child(backend, ifname, ifindex, parent, credit, offload, true)?;
+            }
+            Change::Etf { prio } => {
+                let etf = new.tas.as_ref().map_or(&new.etf, |tas| &tas.etf);
+                let parent = etf_parents(new)[prio];
+                let ifindex =
+                    backend.ifindex(ifname, &format!("tc qdisc replace dev {}", ifname))?;
+                setup_etf_child(backend, ifname, ifindex, parent, &etf[prio])?;
+            }
+            Change::EtfRemoved { prio } => {
+                let parent = etf_parents(old)[prio];
+                let desc = format!(
+                    "tc qdisc delete dev {} parent {:x}:{:x}",
+                    ifname, parent.0, parent.1
+                );
+                let ifindex = backend.ifindex(ifname, &desc)?;
+                let mut msg = Message::new(libc::RTM_DELQDISC, 0);
+                msg.tcmsg(ifindex, 0, tc_handle(parent.0, parent.1));
+                request(backend, &desc, &mut msg)?;
+            }
+        }
     }
     Ok(0)
 }
 
-pub fn delete_vlan(ifname: &str, vlanid: u16) -> Result<i32, String> {
+/// Delete the VLAN interface `vlanid` of `ifname`. The qdiscs are the NIC's
+/// and stay, see [`registry::release_vlan`](crate::registry::release_vlan).
+pub fn delete_vlan(ifname: &str, vlanid: u16) -> Result<i32, TsnError> {
+    delete_link(&mut Netlink::open()?, ifname, vlanid)
+}
+
+/// Delete the VLAN interface `vlanid` of `ifname`, leaving the qdiscs.
+pub(crate) fn delete_link(
+    backend: &mut dyn Backend,
+    ifname: &str,
+    vlanid: u16,
+) -> Result<i32, TsnError> {
     let name = get_vlan_name(ifname, vlanid);
-    let cmd = format!("ip link del {}", name);
-    run_cmd(&cmd)?;
-    let cmd = format!("tc qdisc delete dev {} root", ifname);
-    run_cmd(&cmd)?;
-    Ok(0)
+    let desc = format!("ip link del {}", name);
+    let mut msg = Message::new(libc::RTM_DELLINK, 0);
+    msg.ifinfomsg(0, 0, 0).attr_str(libc::IFLA_IFNAME, &name);
+    request(backend, &desc, &mut msg).map_err(|e| TsnError::VlanDelete {
+        name: name.clone(),
+        source: link_error(e),
+    })
 }
 
 pub fn get_vlan_name(ifname: &str, vlanid: u16) -> String {
@@ -134,3 +692,155 @@ pub fn get_vlan_name(ifname: &str, vlanid: u16) -> String {
         format!("{}.{}", &ifname, vlanid)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Recorder;
+    use crate::config::parse_config;
+    use crate::schema::Format;
+
+    fn config(yaml: &str) -> Config {
+        parse_config(yaml, Format::Yaml)
+            .unwrap()
+            .remove("tsn0")
+            .unwrap()
+    }
+
+    /// The TCA_KIND of a qdisc request.
+    fn kind(payload: &[u8]) -> String {
+        netlink::read_str(netlink::attr(&payload[20..], libc::TCA_KIND).unwrap())
+    }
+
+    const TAS: &str = "
+nics:
+  tsn0:
+    egress-qos-map:
+      10: {2: 2, 3: 3}
+    tas:
+      mode: software
+      base_time: 1000
+      schedule:
+        - {time: 300us, prio: [3]}
+        - {time: 200us, prio: [2]}
+        - {time: 500us, prio: [-1]}
+";
+
+    #[test]
+    fn create_vlan_with_tas() {
+        let mut recorder = Recorder::new();
+        create_vlan_with(&mut recorder, &config(TAS), "tsn0", 10).unwrap();
+        assert_eq!(
+            recorder.commands(),
+            [
+                "ip link add link tsn0 name tsn0.10 up type vlan id 10 egress-qos-map 2:2 3:3",
+                "tc qdisc replace dev tsn0 parent root handle 100 taprio num_tc 3 \
+                 map 2 2 1 0 2 2 2 2 2 2 2 2 2 2 2 2 queues 1@0 1@1 1@2 base-time 1000 \
+                 sched-entry S 1 300000 sched-entry S 2 200000 sched-entry S 4 500000 \
+                 clockid CLOCK_TAI flags 0x0",
+            ]
+        );
+        let [link, taprio] = &recorder.requests[..] else {
+            panic!("two requests expected");
+        };
+        assert_eq!(link.msg_type, libc::RTM_NEWLINK);
+        assert_eq!(taprio.msg_type, libc::RTM_NEWQDISC);
+        assert_eq!(kind(&taprio.payload), "taprio");
+    }
+
+    #[test]
+    fn schedule_change_keeps_traffic_classes() {
+        let old = config(TAS);
+        let new = config(&TAS.replace("time: 300us", "time: 400us"));
+        let mut recorder = Recorder::new();
+        apply_changes(&mut recorder, "tsn0", &old, &new, &[Change::Schedule]).unwrap();
+        assert_eq!(recorder.requests.len(), 1);
+        let options = netlink::attr(&recorder.requests[0].payload[20..], libc::TCA_OPTIONS);
+        let options = options.unwrap();
+        assert!(netlink::attr(options, netlink::TCA_TAPRIO_ATTR_PRIOMAP).is_none());
+        assert!(recorder.commands()[0].contains("sched-entry S 1 400000"));
+    }
+
+    #[test]
+    fn create_vlan_with_cbs() {
+        let yaml = "
+nics:
+  tsn0:
+    egress-qos-map:
+      10: {3: 3}
+    link_speed: 1Gbps
+    cbs:
+      3: {class: a, max_frame: 512B, bandwidth: 70Mbps}
+      2: {class: b, max_frame: 512B, bandwidth: 30Mbps}
+";
+        let mut recorder = Recorder::new();
+        create_vlan_with(&mut recorder, &config(yaml), "tsn0", 10).unwrap();
+        assert_eq!(
+            recorder.commands()[1..],
+            [
+                "tc qdisc add dev tsn0 parent root handle 100 mqprio num_tc 3 \
+                 map 2 2 1 0 2 2 2 2 2 2 2 2 2 2 2 2 queues 1@0 1@1 1@2 hw 0",
+                "tc qdisc replace dev tsn0 parent 100:1 handle 1111 cbs idleslope 70000 \
+                 sendslope -930000 hicredit 287 locredit -3809 offload 1",
+                "tc qdisc replace dev tsn0 parent 100:2 handle 2222 cbs idleslope 30000 \
+                 sendslope -970000 hicredit 256 locredit -3973 offload 1",
+            ]
+        );
+        let kinds: Vec<String> = recorder.requests[1..]
+            .iter()
+            .map(|request| kind(&request.payload))
+            .collect();
+        assert_eq!(kinds, ["mqprio", "cbs", "cbs"]);
+    }
+
+    #[test]
+    fn cbs_under_software_taprio_is_not_offloaded() {
+        let yaml = format!(
+            "{}    link_speed: 1Gbps\n    cbs:\n      3: {{class: a, max_frame: 512B, bandwidth: 70Mbps}}\n",
+            TAS
+        );
+        let mut recorder = Recorder::new();
+        create_vlan_with(&mut recorder, &config(&yaml), "tsn0", 10).unwrap();
+        let commands = recorder.commands();
+        assert_eq!(commands.len(), 3);
+        assert!(commands[2].starts_with("tc qdisc replace dev tsn0 parent 100:1 handle 1111 cbs"));
+        assert!(commands[2].ends_with("offload 0"));
+    }
+
+    #[test]
+    fn nic_etf_gets_a_traffic_class_per_prio() {
+        let yaml = "
+nics:
+  tsn0:
+    egress-qos-map:
+      10: {3: 3}
+    etf:
+      5: {delta: 100us}
+      3: {delta: 200us, offload: true}
+";
+        let mut recorder = Recorder::new();
+        create_vlan_with(&mut recorder, &config(yaml), "tsn0", 10).unwrap();
+        assert_eq!(
+            recorder.commands()[1..],
+            [
+                "tc qdisc add dev tsn0 parent root handle 100 mqprio num_tc 3 \
+                 map 2 2 2 0 2 1 2 2 2 2 2 2 2 2 2 2 queues 1@0 1@1 1@2 hw 0",
+                "tc qdisc replace dev tsn0 parent 100:1 etf clockid CLOCK_TAI delta 200000 offload",
+                "tc qdisc replace dev tsn0 parent 100:2 etf clockid CLOCK_TAI delta 100000",
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_egress_qos_map() {
+        let mut recorder = Recorder::new();
+        assert!(create_vlan_with(&mut recorder, &config(TAS), "tsn0", 20).is_err());
+        assert!(recorder.requests.is_empty());
+    }
+
+    #[test]
+    fn vlan_name_fits_ifnamsiz() {
+        assert_eq!(get_vlan_name("enp37s0", 10), "enp37s0.10");
+        assert_eq!(get_vlan_name("enp0s31f6abc", 4095), "enp0s31f6a.4095");
+    }
+}
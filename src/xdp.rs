@@ -0,0 +1,843 @@
+//! AF_XDP sockets, for the traffic class that cannot wait for the stack.
+//!
+//! The socket is bound to one queue of the NIC itself, not to the VLAN
+//! interface: by default the TX queue of the traffic class the root taprio
+//! or mqprio, see [`crate::vlan`], gives the priority of the socket. An XDP
+//! program on the NIC redirects the frames of the VLAN that arrive on that
+//! queue to the socket, every other frame goes on to the stack.
+//!
+//! Frames are sent and received as on an `AF_PACKET` [`TsnSocket`]: the
+//! VLAN tag, with the PCP of the egress-qos-map of the VLAN, is added on send
+//! and removed on receive. They skip the qdiscs though, so the gates of
+//! taprio only apply when it is offloaded to the NIC. The NIC must leave the
+//! tag of received frames in place, `ethtool -K <interface> rxvlan off`.
+//!
+//! [`XdpMode::Generic`] runs the program on skbs, which works on any
+//! interface, veth included.
+
+use crate::{
+    error::TsnError,
+    ring::{self, Mapping},
+    state, vlan, TsnSocket,
+};
+use std::ffi::CStr;
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// include/uapi/linux/if_xdp.h
+const AF_XDP: libc::c_int = 44;
+const SOL_XDP: libc::c_int = 283;
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+const XDP_COPY: u16 = 1 << 1;
+const XDP_ZEROCOPY: u16 = 1 << 2;
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x8000_0000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x1_0000_0000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x1_8000_0000;
+// include/uapi/linux/if_link.h
+const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+const XDP_FLAGS_DRV_MODE: u32 = 1 << 2;
+// include/uapi/linux/bpf.h
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_LINK_CREATE: libc::c_long = 28;
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+const XDP_PASS: i32 = 2;
+const ETH_P_8021Q: u16 = 0x8100;
+/// Length of a VLAN tag, TPID and TCI
+const VLAN_HLEN: usize = 4;
+/// Size of the verifier log asked for when the program is rejected
+const LOG_SIZE: usize = 64 * 1024;
+
+/// How the XDP program is attached, and whether frames are copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XdpMode {
+    /// On skbs, after the driver, with copies; works on any interface
+    Generic,
+    /// In the driver, with copies to and from the UMEM
+    Native,
+    /// In the driver, with the NIC reading and writing the UMEM
+    ZeroCopy,
+}
+
+impl XdpMode {
+    fn attach_flags(self) -> u32 {
+        match self {
+            XdpMode::Generic => XDP_FLAGS_SKB_MODE,
+            XdpMode::Native | XdpMode::ZeroCopy => XDP_FLAGS_DRV_MODE,
+        }
+    }
+
+    fn bind_flags(self) -> u16 {
+        match self {
+            XdpMode::Generic | XdpMode::Native => XDP_COPY,
+            XdpMode::ZeroCopy => XDP_ZEROCOPY,
+        }
+    }
+
+    /// The name `ip link set dev <interface> <mode>` gives it.
+    fn name(self) -> &'static str {
+        match self {
+            XdpMode::Generic => "xdpgeneric",
+            XdpMode::Native | XdpMode::ZeroCopy => "xdpdrv",
+        }
+    }
+}
+
+/// Options of an AF_XDP socket, see [`crate::TsnSocketBuilder::xdp`].
+#[derive(Debug, Clone, Copy)]
+pub struct XdpConfig {
+    pub mode: XdpMode,
+    /// Frames of the UMEM, half to receive and half to send, a power of 2
+    pub frame_count: u32,
+    /// Bytes of each frame, a power of 2 from 2048 to the page size
+    pub frame_size: u32,
+    /// The queue to bind to instead of the one of the traffic class, below
+    /// `u32::MAX`
+    pub queue: Option<u32>,
+}
+
+impl Default for XdpConfig {
+    fn default() -> XdpConfig {
+        XdpConfig {
+            mode: XdpMode::Generic,
+            frame_count: 4096,
+            frame_size: 2048,
+            queue: None,
+        }
+    }
+}
+
+/// `struct xdp_umem_reg`
+#[repr(C)]
+struct UmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+/// `struct xdp_ring_offset`
+#[repr(C)]
+#[derive(Default)]
+struct RingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+/// `struct xdp_mmap_offsets`
+#[repr(C)]
+#[derive(Default)]
+struct MmapOffsets {
+    rx: RingOffset,
+    tx: RingOffset,
+    fill: RingOffset,
+    completion: RingOffset,
+}
+
+/// `struct sockaddr_xdp`
+#[repr(C)]
+struct SockaddrXdp {
+    family: u16,
+    flags: u16,
+    ifindex: u32,
+    queue_id: u32,
+    shared_umem_fd: u32,
+}
+
+/// `struct xdp_desc`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+/// `union bpf_attr` of `BPF_MAP_CREATE`
+#[repr(C)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+}
+
+/// `union bpf_attr` of `BPF_MAP_UPDATE_ELEM`
+#[repr(C)]
+struct MapUpdateAttr {
+    map_fd: u32,
+    pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+/// `union bpf_attr` of `BPF_PROG_LOAD`
+#[repr(C)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+/// `union bpf_attr` of `BPF_LINK_CREATE`
+#[repr(C)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+/// `struct bpf_insn`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Insn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+    #[cfg(target_endian = "little")]
+    let regs = dst | src << 4;
+    #[cfg(target_endian = "big")]
+    let regs = dst << 4 | src;
+    Insn {
+        code,
+        regs,
+        off,
+        imm,
+    }
+}
+
+// Opcodes of the program, include/uapi/linux/bpf_common.h
+const MOV64_X: u8 = 0xbf;
+const MOV64_K: u8 = 0xb7;
+const ADD64_K: u8 = 0x07;
+const AND64_K: u8 = 0x57;
+const TO_BE: u8 = 0xdc;
+const LDX_W: u8 = 0x61;
+const LDX_H: u8 = 0x69;
+const LD_DW: u8 = 0x18;
+const JGT_X: u8 = 0x2d;
+const JNE_K: u8 = 0x55;
+const CALL: u8 = 0x85;
+const EXIT: u8 = 0x95;
+/// Jump offset patched to the end of the program, which passes the frame
+const TO_PASS: i16 = i16::MAX;
+
+/// The XDP program: frames of `vlanid`, and `protocol` unless it is
+/// `ETH_P_ALL`, go to the socket in `map` of the queue they came from.
+fn redirect_program(map_fd: RawFd, vlanid: u16, protocol: u16) -> Vec<Insn> {
+    let mut prog = vec![
+        insn(MOV64_X, 6, 1, 0, 0),
+        // struct xdp_md: data, data_end
+        insn(LDX_W, 2, 6, 0, 0),
+        insn(LDX_W, 3, 6, 4, 0),
+        insn(MOV64_X, 4, 2, 0, 0),
+        insn(ADD64_K, 4, 0, 0, 18),
+        insn(JGT_X, 4, 3, TO_PASS, 0),
+        insn(LDX_H, 4, 2, 12, 0),
+        insn(TO_BE, 4, 0, 0, 16),
+        insn(JNE_K, 4, 0, TO_PASS, ETH_P_8021Q as i32),
+        insn(LDX_H, 4, 2, 14, 0),
+        insn(TO_BE, 4, 0, 0, 16),
+        insn(AND64_K, 4, 0, 0, 0xfff),
+        insn(JNE_K, 4, 0, TO_PASS, vlanid as i32),
+    ];
+    if protocol != libc::ETH_P_ALL as u16 {
+        prog.extend([
+            insn(LDX_H, 4, 2, 16, 0),
+            insn(TO_BE, 4, 0, 0, 16),
+            insn(JNE_K, 4, 0, TO_PASS, protocol as i32),
+        ]);
+    }
+    prog.extend([
+        // rx_queue_index
+        insn(LDX_W, 2, 6, 16, 0),
+        insn(LD_DW, 1, BPF_PSEUDO_MAP_FD, 0, map_fd),
+        insn(0, 0, 0, 0, 0),
+        // What bpf_redirect_map returns when the queue has no socket
+        insn(MOV64_K, 3, 0, 0, XDP_PASS),
+        insn(CALL, 0, 0, 0, BPF_FUNC_REDIRECT_MAP),
+        insn(EXIT, 0, 0, 0, 0),
+    ]);
+    let pass = prog.len();
+    prog.extend([insn(MOV64_K, 0, 0, 0, XDP_PASS), insn(EXIT, 0, 0, 0, 0)]);
+    for (i, insn) in prog.iter_mut().enumerate() {
+        if insn.off == TO_PASS && insn.code & 0x07 == 0x05 {
+            insn.off = (pass - i - 1) as i16;
+        }
+    }
+    prog
+}
+
+fn bpf<T>(cmd: libc::c_long, attr: &mut T) -> Result<libc::c_long, Error> {
+    let res = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *mut T, mem::size_of::<T>()) };
+    if res < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(res)
+}
+
+fn bpf_fd<T>(cmd: libc::c_long, attr: &mut T) -> Result<OwnedFd, Error> {
+    bpf(cmd, attr).map(|fd| unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Load `prog`, with the verifier log in the error when it is rejected.
+fn load_program(prog: &[Insn]) -> Result<OwnedFd, Error> {
+    let license = b"GPL\0";
+    let mut attr = ProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_XDP,
+        insn_cnt: prog.len() as u32,
+        insns: prog.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        log_level: 0,
+        log_size: 0,
+        log_buf: 0,
+        kern_version: 0,
+        prog_flags: 0,
+        prog_name: *b"tsn_xsk\0\0\0\0\0\0\0\0\0",
+        prog_ifindex: 0,
+        expected_attach_type: BPF_XDP,
+    };
+    match bpf_fd(BPF_PROG_LOAD, &mut attr) {
+        Err(e)
+            if e.raw_os_error() == Some(libc::EACCES) || e.raw_os_error() == Some(libc::EINVAL) =>
+        {
+            let mut log = vec![0u8; LOG_SIZE];
+            attr.log_level = 1;
+            attr.log_size = LOG_SIZE as u32;
+            attr.log_buf = log.as_mut_ptr() as u64;
+            bpf_fd(BPF_PROG_LOAD, &mut attr).map_err(|_| {
+                let log = CStr::from_bytes_until_nul(&log)
+                    .map(|log| log.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                Error::new(e.kind(), format!("{}: {}", e, log.trim()))
+            })
+        }
+        res => res,
+    }
+}
+
+/// A ring of the socket: the kernel consumes the fill and TX rings, this
+/// side the RX and completion rings.
+struct Ring {
+    _map: Mapping,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    descs: *mut u8,
+    size: u32,
+}
+
+impl Ring {
+    fn new(
+        fd: RawFd,
+        offset: &RingOffset,
+        size: u32,
+        entry: usize,
+        pgoff: libc::off_t,
+    ) -> Result<Ring, TsnError> {
+        let len = offset.desc as usize + size as usize * entry;
+        let map = Mapping::at(fd, len, pgoff)?;
+        unsafe {
+            Ok(Ring {
+                producer: map.addr.add(offset.producer as usize) as *const AtomicU32,
+                consumer: map.addr.add(offset.consumer as usize) as *const AtomicU32,
+                descs: map.addr.add(offset.desc as usize),
+                size,
+                _map: map,
+            })
+        }
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { &*self.consumer }
+    }
+
+    fn entry<T>(&self, index: u32) -> *mut T {
+        unsafe { (self.descs as *mut T).add((index & (self.size - 1)) as usize) }
+    }
+
+    /// Entries to consume, when the kernel produces them.
+    fn ready(&self) -> u32 {
+        let producer = self.producer().load(Ordering::Acquire);
+        producer.wrapping_sub(self.consumer().load(Ordering::Relaxed))
+    }
+
+    /// Entries not consumed yet, when the kernel consumes them.
+    fn pending(&self) -> u32 {
+        let consumer = self.consumer().load(Ordering::Acquire);
+        self.producer()
+            .load(Ordering::Relaxed)
+            .wrapping_sub(consumer)
+    }
+}
+
+/// The RX and fill rings.
+struct RxRings {
+    rx: Ring,
+    fill: Ring,
+}
+
+/// The TX and completion rings, and the frames of the UMEM free to send.
+struct TxRings {
+    tx: Ring,
+    completion: Ring,
+    free: Vec<u64>,
+}
+
+// The rings point into mappings owned by the same struct
+unsafe impl Send for RxRings {}
+unsafe impl Send for TxRings {}
+
+/// The AF_XDP part of a [`TsnSocket`], whose fd is the AF_XDP socket.
+pub(crate) struct XdpSocket {
+    rx: Mutex<RxRings>,
+    tx: Mutex<TxRings>,
+    umem: Mapping,
+    frame_size: usize,
+    /// TPID and TCI of the frames sent
+    tag: [u8; VLAN_HLEN],
+    pub(crate) timeout: Option<Duration>,
+    /// Detaches the program when closed
+    _link: OwnedFd,
+    _prog: OwnedFd,
+    _map: OwnedFd,
+}
+
+unsafe impl Send for XdpSocket {}
+unsafe impl Sync for XdpSocket {}
+
+fn setsockopt<T>(fd: RawFd, opt: libc::c_int, value: &T, op: &'static str) -> Result<(), TsnError> {
+    if ring::setsockopt(fd, SOL_XDP, opt, value) < 0 {
+        return Err(TsnError::socket(op));
+    }
+    Ok(())
+}
+
+impl XdpSocket {
+    /// Open an AF_XDP socket on `ifname` for the frames of `vlanid` and
+    /// `protocol`, and attach the program that redirects them to it.
+    pub(crate) fn open(
+        ifname: &str,
+        vlanid: u16,
+        priority: u32,
+        protocol: u16,
+        config: &XdpConfig,
+    ) -> Result<(OwnedFd, XdpSocket), TsnError> {
+        let frame_count = config.frame_count;
+        if frame_count < 2 || !frame_count.is_power_of_two() {
+            return Err(TsnError::invalid(
+                "umem",
+                "the frame count must be a power of 2",
+            ));
+        }
+        // The xsk map needs an entry past it
+        if config.queue == Some(u32::MAX) {
+            return Err(TsnError::invalid("xsk map", "the queue is out of range"));
+        }
+        let live = state::read(ifname)?;
+        let queue = config.queue.unwrap_or_else(|| {
+            live.traffic_classes()
+                .and_then(|classes| classes.queue(priority))
+                .unwrap_or(0) as u32
+        });
+        let pcp = live
+            .vlans
+            .iter()
+            .find(|vlan| vlan.vlanid == vlanid)
+            .and_then(|vlan| vlan.egress_qos_map.get(&priority).copied())
+            .unwrap_or(0) as u16;
+        let desc = format!("ip link set dev {} {}", ifname, config.mode.name());
+        let ifindex = vlan::get_ifindex(ifname, &desc)?;
+
+        let fd = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(TsnError::socket("open"));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let raw = fd.as_raw_fd();
+        if ring::setsockopt(raw, libc::SOL_SOCKET, libc::SO_PRIORITY, &priority) < 0 {
+            return Err(TsnError::socket("setsockopt SO_PRIORITY"));
+        }
+
+        let frame_size = config.frame_size as usize;
+        let umem = Mapping::anonymous(frame_count as usize * frame_size)?;
+        let reg = UmemReg {
+            addr: umem.addr as u64,
+            len: frame_count as u64 * frame_size as u64,
+            chunk_size: config.frame_size,
+            headroom: 0,
+            flags: 0,
+            tx_metadata_len: 0,
+        };
+        setsockopt(raw, XDP_UMEM_REG, &reg, "setsockopt XDP_UMEM_REG")?;
+        let size = frame_count / 2;
+        setsockopt(
+            raw,
+            XDP_UMEM_FILL_RING,
+            &size,
+            "setsockopt XDP_UMEM_FILL_RING",
+        )?;
+        setsockopt(
+            raw,
+            XDP_UMEM_COMPLETION_RING,
+            &size,
+            "setsockopt XDP_UMEM_COMPLETION_RING",
+        )?;
+        setsockopt(raw, XDP_RX_RING, &size, "setsockopt XDP_RX_RING")?;
+        setsockopt(raw, XDP_TX_RING, &size, "setsockopt XDP_TX_RING")?;
+
+        let mut offsets = MmapOffsets::default();
+        let mut len = mem::size_of::<MmapOffsets>() as u32;
+        let res = unsafe {
+            libc::getsockopt(
+                raw,
+                SOL_XDP,
+                XDP_MMAP_OFFSETS,
+                &mut offsets as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if res < 0 {
+            return Err(TsnError::socket("getsockopt XDP_MMAP_OFFSETS"));
+        }
+        let desc_size = mem::size_of::<XdpDesc>();
+        let rx = RxRings {
+            rx: Ring::new(raw, &offsets.rx, size, desc_size, XDP_PGOFF_RX_RING)?,
+            fill: Ring::new(raw, &offsets.fill, size, 8, XDP_UMEM_PGOFF_FILL_RING)?,
+        };
+        let tx = TxRings {
+            tx: Ring::new(raw, &offsets.tx, size, desc_size, XDP_PGOFF_TX_RING)?,
+            completion: Ring::new(
+                raw,
+                &offsets.completion,
+                size,
+                8,
+                XDP_UMEM_PGOFF_COMPLETION_RING,
+            )?,
+            // The second half of the UMEM
+            free: (size..frame_count)
+                .rev()
+                .map(|i| i as u64 * frame_size as u64)
+                .collect(),
+        };
+        // The first half is for the kernel to receive into
+        for i in 0..size {
+            unsafe { *rx.fill.entry::<u64>(i) = i as u64 * frame_size as u64 };
+        }
+        rx.fill.producer().store(size, Ordering::Release);
+
+        let addr = SockaddrXdp {
+            family: AF_XDP as u16,
+            flags: config.mode.bind_flags(),
+            ifindex: ifindex as u32,
+            queue_id: queue,
+            shared_umem_fd: 0,
+        };
+        let res = unsafe {
+            libc::bind(
+                raw,
+                &addr as *const SockaddrXdp as *const libc::sockaddr,
+                mem::size_of::<SockaddrXdp>() as u32,
+            )
+        };
+        if res < 0 {
+            return Err(TsnError::socket("bind"));
+        }
+
+        let bpf_error = |op| move |source| TsnError::Socket { op, source };
+        let map = bpf_fd(
+            BPF_MAP_CREATE,
+            &mut MapCreateAttr {
+                map_type: BPF_MAP_TYPE_XSKMAP,
+                key_size: 4,
+                value_size: 4,
+                max_entries: queue + 1,
+            },
+        )
+        .map_err(bpf_error("create xsk map"))?;
+        let value = raw as u32;
+        bpf(
+            BPF_MAP_UPDATE_ELEM,
+            &mut MapUpdateAttr {
+                map_fd: map.as_raw_fd() as u32,
+                pad: 0,
+                key: &queue as *const u32 as u64,
+                value: &value as *const u32 as u64,
+                flags: 0,
+            },
+        )
+        .map_err(bpf_error("update xsk map"))?;
+        let prog = load_program(&redirect_program(map.as_raw_fd(), vlanid, protocol))
+            .map_err(bpf_error("load XDP program"))?;
+        // The link detaches the program when closed, by us or the kernel
+        // when this process dies
+        let link = bpf_fd(
+            BPF_LINK_CREATE,
+            &mut LinkCreateAttr {
+                prog_fd: prog.as_raw_fd() as u32,
+                target_ifindex: ifindex as u32,
+                attach_type: BPF_XDP,
+                flags: config.mode.attach_flags(),
+            },
+        )
+        .map_err(|source| TsnError::Netlink {
+            request: desc,
+            extack: (source.raw_os_error() == Some(libc::EBUSY))
+                .then(|| "another XDP program is attached".to_string()),
+            source,
+        })?;
+
+        let tci = pcp << 13 | vlanid;
+        let mut tag = [0; VLAN_HLEN];
+        tag[..2].copy_from_slice(&ETH_P_8021Q.to_be_bytes());
+        tag[2..].copy_from_slice(&tci.to_be_bytes());
+        Ok((
+            fd,
+            XdpSocket {
+                rx: Mutex::new(rx),
+                tx: Mutex::new(tx),
+                umem,
+                frame_size,
+                tag,
+                timeout: None,
+                _link: link,
+                _prog: prog,
+                _map: map,
+            },
+        ))
+    }
+
+    /// The frame at `addr` of the UMEM, which must not be in a ring.
+    fn frame(&self, addr: u64) -> *mut u8 {
+        unsafe { self.umem.addr.add(addr as usize) }
+    }
+
+    /// Have the kernel send what is on the TX ring.
+    fn kick(&self, fd: RawFd) -> Result<(), TsnError> {
+        let res = unsafe { libc::sendto(fd, ptr::null(), 0, libc::MSG_DONTWAIT, ptr::null(), 0) };
+        if res < 0 {
+            let err = Error::last_os_error();
+            match err.raw_os_error() {
+                // Busy with earlier frames, they are waited for with poll
+                Some(libc::EAGAIN | libc::EBUSY | libc::ENOBUFS) => {}
+                _ => {
+                    return Err(TsnError::Socket {
+                        op: "send",
+                        source: err,
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Put the frames the kernel has sent back on the free list.
+    fn complete(tx: &mut TxRings) {
+        let ready = tx.completion.ready();
+        let consumer = tx.completion.consumer().load(Ordering::Relaxed);
+        for i in 0..ready {
+            tx.free
+                .push(unsafe { *tx.completion.entry::<u64>(consumer.wrapping_add(i)) });
+        }
+        tx.completion
+            .consumer()
+            .store(consumer.wrapping_add(ready), Ordering::Release);
+    }
+
+    /// Kick the kernel and wait for room on the TX ring, or fail with
+    /// `EAGAIN` once `deadline` has passed.
+    fn wait_tx(&self, fd: RawFd, deadline: Option<Instant>) -> Result<(), TsnError> {
+        self.kick(fd)?;
+        let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if timeout == Some(Duration::ZERO) || !ring::poll(fd, libc::POLLOUT, timeout)? {
+            return Err(TsnError::Socket {
+                op: "send",
+                source: Error::from_raw_os_error(libc::EAGAIN),
+            });
+        }
+        thread::yield_now();
+        Ok(())
+    }
+
+    /// When a send started now has to give up.
+    fn deadline(&self) -> Option<Instant> {
+        self.timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    /// Put `frame` on the TX ring with the VLAN tag, waiting until `deadline`
+    /// for a free frame of the UMEM if there is none.
+    fn push(
+        &self,
+        fd: RawFd,
+        tx: &mut TxRings,
+        frame: &[u8],
+        deadline: Option<Instant>,
+    ) -> Result<(), TsnError> {
+        if frame.len() < 14 {
+            return Err(TsnError::invalid(
+                "send",
+                "frame shorter than an Ethernet header",
+            ));
+        }
+        let len = frame.len() + VLAN_HLEN;
+        if len > self.frame_size {
+            return Err(TsnError::Socket {
+                op: "send",
+                source: Error::from_raw_os_error(libc::EMSGSIZE),
+            });
+        }
+        let addr = loop {
+            Self::complete(tx);
+            if let Some(addr) = tx.free.pop() {
+                break addr;
+            }
+            self.wait_tx(fd, deadline)?;
+        };
+        let data = unsafe { slice::from_raw_parts_mut(self.frame(addr), len) };
+        data[..12].copy_from_slice(&frame[..12]);
+        data[12..16].copy_from_slice(&self.tag);
+        data[16..].copy_from_slice(&frame[12..]);
+        let producer = tx.tx.producer().load(Ordering::Relaxed);
+        unsafe {
+            *tx.tx.entry::<XdpDesc>(producer) = XdpDesc {
+                addr,
+                len: len as u32,
+                options: 0,
+            }
+        };
+        tx.tx
+            .producer()
+            .store(producer.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    pub(crate) fn send(&self, fd: RawFd, frame: &[u8]) -> Result<isize, TsnError> {
+        let mut tx = self.tx.lock().unwrap();
+        self.push(fd, &mut tx, frame, self.deadline())?;
+        self.kick(fd)?;
+        Ok(frame.len() as isize)
+    }
+
+    /// Send `frames`, waiting until the kernel has taken all of them.
+    pub(crate) fn send_batch(&self, fd: RawFd, frames: &[&[u8]]) -> Result<usize, TsnError> {
+        let mut tx = self.tx.lock().unwrap();
+        let deadline = self.deadline();
+        let mut sent = 0;
+        for frame in frames {
+            match self.push(fd, &mut tx, frame, deadline) {
+                Ok(()) => sent += 1,
+                Err(e) if sent == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        // The kernel takes a few frames each time, in copy mode
+        while tx.tx.pending() > 0 {
+            self.wait_tx(fd, deadline)?;
+        }
+        Self::complete(&mut tx);
+        Ok(sent)
+    }
+
+    /// Wait for a frame, then hand it and the frames already waiting, up to
+    /// `max`, to `each` without their VLAN tag, and return how many there
+    /// were.
+    pub(crate) fn recv(
+        &self,
+        fd: RawFd,
+        max: usize,
+        mut each: impl FnMut(&[u8]),
+    ) -> Result<usize, TsnError> {
+        let rings = self.rx.lock().unwrap();
+        let ready = loop {
+            let ready = rings.rx.ready();
+            if ready > 0 {
+                break ready.min(max as u32);
+            }
+            if !ring::poll(fd, libc::POLLIN, self.timeout)? {
+                return Err(TsnError::Socket {
+                    op: "recv",
+                    source: Error::from_raw_os_error(libc::EAGAIN),
+                });
+            }
+        };
+        let consumer = rings.rx.consumer().load(Ordering::Relaxed);
+        let producer = rings.fill.producer().load(Ordering::Relaxed);
+        for i in 0..ready {
+            let desc = unsafe { *rings.rx.entry::<XdpDesc>(consumer.wrapping_add(i)) };
+            let frame =
+                unsafe { slice::from_raw_parts_mut(self.frame(desc.addr), desc.len as usize) };
+            if frame.len() >= 14 + VLAN_HLEN && frame[12..14] == ETH_P_8021Q.to_be_bytes() {
+                frame.copy_within(..12, VLAN_HLEN);
+                each(&frame[VLAN_HLEN..]);
+            } else {
+                each(frame);
+            }
+            // The fill ring has room for every frame it has lent
+            let chunk = desc.addr & !(self.frame_size as u64 - 1);
+            unsafe { *rings.fill.entry::<u64>(producer.wrapping_add(i)) = chunk };
+        }
+        rings
+            .rx
+            .consumer()
+            .store(consumer.wrapping_add(ready), Ordering::Release);
+        rings
+            .fill
+            .producer()
+            .store(producer.wrapping_add(ready), Ordering::Release);
+        Ok(ready as usize)
+    }
+}
+
+impl TsnSocket {
+    /// Fail with `op` on an AF_XDP socket, for what only `AF_PACKET` sockets
+    /// have.
+    pub(crate) fn packet_socket(&self, op: &'static str) -> Result<(), TsnError> {
+        match self.xdp {
+            Some(_) => Err(TsnError::Socket {
+                op,
+                source: Error::new(ErrorKind::Unsupported, "not on an AF_XDP socket"),
+            }),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! Runs a test in a network namespace of its own, with a veth pair `veth0` and
+//! `veth1` whose ends can see each other.
+//!
+//! The test binary runs itself again for the one test, in a new network
+//! namespace, and also in a new user namespace when not root, so nothing
+//! outside is touched and no privileges are needed where unprivileged user
+//! namespaces are allowed. The registry of VLAN users goes in a directory of
+//! the test's own.
+//!
+//! A test is skipped, and passes, when the kernel lacks something it needs:
+//! user namespaces, VLANs or a qdisc. With `CI` or `TSN_REQUIRE_NETNS` set
+//! in the environment a skipped test fails instead, so that CI runs them all.
+
+#![allow(dead_code)]
+
+use std::ffi::CString;
+use std::io::{Error, ErrorKind};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output};
+use std::thread;
+use std::time::{Duration, Instant};
+use tsn::TsnError;
+
+/// Set in the namespace to the name of the test to run there.
+const NETNS_ENV: &str = "TSN_TEST_NETNS";
+/// Exit code of a test that skips itself, as in automake.
+const SKIP: i32 = 77;
+
+/// Pass the test that could not run for `reason`, unless skipping is not
+/// allowed here.
+fn skipped(name: &str, reason: &str) {
+    if std::env::var_os("CI").is_some() || std::env::var_os("TSN_REQUIRE_NETNS").is_some() {
+        panic!("{} was skipped: {}", name, reason);
+    }
+    eprintln!("skipped: {}", reason);
+}
+
+/// Run `body` in a network namespace with a veth pair, `name` being the full
+/// name of the calling test, e.g. `"vlan_follows_its_sockets"`.
+pub fn in_netns(name: &str, body: fn()) {
+    if std::env::var(NETNS_ENV).as_deref() == Ok(name) {
+        setup();
+        body();
+        return;
+    }
+    let registry =
+        std::env::temp_dir().join(format!("libtsn-test-{}-{}", name, std::process::id()));
+    let mut cmd = Command::new(std::env::current_exe().unwrap());
+    cmd.args([name, "--exact", "--nocapture", "--test-threads=1"])
+        .env(NETNS_ENV, name)
+        .env("TSN_REGISTRY_DIR", &registry);
+    unshare_on_exec(&mut cmd);
+    let status = match cmd.status() {
+        Ok(status) => status,
+        Err(e) => return skipped(name, &format!("no network namespace: {}", e)),
+    };
+    let _ = std::fs::remove_dir_all(&registry);
+    match status.code() {
+        Some(0) => {}
+        Some(SKIP) => skipped(name, "see above"),
+        _ => panic!("{} failed in its namespace: {}", name, status),
+    }
+}
+
+/// Write `data` to `path` without allocating, which is not safe after fork.
+fn write_file(path: &CString, data: &[u8]) -> std::io::Result<()> {
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_WRONLY) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    let res = unsafe { libc::write(fd, data.as_ptr() as *const libc::c_void, data.len()) };
+    unsafe { libc::close(fd) };
+    if res < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Make `cmd` start in a new network namespace, and a new user namespace
+/// where it is root when we are not.
+fn unshare_on_exec(cmd: &mut Command) {
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let files = [
+        ("/proc/self/setgroups", "deny".to_string()),
+        ("/proc/self/uid_map", format!("0 {} 1", uid)),
+        ("/proc/self/gid_map", format!("0 {} 1", gid)),
+    ]
+    .map(|(path, data)| (CString::new(path).unwrap(), data.into_bytes()));
+    unsafe {
+        cmd.pre_exec(move || {
+            if uid == 0 {
+                if libc::unshare(libc::CLONE_NEWNET) < 0 {
+                    return Err(Error::last_os_error());
+                }
+                return Ok(());
+            }
+            if libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) < 0 {
+                return Err(Error::last_os_error());
+            }
+            for (path, data) in &files {
+                write_file(path, data)?;
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Skip the test that is running in its namespace.
+pub fn skip(reason: &str) -> ! {
+    eprintln!("skipped: {}", reason);
+    std::process::exit(SKIP);
+}
+
+pub fn ip(args: &str) -> Output {
+    Command::new("ip")
+        .args(args.split_whitespace())
+        .output()
+        .unwrap_or_else(|e| skip(&format!("cannot run ip: {}", e)))
+}
+
+fn setup() {
+    std::fs::create_dir_all(std::env::var("TSN_REGISTRY_DIR").unwrap()).unwrap();
+    // Several TX queues, for taprio and mqprio to have one per traffic class
+    let veth = ip("link add veth0 numtxqueues 4 numrxqueues 4 type veth \
+                   peer name veth1 numtxqueues 4 numrxqueues 4");
+    if !veth.status.success() {
+        skip(&format!(
+            "no veth: {}",
+            String::from_utf8_lossy(&veth.stderr)
+        ));
+    }
+    for ifname in ["lo", "veth0", "veth1"] {
+        assert!(ip(&format!("link set {} up", ifname)).status.success());
+    }
+}
+
+/// `res`, or skip the test when the kernel lacks `what`, e.g. the 8021q
+/// module or a qdisc.
+pub fn require<T>(res: Result<T, TsnError>, what: &str) -> T {
+    let kind = match &res {
+        Err(TsnError::VlanCreate { source, .. }) => Some(source.kind()),
+        Err(TsnError::Netlink { source, .. }) => Some(source.kind()),
+        _ => None,
+    };
+    match kind {
+        // An unknown link type, or qdisc kind
+        Some(ErrorKind::Unsupported) | Some(ErrorKind::NotFound) => skip(&format!(
+            "{} is not supported: {}",
+            what,
+            res.err().unwrap()
+        )),
+        _ => res.unwrap_or_else(|e| panic!("{}: {}", what, e)),
+    }
+}
+
+/// Skip the test unless VLANs can be created.
+pub fn require_vlan() {
+    let probe = ip("link add link veth0 name probe type vlan id 4094");
+    if !probe.status.success() {
+        skip(&format!(
+            "vlan is not supported: {}",
+            String::from_utf8_lossy(&probe.stderr)
+        ));
+    }
+    ip("link del probe");
+}
+
+pub fn exists(ifname: &str) -> bool {
+    Path::new("/sys/class/net").join(ifname).exists()
+}
+
+pub fn mac(ifname: &str) -> String {
+    let path = Path::new("/sys/class/net").join(ifname).join("address");
+    std::fs::read_to_string(path).unwrap().trim().to_string()
+}
+
+/// A config file for the test, in the registry directory.
+pub fn config_file(yaml: &str) -> PathBuf {
+    let path = PathBuf::from(std::env::var("TSN_REGISTRY_DIR").unwrap()).join("config.yaml");
+    std::fs::write(&path, yaml).unwrap();
+    path
+}
+
+/// Wait for `cond`, for at most `timeout`.
+pub fn wait_for(timeout: Duration, mut cond: impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if cond() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    cond()
+}
+
+/// Wait for `child` to exit, killing it after `timeout`.
+pub fn wait_timeout(child: &mut Child, timeout: Duration) -> Option<ExitStatus> {
+    if wait_for(timeout, || matches!(child.try_wait(), Ok(Some(_)))) {
+        return child.wait().ok();
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    None
+}
+
+pub fn interrupt(child: &Child) {
+    unsafe { libc::kill(child.id() as i32, libc::SIGINT) };
+}
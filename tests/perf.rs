@@ -0,0 +1,96 @@
+//! The latency and throughput tools against each other over a veth pair.
+
+mod common;
+
+use common::{config_file, exists, in_netns, interrupt, mac, require_vlan, wait_for, wait_timeout};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+const CONFIG: &str = "
+nics:
+  veth0:
+    egress-qos-map:
+      10: {3: 3}
+  veth1:
+    egress-qos-map:
+      10: {3: 3}
+";
+
+/// Start the server of `tool` on veth1, and wait for its VLAN.
+fn server(tool: &str) -> Child {
+    require_vlan();
+    let mut server = Command::new(tool)
+        .args(["server", "-i", "veth1"])
+        .env("CONFIG_PATH", config_file(CONFIG))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let up = wait_for(Duration::from_secs(5), || {
+        exists("veth1.10") || matches!(server.try_wait(), Ok(Some(_)))
+    });
+    if !up || !exists("veth1.10") {
+        let _ = server.kill();
+        let output = server.wait_with_output().unwrap();
+        panic!(
+            "the server could not open its socket: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    server
+}
+
+/// Stop `server` and check that it removed its VLAN.
+fn stop(server: Child) {
+    let mut server = server;
+    interrupt(&server);
+    let status = wait_timeout(&mut server, Duration::from_secs(10));
+    assert!(status.is_some(), "the server did not stop");
+    assert!(!exists("veth1.10"), "the server left its vlan behind");
+}
+
+#[test]
+fn latency_ping_pong() {
+    in_netns("latency_ping_pong", || {
+        let server = server(env!("CARGO_BIN_EXE_latency"));
+        let client = Command::new(env!("CARGO_BIN_EXE_latency"))
+            .args(["client", "-i", "veth0", "-t", &mac("veth1"), "-c", "5"])
+            .args(["--interval", "10000000"])
+            .env("CONFIG_PATH", config_file(CONFIG))
+            .output()
+            .unwrap();
+        assert!(client.status.success(), "{:?}", client);
+        let stdout = String::from_utf8_lossy(&client.stdout);
+        let pongs: Vec<&str> = stdout
+            .lines()
+            .filter(|line| line.ends_with(" ns"))
+            .collect();
+        assert_eq!(pongs.len(), 5, "{}", stdout);
+        assert!(!exists("veth0.10"), "the client left its vlan behind");
+        stop(server);
+    });
+}
+
+#[test]
+fn throughput_runs() {
+    in_netns("throughput_runs", || {
+        let server = server(env!("CARGO_BIN_EXE_throughput"));
+        let mut client = Command::new(env!("CARGO_BIN_EXE_throughput"))
+            .args(["client", "-i", "veth0", "-t", &mac("veth1"), "-d", "1"])
+            .env("CONFIG_PATH", config_file(CONFIG))
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let status = wait_timeout(&mut client, Duration::from_secs(30));
+        assert!(
+            status.is_some_and(|status| status.success()),
+            "{:?}",
+            status
+        );
+        let output = client.wait_with_output().unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("Sending data"), "{}", stdout);
+        assert!(stdout.contains("Requesting end"), "{}", stdout);
+        stop(server);
+    });
+}
@@ -0,0 +1,517 @@
+//! `TsnSocket`s on a veth pair: VLAN refcounting, qdiscs and what goes on the
+//! wire.
+
+mod common;
+
+use common::{exists, in_netns, mac, require};
+use std::collections::HashMap;
+use std::mem;
+use std::time::Duration;
+use tsn::batch::RecvSlot;
+use tsn::config::{parse_config, Config};
+use tsn::ring::{RxRingConfig, TxRingConfig};
+use tsn::rxtime::{PacketType, RxTimestampMode};
+use tsn::schema::Format;
+use tsn::state::{self, QdiscOptions};
+use tsn::xdp::XdpConfig;
+use tsn::{TsnError, TsnSocket};
+
+const ETHERTYPE: u16 = 0x1337;
+
+/// Only the egress-qos-map, no qdisc.
+const PLAIN: &str = "
+nics:
+  veth0:
+    egress-qos-map:
+      10: {3: 3, 5: 5}
+  veth1:
+    egress-qos-map:
+      10: {3: 3}
+";
+
+/// taprio run by the kernel with cbs on the traffic class of prio 3.
+const TAS_CBS: &str = "
+nics:
+  veth0:
+    egress-qos-map:
+      10: {3: 3, 2: 2}
+    tas:
+      mode: software
+      schedule:
+        - {time: 300us, prio: [3]}
+        - {time: 200us, prio: [2]}
+        - {time: 500us, prio: [-1]}
+    cbs:
+      3: {class: a, max_frame: 1522B, bandwidth: 100Mbps}
+  veth1:
+    egress-qos-map:
+      10: {3: 3}
+";
+
+fn config(yaml: &str, ifname: &str) -> Config {
+    let mut configs = parse_config(yaml, Format::Yaml).unwrap();
+    configs.remove(ifname).unwrap()
+}
+
+fn open(yaml: &str, ifname: &str, priority: u32) -> Result<TsnSocket, TsnError> {
+    TsnSocket::builder(ifname)
+        .vlan(10)
+        .priority(priority)
+        .protocol(ETHERTYPE)
+        .config(config(yaml, ifname))
+        .open()
+}
+
+fn frame(dst: &str, src: &str, payload: &[u8]) -> Vec<u8> {
+    let mac = |mac: &str| -> Vec<u8> {
+        mac.split(':')
+            .map(|byte| u8::from_str_radix(byte, 16).unwrap())
+            .collect()
+    };
+    let mut frame = mac(dst);
+    frame.extend(mac(src));
+    frame.extend(ETHERTYPE.to_be_bytes());
+    frame.extend(payload);
+    frame.resize(frame.len().max(60), 0);
+    frame
+}
+
+/// `struct tpacket_auxdata`
+#[repr(C)]
+#[derive(Default)]
+struct AuxData {
+    status: u32,
+    len: u32,
+    snaplen: u32,
+    mac: u16,
+    net: u16,
+    vlan_tci: u16,
+    vlan_tpid: u16,
+}
+
+const PACKET_AUXDATA: libc::c_int = 8;
+const TP_STATUS_VLAN_VALID: u32 = 1 << 4;
+
+/// A raw socket on `ifname` itself, under the VLAN, that sees the tags.
+fn sniffer(ifname: &str) -> i32 {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_PACKET,
+            libc::SOCK_RAW,
+            (libc::ETH_P_ALL as u16).to_be() as i32,
+        )
+    };
+    assert!(fd >= 0);
+    let one: libc::c_int = 1;
+    let timeout = libc::timeval {
+        tv_sec: 1,
+        tv_usec: 0,
+    };
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+    addr.sll_ifindex = nix::net::if_::if_nametoindex(ifname).unwrap() as i32;
+    unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_PACKET,
+            PACKET_AUXDATA,
+            &one as *const _ as *const libc::c_void,
+            mem::size_of_val(&one) as u32,
+        );
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeout as *const _ as *const libc::c_void,
+            mem::size_of_val(&timeout) as u32,
+        );
+        assert_eq!(
+            libc::bind(
+                fd,
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of_val(&addr) as u32,
+            ),
+            0
+        );
+    }
+    fd
+}
+
+/// The VLAN TCI and payload of the next frame of ETHERTYPE on `fd`, whether
+/// the tag is still in the frame or was taken off by the kernel.
+fn next_tagged(fd: i32) -> Option<(u16, Vec<u8>)> {
+    for _ in 0..100 {
+        let mut buf = [0u8; 1522];
+        let mut control = [0u8; 64];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len();
+        let len = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+        if len < 0 {
+            return None;
+        }
+        let frame = &buf[..len as usize];
+        let mut aux = AuxData::default();
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            let hdr = unsafe { &*cmsg };
+            if hdr.cmsg_level == libc::SOL_PACKET && hdr.cmsg_type == PACKET_AUXDATA {
+                aux = unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const AuxData) };
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+        let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+        if aux.status & TP_STATUS_VLAN_VALID != 0 && ethertype == ETHERTYPE {
+            return Some((aux.vlan_tci, frame[14..].to_vec()));
+        }
+        if ethertype == 0x8100 && u16::from_be_bytes([frame[16], frame[17]]) == ETHERTYPE {
+            let tci = u16::from_be_bytes([frame[14], frame[15]]);
+            return Some((tci, frame[18..].to_vec()));
+        }
+    }
+    None
+}
+
+#[test]
+fn vlan_follows_its_sockets() {
+    in_netns("vlan_follows_its_sockets", || {
+        let first = require(open(PLAIN, "veth0", 3), "vlan");
+        let second = open(PLAIN, "veth0", 3).unwrap();
+        assert!(exists("veth0.10"));
+        let registry = tsn::registry::Registry::lock().unwrap();
+        assert_eq!(registry.get("veth0", 10).unwrap().users.len(), 2);
+        drop(registry);
+
+        drop(first);
+        assert!(exists("veth0.10"), "the second socket still needs the vlan");
+        second.close().unwrap();
+        assert!(!exists("veth0.10"));
+        let registry = tsn::registry::Registry::lock().unwrap();
+        assert!(registry.list().unwrap().is_empty());
+        assert!(registry.applied("veth0").unwrap().is_none());
+    });
+}
+
+#[test]
+fn frames_carry_vlan_tag_and_pcp() {
+    in_netns("frames_carry_vlan_tag_and_pcp", || {
+        let sniffer = sniffer("veth1");
+        let tx = require(open(PLAIN, "veth0", 3), "vlan");
+        let mut rx = open(PLAIN, "veth1", 0).unwrap();
+        rx.set_timeout(Duration::from_secs(1)).unwrap();
+
+        let sent = frame(&mac("veth1"), &mac("veth0"), b"libtsn");
+        tx.send(&sent).unwrap();
+
+        let (tci, payload) = next_tagged(sniffer).expect("no tagged frame on veth1");
+        assert_eq!(tci & 0xfff, 10, "vlan id");
+        assert_eq!(tci >> 13, 3, "pcp of prio 3 in the egress-qos-map");
+        assert_eq!(&payload[..6], b"libtsn");
+
+        let mut buf = [0u8; 1514];
+        let len = rx.recv(&mut buf).unwrap() as usize;
+        assert_eq!(&buf[..len], &sent[..]);
+
+        // A prio missing from the egress-qos-map goes out with pcp 0
+        let tx0 = open(PLAIN, "veth0", 1).unwrap();
+        tx0.send(&sent).unwrap();
+        let (tci, _) = next_tagged(sniffer).expect("no tagged frame on veth1");
+        assert_eq!(tci >> 13, 0);
+        unsafe { libc::close(sniffer) };
+    });
+}
+
+#[test]
+fn batches_of_frames() {
+    in_netns("batches_of_frames", || {
+        let tx = require(open(PLAIN, "veth0", 3), "vlan");
+        let mut rx = open(PLAIN, "veth1", 0).unwrap();
+        rx.set_timeout(Duration::from_secs(1)).unwrap();
+
+        let frames: Vec<Vec<u8>> = (0..100u8)
+            .map(|i| frame(&mac("veth1"), &mac("veth0"), &[b'b', i]))
+            .collect();
+        let batch: Vec<&[u8]> = frames.iter().map(|frame| frame.as_slice()).collect();
+        assert_eq!(tx.send_batch(&batch).unwrap(), 100);
+
+        let mut slots = vec![RecvSlot::new(1514); 32];
+        let mut received = Vec::new();
+        while received.len() < 100 {
+            let n = rx.recv_batch(&mut slots).expect("frames missing");
+            assert!(n <= slots.len());
+            for slot in &slots[..n] {
+                assert!(!slot.truncated());
+                assert_eq!(slot.len, 60);
+                assert_eq!(slot.source, frames[0][6..12]);
+                received.push(slot.frame()[15]);
+            }
+        }
+        assert_eq!(received, (0..100u8).collect::<Vec<_>>());
+
+        // A frame larger than its slot
+        tx.send(&frame(&mac("veth1"), &mac("veth0"), &[0; 200]))
+            .unwrap();
+        let mut small = [RecvSlot::new(64)];
+        assert_eq!(rx.recv_batch(&mut small).unwrap(), 1);
+        assert!(small[0].truncated());
+        assert_eq!((small[0].len, small[0].frame().len()), (214, 64));
+    });
+}
+
+#[test]
+fn rings_of_frames() {
+    in_netns("rings_of_frames", || {
+        let tx = require(open(PLAIN, "veth0", 3), "vlan");
+        let rx = open(PLAIN, "veth1", 0).unwrap();
+
+        let frames: Vec<Vec<u8>> = (0..200u8)
+            .map(|i| frame(&mac("veth1"), &mac("veth0"), &[b'r', i]))
+            .collect();
+        let batch: Vec<&[u8]> = frames.iter().map(|frame| frame.as_slice()).collect();
+        {
+            let mut rx_ring = rx.rx_ring(RxRingConfig::default()).unwrap();
+            let mut tx_ring = tx.tx_ring(TxRingConfig::default()).unwrap();
+            assert!(tx_ring.push(&vec![0; tx_ring.max_frame() + 1]).is_err());
+            tx_ring.send(&batch).unwrap();
+
+            let mut received = Vec::new();
+            while received.len() < 200 {
+                let block = rx_ring
+                    .next_block(Some(Duration::from_secs(1)))
+                    .unwrap()
+                    .expect("frames missing");
+                assert_eq!(block.frames().count(), block.len());
+                for frame in block.frames() {
+                    assert_eq!(frame.len, 60);
+                    assert_eq!(frame.data[6..12], frames[0][6..12]);
+                    assert!(frame.timestamp.tv_sec > 0);
+                    received.push(frame.data[15]);
+                }
+            }
+            assert_eq!(received, (0..200u8).collect::<Vec<_>>());
+        }
+
+        // Without the rings the socket is as it was
+        let mut rx = rx;
+        rx.set_timeout(Duration::from_secs(1)).unwrap();
+        tx.send(&frames[7]).unwrap();
+        let mut buf = [0; 1514];
+        assert_eq!(rx.recv(&mut buf).unwrap(), 60);
+        assert_eq!(buf[15], 7);
+    });
+}
+
+#[test]
+fn af_xdp_frames() {
+    in_netns("af_xdp_frames", || {
+        let xdp = |ifname: &str, priority| {
+            TsnSocket::builder(ifname)
+                .vlan(10)
+                .priority(priority)
+                .protocol(ETHERTYPE)
+                .config(config(PLAIN, ifname))
+                .xdp(XdpConfig::default())
+                .open()
+        };
+        let sniffer = sniffer("veth1");
+        let tx = require(xdp("veth0", 3), "vlan or generic XDP");
+
+        // Tagged as by the VLAN interface
+        let sent = frame(&mac("veth1"), &mac("veth0"), b"xdp");
+        tx.send(&sent).unwrap();
+        let (tci, payload) = next_tagged(sniffer).expect("no tagged frame on veth1");
+        assert_eq!(tci & 0xfff, 10, "vlan id");
+        assert_eq!(tci >> 13, 3, "pcp of prio 3 in the egress-qos-map");
+        assert_eq!(&payload[..3], b"xdp");
+
+        let mut rx = xdp("veth1", 0).unwrap();
+        rx.set_timeout(Duration::from_secs(1)).unwrap();
+        let frames: Vec<Vec<u8>> = (0..100u8)
+            .map(|i| frame(&mac("veth1"), &mac("veth0"), &[b'x', i]))
+            .collect();
+        let batch: Vec<&[u8]> = frames.iter().map(|frame| frame.as_slice()).collect();
+        assert_eq!(tx.send_batch(&batch).unwrap(), 100);
+        let mut slots = vec![RecvSlot::new(1514); 32];
+        let mut received = Vec::new();
+        while received.len() < 100 {
+            let n = rx.recv_batch(&mut slots).expect("frames missing");
+            for slot in &slots[..n] {
+                assert_eq!(slot.frame(), frames[received.len()]);
+                received.push(slot.frame()[15]);
+            }
+        }
+        assert_eq!(received, (0..100u8).collect::<Vec<_>>());
+        assert!(
+            next_tagged(sniffer).is_none(),
+            "the frames of the socket skip the stack"
+        );
+        assert!(rx.recv(&mut [0; 1514]).is_err(), "nothing left");
+
+        // The program goes with the socket
+        drop(rx);
+        tx.send(&sent).unwrap();
+        assert!(next_tagged(sniffer).is_some());
+        unsafe { libc::close(sniffer) };
+    });
+}
+
+#[test]
+fn rx_timestamps() {
+    in_netns("rx_timestamps", || {
+        let tx = require(open(PLAIN, "veth0", 3), "vlan");
+        let mut rx = open(PLAIN, "veth1", 0).unwrap();
+        rx.set_timeout(Duration::from_secs(1)).unwrap();
+        assert!(
+            rx.enable_rx_timestamp(RxTimestampMode::Hardware).is_err(),
+            "veth has no hardware timestamps"
+        );
+        rx.enable_rx_timestamp(RxTimestampMode::Software).unwrap();
+
+        let sent = frame(&mac("veth1"), &mac("veth0"), b"rx timestamp");
+        tx.send(&sent).unwrap();
+        let mut buf = [0u8; 1514];
+        let (len, meta) = rx.recv_with_timestamp(&mut buf).unwrap();
+        assert_eq!(&buf[..len as usize], &sent[..]);
+        assert!(meta.software.expect("no software timestamp").tv_sec > 0);
+        assert!(meta.hardware.is_none() && meta.raw_hardware.is_none());
+        assert_eq!(meta.source, sent[6..12]);
+        assert_eq!(meta.packet_type, PacketType::Host);
+        assert_eq!(meta.vlan, None, "the VLAN interface took the tag off");
+
+        let broadcast = frame("ff:ff:ff:ff:ff:ff", &mac("veth0"), b"all");
+        tx.send(&broadcast).unwrap();
+        let (_, meta) = rx.recv_with_timestamp(&mut buf).unwrap();
+        assert_eq!(meta.packet_type, PacketType::Broadcast);
+    });
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn async_frames() {
+    in_netns("async_frames", || {
+        let tx = require(open(PLAIN, "veth0", 3), "vlan");
+        let rx = open(PLAIN, "veth1", 0).unwrap();
+        tx.enable_tx_timestamp().unwrap();
+        rx.enable_rx_timestamp(RxTimestampMode::Software).unwrap();
+        let sent = frame(&mac("veth1"), &mac("veth0"), b"async");
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let tx = tsn::AsyncTsnSocket::new(tx).unwrap();
+            let rx = tsn::AsyncTsnSocket::new(rx).unwrap();
+            let mut buf = [0u8; 1514];
+            let (received, sent_len, tx_timestamp) = tokio::join!(
+                tokio::time::timeout(Duration::from_secs(1), rx.recv_with_timestamp(&mut buf)),
+                tx.send(&sent),
+                tokio::time::timeout(Duration::from_secs(1), tx.tx_timestamp()),
+            );
+            assert_eq!(sent_len.unwrap(), sent.len() as isize);
+            let (len, meta) = received.expect("no frame").unwrap();
+            assert_eq!(&buf[..len as usize], &sent[..]);
+            let rx_timestamp = meta.software.expect("no rx timestamp");
+            let tx_timestamp = tx_timestamp.expect("no tx timestamp").unwrap();
+            assert!(tx_timestamp.tv_sec > 0);
+            assert!(
+                (rx_timestamp.tv_sec, rx_timestamp.tv_nsec)
+                    >= (tx_timestamp.tv_sec, tx_timestamp.tv_nsec)
+            );
+
+            // Nothing waiting: the wait is for the reactor, not a thread
+            let idle = tokio::time::timeout(Duration::from_millis(100), rx.recv(&mut buf)).await;
+            assert!(idle.is_err());
+
+            let mut rx = rx.into_inner().unwrap();
+            rx.set_timeout(Duration::from_millis(100)).unwrap();
+            assert!(rx.recv(&mut buf).is_err(), "blocking again, with a timeout");
+        });
+    });
+}
+
+#[test]
+fn tas_and_cbs_in_software() {
+    in_netns("tas_and_cbs_in_software", || {
+        let config = config(TAS_CBS, "veth0");
+        let tx = require(open(TAS_CBS, "veth0", 3), "vlan, taprio or cbs");
+
+        let live = state::read("veth0").unwrap();
+        let root = live.qdisc(0xffff_ffff).expect("no root qdisc");
+        assert_eq!(root.kind, "taprio");
+        let cbs = live
+            .qdisc(0x100_0001)
+            .expect("no cbs on the class of prio 3");
+        assert!(matches!(
+            cbs.options,
+            QdiscOptions::Cbs { offload: false, .. }
+        ));
+        assert_eq!(live.drift(&config), Vec::<String>::new());
+
+        // Frames go through the gates
+        let sniffer = sniffer("veth1");
+        let sent = frame(&mac("veth1"), &mac("veth0"), b"gated");
+        for _ in 0..10 {
+            tx.send(&sent).unwrap();
+        }
+        let (tci, _) = next_tagged(sniffer).expect("no frame through taprio");
+        assert_eq!(tci >> 13, 3);
+        unsafe { libc::close(sniffer) };
+
+        tx.close().unwrap();
+        assert!(!exists("veth0.10"));
+        let live = state::read("veth0").unwrap();
+        let kinds: HashMap<u32, String> = live
+            .qdiscs
+            .iter()
+            .map(|qdisc| (qdisc.parent, qdisc.kind.clone()))
+            .collect();
+        assert_ne!(
+            kinds.get(&0xffff_ffff).map(|kind| kind.as_str()),
+            Some("taprio")
+        );
+    });
+}
+
+#[test]
+fn qdiscs_stay_for_the_other_vlans() {
+    in_netns("qdiscs_stay_for_the_other_vlans", || {
+        let yaml = TAS_CBS.replace("10: {3: 3, 2: 2}", "10: {3: 3, 2: 2}\n      20: {3: 3}");
+        let open = |vlanid| {
+            TsnSocket::builder("veth0")
+                .vlan(vlanid)
+                .priority(3)
+                .protocol(ETHERTYPE)
+                .config(config(&yaml, "veth0"))
+                .open()
+        };
+        let ten = require(open(10), "vlan, taprio or cbs");
+        let twenty = open(20).unwrap();
+
+        ten.close().unwrap();
+        assert!(!exists("veth0.10"));
+        let live = state::read("veth0").unwrap();
+        let root = live
+            .qdisc(0xffff_ffff)
+            .expect("vlan 20 lost the root qdisc");
+        assert_eq!(root.kind, "taprio");
+        let registry = tsn::registry::Registry::lock().unwrap();
+        assert!(registry.applied("veth0").unwrap().is_some());
+        drop(registry);
+
+        twenty.close().unwrap();
+        assert!(!exists("veth0.20"));
+        let live = state::read("veth0").unwrap();
+        let root = live.qdisc(0xffff_ffff).map(|qdisc| qdisc.kind.as_str());
+        assert_ne!(root, Some("taprio"));
+        let registry = tsn::registry::Registry::lock().unwrap();
+        assert!(registry.applied("veth0").unwrap().is_none());
+    });
+}